@@ -0,0 +1,210 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::{
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use time::OffsetDateTime;
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+use crate::events::Envelope;
+
+// Lines queued for the writer thread. A disk that can't keep up costs log
+// lines (counted in `dropped`), never memory or connection latency.
+const QUEUE_LINES: usize = 8192;
+
+// When the access log moves on to a new file. With `daily` each UTC day
+// gets its own `<path>.YYYY-MM-DD`; with `max_size` a file that reaches it
+// is renamed to `.1` (older ones shifting up to `.keep`) and a fresh one
+// started. Daily files are never deleted here.
+#[derive(Clone, Copy)]
+pub struct Rotation {
+    pub max_size: Option<u64>,
+    pub keep: usize,
+    pub daily: bool,
+}
+
+enum Message {
+    Line(Vec<u8>),
+    Flush(oneshot::Sender<()>),
+}
+
+// Appends one JSON line per closed or blocked connection (--access-log),
+// independent of history trimming. Callers only queue the line; a writer
+// thread does the I/O and flushes whenever the queue runs dry, so
+// recording a connection never waits on the disk.
+#[derive(Clone, Default)]
+pub struct AccessLog {
+    sender: Option<mpsc::Sender<Message>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AccessLog {
+    pub fn open(path: Option<&Path>, rotation: Rotation) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let mut writer = Writer {
+            path: path.to_path_buf(),
+            rotation,
+            current: PathBuf::new(),
+            file: None,
+            size: 0,
+        };
+        writer
+            .prepare()
+            .map_err(|err| anyhow!("Access log {}: {}", writer.current.display(), err))?;
+        let (sender, receiver) = mpsc::channel(QUEUE_LINES);
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || writer.run(receiver))?;
+        Ok(Self {
+            sender: Some(sender),
+            dropped: Arc::default(),
+        })
+    }
+
+    pub fn write<T: Serialize>(&self, event: &str, payload: &T) {
+        let Some(sender) = self.sender.as_ref() else {
+            return;
+        };
+        if let Ok(mut line) = serde_json::to_vec(&Envelope { event, payload }) {
+            line.push(b'\n');
+            if sender.try_send(Message::Line(line)).is_err() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // Lines lost since start because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // Resolves once everything queued before the call is on disk.
+    pub async fn flush(&self) {
+        let Some(sender) = self.sender.as_ref() else {
+            return;
+        };
+        let (done, flushed) = oneshot::channel();
+        if sender.send(Message::Flush(done)).await.is_ok() {
+            let _ = flushed.await;
+        }
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    rotation: Rotation,
+    // File lines go to right now: `path`, or today's file with `daily`.
+    current: PathBuf,
+    file: Option<BufWriter<File>>,
+    size: u64,
+}
+
+impl Writer {
+    fn run(mut self, mut receiver: mpsc::Receiver<Message>) {
+        while let Some(message) = receiver.blocking_recv() {
+            self.handle(message);
+            while let Ok(message) = receiver.try_recv() {
+                self.handle(message);
+            }
+            self.flush();
+        }
+    }
+
+    fn handle(&mut self, message: Message) {
+        match message {
+            Message::Line(line) => self.write(&line),
+            Message::Flush(done) => {
+                self.flush();
+                let _ = done.send(());
+            }
+        }
+    }
+
+    // A line that can't be written is dropped with a warning; the file is
+    // reopened for the next one.
+    fn write(&mut self, line: &[u8]) {
+        let result = self.prepare().and_then(|()| match self.file.as_mut() {
+            Some(file) => file.write_all(line),
+            None => Ok(()),
+        });
+        match result {
+            Ok(()) => self.size += line.len() as u64,
+            Err(err) => {
+                warn!("Access log {}: {}", self.current.display(), err);
+                self.file = None;
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(file) = self.file.as_mut() {
+            if let Err(err) = file.flush() {
+                warn!("Access log {}: {}", self.current.display(), err);
+                self.file = None;
+            }
+        }
+    }
+
+    fn prepare(&mut self) -> io::Result<()> {
+        let target = if self.rotation.daily {
+            suffixed(&self.path, &OffsetDateTime::now_utc().date().to_string())
+        } else {
+            self.path.clone()
+        };
+        if target != self.current {
+            self.close();
+            self.current = target;
+        }
+        if self.file.is_none() {
+            self.open_current()?;
+        }
+        if self.rotation.max_size.is_some_and(|max| self.size >= max) {
+            self.close();
+            self.rotate()?;
+            self.open_current()?;
+        }
+        Ok(())
+    }
+
+    fn open_current(&mut self) -> io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(&self.current)?;
+        self.size = file.metadata()?.len();
+        self.file = Some(BufWriter::new(file));
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.flush();
+        self.file = None;
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        let keep = self.rotation.keep.max(1);
+        for index in (1..keep).rev() {
+            let from = suffixed(&self.current, &index.to_string());
+            let to = suffixed(&self.current, &(index + 1).to_string());
+            match std::fs::rename(from, to) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        std::fs::rename(&self.current, suffixed(&self.current, "1"))
+    }
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
@@ -0,0 +1,52 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const WINDOW: Duration = Duration::from_secs(60);
+const MAX_ENTRIES: usize = 10_000;
+
+struct Window {
+    started: Instant,
+    requests: u32,
+}
+
+// Caps panel API requests per client address in fixed one-minute windows
+// (--api-max-requests-per-minute). A refused request learns how long until
+// its window resets, for the Retry-After header.
+pub struct ApiLimiter {
+    limit: u32,
+    windows: Mutex<HashMap<IpAddr, Window>>,
+}
+
+impl ApiLimiter {
+    pub fn new(limit: u32) -> Self {
+        Self {
+            limit,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap_or_else(|err| err.into_inner());
+        if windows.len() >= MAX_ENTRIES && !windows.contains_key(&ip) {
+            windows.retain(|_, window| now.duration_since(window.started) < WINDOW);
+        }
+        let window = windows.entry(ip).or_insert(Window {
+            started: now,
+            requests: 0,
+        });
+        if now.duration_since(window.started) >= WINDOW {
+            window.started = now;
+            window.requests = 0;
+        }
+        if window.requests >= self.limit {
+            return Err(WINDOW.saturating_sub(now.duration_since(window.started)));
+        }
+        window.requests += 1;
+        Ok(())
+    }
+}
@@ -1,31 +1,64 @@
+use crate::access_log;
+use crate::api_limit::ApiLimiter;
+use crate::authz;
+use crate::balance::{self, BalanceMode};
+use crate::block_reason::{BlockReason, Refused};
+use crate::blocklist_file;
+use crate::events;
 use crate::geo;
 use crate::geo_update;
+use crate::health;
+use crate::http_connect;
+use crate::live;
+use crate::log_buffer;
+use crate::metrics;
+use crate::migrate;
+#[cfg(unix)]
+use crate::panel_socket;
+use crate::panel_socket::PanelAddr;
 use crate::port_range;
 use crate::protocol::ProtocolMode;
+use crate::proxy_protocol::{self, ProxyProtocolMode};
+use crate::rdns;
+use crate::resolve;
+use crate::rules_file;
+use crate::sni;
+use crate::sockopt;
+use crate::talkers;
+use crate::tls;
 use crate::udp_proxy;
+use crate::webhook;
 use anyhow::{anyhow, Result};
 use axum::{
-    body::Body,
-    extract::{ConnectInfo, Path, Query, State},
-    http::{Request, StatusCode},
-    response::{Html, Response},
+    body::{Body, Bytes},
+    extract::{ws::WebSocketUpgrade, ConnectInfo, DefaultBodyLimit, Path, Query, State},
+    http::{header, HeaderName, Request, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
     routing::{delete, get, post},
     Json, Router,
     middleware::{self, Next},
 };
+use futures_util::Stream;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet, VecDeque},
-    net::{IpAddr, SocketAddr},
+    convert::Infallible,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     path::{Path as StdPath, PathBuf},
-    sync::Arc,
-    time::{Duration, Instant},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
-    sync::RwLock,
+    sync::{Notify, RwLock},
     task::JoinHandle,
 };
 use tokio_util::sync::CancellationToken;
@@ -35,7 +68,7 @@ use tracing::{error, info, warn};
 // Middleware функция для проверки IP адреса
 async fn ip_filter_middleware(
     State(config): State<Arc<AppConfig>>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
     request: Request<Body>,
     next: Next<Body>,
 ) -> Result<Response, StatusCode> {
@@ -44,7 +77,13 @@ async fn ip_filter_middleware(
         return Ok(next.run(request).await);
     }
 
-    let client_ip = addr.ip();
+    // Requests over a unix: panel socket carry no client address; whoever
+    // may open the socket file is let in.
+    let Some(ConnectInfo(addr)) = connect_info else {
+        return Ok(next.run(request).await);
+    };
+
+    let client_ip = addr.ip().to_canonical();
     
     // Проверяем каждый IP/сеть в разрешенном списке
     for network in &config.allowed_networks {
@@ -57,6 +96,33 @@ async fn ip_filter_middleware(
     Err(StatusCode::FORBIDDEN)
 }
 
+// Runs after the IP filter, so only permitted clients use up the budget.
+async fn api_limit_middleware(
+    State(limiter): State<Option<Arc<ApiLimiter>>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let (Some(limiter), Some(ConnectInfo(addr))) = (limiter, connect_info) else {
+        return next.run(request).await;
+    };
+    match limiter.check(addr.ip().to_canonical()) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => {
+            let secs = (retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)).max(1);
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, secs.to_string())],
+                Json(ErrorResponse {
+                    code: "rate_limited",
+                    error: format!("Too many API requests, retry in {}s", secs),
+                }),
+            )
+                .into_response()
+        }
+    }
+}
+
 // Функция проверки IP в сети CIDR
 fn is_ip_allowed(ip: IpAddr, network: &str) -> bool {
     if let Some((network_str, mask_str)) = network.split_once('/') {
@@ -90,83 +156,392 @@ fn ip_in_network(ip: IpAddr, network: IpAddr, mask: u8) -> bool {
 }
 
 const STATE_FILE: &str = "state.json";
-const MAX_HISTORY: usize = 10_000;
+const STATE_TMP_EXTENSION: &str = "json.tmp";
+const STATE_BACKUP_EXTENSION: &str = "json.bak";
+// Entries kept in history unless --max-history says otherwise.
+pub const DEFAULT_MAX_HISTORY: usize = 10_000;
+// Caps on rules and on listen addresses after range expansion, so a runaway
+// client can't exhaust file descriptors (--max-rules, --max-listen-ports).
+pub const DEFAULT_MAX_RULES: usize = 1_000;
+pub const DEFAULT_MAX_LISTEN_PORTS: usize = 10_000;
+// Size-rotated access log files kept (--access-log-keep).
+pub const DEFAULT_ACCESS_LOG_KEEP: usize = 5;
+// Upper bound on how many entries one history/recent/blocked request returns.
+const MAX_PAGE_SIZE: usize = 10_000;
+const BLOCK_REAP_INTERVAL: Duration = Duration::from_secs(30);
+const RATE_COUNTER_REAP_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_TARPIT_DELAY: Duration = Duration::from_secs(3);
+const GEO_DB_UPLOAD_LIMIT: usize = 128 * 1024 * 1024;
+// An export carries the whole connection history (skipped on import), which
+// outgrows axum's 2 MB default body limit long before --max-history is
+// reached.
+const IMPORT_BODY_LIMIT: usize = 256 * 1024 * 1024;
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_PERSIST_INTERVAL: Duration = Duration::from_millis(1000);
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// After force-closing, how long connection tasks get to record their end.
+const SHUTDOWN_CLOSE_GRACE: Duration = Duration::from_secs(2);
+// Per-direction relay buffer for TCP connections (--buffer-size).
+pub const DEFAULT_BUFFER_SIZE: usize = 8192;
+pub const MIN_BUFFER_SIZE: usize = 1024;
+pub const MAX_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+// Accept queue per TCP listener (--listen-backlog); room for a burst of
+// connects while the acceptor is busy.
+pub const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+// Upper bound for --history-retention-days (about a century).
+pub const MAX_HISTORY_RETENTION_DAYS: u64 = 36500;
+// Pause after an accept error that didn't cost a connection (e.g. EMFILE), so
+// the listener doesn't spin on a backlog it can't drain.
+pub(crate) const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+// Comment lines sent on an idle /api/events stream so proxies keep it open.
+const SSE_KEEPALIVE: Duration = Duration::from_secs(15);
+// How often changed byte counters are pushed to the live feed.
+const BYTES_PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+// Larger windows get wider buckets rather than more of them.
+const MAX_TIMESERIES_BUCKETS: u64 = 1440;
 
 #[derive(Clone)]
 pub struct AppConfig {
-    pub http_addr: SocketAddr,
+    pub http_addr: PanelAddr,
     pub data_dir: PathBuf,
     pub allowed_networks: Vec<String>,
+    pub geo_update_jitter: f64,
+    pub geo_update_enabled: bool,
+    pub geo_update_interval: Duration,
+    pub geo_db_urls: Vec<String>,
+    pub drain_timeout: Option<Duration>,
+    pub shutdown_timeout: Duration,
+    pub event_socket: Option<PathBuf>,
+    // JSON lines file of closed and blocked connections, never trimmed.
+    pub access_log: Option<PathBuf>,
+    pub access_log_max_size: Option<u64>,
+    pub access_log_keep: usize,
+    pub access_log_daily: bool,
+    pub disable_invalid_rules: bool,
+    pub compact_state: bool,
+    pub connect_timeout: Duration,
+    pub auth_url: Option<String>,
+    pub auth_cache_ttl: Duration,
+    pub auth_timeout: Duration,
+    pub auth_fail_open: bool,
+    pub tcp_idle_timeout: Option<Duration>,
+    pub udp_idle_timeout: Duration,
+    // End a UDP session after this many sends in a row fail; None never does.
+    pub udp_max_send_errors: Option<u32>,
+    // How long resolved target hostnames are reused; zero disables caching.
+    pub dns_cache_ttl: Duration,
+    pub metrics_country_limit: usize,
+    pub asn_db_urls: Vec<String>,
+    // Store only the client's /24 (IPv4) or /64 (IPv6) in the connection log.
+    pub redact_client_ip: bool,
+    // Look up verified reverse DNS names of clients for display.
+    pub resolve_client_hostnames: bool,
+    // Refuse new connections after this many consecutive failed state saves.
+    pub persist_fail_safe: Option<u64>,
+    // Changes are written out at most this often; zero saves after each one.
+    pub persist_interval: Duration,
+    // PEM certificate chain and private key; with both set the panel is
+    // served over HTTPS only.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub webhook_url: Option<String>,
+    // Event types sent to the webhook; empty sends all of them.
+    pub webhook_events: Vec<String>,
+    // A geo/ASN database this old whose refresh fails raises geo_db_stale.
+    pub geo_stale_after: Duration,
+    // Externally maintained IP/CIDR lists, reloaded when they change.
+    pub blocklist_files: Vec<PathBuf>,
+    // Acceptor tasks per TCP listen address, each on its own SO_REUSEPORT
+    // socket; None keeps one plain listener.
+    pub reuseport_acceptors: Option<usize>,
+    // Pending-connection queue requested for each TCP listener.
+    pub listen_backlog: u32,
+    pub buffer_size: usize,
+    pub max_history: usize,
+    pub max_blocked_history: usize,
+    pub max_rules: usize,
+    pub max_listen_ports: usize,
+    // Panel API requests allowed per client address per minute; over it the
+    // API answers 429 with Retry-After.
+    pub api_max_requests_per_minute: Option<u32>,
+    // TOML file of rules kept in sync with the running set.
+    pub rules_file: Option<PathBuf>,
+    // History entries older than this are dropped even under max_history.
+    pub history_retention: Option<Duration>,
 }
 
 impl AppConfig {
     pub fn new(http_addr: &str, data_dir: &str, allowed_networks: Vec<String>) -> Result<Self> {
-        let http_addr: SocketAddr = http_addr
-            .parse()
-            .map_err(|_| anyhow!("Invalid http-addr: {}", http_addr))?;
+        let http_addr = PanelAddr::parse(http_addr)?;
         Ok(Self {
             http_addr,
             data_dir: PathBuf::from(data_dir),
             allowed_networks,
+            geo_update_jitter: 0.1,
+            geo_update_enabled: true,
+            geo_update_interval: geo_update::DEFAULT_UPDATE_INTERVAL,
+            geo_db_urls: geo_update::DEFAULT_GEO_URLS.iter().map(|url| url.to_string()).collect(),
+            drain_timeout: None,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            event_socket: None,
+            access_log: None,
+            access_log_max_size: None,
+            access_log_keep: DEFAULT_ACCESS_LOG_KEEP,
+            access_log_daily: false,
+            disable_invalid_rules: false,
+            compact_state: false,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            auth_url: None,
+            auth_cache_ttl: Duration::from_secs(30),
+            auth_timeout: Duration::from_millis(500),
+            auth_fail_open: false,
+            tcp_idle_timeout: None,
+            udp_idle_timeout: udp_proxy::DEFAULT_UDP_IDLE_TIMEOUT,
+            udp_max_send_errors: Some(udp_proxy::DEFAULT_UDP_MAX_SEND_ERRORS),
+            dns_cache_ttl: resolve::DEFAULT_TTL,
+            metrics_country_limit: 20,
+            asn_db_urls: geo_update::DEFAULT_ASN_URLS.iter().map(|url| url.to_string()).collect(),
+            redact_client_ip: false,
+            resolve_client_hostnames: false,
+            persist_fail_safe: None,
+            persist_interval: DEFAULT_PERSIST_INTERVAL,
+            tls_cert: None,
+            tls_key: None,
+            webhook_url: None,
+            webhook_events: Vec::new(),
+            geo_stale_after: Duration::from_secs(3 * 24 * 60 * 60),
+            blocklist_files: Vec::new(),
+            reuseport_acceptors: None,
+            listen_backlog: DEFAULT_LISTEN_BACKLOG,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            max_history: DEFAULT_MAX_HISTORY,
+            max_blocked_history: DEFAULT_MAX_HISTORY,
+            max_rules: DEFAULT_MAX_RULES,
+            max_listen_ports: DEFAULT_MAX_LISTEN_PORTS,
+            api_max_requests_per_minute: None,
+            rules_file: None,
+            history_retention: None,
         })
     }
 }
 
 pub async fn run_app(config: AppConfig, shutdown: CancellationToken) -> Result<()> {
-    let state = Arc::new(RwLock::new(load_state(&config.data_dir).await?));
-    geo_update::start_geo_updater(state.clone(), config.data_dir.clone());
+    let state = Arc::new(RwLock::new(load_state(&config).await?));
+    start_state_writer(state.clone());
+    geo_update::start_geo_updater(state.clone(), &config);
+    start_block_reaper(state.clone());
+    start_rate_counter_reaper(state.clone());
+    start_bytes_publisher(state.clone());
+    blocklist_file::start_reloader(state.clone(), config.blocklist_files.clone());
+    if let Some(path) = config.rules_file.as_ref() {
+        // Nothing is listening yet, so the plan's starts and stops are
+        // covered by starting every enabled rule below.
+        let summary = load_rules_file(&state, path).await?.summary();
+        info!("Loaded rules file {}: {}", path.display(), summary);
+        rules_file::start_reloader(state.clone(), path.clone());
+    }
 
     let rules_to_start = {
         let guard = state.read().await;
-        guard
-            .rules
-            .iter()
+        ordered_rules(&guard.rules)
+            .into_iter()
             .filter(|rule| rule.enabled)
             .cloned()
             .collect::<Vec<_>>()
     };
 
     for rule in rules_to_start {
-        if let Err(err) = start_rule_listeners(&state, &rule).await {
+        if let Err(err) = start_rule_listeners_with(&state, &rule, UnresolvedTarget::Warn).await {
             warn!(
                 "Failed to start listener {} -> {}: {}",
                 rule.listen_addr, rule.target_addr, err
             );
-            disable_rule_after_start_failure(&state, rule.id).await;
+            disable_rule_after_start_failure(&state, &rule, &err).await;
         }
     }
 
+    reload_on_sighup(state.clone())?;
+    let drain = tokio::spawn(drain_on_shutdown(state.clone(), shutdown.clone()));
+    let panel_tls = state.read().await.tls.clone();
     let app = build_router(state, Arc::new(config.clone()));
-    info!("Web panel listening on {}", config.http_addr);
-    axum::Server::bind(&config.http_addr)
+    let served = serve_panel(&config, app, panel_tls, shutdown.clone()).await;
+    // A panel that failed to start takes the proxies down with it.
+    shutdown.cancel();
+    let _ = drain.await;
+    served
+}
+
+async fn serve_panel(
+    config: &AppConfig,
+    app: Router,
+    panel_tls: Option<Arc<tls::PanelTls>>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let http_addr = match &config.http_addr {
+        PanelAddr::Tcp(addr) => *addr,
+        #[cfg(unix)]
+        PanelAddr::Unix(path) => {
+            if panel_tls.is_some() {
+                return Err(anyhow!("--tls-cert cannot be used with a unix: --http-addr"));
+            }
+            info!("Web panel listening on {}", config.http_addr);
+            return panel_socket::serve(path, app, shutdown).await;
+        }
+    };
+    if let Some(panel_tls) = panel_tls {
+        tls::start_reloader(panel_tls.clone());
+        info!("Web panel listening on {} (HTTPS)", http_addr);
+        return tls::serve(http_addr, app, panel_tls, shutdown).await;
+    }
+    info!("Web panel listening on {}", http_addr);
+    axum::Server::bind(&http_addr)
         .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(shutdown.cancelled())
         .await?;
     Ok(())
 }
 
+// Once shutdown is requested: stops every listener, gives open connections
+// up to --shutdown-timeout to finish, force-closes the rest and saves the
+// final state, so a service stop never cuts transfers off unannounced or
+// loses their history entries.
+async fn drain_on_shutdown(state: Arc<RwLock<AppState>>, shutdown: CancellationToken) {
+    shutdown.cancelled().await;
+    let (rule_ids, health_checks, timeout) = {
+        let mut guard = state.write().await;
+        let rule_ids = guard
+            .listeners
+            .keys()
+            .chain(guard.udp_listeners.keys())
+            .copied()
+            .collect::<HashSet<_>>();
+        let health_checks = guard.health_checks.drain().map(|(_, handle)| handle).collect::<Vec<_>>();
+        (rule_ids, health_checks, guard.config.shutdown_timeout)
+    };
+    for handle in health_checks {
+        handle.shutdown.cancel();
+        handle.task.abort();
+    }
+    // UDP has no connection to finish: stopping its listeners ends every
+    // session right away.
+    for rule_id in rule_ids {
+        stop_tcp_listener(&state, rule_id).await;
+        stop_udp_listener(&state, rule_id).await;
+    }
+
+    let open = state.read().await.active.len();
+    if open > 0 {
+        info!(
+            "Shutting down: waiting up to {}s for {} open connections",
+            timeout.as_secs(),
+            open
+        );
+    }
+    if wait_for_connections(&state, timeout).await > 0 {
+        let tokens = {
+            let mut guard = state.write().await;
+            let mut tokens = std::mem::take(&mut guard.draining_tokens);
+            tokens.extend(guard.connection_tokens.drain().map(|(_, token)| token));
+            tokens
+        };
+        for token in tokens {
+            token.cancel();
+        }
+        let left = wait_for_connections(&state, SHUTDOWN_CLOSE_GRACE).await;
+        if left > 0 {
+            warn!("{} connections still open at exit", left);
+        }
+    }
+
+    if let Err(err) = save_state(&state).await {
+        error!("Failed to save state at shutdown: {}", err);
+    }
+    let access_log = state.read().await.access_log.clone();
+    access_log.flush().await;
+}
+
+// Returns how many connections are still open when `timeout` runs out.
+async fn wait_for_connections(state: &Arc<RwLock<AppState>>, timeout: Duration) -> usize {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let open = state.read().await.active.len();
+        if open == 0 || Instant::now() >= deadline {
+            return open;
+        }
+        tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+    }
+}
+
 fn build_router(state: Arc<RwLock<AppState>>, config: Arc<AppConfig>) -> Router {
     Router::new()
         .route("/", get(index))
         .route("/api/status", get(status))
+        .route("/api/config", get(effective_config))
+        .route("/metrics", get(metrics_endpoint))
+        .route("/api/metrics/timeseries", get(metrics_timeseries))
+        .route(
+            "/api/debug/consistency",
+            get(check_consistency).post(repair_consistency),
+        )
         .route("/api/rules", get(list_rules).post(create_rule))
+        .route("/api/rules/enable-all", post(enable_all_rules))
+        .route("/api/rules/disable-all", post(disable_all_rules))
+        .route("/api/rules/reorder", post(reorder_rules))
+        .route("/api/export", get(export_state))
+        .route(
+            "/api/import",
+            post(import_state).layer(DefaultBodyLimit::max(IMPORT_BODY_LIMIT)),
+        )
         .route("/api/rules/:id/enable", post(enable_rule))
         .route("/api/rules/:id/disable", post(disable_rule))
         .route("/api/rules/:id", delete(remove_rule).put(update_rule))
+        .route("/api/rules/:id/stats", get(rule_stats))
+        .route("/api/rules/:id/listeners", get(rule_listeners))
+        .route("/api/targets/health", get(targets_health))
         .route("/api/active", get(active_connections))
+        .route("/api/ws", get(live_updates))
+        .route("/api/events", get(event_stream))
+        .route("/api/tls/reload", post(reload_tls))
         .route("/api/recent", get(recent_connections))
         .route("/api/ddos", get(ddos_list))
+        .route("/api/top-talkers", get(top_talkers))
         .route("/api/blocked", get(blocked_connections))
-        .route("/api/history", get(history))
+        .route("/api/blocked/categories", get(blocked_categories))
+        .route("/api/history", get(history).delete(clear_history))
         .route("/api/blocklist", get(blocklist).post(add_block))
         .route("/api/blocklist/:ip", delete(remove_block))
+        .route("/api/blocklist-files", get(blocklist_files))
         .route("/api/geo-blocklist", get(geo_blocklist).post(add_geo_block))
         .route("/api/geo-blocklist/:country", delete(remove_geo_block))
+        .route(
+            "/api/geo-db",
+            post(upload_geo_db).layer(DefaultBodyLimit::max(GEO_DB_UPLOAD_LIMIT)),
+        )
+        .route("/api/asn-blocklist", get(asn_blocklist).post(add_asn_block))
+        .route("/api/asn-blocklist/:asn", delete(remove_asn_block))
         .route("/api/allowlist", get(allowlist).post(add_allow))
         .route("/api/allowlist/:ip", delete(remove_allow))
         .route("/api/allowlist-mode", get(allowlist_mode).post(update_allowlist_mode))
+        .route("/api/check-ip/:ip", get(check_ip))
         .route("/api/rate-limit", get(rate_limit).post(update_rate_limit))
+        .route("/api/rate-limit/ports", get(port_rate_limits))
+        .route(
+            "/api/rate-limit/port/:port",
+            post(update_port_rate_limit).delete(remove_port_rate_limit),
+        )
+        .route("/api/log", get(log_entries).delete(clear_log))
+        .layer(middleware::from_fn_with_state(
+            config.api_max_requests_per_minute.map(|limit| Arc::new(ApiLimiter::new(limit))),
+            api_limit_middleware,
+        ))
         .layer(middleware::from_fn_with_state(config.clone(), ip_filter_middleware))
+        // Added after the IP filter so load balancer health checks reach it
+        // from any address.
+        .route("/healthz", get(healthz))
         .layer(CorsLayer::permissive())
         .with_state(state)
 }
@@ -180,6 +555,317 @@ struct ProxyRule {
     created_at: String,
     #[serde(default)]
     protocol: ProtocolMode,
+    // Throughput cap applied to each direction separately; None means unlimited.
+    #[serde(default)]
+    max_bytes_per_sec: Option<u64>,
+    #[serde(default)]
+    log_five_tuple: bool,
+    // Whether closed connections go into the history and the access log. The
+    // event socket and live feed still get every close, to match the open
+    // and bytes events they already had. Rule stats and top talkers count
+    // unlogged connections too, but rule stats are rebuilt from history on
+    // load, so those counts are lost on restart.
+    #[serde(default = "default_true")]
+    log_connections: bool,
+    // The same for blocked attempts, which have no open event, so unlogged
+    // ones aren't sent to the event socket or live feed either.
+    #[serde(default = "default_true")]
+    log_blocked: bool,
+    #[serde(default)]
+    max_total_connections: Option<u64>,
+    #[serde(default)]
+    max_total_bytes: Option<u64>,
+    #[serde(default)]
+    usage: RuleUsage,
+    #[serde(default)]
+    disabled_reason: Option<String>,
+    // When non-empty, only clients whose forward-confirmed PTR name ends with
+    // one of these domains are accepted (TCP only).
+    #[serde(default)]
+    rdns_allow_suffixes: Vec<String>,
+    // Extra backends balanced together with `target_addr` (TCP).
+    #[serde(default)]
+    target_addrs: Vec<String>,
+    #[serde(default)]
+    balance: BalanceMode,
+    // Weights for weighted_random, in the order target_addr, target_addrs...;
+    // missing entries count as 1, extra ones are ignored, 0 takes a target
+    // out of rotation.
+    #[serde(default)]
+    target_weights: Vec<u32>,
+    // Open connections allowed on each backend; a full one is skipped when
+    // balancing (TCP).
+    #[serde(default)]
+    max_connections_per_target: Option<u32>,
+    // Periodic TCP connect checks; unhealthy targets are skipped.
+    #[serde(default)]
+    health_check_interval_secs: Option<u64>,
+    #[serde(default)]
+    health_check_timeout_ms: Option<u64>,
+    // DSCP codepoint (0-63) set on proxied sockets.
+    #[serde(default)]
+    dscp: Option<u8>,
+    // Overrides --connect-timeout for this rule's targets.
+    #[serde(default)]
+    connect_timeout_ms: Option<u64>,
+    // Overrides --tcp-idle-timeout: close after this long with no bytes
+    // flowing in either direction.
+    #[serde(default)]
+    tcp_idle_timeout_secs: Option<u64>,
+    // Close TCP sessions this long after they start, busy or not, so clients
+    // reconnect and get rebalanced.
+    #[serde(default)]
+    max_connection_duration_secs: Option<u64>,
+    // TCP_NODELAY on the client and target sockets.
+    #[serde(default)]
+    tcp_nodelay: bool,
+    // SO_KEEPALIVE on the client and target sockets: first probe after this
+    // much idle time, then every tcp_keepalive_interval_secs (OS default
+    // when unset). Keeps idle sessions alive through NAT and firewalls.
+    #[serde(default)]
+    tcp_keepalive_secs: Option<u64>,
+    #[serde(default)]
+    tcp_keepalive_interval_secs: Option<u64>,
+    // Overrides --udp-idle-timeout: end a UDP session after this long with
+    // no datagrams in either direction.
+    #[serde(default)]
+    udp_idle_timeout_secs: Option<u64>,
+    // PROXY protocol header written to the target before relaying (TCP).
+    // Stored as `proxy_protocol` before the field was renamed.
+    #[serde(default, alias = "proxy_protocol")]
+    send_proxy_protocol: ProxyProtocolMode,
+    // Expect a v1/v2 PROXY header from the client (a load balancer in front
+    // of us) and treat its source address as the client (TCP).
+    #[serde(default)]
+    accept_proxy_protocol: bool,
+    // Act as an HTTP forward proxy (TCP): each client sends `CONNECT
+    // host:port` and is tunnelled to that destination instead of the rule's
+    // targets, which then only pair listen ports as usual.
+    #[serde(default)]
+    http_connect: bool,
+    // Destinations CONNECT may reach, as `host:port` with `*` or `*.domain`
+    // hosts and `*` ports; empty allows none.
+    #[serde(default)]
+    connect_allowed_targets: Vec<String>,
+    // Read the client's TLS ClientHello without terminating TLS and check
+    // its SNI host (TCP): names in sni_deny are refused and, when sni_allow
+    // is set, only names in it get through. Entries are hosts or `*.domain`;
+    // with both empty nothing is read.
+    #[serde(default)]
+    sni_allow: Vec<String>,
+    #[serde(default)]
+    sni_deny: Vec<String>,
+    // Let clients through when there is no SNI to check (not TLS, no
+    // server_name, a malformed or slow ClientHello) instead of refusing them.
+    // A protocol where the server speaks first gets through only after the
+    // ClientHello wait (CLIENT_HELLO_TIMEOUT, 5 s), since its client sends
+    // nothing until then.
+    #[serde(default)]
+    sni_fail_open: bool,
+    // Connect to targets from the client's own IP via IP_TRANSPARENT (Linux,
+    // TCP only; see sockopt::transparent_socket for the required setup).
+    #[serde(default)]
+    transparent_egress: bool,
+    // Local address outbound TCP connections and UDP upstream sockets bind
+    // to, for multi-homed hosts. Must match the targets' address family.
+    #[serde(default)]
+    source_addr: Option<IpAddr>,
+    // Rule-wide caps across all clients, checked alongside the global rate
+    // limits (the stricter one refuses first).
+    #[serde(default)]
+    max_concurrent: Option<u32>,
+    #[serde(default)]
+    max_new_per_minute: Option<u32>,
+    // Collector that gets a copy of every client->target datagram (UDP).
+    #[serde(default)]
+    mirror_addr: Option<String>,
+    // Shedding tier used once the panel nears max_concurrent_total.
+    #[serde(default)]
+    priority: RulePriority,
+    // Free-form label shown in the panel.
+    #[serde(default)]
+    name: Option<String>,
+    // Lowercased labels for grouping, e.g. `GET /api/rules?tag=prod`.
+    #[serde(default)]
+    tags: Vec<String>,
+    // Position in the rule list (ascending, ties by id). Listeners start in
+    // this order, so when two rules clash the earlier one gets the bind.
+    #[serde(default)]
+    order: i32,
+    // Declared in --rules-file: reconciled from the file, read-only over the
+    // API.
+    #[serde(default)]
+    from_rules_file: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum RulePriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl RulePriority {
+    fn as_str(self) -> &'static str {
+        match self {
+            RulePriority::Low => "low",
+            RulePriority::Normal => "normal",
+            RulePriority::High => "high",
+        }
+    }
+
+    // Active connection count at which this tier stops getting new
+    // connections. Low sheds at `shed_percent` of the total cap, normal
+    // halfway between that and the cap, high only at the cap itself.
+    fn shed_limit(self, max_total: u32, shed_percent: u8) -> Option<u32> {
+        let percent = u64::from(shed_percent.min(100));
+        let percent = match self {
+            RulePriority::Low => percent,
+            RulePriority::Normal => percent + (100 - percent) / 2,
+            RulePriority::High => return None,
+        };
+        Some((u64::from(max_total) * percent / 100) as u32)
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct RuleUsage {
+    connections: u64,
+    bytes: u64,
+}
+
+#[derive(Clone, Default)]
+struct RuleStats {
+    connections: u64,
+    bytes_up: u64,
+    bytes_down: u64,
+    blocked: u64,
+}
+
+impl RuleStats {
+    fn record(&mut self, entry: &ConnectionLog) {
+        if entry.blocked {
+            self.blocked += 1;
+        } else {
+            self.connections += 1;
+            self.bytes_up = self.bytes_up.saturating_add(entry.bytes_up);
+            self.bytes_down = self.bytes_down.saturating_add(entry.bytes_down);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RuleStatsResponse {
+    rule_id: u64,
+    connections: u64,
+    active: usize,
+    bytes_up: u64,
+    bytes_down: u64,
+    blocked: u64,
+    accept_errors: u64,
+    dropped_connections: u64,
+    udp_send_errors: u64,
+}
+
+// Outcome of the last start for one expanded listen address. `stopped`
+// covers listeners that bound but were closed again, either because the
+// rule stopped or because another of its ports failed.
+#[derive(Clone, Serialize)]
+struct ListenerStatus {
+    listen_addr: String,
+    listen_port: u16,
+    protocol: ProtocolMode,
+    state: ListenerState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ListenerState {
+    Listening,
+    Failed,
+    Stopped,
+}
+
+// Listener-level failures since start. `errors` counts every failed
+// accept/recv; `dropped` counts connections that reached us but were lost
+// before a rule could handle them; `send_errors` counts UDP datagrams that
+// failed to go out in either direction.
+#[derive(Default)]
+pub(crate) struct AcceptErrorStats {
+    errors: AtomicU64,
+    dropped: AtomicU64,
+    send_errors: AtomicU64,
+}
+
+impl AcceptErrorStats {
+    fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn send_errors(&self) -> u64 {
+        self.send_errors.load(Ordering::Relaxed)
+    }
+}
+
+// Handed to a rule's listeners; each record lands in both the rule's and the
+// global counters.
+#[derive(Clone)]
+pub(crate) struct AcceptErrorCounter {
+    rule: Arc<AcceptErrorStats>,
+    total: Arc<AcceptErrorStats>,
+}
+
+impl AcceptErrorCounter {
+    pub(crate) fn record_error(&self) {
+        self.rule.errors.fetch_add(1, Ordering::Relaxed);
+        self.total.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_dropped(&self) {
+        self.rule.dropped.fetch_add(1, Ordering::Relaxed);
+        self.total.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_send_error(&self) {
+        self.rule.send_errors.fetch_add(1, Ordering::Relaxed);
+        self.total.send_errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Serialize)]
+struct RuleView {
+    #[serde(flatten)]
+    rule: ProxyRule,
+    remaining_connections: Option<u64>,
+    remaining_bytes: Option<u64>,
+}
+
+impl From<ProxyRule> for RuleView {
+    fn from(rule: ProxyRule) -> Self {
+        let remaining_connections = rule
+            .max_total_connections
+            .map(|max| max.saturating_sub(rule.usage.connections));
+        let remaining_bytes = rule
+            .max_total_bytes
+            .map(|max| max.saturating_sub(rule.usage.bytes));
+        Self {
+            rule,
+            remaining_connections,
+            remaining_bytes,
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -194,16 +880,36 @@ struct PortAllowEntry {
     port: u16,
 }
 
+// Free-text reason attached to a blocklist or allowlist entry.
+#[derive(Clone, Serialize, Deserialize)]
+struct EntryNote {
+    ip: String,
+    port: Option<u16>,
+    note: String,
+}
+
+// Expiry of a temporary block; entries without one are permanent.
+#[derive(Clone, Serialize, Deserialize)]
+struct BlockExpiryEntry {
+    ip: String,
+    port: Option<u16>,
+    expires_at: String,
+}
+
 #[derive(Clone, Serialize)]
 struct BlockEntry {
     ip: String,
     port: Option<u16>,
+    expires_at: Option<String>,
+    remaining_secs: Option<u64>,
+    note: Option<String>,
 }
 
 #[derive(Clone, Serialize)]
 struct AllowEntry {
     ip: String,
     port: Option<u16>,
+    note: Option<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -213,12 +919,45 @@ struct ConnectionLog {
     client_ip: String,
     #[serde(default)]
     listen_port: Option<u16>,
+    // tcp or udp; None on entries recorded before this was tracked.
+    #[serde(default)]
+    protocol: Option<ProtocolMode>,
     started_at: String,
     ended_at: Option<String>,
     bytes_up: u64,
     bytes_down: u64,
     blocked: bool,
     reason: Option<String>,
+    // Category of a blocked attempt's reason.
+    #[serde(default)]
+    block_reason: Option<BlockReason>,
+    #[serde(default)]
+    five_tuple: Option<FiveTuple>,
+    // Delay imposed before the connection was relayed, if it was tarpitted.
+    #[serde(default)]
+    tarpit_ms: Option<u64>,
+    // Verified reverse DNS name, with --resolve-client-hostnames.
+    #[serde(default)]
+    hostname: Option<String>,
+    // ISO country code of the client when the geo DB knew it.
+    #[serde(default)]
+    country: Option<String>,
+    // Backend address the connection was forwarded to; None for blocked
+    // attempts and connections that never reached a target.
+    #[serde(default)]
+    target_addr: Option<String>,
+    // Measured on a monotonic clock, so it's exact even if the wall clock
+    // moved mid-connection; None for blocked attempts and older entries.
+    #[serde(default)]
+    duration_ms: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct FiveTuple {
+    pub(crate) protocol: ProtocolMode,
+    pub(crate) client_addr: String,
+    pub(crate) local_addr: String,
+    pub(crate) target_addr: Option<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -226,6 +965,51 @@ struct RateLimitConfig {
     max_new_connections_per_minute: u32,
     max_concurrent_connections_per_ip: u32,
     max_concurrent_total: u32,
+    #[serde(default)]
+    max_concurrent_per_ip_per_rule: Option<u32>,
+    // When set, tripping the per-minute limit also blocks the IP this long.
+    #[serde(default)]
+    auto_ban_secs: Option<u64>,
+    // Soft limit below max_new_connections_per_minute: clients above it are
+    // still served, but only after tarpit_delay_ms (TCP).
+    #[serde(default)]
+    tarpit_threshold_per_minute: Option<u32>,
+    #[serde(default)]
+    tarpit_delay_ms: Option<u64>,
+    // Percentage of max_concurrent_total at which low-priority rules start
+    // refusing new connections; None turns tiered shedding off.
+    #[serde(default)]
+    load_shed_percent: Option<u8>,
+    // Clients in the global allowlist skip the per-client limits: per-IP
+    // concurrency, the per-minute rate, tarpit and auto-ban. They still count
+    // toward max_concurrent_total, rule limits and load shedding, and their
+    // connections are still recorded. Applies whether or not allowlist mode
+    // is enabled.
+    #[serde(default)]
+    allowlist_bypass: bool,
+}
+
+// Overrides for one listen port, checked in addition to the global limits so
+// the lower value always wins. Counted per port: a client's connections to
+// other ports don't use up this port's allowance, and exceeding it refuses
+// the connection without an auto-ban.
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct PortRateLimit {
+    port: u16,
+    #[serde(default)]
+    max_new_connections_per_minute: Option<u32>,
+    #[serde(default)]
+    max_concurrent_connections_per_ip: Option<u32>,
+    #[serde(default)]
+    max_concurrent_total: Option<u32>,
+}
+
+impl PortRateLimit {
+    fn is_empty(&self) -> bool {
+        self.max_new_connections_per_minute.is_none()
+            && self.max_concurrent_connections_per_ip.is_none()
+            && self.max_concurrent_total.is_none()
+    }
 }
 
 impl Default for RateLimitConfig {
@@ -234,12 +1018,21 @@ impl Default for RateLimitConfig {
             max_new_connections_per_minute: 120,
             max_concurrent_connections_per_ip: 50,
             max_concurrent_total: 2000,
+            max_concurrent_per_ip_per_rule: None,
+            auto_ban_secs: None,
+            tarpit_threshold_per_minute: None,
+            tarpit_delay_ms: None,
+            load_shed_percent: None,
+            allowlist_bypass: false,
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Default, Serialize, Deserialize)]
 struct PersistedState {
+    // Shape of this file; older ones are upgraded by migrate::migrate first.
+    #[serde(default = "migrate::default_version")]
+    version: u32,
     rules: Vec<ProxyRule>,
     blocklist: Vec<String>,
     #[serde(default)]
@@ -254,25 +1047,20 @@ struct PersistedState {
     geo_blocklist: Vec<String>,
     #[serde(default)]
     geo_port_blocklist: Vec<geo::GeoPortEntry>,
+    #[serde(default)]
+    asn_blocklist: Vec<u32>,
     history: Vec<ConnectionLog>,
+    #[serde(default)]
+    blocked_history: Vec<ConnectionLog>,
     rate_limit: RateLimitConfig,
-}
-
-impl Default for PersistedState {
-    fn default() -> Self {
-        Self {
-            rules: Vec::new(),
-            blocklist: Vec::new(),
-            port_blocklist: Vec::new(),
-            allowlist: Vec::new(),
-            allowlist_ports: Vec::new(),
-            allowlist_enabled: false,
-            geo_blocklist: Vec::new(),
-            geo_port_blocklist: Vec::new(),
-            history: Vec::new(),
-            rate_limit: RateLimitConfig::default(),
-        }
-    }
+    #[serde(default)]
+    port_rate_limits: Vec<PortRateLimit>,
+    #[serde(default)]
+    block_expiry: Vec<BlockExpiryEntry>,
+    #[serde(default)]
+    block_notes: Vec<EntryNote>,
+    #[serde(default)]
+    allow_notes: Vec<EntryNote>,
 }
 
 #[derive(Clone, Serialize)]
@@ -281,9 +1069,84 @@ struct ActiveConn {
     rule_id: u64,
     client_ip: String,
     listen_port: Option<u16>,
+    protocol: ProtocolMode,
     started_at: String,
-    bytes_transferred: u64,
-    last_update: String,
+    #[serde(skip)]
+    started: Instant,
+    // Serialized as `bytes_transferred`, `last_update` and `send_errors`.
+    #[serde(flatten)]
+    bytes: Arc<ByteCounter>,
+    #[serde(skip)]
+    five_tuple: Option<FiveTuple>,
+    tarpit_ms: Option<u64>,
+    // Filled in by a background lookup with --resolve-client-hostnames.
+    hostname: Option<String>,
+    country: Option<String>,
+}
+
+// Bytes relayed by one connection, both directions combined. The relay loops
+// add to it without touching the state lock; readers (/api/active, the live
+// feed) load it when they serialize the connection.
+pub(crate) struct ByteCounter {
+    transferred: AtomicU64,
+    // Unix milliseconds of the last change.
+    updated_ms: AtomicU64,
+    // Total at the last live "bytes" event, so unchanged connections are skipped.
+    published: AtomicU64,
+    // Failed UDP sends in either direction; always 0 for TCP.
+    send_errors: AtomicU64,
+}
+
+impl ByteCounter {
+    fn new() -> Self {
+        Self {
+            transferred: AtomicU64::new(0),
+            updated_ms: AtomicU64::new(unix_millis()),
+            published: AtomicU64::new(0),
+            send_errors: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn add(&self, bytes: u64) {
+        self.transferred.fetch_add(bytes, Ordering::Relaxed);
+        self.updated_ms.store(unix_millis(), Ordering::Relaxed);
+    }
+
+    fn total(&self) -> u64 {
+        self.transferred.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_send_error(&self) {
+        self.send_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // True once per change since the previous call.
+    fn take_unpublished(&self) -> bool {
+        let total = self.total();
+        self.published.swap(total, Ordering::Relaxed) != total
+    }
+}
+
+impl Serialize for ByteCounter {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let updated = OffsetDateTime::from_unix_timestamp_nanos(
+            self.updated_ms.load(Ordering::Relaxed) as i128 * 1_000_000,
+        )
+        .ok()
+        .and_then(|time| time.format(&Rfc3339).ok())
+        .unwrap_or_else(now_string);
+        let mut fields = serializer.serialize_struct("ByteCounter", 3)?;
+        fields.serialize_field("bytes_transferred", &self.total())?;
+        fields.serialize_field("last_update", &updated)?;
+        fields.serialize_field("send_errors", &self.send_errors.load(Ordering::Relaxed))?;
+        fields.end()
+    }
+}
+
+fn unix_millis() -> u64 {
+    (OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as u64
 }
 
 pub(crate) struct ListenerHandle {
@@ -295,20 +1158,77 @@ pub(crate) struct AppState {
     rules: Vec<ProxyRule>,
     blocklist: HashSet<String>,
     port_blocklist: HashMap<u16, HashSet<String>>,
+    // Keyed by (ip, port); None port is the global blocklist.
+    block_expiry: HashMap<(String, Option<u16>), OffsetDateTime>,
     allowlist: HashSet<String>,
     allowlist_ports: HashMap<u16, HashSet<String>>,
     allowlist_enabled: bool,
+    // Notes on blocklist / allowlist entries, keyed like block_expiry.
+    block_notes: HashMap<(String, Option<u16>), String>,
+    allow_notes: HashMap<(String, Option<u16>), String>,
     geo_blocklist: HashSet<String>,
     geo_port_blocklist: HashMap<u16, HashSet<String>>,
     pub(crate) geo_db: Option<geo::SharedGeoDb>,
+    asn_blocklist: HashSet<u32>,
+    // Read-only; only changed by editing the files themselves.
+    pub(crate) blocklist_files: Vec<blocklist_file::BlocklistFile>,
+    pub(crate) asn_db: Option<geo::SharedGeoDb>,
+    // Closed connections. Blocked attempts are kept apart in blocked_history
+    // with their own cap, so a flood of them can't evict real sessions.
     history: Vec<ConnectionLog>,
+    blocked_history: Vec<ConnectionLog>,
+    // Lifetime per-rule totals, rebuilt from history on load.
+    rule_stats: HashMap<u64, RuleStats>,
+    // Recent bytes per client IP for /api/top-talkers, also rebuilt on load.
+    top_talkers: talkers::TopTalkers,
     rate_limit: RateLimitConfig,
     listeners: HashMap<u64, Vec<ListenerHandle>>,
     udp_listeners: HashMap<u64, Vec<ListenerHandle>>,
+    // Round-robin position per rule, shared with its TCP listeners.
+    target_cursors: HashMap<u64, Arc<AtomicUsize>>,
+    target_load: HashMap<u64, Arc<balance::TargetLoad>>,
+    target_health: HashMap<u64, Arc<health::RuleHealth>>,
+    accept_errors: HashMap<u64, Arc<AcceptErrorStats>>,
+    accept_errors_total: Arc<AcceptErrorStats>,
+    health_checks: HashMap<u64, ListenerHandle>,
+    listener_status: HashMap<u64, Vec<ListenerStatus>>,
+    // Cancelling a rule's token force-closes its in-flight TCP connections.
+    connection_tokens: HashMap<u64, CancellationToken>,
+    // Tokens of stopped rules waiting out --drain-timeout; shutdown cancels
+    // them along with connection_tokens.
+    draining_tokens: Vec<CancellationToken>,
     active: HashMap<u64, ActiveConn>,
     active_by_ip: HashMap<String, usize>,
+    active_by_rule_ip: HashMap<(u64, String), usize>,
+    active_by_rule: HashMap<u64, usize>,
+    // Only connections with a listen port are counted in these two.
+    active_by_port: HashMap<u16, usize>,
+    active_by_port_ip: HashMap<(u16, String), usize>,
+    // Accepted connections per client country since start ("unknown" when
+    // the geo DB has no answer).
+    connections_by_country: HashMap<String, u64>,
     rate_counters: HashMap<String, VecDeque<Instant>>,
+    rule_rate_counters: HashMap<u64, VecDeque<Instant>>,
+    port_rate_limits: HashMap<u16, PortRateLimit>,
+    // Per-client windows for ports with a max_new_connections_per_minute
+    // override.
+    port_rate_counters: HashMap<(u16, String), VecDeque<Instant>>,
     data_path: PathBuf,
+    // Consecutive failed saves; reset by the next successful one.
+    persist_failures: Arc<AtomicU64>,
+    saves: Arc<PendingSave>,
+    // Set when state.json was unreadable at startup and moved aside, so the
+    // running state came from the backup, a salvage, or nothing.
+    state_load_problem: Option<String>,
+    config: Arc<AppConfig>,
+    rdns: Arc<rdns::ReverseDnsCache>,
+    resolver: Arc<resolve::TargetResolver>,
+    auth: Option<Arc<authz::AuthService>>,
+    tls: Option<Arc<tls::PanelTls>>,
+    pub(crate) webhook: Option<Arc<webhook::Webhook>>,
+    events: events::EventSocket,
+    access_log: access_log::AccessLog,
+    live: live::LiveFeed,
     next_rule_id: u64,
     next_conn_id: u64,
 }
@@ -319,6 +1239,27 @@ struct StatusResponse {
     active_connections: usize,
     blocklist: usize,
     history: usize,
+    blocked_history: usize,
+    accept_errors: u64,
+    dropped_connections: u64,
+    udp_send_errors: u64,
+    persist_failures: u64,
+    // --access-log lines lost because the writer fell behind.
+    access_log_dropped: u64,
+    // True while --persist-fail-safe is refusing new connections.
+    fail_safe_active: bool,
+    // Lookup caches of the loaded databases; counts restart on reload.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    geo_cache: Option<geo::CacheStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    asn_cache: Option<geo::CacheStats>,
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    problems: Vec<String>,
 }
 
 #[derive(Deserialize)]
@@ -327,6 +1268,44 @@ struct CreateRuleRequest {
     target_addr: String,
     enabled: Option<bool>,
     protocol: Option<ProtocolMode>,
+    max_bytes_per_sec: Option<u64>,
+    log_five_tuple: Option<bool>,
+    log_connections: Option<bool>,
+    log_blocked: Option<bool>,
+    max_total_connections: Option<u64>,
+    max_total_bytes: Option<u64>,
+    rdns_allow_suffixes: Option<Vec<String>>,
+    target_addrs: Option<Vec<String>>,
+    balance: Option<BalanceMode>,
+    target_weights: Option<Vec<u32>>,
+    max_connections_per_target: Option<u32>,
+    health_check_interval_secs: Option<u64>,
+    health_check_timeout_ms: Option<u64>,
+    dscp: Option<u8>,
+    connect_timeout_ms: Option<u64>,
+    tcp_idle_timeout_secs: Option<u64>,
+    max_connection_duration_secs: Option<u64>,
+    tcp_nodelay: Option<bool>,
+    tcp_keepalive_secs: Option<u64>,
+    tcp_keepalive_interval_secs: Option<u64>,
+    udp_idle_timeout_secs: Option<u64>,
+    #[serde(alias = "proxy_protocol")]
+    send_proxy_protocol: Option<ProxyProtocolMode>,
+    accept_proxy_protocol: Option<bool>,
+    http_connect: Option<bool>,
+    connect_allowed_targets: Option<Vec<String>>,
+    sni_allow: Option<Vec<String>>,
+    sni_deny: Option<Vec<String>>,
+    sni_fail_open: Option<bool>,
+    transparent_egress: Option<bool>,
+    source_addr: Option<String>,
+    max_concurrent: Option<u32>,
+    max_new_per_minute: Option<u32>,
+    mirror_addr: Option<String>,
+    priority: Option<RulePriority>,
+    name: Option<String>,
+    tags: Option<Vec<String>>,
+    order: Option<i32>,
 }
 
 #[derive(Deserialize)]
@@ -335,12 +1314,55 @@ struct UpdateRuleRequest {
     target_addr: Option<String>,
     enabled: Option<bool>,
     protocol: Option<ProtocolMode>,
+    max_bytes_per_sec: Option<u64>,
+    log_five_tuple: Option<bool>,
+    log_connections: Option<bool>,
+    log_blocked: Option<bool>,
+    max_total_connections: Option<u64>,
+    max_total_bytes: Option<u64>,
+    reset_usage: Option<bool>,
+    rdns_allow_suffixes: Option<Vec<String>>,
+    target_addrs: Option<Vec<String>>,
+    balance: Option<BalanceMode>,
+    target_weights: Option<Vec<u32>>,
+    max_connections_per_target: Option<u32>,
+    health_check_interval_secs: Option<u64>,
+    health_check_timeout_ms: Option<u64>,
+    dscp: Option<u8>,
+    connect_timeout_ms: Option<u64>,
+    tcp_idle_timeout_secs: Option<u64>,
+    max_connection_duration_secs: Option<u64>,
+    tcp_nodelay: Option<bool>,
+    tcp_keepalive_secs: Option<u64>,
+    tcp_keepalive_interval_secs: Option<u64>,
+    udp_idle_timeout_secs: Option<u64>,
+    #[serde(alias = "proxy_protocol")]
+    send_proxy_protocol: Option<ProxyProtocolMode>,
+    accept_proxy_protocol: Option<bool>,
+    http_connect: Option<bool>,
+    connect_allowed_targets: Option<Vec<String>>,
+    sni_allow: Option<Vec<String>>,
+    sni_deny: Option<Vec<String>>,
+    sni_fail_open: Option<bool>,
+    transparent_egress: Option<bool>,
+    source_addr: Option<String>,
+    max_concurrent: Option<u32>,
+    max_new_per_minute: Option<u32>,
+    mirror_addr: Option<String>,
+    priority: Option<RulePriority>,
+    name: Option<String>,
+    tags: Option<Vec<String>>,
+    order: Option<i32>,
 }
 
 #[derive(Deserialize)]
 struct BlockRequest {
     ip: String,
     port: Option<u16>,
+    // Omitted or 0 blocks permanently.
+    ttl_seconds: Option<u64>,
+    // Why the entry exists; omitted or blank clears any earlier note.
+    note: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -352,6 +1374,7 @@ struct BlockQuery {
 struct AllowRequest {
     ip: String,
     port: Option<u16>,
+    note: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -374,11 +1397,67 @@ struct RateLimitRequest {
     max_new_connections_per_minute: Option<u32>,
     max_concurrent_connections_per_ip: Option<u32>,
     max_concurrent_total: Option<u32>,
+    // 0 removes the limit.
+    max_concurrent_per_ip_per_rule: Option<u32>,
+    // 0 turns auto-ban off.
+    auto_ban_secs: Option<u64>,
+    // 0 turns the tarpit off / restores the default delay.
+    tarpit_threshold_per_minute: Option<u32>,
+    tarpit_delay_ms: Option<u64>,
+    // 0 turns tiered shedding off; values above 100 are rejected.
+    load_shed_percent: Option<u8>,
+    allowlist_bypass: Option<bool>,
+}
+
+// Omitted fields are left as they are; 0 clears one. An override with no
+// fields left is removed.
+#[derive(Deserialize)]
+struct PortRateLimitRequest {
+    max_new_connections_per_minute: Option<u32>,
+    max_concurrent_connections_per_ip: Option<u32>,
+    max_concurrent_total: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct LogQuery {
+    // RFC3339; only entries logged after it are returned.
+    since: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ClearHistoryQuery {
+    // RFC3339; only entries that ended before this are removed.
+    before: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+struct ClearHistoryResponse {
+    removed: usize,
+}
+
+#[derive(Deserialize)]
+struct ClearLogQuery {
+    // Highest id the client has seen; newer entries are kept.
+    up_to_id: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct GeoDbUploadQuery {
+    // "country" (default) or "asn".
+    kind: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct HistoryQuery {
     limit: Option<usize>,
+    // Skips this many of the newest matches, for paging backwards.
+    offset: Option<usize>,
+    client_ip: Option<String>,
+    rule_id: Option<u64>,
+    blocked: Option<bool>,
+    // RFC3339 bounds on started_at, both inclusive.
+    since: Option<String>,
+    until: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -389,13 +1468,25 @@ struct RecentQuery {
 #[derive(Deserialize)]
 struct BlockedQuery {
     limit: Option<usize>,
+    category: Option<BlockReason>,
 }
 
+// `code` is a stable identifier for clients to branch on (e.g.
+// "invalid_request", "rule_not_found", "rate_limited"); `error` is the
+// human-readable message and may change wording.
 #[derive(Serialize)]
 struct ErrorResponse {
+    code: &'static str,
     error: String,
 }
 
+#[derive(Serialize)]
+struct BlockedCategoryCount {
+    // null for entries logged before categories existed that matched none.
+    category: Option<BlockReason>,
+    count: usize,
+}
+
 #[derive(Serialize)]
 struct DdosEntry {
     ip: String,
@@ -421,45 +1512,588 @@ async fn status(State(state): State<Arc<RwLock<AppState>>>) -> Json<StatusRespon
         active_connections: guard.active.len(),
         blocklist: guard.blocklist.len() + port_blocked,
         history: guard.history.len(),
+        blocked_history: guard.blocked_history.len(),
+        accept_errors: guard.accept_errors_total.errors(),
+        dropped_connections: guard.accept_errors_total.dropped(),
+        udp_send_errors: guard.accept_errors_total.send_errors(),
+        persist_failures: guard.persist_failures.load(Ordering::Relaxed),
+        access_log_dropped: guard.access_log.dropped(),
+        fail_safe_active: persistence_failing(&guard),
+        geo_cache: guard.geo_db.as_ref().map(|db| db.cache_stats()),
+        asn_cache: guard.asn_db.as_ref().map(|db| db.cache_stats()),
     })
 }
 
-async fn list_rules(State(state): State<Arc<RwLock<AppState>>>) -> Json<Vec<ProxyRule>> {
-    let guard = state.read().await;
-    Json(guard.rules.clone())
-}
-
-async fn create_rule(
-    State(state): State<Arc<RwLock<AppState>>>,
-    Json(payload): Json<CreateRuleRequest>,
-) -> Result<Json<ProxyRule>, (StatusCode, Json<ErrorResponse>)> {
-    if payload.listen_addr.trim().is_empty() || payload.target_addr.trim().is_empty() {
+// 503 when state.json couldn't be loaded cleanly, saves are failing under
+// --persist-fail-safe, or an enabled rule has no running listener.
+async fn healthz(State(state): State<Arc<RwLock<AppState>>>) -> (StatusCode, Json<HealthResponse>) {
+    let mut problems = Vec::new();
+    {
+        let guard = state.read().await;
+        problems.extend(guard.state_load_problem.clone());
+        if persistence_failing(&guard) {
+            problems.push("state saves are failing".to_string());
+        }
+        for rule in guard.rules.iter().filter(|rule| rule.enabled) {
+            if !guard.listeners.contains_key(&rule.id) && !guard.udp_listeners.contains_key(&rule.id) {
+                problems.push(format!("rule {} has no listener", rule.id));
+            }
+        }
+    }
+    if problems.is_empty() {
+        return (StatusCode::OK, Json(HealthResponse { status: "ok", problems }));
+    }
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(HealthResponse {
+            status: "degraded",
+            problems,
+        }),
+    )
+}
+
+async fn metrics_endpoint(State(state): State<Arc<RwLock<AppState>>>) -> impl IntoResponse {
+    let guard = state.read().await;
+    let port_blocked = guard
+        .port_blocklist
+        .values()
+        .map(|set| set.len())
+        .sum::<usize>();
+    let mut writer = metrics::MetricsWriter::default();
+    writer.gauge("proxypanel_rules", "Configured proxy rules.", guard.rules.len() as u64);
+    writer.gauge(
+        "proxypanel_active_connections",
+        "Currently open proxied connections.",
+        guard.active.len() as u64,
+    );
+    writer.gauge(
+        "proxypanel_blocklist_entries",
+        "Global and per-port blocklist entries.",
+        (guard.blocklist.len() + port_blocked) as u64,
+    );
+    writer.gauge(
+        "proxypanel_history_entries",
+        "Closed-connection log entries kept in memory.",
+        guard.history.len() as u64,
+    );
+    writer.gauge(
+        "proxypanel_blocked_history_entries",
+        "Blocked-attempt log entries kept in memory.",
+        guard.blocked_history.len() as u64,
+    );
+    writer.counter(
+        "proxypanel_connections_total",
+        "Connections accepted since start.",
+        guard.connections_by_country.values().sum(),
+    );
+    writer.labeled_gauge(
+        "proxypanel_connections_by_country",
+        "Connections accepted since start by client country; only the busiest countries are listed, the rest are summed as \"other\", so the set of countries changes between scrapes.",
+        "country",
+        &metrics::top_with_other(&guard.connections_by_country, guard.config.metrics_country_limit),
+    );
+    writer.gauge(
+        "proxypanel_persist_failures",
+        "Consecutive failed state saves.",
+        guard.persist_failures.load(Ordering::Relaxed),
+    );
+    writer.counter(
+        "proxypanel_accept_errors_total",
+        "Failed TCP accepts and UDP receives since start.",
+        guard.accept_errors_total.errors(),
+    );
+    writer.counter(
+        "proxypanel_dropped_connections_total",
+        "Connections lost to listener errors before a rule could handle them.",
+        guard.accept_errors_total.dropped(),
+    );
+    writer.counter(
+        "proxypanel_access_log_dropped_total",
+        "Access log lines lost because the writer fell behind.",
+        guard.access_log.dropped(),
+    );
+    writer.counter(
+        "proxypanel_udp_send_errors_total",
+        "Failed UDP datagram sends, to targets and back to clients.",
+        guard.accept_errors_total.send_errors(),
+    );
+    let mut rule_errors = guard
+        .accept_errors
+        .iter()
+        .map(|(rule_id, stats)| (*rule_id, stats.errors(), stats.dropped(), stats.send_errors()))
+        .collect::<Vec<_>>();
+    rule_errors.sort_by_key(|(rule_id, _, _, _)| *rule_id);
+    writer.labeled_counter(
+        "proxypanel_rule_accept_errors_total",
+        "Failed TCP accepts and UDP receives since start, per rule.",
+        "rule",
+        &rule_errors
+            .iter()
+            .map(|(rule_id, errors, _, _)| (rule_id.to_string(), *errors))
+            .collect::<Vec<_>>(),
+    );
+    writer.labeled_counter(
+        "proxypanel_rule_dropped_connections_total",
+        "Connections lost to listener errors, per rule.",
+        "rule",
+        &rule_errors
+            .iter()
+            .map(|(rule_id, _, dropped, _)| (rule_id.to_string(), *dropped))
+            .collect::<Vec<_>>(),
+    );
+    writer.labeled_counter(
+        "proxypanel_rule_udp_send_errors_total",
+        "Failed UDP datagram sends, per rule.",
+        "rule",
+        &rule_errors
+            .iter()
+            .map(|(rule_id, _, _, send_errors)| (rule_id.to_string(), *send_errors))
+            .collect::<Vec<_>>(),
+    );
+    ([(header::CONTENT_TYPE, metrics::CONTENT_TYPE)], writer.finish())
+}
+
+#[derive(Deserialize)]
+struct RuleListQuery {
+    tag: Option<String>,
+}
+
+// Effective runtime configuration. URLs that may carry credentials (the
+// auth service and webhook) are cut down to scheme and host.
+#[derive(Serialize)]
+struct ConfigView {
+    http_addr: String,
+    data_dir: String,
+    allowed_networks: Vec<String>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    geo_update_enabled: bool,
+    geo_update_interval_secs: u64,
+    geo_update_jitter: f64,
+    geo_db_urls: Vec<String>,
+    asn_db_urls: Vec<String>,
+    geo_db_loaded: bool,
+    asn_db_loaded: bool,
+    geo_stale_after_secs: u64,
+    drain_timeout_secs: Option<u64>,
+    shutdown_timeout_secs: u64,
+    event_socket: Option<String>,
+    access_log: Option<String>,
+    access_log_max_size: Option<u64>,
+    access_log_keep: usize,
+    access_log_daily: bool,
+    disable_invalid_rules: bool,
+    compact_state: bool,
+    connect_timeout_ms: u64,
+    auth_url: Option<String>,
+    auth_cache_ttl_secs: u64,
+    auth_timeout_ms: u64,
+    auth_fail_open: bool,
+    tcp_idle_timeout_secs: Option<u64>,
+    udp_idle_timeout_secs: u64,
+    udp_max_send_errors: Option<u32>,
+    dns_cache_ttl_secs: u64,
+    metrics_country_limit: usize,
+    redact_client_ip: bool,
+    resolve_client_hostnames: bool,
+    persist_fail_safe: Option<u64>,
+    persist_interval_ms: u64,
+    webhook_url: Option<String>,
+    webhook_events: Vec<String>,
+    blocklist_files: Vec<String>,
+    reuseport_acceptors: Option<usize>,
+    listen_backlog: u32,
+    buffer_size: usize,
+    max_history: usize,
+    max_blocked_history: usize,
+    max_rules: usize,
+    max_listen_ports: usize,
+    api_max_requests_per_minute: Option<u32>,
+    rules_file: Option<String>,
+    history_retention_days: Option<u64>,
+    allowlist_enabled: bool,
+    rate_limit: RateLimitConfig,
+}
+
+async fn effective_config(State(state): State<Arc<RwLock<AppState>>>) -> Json<ConfigView> {
+    let guard = state.read().await;
+    let config = &guard.config;
+    let path = |path: &PathBuf| path.display().to_string();
+    Json(ConfigView {
+        http_addr: config.http_addr.to_string(),
+        data_dir: path(&config.data_dir),
+        allowed_networks: config.allowed_networks.clone(),
+        tls_cert: config.tls_cert.as_ref().map(path),
+        tls_key: config.tls_key.as_ref().map(path),
+        geo_update_enabled: config.geo_update_enabled,
+        geo_update_interval_secs: config.geo_update_interval.as_secs(),
+        geo_update_jitter: config.geo_update_jitter,
+        geo_db_urls: config.geo_db_urls.clone(),
+        asn_db_urls: config.asn_db_urls.clone(),
+        geo_db_loaded: guard.geo_db.is_some(),
+        asn_db_loaded: guard.asn_db.is_some(),
+        geo_stale_after_secs: config.geo_stale_after.as_secs(),
+        drain_timeout_secs: config.drain_timeout.map(|value| value.as_secs()),
+        shutdown_timeout_secs: config.shutdown_timeout.as_secs(),
+        event_socket: config.event_socket.as_ref().map(path),
+        access_log: config.access_log.as_ref().map(path),
+        access_log_max_size: config.access_log_max_size,
+        access_log_keep: config.access_log_keep,
+        access_log_daily: config.access_log_daily,
+        disable_invalid_rules: config.disable_invalid_rules,
+        compact_state: config.compact_state,
+        connect_timeout_ms: config.connect_timeout.as_millis() as u64,
+        auth_url: config.auth_url.as_deref().map(redact_url),
+        auth_cache_ttl_secs: config.auth_cache_ttl.as_secs(),
+        auth_timeout_ms: config.auth_timeout.as_millis() as u64,
+        auth_fail_open: config.auth_fail_open,
+        tcp_idle_timeout_secs: config.tcp_idle_timeout.map(|value| value.as_secs()),
+        udp_idle_timeout_secs: config.udp_idle_timeout.as_secs(),
+        udp_max_send_errors: config.udp_max_send_errors,
+        dns_cache_ttl_secs: config.dns_cache_ttl.as_secs(),
+        metrics_country_limit: config.metrics_country_limit,
+        redact_client_ip: config.redact_client_ip,
+        resolve_client_hostnames: config.resolve_client_hostnames,
+        persist_fail_safe: config.persist_fail_safe,
+        persist_interval_ms: config.persist_interval.as_millis() as u64,
+        webhook_url: config.webhook_url.as_deref().map(redact_url),
+        webhook_events: config.webhook_events.clone(),
+        blocklist_files: config.blocklist_files.iter().map(path).collect(),
+        reuseport_acceptors: config.reuseport_acceptors,
+        listen_backlog: config.listen_backlog,
+        buffer_size: config.buffer_size,
+        max_history: config.max_history,
+        max_blocked_history: config.max_blocked_history,
+        max_rules: config.max_rules,
+        max_listen_ports: config.max_listen_ports,
+        api_max_requests_per_minute: config.api_max_requests_per_minute,
+        rules_file: config.rules_file.as_ref().map(|path| path.display().to_string()),
+        history_retention_days: config.history_retention.map(|retention| retention.as_secs() / (24 * 60 * 60)),
+        allowlist_enabled: guard.allowlist_enabled,
+        rate_limit: guard.rate_limit.clone(),
+    })
+}
+
+// Keeps only scheme and host: webhook paths and query strings are often the
+// secret itself, and user info is a password.
+fn redact_url(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) => match parsed.host_str() {
+            Some(host) => format!("{}://{}/<redacted>", parsed.scheme(), host),
+            None => "<redacted>".to_string(),
+        },
+        Err(_) => "<redacted>".to_string(),
+    }
+}
+
+async fn list_rules(
+    State(state): State<Arc<RwLock<AppState>>>,
+    Query(query): Query<RuleListQuery>,
+) -> Json<Vec<RuleView>> {
+    let tag = query.tag.map(|tag| tag.trim().to_lowercase());
+    let guard = state.read().await;
+    Json(
+        ordered_rules(&guard.rules)
+            .into_iter()
+            .filter(|rule| tag.as_ref().is_none_or(|tag| rule.tags.contains(tag)))
+            .cloned()
+            .map(RuleView::from)
+            .collect(),
+    )
+}
+
+fn ordered_rules(rules: &[ProxyRule]) -> Vec<&ProxyRule> {
+    let mut ordered = rules.iter().collect::<Vec<_>>();
+    ordered.sort_by_key(|rule| (rule.order, rule.id));
+    ordered
+}
+
+// Body: ids of API-managed rules in their new order. The listed rules move
+// to the front and the rest keep their relative order behind them; rules
+// from the rules file keep the order the file gives them.
+async fn reorder_rules(
+    State(state): State<Arc<RwLock<AppState>>>,
+    Json(ids): Json<Vec<u64>>,
+) -> Result<Json<Vec<RuleView>>, (StatusCode, Json<ErrorResponse>)> {
+    let mut seen = HashSet::new();
+    if let Some(id) = ids.iter().find(|id| !seen.insert(**id)) {
         return Err((
             StatusCode::BAD_REQUEST,
             Json(ErrorResponse {
-                error: "listen_addr and target_addr are required".to_string(),
+                code: "invalid_request",
+                error: format!("Rule {} is listed more than once", id),
             }),
         ));
     }
-    let enabled = payload.enabled.unwrap_or(true);
-    let protocol = payload.protocol.unwrap_or_default();
 
-    let (rule, persist_snapshot) = {
+    let rules = {
         let mut guard = state.write().await;
-        let rule = ProxyRule {
-            id: guard.next_rule_id,
-            listen_addr: payload.listen_addr.trim().to_string(),
-            target_addr: payload.target_addr.trim().to_string(),
-            enabled,
-            created_at: now_string(),
-            protocol,
+        for id in &ids {
+            if !guard.rules.iter().any(|rule| rule.id == *id) {
+                return Err((
+                    StatusCode::NOT_FOUND,
+                    Json(ErrorResponse {
+                        code: "rule_not_found",
+                        error: format!("Rule {} not found", id),
+                    }),
+                ));
+            }
+            ensure_api_managed(&guard.rules, *id)?;
+        }
+        let rest = ordered_rules(&guard.rules)
+            .into_iter()
+            .filter(|rule| !rule.from_rules_file && !seen.contains(&rule.id))
+            .map(|rule| rule.id)
+            .collect::<Vec<_>>();
+        for (position, id) in ids.iter().chain(&rest).enumerate() {
+            let order = i32::try_from(position).unwrap_or(i32::MAX);
+            let Some(rule) = guard.rules.iter_mut().find(|rule| rule.id == *id) else {
+                continue;
+            };
+            if rule.order == order {
+                continue;
+            }
+            rule.order = order;
+            let rule = rule.clone();
+            guard.live.publish("rule_updated", &rule);
+        }
+        ordered_rules(&guard.rules)
+            .into_iter()
+            .cloned()
+            .map(RuleView::from)
+            .collect::<Vec<_>>()
+    };
+    persist_state(&state).await;
+    Ok(Json(rules))
+}
+
+// The whole persisted state, history included, as a file to download and
+// feed to /api/import on another server.
+async fn export_state(State(state): State<Arc<RwLock<AppState>>>) -> impl IntoResponse {
+    let snapshot = snapshot_state(&*state.read().await);
+    (
+        [(header::CONTENT_DISPOSITION, "attachment; filename=\"proxypanel-export.json\"")],
+        Json(snapshot),
+    )
+}
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ImportMode {
+    // The export's rules, lists and rate limits replace the current ones.
+    #[default]
+    Replace,
+    // The export's rules are added with new ids and its list entries added
+    // to the current ones; port rate limits from the export win per port and
+    // the global rate limit and allowlist mode stay as they are.
+    Merge,
+}
+
+#[derive(Deserialize)]
+struct ImportQuery {
+    mode: Option<ImportMode>,
+}
+
+#[derive(Serialize)]
+struct ImportResult {
+    added: usize,
+    updated: usize,
+    removed: usize,
+    unchanged: usize,
+}
+
+// Applies an /api/export body. Every rule is validated as if created over
+// the API before anything changes, then only the listeners of rules that
+// changed are restarted. History isn't imported, and rules-file rules in the
+// export are skipped since the file owns them.
+async fn import_state(
+    State(state): State<Arc<RwLock<AppState>>>,
+    Query(query): Query<ImportQuery>,
+    Json(body): Json<serde_json::Value>,
+) -> Result<Json<ImportResult>, (StatusCode, Json<ErrorResponse>)> {
+    let invalid = |error: String| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                code: "invalid_request",
+                error,
+            }),
+        )
+    };
+    let serde_json::Value::Object(mut fields) = body else {
+        return Err(invalid("Expected an exported state object".to_string()));
+    };
+    migrate::migrate(&mut fields).map_err(|err| invalid(err.to_string()))?;
+    let mut imported = serde_json::from_value::<PersistedState>(serde_json::Value::Object(fields))
+        .map_err(|err| invalid(format!("Invalid export: {}", err)))?;
+    let mode = query.mode.unwrap_or_default();
+
+    let plan = {
+        let mut guard = state.write().await;
+        let rules = import_rules(&guard, std::mem::take(&mut imported.rules), mode)?;
+        if mode == ImportMode::Merge {
+            imported = merged_policy(snapshot_state(&guard), imported);
+        }
+        apply_persisted_policy(&mut guard, &mut imported);
+        reconcile_state_rules(&mut guard, rules, false)
+    };
+    persist_state(&state).await;
+    apply_listener_plan(&state, &plan).await;
+    info!("Imported state ({})", plan.summary());
+    Ok(Json(ImportResult {
+        added: plan.added,
+        updated: plan.updated,
+        removed: plan.removed,
+        unchanged: plan.unchanged,
+    }))
+}
+
+// The API-managed rules the import leaves behind, checked like create_rule
+// would check them. Imported ids are kept on replace unless they clash with
+// a rules-file rule; a merge gives every imported rule a new id and puts it
+// at the end of the list.
+fn import_rules(
+    state: &AppState,
+    imported: Vec<ProxyRule>,
+    mode: ImportMode,
+) -> Result<Vec<ProxyRule>, (StatusCode, Json<ErrorResponse>)> {
+    let file_rules = state
+        .rules
+        .iter()
+        .filter(|rule| rule.from_rules_file)
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut rules = match mode {
+        ImportMode::Replace => Vec::new(),
+        ImportMode::Merge => state
+            .rules
+            .iter()
+            .filter(|rule| !rule.from_rules_file)
+            .cloned()
+            .collect::<Vec<_>>(),
+    };
+    let mut taken_ids = file_rules.iter().map(|rule| rule.id).collect::<HashSet<_>>();
+    // Above every current and imported id, so a new id never takes one an
+    // imported rule keeps.
+    let mut next_id = imported
+        .iter()
+        .map(|rule| rule.id.saturating_add(1))
+        .fold(state.next_rule_id, u64::max);
+    for rule in imported.into_iter().filter(|rule| !rule.from_rules_file) {
+        let describe = |error: String| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    code: "invalid_request",
+                    error: format!("rule {} ({}): {}", rule.id, rule.listen_addr, error),
+                }),
+            )
+        };
+        let request = serde_json::to_value(&rule)
+            .and_then(serde_json::from_value::<CreateRuleRequest>)
+            .map_err(|err| describe(err.to_string()))?;
+        let mut built = build_rule(request).map_err(|(_, Json(err))| describe(err.error))?;
+        validate_rule_addresses(&built).map_err(|err| describe(err.to_string()))?;
+        built.id = if mode == ImportMode::Replace && taken_ids.insert(rule.id) {
+            rule.id
+        } else {
+            next_id += 1;
+            next_id - 1
         };
+        if mode == ImportMode::Merge {
+            built.order = rules.iter().map(|rule| rule.order.saturating_add(1)).max().unwrap_or(0);
+        }
+        built.created_at = rule.created_at;
+        built.usage = rule.usage;
+        built.disabled_reason = rule.disabled_reason;
+        let others = file_rules.iter().chain(&rules).cloned().collect::<Vec<_>>();
+        if let Some(conflict) = find_listen_conflict(&others, &built) {
+            return Err(listen_conflict_error(conflict));
+        }
+        rules.push(built);
+    }
+
+    let rule_count = rules.len() + file_rules.len();
+    if rule_count > state.config.max_rules {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                code: "rule_limit_reached",
+                error: format!("{} rules exceeds --max-rules {}", rule_count, state.config.max_rules),
+            }),
+        ));
+    }
+    let ports = rules.iter().chain(&file_rules).map(listen_port_count).sum::<usize>();
+    if ports > state.config.max_listen_ports {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                code: "listen_port_limit",
+                error: format!(
+                    "{} listen ports exceeds --max-listen-ports {}",
+                    ports, state.config.max_listen_ports
+                ),
+            }),
+        ));
+    }
+    Ok(rules)
+}
+
+// ImportMode::Merge for the lists: entries are appended to the current ones
+// and apply_persisted_policy folds duplicates, the later (imported) note or
+// expiry winning.
+fn merged_policy(mut current: PersistedState, mut imported: PersistedState) -> PersistedState {
+    current.blocklist.append(&mut imported.blocklist);
+    current.port_blocklist.append(&mut imported.port_blocklist);
+    current.block_expiry.append(&mut imported.block_expiry);
+    current.allowlist.append(&mut imported.allowlist);
+    current.allowlist_ports.append(&mut imported.allowlist_ports);
+    current.block_notes.append(&mut imported.block_notes);
+    current.allow_notes.append(&mut imported.allow_notes);
+    current.geo_blocklist.append(&mut imported.geo_blocklist);
+    current.geo_port_blocklist.append(&mut imported.geo_port_blocklist);
+    current.asn_blocklist.append(&mut imported.asn_blocklist);
+    current
+        .port_rate_limits
+        .retain(|limit| !imported.port_rate_limits.iter().any(|other| other.port == limit.port));
+    current.port_rate_limits.append(&mut imported.port_rate_limits);
+    current
+}
+
+async fn create_rule(
+    State(state): State<Arc<RwLock<AppState>>>,
+    Json(payload): Json<CreateRuleRequest>,
+) -> Result<Json<ProxyRule>, (StatusCode, Json<ErrorResponse>)> {
+    let order = payload.order;
+    let mut rule = build_rule(payload)?;
+
+    let rule = {
+        let mut guard = state.write().await;
+        rule.id = guard.next_rule_id;
+        // Without an explicit order a new rule goes to the end of the list.
+        if order.is_none() {
+            rule.order = guard.rules.iter().map(|rule| rule.order.saturating_add(1)).max().unwrap_or(0);
+        }
+        if guard.rules.len() >= guard.config.max_rules {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    code: "rule_limit_reached",
+                    error: format!("Rule limit reached ({} rules, see --max-rules)", guard.config.max_rules),
+                }),
+            ));
+        }
+        check_listen_port_limit(&guard, &rule)?;
+        if let Some(conflict) = find_listen_conflict(&guard.rules, &rule) {
+            return Err(listen_conflict_error(conflict));
+        }
         guard.next_rule_id += 1;
         guard.rules.push(rule.clone());
-        (rule, snapshot_state(&guard))
+        guard.live.publish("rule_added", &rule);
+        rule
     };
 
-    persist_state(state.clone(), persist_snapshot).await;
+    persist_state(&state).await;
 
     if rule.enabled {
         if let Err(err) = start_rule_listeners(&state, &rule).await {
@@ -467,10 +2101,11 @@ async fn create_rule(
                 "Failed to start listener {} -> {}: {}",
                 rule.listen_addr, rule.target_addr, err
             );
-            disable_rule_after_start_failure(&state, rule.id).await;
+            disable_rule_after_start_failure(&state, &rule, &err).await;
             return Err((
                 StatusCode::BAD_REQUEST,
                 Json(ErrorResponse {
+                    code: "listener_failed",
                     error: format!("Listener failed: {}", err),
                 }),
             ));
@@ -480,22 +2115,263 @@ async fn create_rule(
     Ok(Json(rule))
 }
 
+// Validates a create request and builds the rule it describes; the caller
+// assigns the id.
+fn build_rule(payload: CreateRuleRequest) -> Result<ProxyRule, (StatusCode, Json<ErrorResponse>)> {
+    if payload.listen_addr.trim().is_empty() || payload.target_addr.trim().is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                code: "invalid_request",
+                error: "listen_addr and target_addr are required".to_string(),
+            }),
+        ));
+    }
+    validate_dscp(payload.dscp)?;
+    validate_transparent_egress(payload.transparent_egress)?;
+    let source_addr = parse_source_addr(payload.source_addr.as_deref().unwrap_or_default())?;
+    let connect_allowed_targets = parse_connect_targets(payload.connect_allowed_targets.as_deref().unwrap_or_default())?;
+    let sni_allow = parse_sni_hosts("sni_allow", payload.sni_allow.as_deref().unwrap_or_default())?;
+    let sni_deny = parse_sni_hosts("sni_deny", payload.sni_deny.as_deref().unwrap_or_default())?;
+    validate_sni_filter(payload.http_connect.unwrap_or(false), &sni_allow, &sni_deny)?;
+    let mut targets = vec![payload.target_addr.trim().to_string()];
+    targets.extend(normalize_targets(payload.target_addrs.as_deref()));
+    validate_source_addr(source_addr, payload.transparent_egress.unwrap_or(false), &targets)?;
+
+    Ok(ProxyRule {
+        id: 0,
+        listen_addr: payload.listen_addr.trim().to_string(),
+        target_addr: payload.target_addr.trim().to_string(),
+        enabled: payload.enabled.unwrap_or(true),
+        created_at: now_string(),
+        protocol: payload.protocol.unwrap_or_default(),
+        max_bytes_per_sec: payload.max_bytes_per_sec.filter(|value| *value > 0),
+        log_five_tuple: payload.log_five_tuple.unwrap_or(false),
+        log_connections: payload.log_connections.unwrap_or(true),
+        log_blocked: payload.log_blocked.unwrap_or(true),
+        max_total_connections: payload.max_total_connections.filter(|value| *value > 0),
+        max_total_bytes: payload.max_total_bytes.filter(|value| *value > 0),
+        usage: RuleUsage::default(),
+        disabled_reason: None,
+        rdns_allow_suffixes: normalize_suffixes(payload.rdns_allow_suffixes.as_deref()),
+        target_addrs: normalize_targets(payload.target_addrs.as_deref()),
+        balance: payload.balance.unwrap_or_default(),
+        target_weights: payload.target_weights.unwrap_or_default(),
+        max_connections_per_target: payload.max_connections_per_target.filter(|value| *value > 0),
+        health_check_interval_secs: payload.health_check_interval_secs.filter(|value| *value > 0),
+        health_check_timeout_ms: payload.health_check_timeout_ms.filter(|value| *value > 0),
+        dscp: payload.dscp,
+        connect_timeout_ms: payload.connect_timeout_ms.filter(|value| *value > 0),
+        tcp_idle_timeout_secs: payload.tcp_idle_timeout_secs.filter(|value| *value > 0),
+        max_connection_duration_secs: payload.max_connection_duration_secs.filter(|value| *value > 0),
+        tcp_nodelay: payload.tcp_nodelay.unwrap_or(false),
+        tcp_keepalive_secs: payload.tcp_keepalive_secs.filter(|value| *value > 0),
+        tcp_keepalive_interval_secs: payload.tcp_keepalive_interval_secs.filter(|value| *value > 0),
+        udp_idle_timeout_secs: payload.udp_idle_timeout_secs.filter(|value| *value > 0),
+        send_proxy_protocol: payload.send_proxy_protocol.unwrap_or_default(),
+        accept_proxy_protocol: payload.accept_proxy_protocol.unwrap_or(false),
+        http_connect: payload.http_connect.unwrap_or(false),
+        connect_allowed_targets,
+        sni_allow,
+        sni_deny,
+        sni_fail_open: payload.sni_fail_open.unwrap_or(false),
+        transparent_egress: payload.transparent_egress.unwrap_or(false),
+        source_addr,
+        max_concurrent: payload.max_concurrent.filter(|value| *value > 0),
+        max_new_per_minute: payload.max_new_per_minute.filter(|value| *value > 0),
+        mirror_addr: normalize_optional(payload.mirror_addr.as_deref()),
+        priority: payload.priority.unwrap_or_default(),
+        name: normalize_optional(payload.name.as_deref()),
+        tags: normalize_tags(payload.tags.as_deref()),
+        order: payload.order.unwrap_or_default(),
+        from_rules_file: false,
+    })
+}
+
+async fn rule_stats(
+    Path(id): Path<u64>,
+    State(state): State<Arc<RwLock<AppState>>>,
+) -> Result<Json<RuleStatsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let guard = state.read().await;
+    if !guard.rules.iter().any(|rule| rule.id == id) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                code: "rule_not_found",
+                error: "Rule not found".to_string(),
+            }),
+        ));
+    }
+    let stats = guard.rule_stats.get(&id).cloned().unwrap_or_default();
+    let accept_errors = guard.accept_errors.get(&id);
+    let active = guard
+        .active
+        .values()
+        .filter(|conn| conn.rule_id == id)
+        .count();
+    Ok(Json(RuleStatsResponse {
+        rule_id: id,
+        connections: stats.connections,
+        active,
+        bytes_up: stats.bytes_up,
+        bytes_down: stats.bytes_down,
+        blocked: stats.blocked,
+        accept_errors: accept_errors.map(|stats| stats.errors()).unwrap_or(0),
+        dropped_connections: accept_errors.map(|stats| stats.dropped()).unwrap_or(0),
+        udp_send_errors: accept_errors.map(|stats| stats.send_errors()).unwrap_or(0),
+    }))
+}
+
+async fn rule_listeners(
+    Path(id): Path<u64>,
+    State(state): State<Arc<RwLock<AppState>>>,
+) -> Result<Json<Vec<ListenerStatus>>, (StatusCode, Json<ErrorResponse>)> {
+    let guard = state.read().await;
+    if !guard.rules.iter().any(|rule| rule.id == id) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                code: "rule_not_found",
+                error: "Rule not found".to_string(),
+            }),
+        ));
+    }
+    Ok(Json(guard.listener_status.get(&id).cloned().unwrap_or_default()))
+}
+
+#[derive(Serialize)]
+struct BulkRuleResult {
+    succeeded: usize,
+    failed: Vec<BulkRuleFailure>,
+}
+
+#[derive(Serialize)]
+struct BulkRuleFailure {
+    id: u64,
+    error: String,
+}
+
+// Starts every disabled rule, persisting once at the end. A rule whose
+// address is held by an enabled rule (including one enabled earlier in the
+// same pass), or whose listener can't start, stays disabled and is reported
+// in `failed`.
+async fn enable_all_rules(State(state): State<Arc<RwLock<AppState>>>) -> Json<BulkRuleResult> {
+    let mut result = BulkRuleResult {
+        succeeded: 0,
+        failed: Vec::new(),
+    };
+    let rules = {
+        let mut guard = state.write().await;
+        let mut candidates = guard
+            .rules
+            .iter()
+            .filter(|rule| !rule.enabled && !rule.from_rules_file)
+            .map(|rule| ProxyRule {
+                enabled: true,
+                disabled_reason: None,
+                ..rule.clone()
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|rule| (rule.order, rule.id));
+        let mut rules = Vec::new();
+        for candidate in candidates {
+            if let Some((other_id, addr)) = find_listen_conflict(&guard.rules, &candidate) {
+                result.failed.push(BulkRuleFailure {
+                    id: candidate.id,
+                    error: format!("{} is already in use by rule {}", addr, other_id),
+                });
+                continue;
+            }
+            if let Some(rule) = guard.rules.iter_mut().find(|rule| rule.id == candidate.id) {
+                *rule = candidate.clone();
+            }
+            rules.push(candidate);
+        }
+        rules
+    };
+
+    for rule in rules {
+        match start_rule_listeners(&state, &rule).await {
+            Ok(()) => {
+                result.succeeded += 1;
+                state.read().await.live.publish("rule_updated", &rule);
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to start listener {} -> {}: {}",
+                    rule.listen_addr, rule.target_addr, err
+                );
+                disable_rule_after_start_failure(&state, &rule, &err).await;
+                result.failed.push(BulkRuleFailure {
+                    id: rule.id,
+                    error: format!("Listener failed: {}", err),
+                });
+            }
+        }
+    }
+
+    persist_state(&state).await;
+    Json(result)
+}
+
+async fn disable_all_rules(State(state): State<Arc<RwLock<AppState>>>) -> Json<BulkRuleResult> {
+    let rules = {
+        let mut guard = state.write().await;
+        guard
+            .rules
+            .iter_mut()
+            .filter(|rule| rule.enabled && !rule.from_rules_file)
+            .map(|rule| {
+                rule.enabled = false;
+                rule.clone()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    for rule in &rules {
+        stop_rule_listeners(&state, rule.id).await;
+    }
+    {
+        let guard = state.read().await;
+        for rule in &rules {
+            guard.live.publish("rule_updated", rule);
+        }
+    }
+    persist_state(&state).await;
+    Json(BulkRuleResult {
+        succeeded: rules.len(),
+        failed: Vec::new(),
+    })
+}
+
 async fn enable_rule(
     Path(id): Path<u64>,
     State(state): State<Arc<RwLock<AppState>>>,
 ) -> Result<Json<ProxyRule>, (StatusCode, Json<ErrorResponse>)> {
     let rule = {
         let mut guard = state.write().await;
+        ensure_api_managed(&guard.rules, id)?;
+        if let Some(current) = guard.rules.iter().find(|rule| rule.id == id) {
+            let candidate = ProxyRule {
+                enabled: true,
+                ..current.clone()
+            };
+            if let Some(conflict) = find_listen_conflict(&guard.rules, &candidate) {
+                return Err(listen_conflict_error(conflict));
+            }
+        }
         let rule = guard.rules.iter_mut().find(|rule| rule.id == id);
         match rule {
             Some(rule) => {
                 rule.enabled = true;
+                rule.disabled_reason = None;
                 rule.clone()
             }
             None => {
                 return Err((
                     StatusCode::NOT_FOUND,
                     Json(ErrorResponse {
+                        code: "rule_not_found",
                         error: "Rule not found".to_string(),
                     }),
                 ))
@@ -504,20 +2380,18 @@ async fn enable_rule(
     };
 
     if let Err(err) = start_rule_listeners(&state, &rule).await {
-        disable_rule_after_start_failure(&state, rule.id).await;
+        disable_rule_after_start_failure(&state, &rule, &err).await;
         return Err((
             StatusCode::BAD_REQUEST,
             Json(ErrorResponse {
+                code: "listener_failed",
                 error: format!("Listener failed: {}", err),
             }),
         ));
     }
 
-    let snapshot = {
-        let guard = state.read().await;
-        snapshot_state(&guard)
-    };
-    persist_state(state.clone(), snapshot).await;
+    state.read().await.live.publish("rule_updated", &rule);
+    persist_state(&state).await;
     Ok(Json(rule))
 }
 
@@ -527,6 +2401,7 @@ async fn disable_rule(
 ) -> Result<Json<ProxyRule>, (StatusCode, Json<ErrorResponse>)> {
     let rule = {
         let mut guard = state.write().await;
+        ensure_api_managed(&guard.rules, id)?;
         let rule = guard.rules.iter_mut().find(|rule| rule.id == id);
         match rule {
             Some(rule) => {
@@ -537,6 +2412,7 @@ async fn disable_rule(
                 return Err((
                     StatusCode::NOT_FOUND,
                     Json(ErrorResponse {
+                        code: "rule_not_found",
                         error: "Rule not found".to_string(),
                     }),
                 ))
@@ -545,11 +2421,8 @@ async fn disable_rule(
     };
 
     stop_rule_listeners(&state, id).await;
-    let snapshot = {
-        let guard = state.read().await;
-        snapshot_state(&guard)
-    };
-    persist_state(state.clone(), snapshot).await;
+    state.read().await.live.publish("rule_updated", &rule);
+    persist_state(&state).await;
     Ok(Json(rule))
 }
 
@@ -563,6 +2436,7 @@ async fn update_rule(
             return Err((
                 StatusCode::BAD_REQUEST,
                 Json(ErrorResponse {
+                    code: "invalid_request",
                     error: "listen_addr cannot be empty".to_string(),
                 }),
             ));
@@ -573,18 +2447,75 @@ async fn update_rule(
             return Err((
                 StatusCode::BAD_REQUEST,
                 Json(ErrorResponse {
+                    code: "invalid_request",
                     error: "target_addr cannot be empty".to_string(),
                 }),
             ));
         }
     }
+    validate_dscp(payload.dscp)?;
+    validate_transparent_egress(payload.transparent_egress)?;
+    let source_addr = payload.source_addr.as_deref().map(parse_source_addr).transpose()?;
+    let connect_allowed_targets = payload
+        .connect_allowed_targets
+        .as_deref()
+        .map(parse_connect_targets)
+        .transpose()?;
+    let sni_allow = payload
+        .sni_allow
+        .as_deref()
+        .map(|values| parse_sni_hosts("sni_allow", values))
+        .transpose()?;
+    let sni_deny = payload
+        .sni_deny
+        .as_deref()
+        .map(|values| parse_sni_hosts("sni_deny", values))
+        .transpose()?;
 
     let (rule, was_enabled) = {
         let mut guard = state.write().await;
+        ensure_api_managed(&guard.rules, id)?;
+        // Checked before anything is changed, against the updated addresses.
+        if let Some(current) = guard.rules.iter().find(|rule| rule.id == id) {
+            let mut candidate = current.clone();
+            if let Some(listen_addr) = payload.listen_addr.as_ref() {
+                candidate.listen_addr = listen_addr.trim().to_string();
+            }
+            if let Some(target_addr) = payload.target_addr.as_ref() {
+                candidate.target_addr = target_addr.trim().to_string();
+            }
+            candidate.enabled = payload.enabled.unwrap_or(candidate.enabled);
+            candidate.protocol = payload.protocol.unwrap_or(candidate.protocol);
+            check_listen_port_limit(&guard, &candidate)?;
+            if let Some(conflict) = find_listen_conflict(&guard.rules, &candidate) {
+                return Err(listen_conflict_error(conflict));
+            }
+        }
         let rule = guard.rules.iter_mut().find(|rule| rule.id == id);
         match rule {
             Some(rule) => {
                 let was_enabled = rule.enabled;
+                // Checked against the rule as it will be after the update.
+                let mut targets = vec![payload
+                    .target_addr
+                    .as_deref()
+                    .unwrap_or(&rule.target_addr)
+                    .trim()
+                    .to_string()];
+                match payload.target_addrs.as_deref() {
+                    Some(extra) => targets.extend(normalize_targets(Some(extra))),
+                    None => targets.extend(rule.target_addrs.iter().cloned()),
+                }
+                validate_source_addr(
+                    source_addr.unwrap_or(rule.source_addr),
+                    payload.transparent_egress.unwrap_or(rule.transparent_egress),
+                    &targets,
+                )?;
+                validate_sni_filter(
+                    payload.http_connect.unwrap_or(rule.http_connect),
+                    sni_allow.as_deref().unwrap_or(&rule.sni_allow),
+                    sni_deny.as_deref().unwrap_or(&rule.sni_deny),
+                )?;
                 if let Some(listen_addr) = payload.listen_addr.as_ref() {
                     rule.listen_addr = listen_addr.trim().to_string();
                 }
@@ -597,61 +2528,478 @@ async fn update_rule(
                 if let Some(protocol) = payload.protocol {
                     rule.protocol = protocol;
                 }
-                (rule.clone(), was_enabled)
-            }
-            None => {
-                return Err((
-                    StatusCode::NOT_FOUND,
-                    Json(ErrorResponse {
-                        error: "Rule not found".to_string(),
-                    }),
-                ))
-            }
-        }
-    };
-
-    if was_enabled {
-        stop_rule_listeners(&state, id).await;
-    }
-
-    if rule.enabled {
-        if let Err(err) = start_rule_listeners(&state, &rule).await {
-            disable_rule_after_start_failure(&state, rule.id).await;
-            return Err((
-                StatusCode::BAD_REQUEST,
-                Json(ErrorResponse {
-                    error: format!("Listener failed: {}", err),
+                if let Some(value) = payload.max_bytes_per_sec {
+                    rule.max_bytes_per_sec = Some(value).filter(|value| *value > 0);
+                }
+                if let Some(value) = payload.log_five_tuple {
+                    rule.log_five_tuple = value;
+                }
+                if let Some(value) = payload.log_connections {
+                    rule.log_connections = value;
+                }
+                if let Some(value) = payload.log_blocked {
+                    rule.log_blocked = value;
+                }
+                if let Some(value) = payload.max_total_connections {
+                    rule.max_total_connections = Some(value).filter(|value| *value > 0);
+                }
+                if let Some(value) = payload.max_total_bytes {
+                    rule.max_total_bytes = Some(value).filter(|value| *value > 0);
+                }
+                if payload.reset_usage.unwrap_or(false) {
+                    rule.usage = RuleUsage::default();
+                }
+                if let Some(suffixes) = payload.rdns_allow_suffixes.as_deref() {
+                    rule.rdns_allow_suffixes = normalize_suffixes(Some(suffixes));
+                }
+                if let Some(targets) = payload.target_addrs.as_deref() {
+                    rule.target_addrs = normalize_targets(Some(targets));
+                }
+                if let Some(value) = payload.balance {
+                    rule.balance = value;
+                }
+                if let Some(value) = payload.target_weights {
+                    rule.target_weights = value;
+                }
+                if let Some(value) = payload.max_connections_per_target {
+                    rule.max_connections_per_target = Some(value).filter(|value| *value > 0);
+                }
+                if let Some(value) = payload.health_check_interval_secs {
+                    rule.health_check_interval_secs = Some(value).filter(|value| *value > 0);
+                }
+                if let Some(value) = payload.health_check_timeout_ms {
+                    rule.health_check_timeout_ms = Some(value).filter(|value| *value > 0);
+                }
+                if payload.dscp.is_some() {
+                    rule.dscp = payload.dscp;
+                }
+                if let Some(value) = payload.connect_timeout_ms {
+                    rule.connect_timeout_ms = Some(value).filter(|value| *value > 0);
+                }
+                if let Some(value) = payload.tcp_idle_timeout_secs {
+                    rule.tcp_idle_timeout_secs = Some(value).filter(|value| *value > 0);
+                }
+                if let Some(value) = payload.max_connection_duration_secs {
+                    rule.max_connection_duration_secs = Some(value).filter(|value| *value > 0);
+                }
+                if let Some(value) = payload.tcp_nodelay {
+                    rule.tcp_nodelay = value;
+                }
+                if let Some(value) = payload.tcp_keepalive_secs {
+                    rule.tcp_keepalive_secs = Some(value).filter(|value| *value > 0);
+                }
+                if let Some(value) = payload.tcp_keepalive_interval_secs {
+                    rule.tcp_keepalive_interval_secs = Some(value).filter(|value| *value > 0);
+                }
+                if let Some(value) = payload.udp_idle_timeout_secs {
+                    rule.udp_idle_timeout_secs = Some(value).filter(|value| *value > 0);
+                }
+                if let Some(value) = payload.send_proxy_protocol {
+                    rule.send_proxy_protocol = value;
+                }
+                if let Some(value) = payload.accept_proxy_protocol {
+                    rule.accept_proxy_protocol = value;
+                }
+                if let Some(value) = payload.http_connect {
+                    rule.http_connect = value;
+                }
+                if let Some(value) = connect_allowed_targets {
+                    rule.connect_allowed_targets = value;
+                }
+                if let Some(value) = sni_allow {
+                    rule.sni_allow = value;
+                }
+                if let Some(value) = sni_deny {
+                    rule.sni_deny = value;
+                }
+                if let Some(value) = payload.sni_fail_open {
+                    rule.sni_fail_open = value;
+                }
+                if let Some(value) = payload.transparent_egress {
+                    rule.transparent_egress = value;
+                }
+                if let Some(value) = source_addr {
+                    rule.source_addr = value;
+                }
+                if let Some(value) = payload.max_concurrent {
+                    rule.max_concurrent = Some(value).filter(|value| *value > 0);
+                }
+                if let Some(value) = payload.max_new_per_minute {
+                    rule.max_new_per_minute = Some(value).filter(|value| *value > 0);
+                }
+                if payload.mirror_addr.is_some() {
+                    rule.mirror_addr = normalize_optional(payload.mirror_addr.as_deref());
+                }
+                if let Some(value) = payload.priority {
+                    rule.priority = value;
+                }
+                if payload.name.is_some() {
+                    rule.name = normalize_optional(payload.name.as_deref());
+                }
+                if let Some(tags) = payload.tags.as_deref() {
+                    rule.tags = normalize_tags(Some(tags));
+                }
+                if let Some(value) = payload.order {
+                    rule.order = value;
+                }
+                if rule.enabled {
+                    rule.disabled_reason = None;
+                }
+                (rule.clone(), was_enabled)
+            }
+            None => {
+                return Err((
+                    StatusCode::NOT_FOUND,
+                    Json(ErrorResponse {
+                        code: "rule_not_found",
+                        error: "Rule not found".to_string(),
+                    }),
+                ))
+            }
+        }
+    };
+
+    if was_enabled {
+        stop_rule_listeners(&state, id).await;
+    }
+
+    if rule.enabled {
+        if let Err(err) = start_rule_listeners(&state, &rule).await {
+            disable_rule_after_start_failure(&state, &rule, &err).await;
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    code: "listener_failed",
+                    error: format!("Listener failed: {}", err),
                 }),
             ));
         }
     }
 
-    let snapshot = {
-        let guard = state.read().await;
-        snapshot_state(&guard)
-    };
-    persist_state(state.clone(), snapshot).await;
+    state.read().await.live.publish("rule_updated", &rule);
+    persist_state(&state).await;
     Ok(Json(rule))
 }
 
+fn validate_dscp(dscp: Option<u8>) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    match dscp {
+        Some(value) if value > sockopt::MAX_DSCP => Err((
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                code: "invalid_request",
+                error: format!("dscp must be between 0 and {}", sockopt::MAX_DSCP),
+            }),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn validate_transparent_egress(enabled: Option<bool>) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    if enabled == Some(true) && !sockopt::TRANSPARENT_SUPPORTED {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                code: "invalid_request",
+                error: "transparent_egress is only supported on Linux".to_string(),
+            }),
+        ));
+    }
+    Ok(())
+}
+
+fn parse_connect_targets(values: &[String]) -> Result<Vec<String>, (StatusCode, Json<ErrorResponse>)> {
+    let mut entries = Vec::new();
+    for value in values {
+        let value = value.trim().to_ascii_lowercase();
+        if value.is_empty() {
+            continue;
+        }
+        if !http_connect::valid_entry(&value) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    code: "invalid_request",
+                    error: format!("Invalid connect_allowed_targets entry {}: expected host:port", value),
+                }),
+            ));
+        }
+        if !entries.contains(&value) {
+            entries.push(value);
+        }
+    }
+    Ok(entries)
+}
+
+fn parse_sni_hosts(field: &str, values: &[String]) -> Result<Vec<String>, (StatusCode, Json<ErrorResponse>)> {
+    let mut entries = Vec::new();
+    for value in values {
+        if value.trim().is_empty() {
+            continue;
+        }
+        let Some(entry) = sni::normalize_entry(value) else {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    code: "invalid_request",
+                    error: format!("Invalid {} entry {}: expected a host name or *.domain", field, value.trim()),
+                }),
+            ));
+        };
+        if !entries.contains(&entry) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+// A CONNECT client's first bytes are the HTTP request, not a ClientHello.
+fn validate_sni_filter(
+    http_connect: bool,
+    sni_allow: &[String],
+    sni_deny: &[String],
+) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    if http_connect && !(sni_allow.is_empty() && sni_deny.is_empty()) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                code: "invalid_request",
+                error: "sni_allow and sni_deny cannot be used with http_connect".to_string(),
+            }),
+        ));
+    }
+    Ok(())
+}
+
+fn normalize_suffixes(values: Option<&[String]>) -> Vec<String> {
+    values
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|value| rdns::normalize_suffix(value))
+        .collect()
+}
+
+// Empty clears the source address.
+fn parse_source_addr(value: &str) -> Result<Option<IpAddr>, (StatusCode, Json<ErrorResponse>)> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    value.parse::<IpAddr>().map(Some).map_err(|_| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                code: "invalid_request",
+                error: format!("source_addr must be an IP address without a port: {}", value),
+            }),
+        )
+    })
+}
+
+// Targets given as IP literals must share the source address's family, since
+// a socket bound to an IPv4 address can't reach an IPv6 target or vice versa.
+// Hostnames are resolved to an address of the right family at connect time.
+fn validate_source_addr(
+    source_addr: Option<IpAddr>,
+    transparent_egress: bool,
+    targets: &[String],
+) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    let Some(source) = source_addr else {
+        return Ok(());
+    };
+    let bad_request = |error: String| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                code: "invalid_request",
+                error,
+            }),
+        )
+    };
+    if transparent_egress {
+        return Err(bad_request(
+            "source_addr cannot be combined with transparent_egress".to_string(),
+        ));
+    }
+    let family = |ip: &IpAddr| if ip.is_ipv4() { "IPv4" } else { "IPv6" };
+    for target in targets {
+        let Ok(target_ip) = addr_host(target).parse::<IpAddr>() else {
+            continue;
+        };
+        if target_ip.is_ipv4() != source.is_ipv4() {
+            return Err(bad_request(format!(
+                "source_addr {} is {} but target {} is {}",
+                source,
+                family(&source),
+                target,
+                family(&target_ip)
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Host part of a host:port address, without IPv6 brackets.
+fn addr_host(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map(|(host, _)| host).unwrap_or(addr);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+// Another enabled rule already listening on one of `rule`'s host:port pairs
+// (ranges expanded) over a shared protocol, with the colliding address.
+// Unparseable addresses are left for the bind to report.
+fn find_listen_conflict(rules: &[ProxyRule], rule: &ProxyRule) -> Option<(u64, String)> {
+    if !rule.enabled {
+        return None;
+    }
+    let wanted = port_range::expand_listen_targets(&rule.listen_addr, &rule.target_addr).ok()?;
+    for other in rules.iter().filter(|other| other.enabled && other.id != rule.id) {
+        let shares_protocol = (rule.protocol.uses_tcp() && other.protocol.uses_tcp())
+            || (rule.protocol.uses_udp() && other.protocol.uses_udp());
+        if !shares_protocol {
+            continue;
+        }
+        let Ok(existing) = port_range::expand_listen_targets(&other.listen_addr, &other.target_addr) else {
+            continue;
+        };
+        for target in &wanted {
+            let collides = existing.iter().any(|item| {
+                item.listen_port == target.listen_port && listen_hosts_overlap(&item.listen_addr, &target.listen_addr)
+            });
+            if collides {
+                return Some((other.id, target.listen_addr.clone()));
+            }
+        }
+    }
+    None
+}
+
+// A wildcard host overlaps every address of its family; [::] also takes the
+// IPv4 wildcard's ports on dual-stack hosts, so it overlaps everything.
+fn listen_hosts_overlap(a: &str, b: &str) -> bool {
+    let (a, b) = (addr_host(a), addr_host(b));
+    match (a.parse::<IpAddr>(), b.parse::<IpAddr>()) {
+        (Ok(a), Ok(b)) => {
+            let covers = |wild: IpAddr, other: IpAddr| {
+                wild.is_unspecified() && (wild.is_ipv6() || other.is_ipv4())
+            };
+            a == b || covers(a, b) || covers(b, a)
+        }
+        _ => a.eq_ignore_ascii_case(b),
+    }
+}
+
+fn ensure_api_managed(rules: &[ProxyRule], id: u64) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    if rules.iter().any(|rule| rule.id == id && rule.from_rules_file) {
+        return Err((
+            StatusCode::CONFLICT,
+            Json(ErrorResponse {
+                code: "rules_file_managed",
+                error: format!("Rule {} is managed by the rules file; edit the file instead", id),
+            }),
+        ));
+    }
+    Ok(())
+}
+
+fn listen_port_count(rule: &ProxyRule) -> usize {
+    port_range::expand_listen_targets(&rule.listen_addr, &rule.target_addr).map_or(0, |targets| targets.len())
+}
+
+// Listen addresses across every rule, disabled ones included since they can
+// be enabled without another check. Only a rule that grows is refused, so
+// lowering the cap doesn't lock existing rules out of unrelated edits.
+fn check_listen_port_limit(state: &AppState, rule: &ProxyRule) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    let current = state
+        .rules
+        .iter()
+        .find(|other| other.id == rule.id)
+        .map_or(0, listen_port_count);
+    let wanted = listen_port_count(rule);
+    if wanted <= current {
+        return Ok(());
+    }
+    let total = state.rules.iter().map(listen_port_count).sum::<usize>() - current + wanted;
+    if total > state.config.max_listen_ports {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                code: "listen_port_limit",
+                error: format!(
+                    "Rule needs {} listen ports, which would bring the total to {} (max {}, see --max-listen-ports)",
+                    wanted, total, state.config.max_listen_ports
+                ),
+            }),
+        ));
+    }
+    Ok(())
+}
+
+fn listen_conflict_error((rule_id, addr): (u64, String)) -> (StatusCode, Json<ErrorResponse>) {
+    (
+        StatusCode::CONFLICT,
+        Json(ErrorResponse {
+            code: "listen_conflict",
+            error: format!("{} is already in use by rule {}", addr, rule_id),
+        }),
+    )
+}
+
+// Trimmed, lowercased and deduplicated, keeping the given order.
+fn normalize_tags(values: Option<&[String]>) -> Vec<String> {
+    let mut tags = Vec::new();
+    for value in values.unwrap_or_default() {
+        let tag = value.trim().to_lowercase();
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags
+}
+
+// Trims an optional string field; empty clears it.
+fn normalize_optional(value: Option<&str>) -> Option<String> {
+    value
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+        .map(|value| value.to_string())
+}
+
+fn normalize_targets(values: Option<&[String]>) -> Vec<String> {
+    values
+        .unwrap_or_default()
+        .iter()
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+        .map(|value| value.to_string())
+        .collect()
+}
+
 async fn remove_rule(
     Path(id): Path<u64>,
     State(state): State<Arc<RwLock<AppState>>>,
 ) -> Result<Json<ProxyRule>, (StatusCode, Json<ErrorResponse>)> {
+    ensure_api_managed(&state.read().await.rules, id)?;
     stop_rule_listeners(&state, id).await;
 
-    let (removed, snapshot) = {
+    let removed = {
         let mut guard = state.write().await;
         let idx = guard.rules.iter().position(|rule| rule.id == id);
         match idx {
             Some(index) => {
                 let removed = guard.rules.remove(index);
-                (removed, snapshot_state(&guard))
+                guard.live.publish("rule_removed", &removed);
+                guard.target_cursors.remove(&id);
+                guard.target_load.remove(&id);
+                guard.accept_errors.remove(&id);
+                guard.listener_status.remove(&id);
+                guard.rule_rate_counters.remove(&id);
+                removed
             }
             None => {
                 return Err((
                     StatusCode::NOT_FOUND,
                     Json(ErrorResponse {
+                        code: "rule_not_found",
                         error: "Rule not found".to_string(),
                     }),
                 ))
@@ -659,10 +3007,36 @@ async fn remove_rule(
         }
     };
 
-    persist_state(state.clone(), snapshot).await;
+    persist_state(&state).await;
     Ok(Json(removed))
 }
 
+#[derive(Serialize)]
+struct RuleTargetHealth {
+    rule_id: u64,
+    #[serde(flatten)]
+    health: health::TargetHealth,
+}
+
+async fn targets_health(State(state): State<Arc<RwLock<AppState>>>) -> Json<Vec<RuleTargetHealth>> {
+    let guard = state.read().await;
+    let mut items = Vec::new();
+    for (rule_id, rule_health) in &guard.target_health {
+        for health in rule_health.snapshot() {
+            items.push(RuleTargetHealth {
+                rule_id: *rule_id,
+                health,
+            });
+        }
+    }
+    items.sort_by(|a, b| {
+        a.rule_id
+            .cmp(&b.rule_id)
+            .then_with(|| a.health.target.cmp(&b.health.target))
+    });
+    Json(items)
+}
+
 async fn active_connections(State(state): State<Arc<RwLock<AppState>>>) -> Json<Vec<ActiveConn>> {
     let guard = state.read().await;
     let mut items = guard.active.values().cloned().collect::<Vec<_>>();
@@ -670,17 +3044,31 @@ async fn active_connections(State(state): State<Arc<RwLock<AppState>>>) -> Json<
     Json(items)
 }
 
+async fn live_updates(
+    State(state): State<Arc<RwLock<AppState>>>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let receiver = state.read().await.live.subscribe();
+    ws.on_upgrade(move |socket| live::serve(socket, receiver))
+}
+
+async fn event_stream(
+    State(state): State<Arc<RwLock<AppState>>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let receiver = state.read().await.live.subscribe();
+    Sse::new(live::event_stream(receiver)).keep_alive(KeepAlive::new().interval(SSE_KEEPALIVE))
+}
+
 async fn recent_connections(
     State(state): State<Arc<RwLock<AppState>>>,
     Query(params): Query<RecentQuery>,
 ) -> Json<Vec<ConnectionLog>> {
-    let limit = params.limit.unwrap_or(100).min(MAX_HISTORY);
+    let limit = params.limit.unwrap_or(100).min(MAX_PAGE_SIZE);
     let guard = state.read().await;
     let items = guard
         .history
         .iter()
         .rev()
-        .filter(|entry| !entry.blocked)
         .take(limit)
         .cloned()
         .collect::<Vec<_>>();
@@ -690,14 +3078,11 @@ async fn recent_connections(
 async fn ddos_list(State(state): State<Arc<RwLock<AppState>>>) -> Json<Vec<DdosEntry>> {
     let guard = state.read().await;
     let mut items: HashMap<String, DdosEntry> = HashMap::new();
-    for entry in &guard.history {
-        let reason = match entry.reason.as_deref() {
-            Some(value) if is_ddos_reason(value) => value,
-            _ => continue,
-        };
-        if !entry.blocked {
+    for entry in &guard.blocked_history {
+        if !entry.block_reason.is_some_and(BlockReason::is_ddos) {
             continue;
         }
+        let reason = entry.reason.as_deref().unwrap_or_default();
         let last_seen = entry
             .ended_at
             .clone()
@@ -719,71 +3104,410 @@ async fn ddos_list(State(state): State<Arc<RwLock<AppState>>>) -> Json<Vec<DdosE
     Json(entries)
 }
 
+#[derive(Deserialize)]
+struct TopTalkersQuery {
+    window: Option<u64>,
+    limit: Option<usize>,
+}
+
+// Clients ranked by bytes relayed in connections that ended within the last
+// `window` seconds (default an hour, at most a day). Complements /api/ddos,
+// which only sees refused attempts.
+async fn top_talkers(
+    State(state): State<Arc<RwLock<AppState>>>,
+    Query(params): Query<TopTalkersQuery>,
+) -> Json<Vec<talkers::TalkerEntry>> {
+    let window = params.window.unwrap_or(3600);
+    let limit = params.limit.unwrap_or(20).min(MAX_PAGE_SIZE);
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    Json(state.read().await.top_talkers.top(now, window, limit))
+}
+
 async fn blocked_connections(
     State(state): State<Arc<RwLock<AppState>>>,
     Query(params): Query<BlockedQuery>,
 ) -> Json<Vec<ConnectionLog>> {
-    let limit = params.limit.unwrap_or(200).min(MAX_HISTORY);
+    let limit = params.limit.unwrap_or(200).min(MAX_PAGE_SIZE);
     let guard = state.read().await;
     let items = guard
-        .history
+        .blocked_history
         .iter()
         .rev()
-        .filter(|entry| entry.blocked)
+        .filter(|entry| params.category.is_none_or(|category| entry.block_reason == Some(category)))
         .take(limit)
         .cloned()
         .collect::<Vec<_>>();
     Json(items)
 }
 
-async fn history(
-    State(state): State<Arc<RwLock<AppState>>>,
-    Query(params): Query<HistoryQuery>,
-) -> Json<Vec<ConnectionLog>> {
-    let limit = params.limit.unwrap_or(200).min(MAX_HISTORY);
+// Blocked attempts still in the log per category, most frequent first.
+async fn blocked_categories(State(state): State<Arc<RwLock<AppState>>>) -> Json<Vec<BlockedCategoryCount>> {
     let guard = state.read().await;
-    let mut items = guard.history.clone();
-    if items.len() > limit {
-        items = items.split_off(items.len() - limit);
+    let mut counts: HashMap<Option<BlockReason>, usize> = HashMap::new();
+    for entry in &guard.blocked_history {
+        *counts.entry(entry.block_reason).or_default() += 1;
     }
-    Json(items)
+    let mut entries = counts
+        .into_iter()
+        .map(|(category, count)| BlockedCategoryCount { category, count })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.count));
+    Json(entries)
 }
 
-async fn blocklist(State(state): State<Arc<RwLock<AppState>>>) -> Json<Vec<BlockEntry>> {
+// Returns one page of matching entries, oldest first, with the number of
+// matches in X-Total-Count. Only the page itself is cloned. Without a
+// `blocked` filter this covers both logs, as it did before they were split.
+async fn history(
+    State(state): State<Arc<RwLock<AppState>>>,
+    Query(params): Query<HistoryQuery>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorResponse>)> {
+    let limit = params.limit.unwrap_or(200).min(MAX_PAGE_SIZE);
+    let offset = params.offset.unwrap_or(0);
+    let since = params.since.as_deref().map(|value| parse_time_param("since", value)).transpose()?;
+    let until = params.until.as_deref().map(|value| parse_time_param("until", value)).transpose()?;
+    let has_time_filter = since.is_some() || until.is_some();
+
     let guard = state.read().await;
+    let matches = |entry: &&ConnectionLog| {
+        if params.rule_id.is_some_and(|rule_id| entry.rule_id != rule_id)
+            || params.blocked.is_some_and(|blocked| entry.blocked != blocked)
+            || params.client_ip.as_deref().is_some_and(|ip| entry.client_ip != ip)
+        {
+            return false;
+        }
+        if !has_time_filter {
+            return true;
+        }
+        let Ok(started) = OffsetDateTime::parse(&entry.started_at, &Rfc3339) else {
+            return false;
+        };
+        since.is_none_or(|since| started >= since) && until.is_none_or(|until| started <= until)
+    };
+    let mut total = 0usize;
     let mut items = Vec::new();
-    for ip in &guard.blocklist {
-        items.push(BlockEntry {
-            ip: ip.clone(),
-            port: None,
-        });
-    }
-    for (port, ips) in &guard.port_blocklist {
-        for ip in ips {
-            items.push(BlockEntry {
-                ip: ip.clone(),
-                port: Some(*port),
-            });
+    let entries: Box<dyn Iterator<Item = &ConnectionLog>> = match params.blocked {
+        Some(true) => Box::new(guard.blocked_history.iter().rev()),
+        Some(false) => Box::new(guard.history.iter().rev()),
+        None => Box::new(merged_history_rev(&guard)),
+    };
+    for entry in entries.filter(matches) {
+        if total >= offset && items.len() < limit {
+            items.push(entry.clone());
         }
+        total += 1;
     }
-    items.sort_by(|a, b| {
-        let port_a = a.port.unwrap_or(0);
-        let port_b = b.port.unwrap_or(0);
-        port_a
-            .cmp(&port_b)
-            .then_with(|| a.ip.cmp(&b.ip))
-    });
-    Json(items)
+    items.reverse();
+    Ok(([(HeaderName::from_static("x-total-count"), total.to_string())], Json(items)))
 }
 
-async fn add_block(
+// Purges both connection logs, or only entries older than ?before=.
+// Active connections and the lifetime rule totals are left alone.
+async fn clear_history(
     State(state): State<Arc<RwLock<AppState>>>,
-    Json(payload): Json<BlockRequest>,
-) -> Result<Json<Vec<BlockEntry>>, (StatusCode, Json<ErrorResponse>)> {
-    if payload.ip.trim().is_empty() {
+    Query(params): Query<ClearHistoryQuery>,
+) -> Result<Json<ClearHistoryResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let before = params.before.as_deref().map(|value| parse_time_param("before", value)).transpose()?;
+    let response = {
+        let mut guard = state.write().await;
+        let state_ref = &mut *guard;
+        let count = state_ref.history.len() + state_ref.blocked_history.len();
+        match before {
+            Some(before) => {
+                let keep = |entry: &ConnectionLog| log_time(entry).is_none_or(|at| at >= before);
+                state_ref.history.retain(keep);
+                state_ref.blocked_history.retain(keep);
+            }
+            None => {
+                state_ref.history.clear();
+                state_ref.blocked_history.clear();
+            }
+        }
+        let response = ClearHistoryResponse {
+            removed: count - state_ref.history.len() - state_ref.blocked_history.len(),
+        };
+        // Ids restart after the highest one still in use, so an open
+        // connection never shares an id with a later one.
+        state_ref.next_conn_id = state_ref
+            .history
+            .iter()
+            .chain(&state_ref.blocked_history)
+            .map(|log| log.id)
+            .chain(state_ref.active.keys().copied())
+            .max()
+            .unwrap_or(0)
+            + 1;
+        state_ref.live.publish("history_cleared", &response);
+        response
+    };
+    persist_state(&state).await;
+    Ok(Json(response))
+}
+
+#[derive(Serialize)]
+struct ConsistencyReport {
+    consistent: bool,
+    active: usize,
+    mismatches: Vec<CounterMismatch>,
+}
+
+#[derive(Serialize)]
+struct CounterMismatch {
+    counter: &'static str,
+    key: String,
+    expected: usize,
+    actual: usize,
+}
+
+// Per-key active counters recomputed from `active`, the source of truth.
+struct ExpectedCounters {
+    by_ip: HashMap<String, usize>,
+    by_rule_ip: HashMap<(u64, String), usize>,
+    by_rule: HashMap<u64, usize>,
+    by_port: HashMap<u16, usize>,
+    by_port_ip: HashMap<(u16, String), usize>,
+}
+
+impl ExpectedCounters {
+    fn from_active(state: &AppState) -> Self {
+        let mut expected = Self {
+            by_ip: HashMap::new(),
+            by_rule_ip: HashMap::new(),
+            by_rule: HashMap::new(),
+            by_port: HashMap::new(),
+            by_port_ip: HashMap::new(),
+        };
+        for conn in state.active.values() {
+            *expected.by_ip.entry(conn.client_ip.clone()).or_default() += 1;
+            *expected
+                .by_rule_ip
+                .entry((conn.rule_id, conn.client_ip.clone()))
+                .or_default() += 1;
+            *expected.by_rule.entry(conn.rule_id).or_default() += 1;
+            if let Some(port) = conn.listen_port {
+                *expected.by_port.entry(port).or_default() += 1;
+                *expected.by_port_ip.entry((port, conn.client_ip.clone())).or_default() += 1;
+            }
+        }
+        expected
+    }
+
+    fn report(&self, state: &AppState) -> ConsistencyReport {
+        let mut mismatches = Vec::new();
+        diff_counters(
+            "active_by_ip",
+            &self.by_ip,
+            &state.active_by_ip,
+            |ip| ip.clone(),
+            &mut mismatches,
+        );
+        diff_counters(
+            "active_by_rule_ip",
+            &self.by_rule_ip,
+            &state.active_by_rule_ip,
+            |(rule_id, ip)| format!("{}/{}", rule_id, ip),
+            &mut mismatches,
+        );
+        diff_counters(
+            "active_by_rule",
+            &self.by_rule,
+            &state.active_by_rule,
+            |rule_id| rule_id.to_string(),
+            &mut mismatches,
+        );
+        diff_counters(
+            "active_by_port",
+            &self.by_port,
+            &state.active_by_port,
+            |port| port.to_string(),
+            &mut mismatches,
+        );
+        diff_counters(
+            "active_by_port_ip",
+            &self.by_port_ip,
+            &state.active_by_port_ip,
+            |(port, ip)| format!("{}/{}", port, ip),
+            &mut mismatches,
+        );
+        mismatches.sort_by(|a, b| (a.counter, &a.key).cmp(&(b.counter, &b.key)));
+        ConsistencyReport {
+            consistent: mismatches.is_empty(),
+            active: state.active.len(),
+            mismatches,
+        }
+    }
+}
+
+fn diff_counters<K: std::hash::Hash + Eq>(
+    counter: &'static str,
+    expected: &HashMap<K, usize>,
+    actual: &HashMap<K, usize>,
+    describe: impl Fn(&K) -> String,
+    mismatches: &mut Vec<CounterMismatch>,
+) {
+    for key in expected.keys().chain(actual.keys().filter(|key| !expected.contains_key(*key))) {
+        let expected = expected.get(key).copied().unwrap_or(0);
+        let actual = actual.get(key).copied().unwrap_or(0);
+        if expected != actual {
+            mismatches.push(CounterMismatch {
+                counter,
+                key: describe(key),
+                expected,
+                actual,
+            });
+        }
+    }
+}
+
+// Compares the per-IP/rule/port active counters with the active table. A count
+// left above zero after its connections ended would keep refusing that
+// client under the concurrency limits.
+async fn check_consistency(State(state): State<Arc<RwLock<AppState>>>) -> Json<ConsistencyReport> {
+    let guard = state.read().await;
+    Json(ExpectedCounters::from_active(&guard).report(&guard))
+}
+
+// Rebuilds the counters from the active table; returns what was wrong.
+async fn repair_consistency(State(state): State<Arc<RwLock<AppState>>>) -> Json<ConsistencyReport> {
+    let mut guard = state.write().await;
+    let expected = ExpectedCounters::from_active(&guard);
+    let report = expected.report(&guard);
+    if !report.consistent {
+        warn!("Repairing {} connection counter mismatches", report.mismatches.len());
+        guard.active_by_ip = expected.by_ip;
+        guard.active_by_rule_ip = expected.by_rule_ip;
+        guard.active_by_rule = expected.by_rule;
+        guard.active_by_port = expected.by_port;
+        guard.active_by_port_ip = expected.by_port_ip;
+    }
+    Json(report)
+}
+
+#[derive(Deserialize)]
+struct TimeseriesQuery {
+    window: Option<u64>,
+    bucket: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct TimeseriesResponse {
+    window_secs: u64,
+    bucket_secs: u64,
+    buckets: Vec<TimeseriesBucket>,
+}
+
+#[derive(Default, Serialize)]
+struct TimeseriesBucket {
+    start: String,
+    connections: u64,
+    blocked: u64,
+    bytes: u64,
+}
+
+// History bucketed by start time into fixed intervals aligned to the bucket
+// size, oldest first; the last bucket is the one still filling. Connections
+// show up once they have ended, since that is when they enter the history.
+async fn metrics_timeseries(
+    State(state): State<Arc<RwLock<AppState>>>,
+    Query(params): Query<TimeseriesQuery>,
+) -> Result<Json<TimeseriesResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let window = params.window.unwrap_or(3600);
+    let mut bucket = params.bucket.unwrap_or(60);
+    if window == 0 || bucket == 0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                code: "invalid_request",
+                error: "window and bucket must be positive".to_string(),
+            }),
+        ));
+    }
+    bucket = bucket.max(window.div_ceil(MAX_TIMESERIES_BUCKETS));
+    let count = window.div_ceil(bucket);
+
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let last_start = now - now.rem_euclid(bucket as i64);
+    let first_start = last_start - (count as i64 - 1) * bucket as i64;
+    let mut buckets = (0..count)
+        .map(|index| TimeseriesBucket {
+            start: OffsetDateTime::from_unix_timestamp(first_start + (index * bucket) as i64)
+                .ok()
+                .and_then(|start| start.format(&Rfc3339).ok())
+                .unwrap_or_default(),
+            ..TimeseriesBucket::default()
+        })
+        .collect::<Vec<_>>();
+
+    let guard = state.read().await;
+    for entry in guard.history.iter().chain(&guard.blocked_history) {
+        let Ok(started) = OffsetDateTime::parse(&entry.started_at, &Rfc3339) else {
+            continue;
+        };
+        let offset = started.unix_timestamp() - first_start;
+        if offset < 0 {
+            continue;
+        }
+        let Some(slot) = buckets.get_mut((offset as u64 / bucket) as usize) else {
+            continue;
+        };
+        if entry.blocked {
+            slot.blocked += 1;
+        } else {
+            slot.connections += 1;
+        }
+        slot.bytes = slot.bytes.saturating_add(entry.bytes_up.saturating_add(entry.bytes_down));
+    }
+    Ok(Json(TimeseriesResponse {
+        window_secs: count * bucket,
+        bucket_secs: bucket,
+        buckets,
+    }))
+}
+
+async fn blocklist(State(state): State<Arc<RwLock<AppState>>>) -> Json<Vec<BlockEntry>> {
+    let guard = state.read().await;
+    let now = OffsetDateTime::now_utc();
+    let entry = |ip: &String, port: Option<u16>| {
+        let expires_at = guard.block_expiry.get(&(ip.clone(), port)).copied();
+        if expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return None;
+        }
+        Some(BlockEntry {
+            ip: ip.clone(),
+            port,
+            expires_at: expires_at.and_then(|expires_at| expires_at.format(&Rfc3339).ok()),
+            remaining_secs: expires_at.map(|expires_at| (expires_at - now).whole_seconds().max(0) as u64),
+            note: guard.block_notes.get(&(ip.clone(), port)).cloned(),
+        })
+    };
+    let mut items = Vec::new();
+    for ip in &guard.blocklist {
+        items.extend(entry(ip, None));
+    }
+    for (port, ips) in &guard.port_blocklist {
+        for ip in ips {
+            items.extend(entry(ip, Some(*port)));
+        }
+    }
+    items.sort_by(|a, b| {
+        let port_a = a.port.unwrap_or(0);
+        let port_b = b.port.unwrap_or(0);
+        port_a
+            .cmp(&port_b)
+            .then_with(|| a.ip.cmp(&b.ip))
+    });
+    Json(items)
+}
+
+async fn add_block(
+    State(state): State<Arc<RwLock<AppState>>>,
+    Json(payload): Json<BlockRequest>,
+) -> Result<Json<Vec<BlockEntry>>, (StatusCode, Json<ErrorResponse>)> {
+    if payload.ip.trim().is_empty() {
         return Err((
             StatusCode::BAD_REQUEST,
             Json(ErrorResponse {
+                code: "invalid_request",
                 error: "IP is required".to_string(),
             }),
         ));
@@ -793,31 +3517,25 @@ async fn add_block(
             return Err((
                 StatusCode::BAD_REQUEST,
                 Json(ErrorResponse {
+                    code: "invalid_request",
                     error: "Port must be between 1 and 65535".to_string(),
                 }),
             ));
         }
     }
 
-    let snapshot = {
+    {
         let mut guard = state.write().await;
-        let ip = payload.ip.trim().to_string();
-        match payload.port {
-            Some(port) => {
-                guard
-                    .port_blocklist
-                    .entry(port)
-                    .or_insert_with(HashSet::new)
-                    .insert(ip);
-            }
-            None => {
-                guard.blocklist.insert(ip);
-            }
-        }
-        snapshot_state(&guard)
-    };
+        let ip = normalize_ip_entry(&payload.ip);
+        let ttl = payload
+            .ttl_seconds
+            .filter(|value| *value > 0)
+            .map(Duration::from_secs);
+        set_note(&mut guard.block_notes, &ip, payload.port, payload.note.as_deref());
+        insert_block(&mut guard, ip, payload.port, ttl);
+    }
 
-    persist_state(state.clone(), snapshot).await;
+    persist_state(&state).await;
     Ok(blocklist(State(state)).await)
 }
 
@@ -826,23 +3544,198 @@ async fn remove_block(
     Query(query): Query<BlockQuery>,
     State(state): State<Arc<RwLock<AppState>>>,
 ) -> Result<Json<Vec<BlockEntry>>, (StatusCode, Json<ErrorResponse>)> {
-    let snapshot = {
+    {
         let mut guard = state.write().await;
-        let ip = ip.trim();
-        if let Some(port) = query.port {
-            if let Some(ips) = guard.port_blocklist.get_mut(&port) {
-                ips.remove(ip);
+        let ip = normalize_ip_entry(&ip);
+        remove_block_entry(&mut guard, &ip, query.port);
+    }
+    persist_state(&state).await;
+    Ok(blocklist(State(state)).await)
+}
+
+// A ttl makes the block temporary; without one any previous expiry for the
+// same (ip, port) is dropped so the block becomes permanent.
+fn insert_block(state: &mut AppState, ip: String, port: Option<u16>, ttl: Option<Duration>) {
+    let key = (ip.clone(), port);
+    let note = state.block_notes.get(&key).cloned();
+    let expires_at = ttl.map(|ttl| OffsetDateTime::now_utc() + ttl);
+    match expires_at {
+        Some(expires_at) => {
+            state.block_expiry.insert(key, expires_at);
+        }
+        None => {
+            state.block_expiry.remove(&key);
+        }
+    }
+    state.live.publish(
+        "block_added",
+        &BlockEntry {
+            ip: ip.clone(),
+            port,
+            expires_at: expires_at.and_then(|value| value.format(&Rfc3339).ok()),
+            remaining_secs: ttl.map(|ttl| ttl.as_secs()),
+            note,
+        },
+    );
+    match port {
+        Some(port) => {
+            state.port_blocklist.entry(port).or_default().insert(ip);
+        }
+        None => {
+            state.blocklist.insert(ip);
+        }
+    }
+}
+
+fn remove_block_entry(state: &mut AppState, ip: &str, port: Option<u16>) {
+    state.block_expiry.remove(&(ip.to_string(), port));
+    state.block_notes.remove(&(ip.to_string(), port));
+    let removed = match port {
+        Some(port) => match state.port_blocklist.get_mut(&port) {
+            Some(ips) => {
+                let removed = ips.remove(ip);
                 if ips.is_empty() {
-                    guard.port_blocklist.remove(&port);
+                    state.port_blocklist.remove(&port);
                 }
+                removed
             }
-        } else {
-            guard.blocklist.remove(ip);
+            None => false,
+        },
+        None => state.blocklist.remove(ip),
+    };
+    if removed {
+        state.live.publish(
+            "block_removed",
+            &BlockEntry {
+                ip: ip.to_string(),
+                port,
+                expires_at: None,
+                remaining_secs: None,
+                note: None,
+            },
+        );
+    }
+}
+
+fn set_note(notes: &mut HashMap<(String, Option<u16>), String>, ip: &str, port: Option<u16>, note: Option<&str>) {
+    let key = (ip.to_string(), port);
+    match normalize_optional(note) {
+        Some(note) => {
+            notes.insert(key, note);
         }
-        snapshot_state(&guard)
+        None => {
+            notes.remove(&key);
+        }
+    }
+}
+
+fn block_in_effect(state: &AppState, ip: &str, port: Option<u16>) -> bool {
+    let listed = match port {
+        Some(port) => state
+            .port_blocklist
+            .get(&port)
+            .is_some_and(|ips| ips.contains(ip)),
+        None => state.blocklist.contains(ip),
     };
-    persist_state(state.clone(), snapshot).await;
-    Ok(blocklist(State(state)).await)
+    listed
+        && state
+            .block_expiry
+            .get(&(ip.to_string(), port))
+            .is_none_or(|expires_at| *expires_at > OffsetDateTime::now_utc())
+}
+
+// Drops temporary blocks whose expiry has passed; returns whether any were
+// removed so the caller knows to persist.
+fn reap_expired_blocks(state: &mut AppState) -> bool {
+    let now = OffsetDateTime::now_utc();
+    let expired = state
+        .block_expiry
+        .iter()
+        .filter(|(_, expires_at)| **expires_at <= now)
+        .map(|(key, _)| key.clone())
+        .collect::<Vec<_>>();
+    for (ip, port) in &expired {
+        remove_block_entry(state, ip, *port);
+    }
+    !expired.is_empty()
+}
+
+async fn blocklist_files(
+    State(state): State<Arc<RwLock<AppState>>>,
+) -> Json<Vec<blocklist_file::BlocklistFileInfo>> {
+    let guard = state.read().await;
+    Json(guard.blocklist_files.iter().map(|file| file.info()).collect())
+}
+
+#[derive(Serialize)]
+struct CheckIpResult {
+    ip: String,
+    port: Option<u16>,
+    allowed: bool,
+    reason: Option<String>,
+    block_reason: Option<BlockReason>,
+    country: Option<String>,
+    asn: Option<u32>,
+    rate_limit_bypass: bool,
+    new_connections_last_minute: usize,
+}
+
+// Runs the allow/deny lists against an address without touching rate
+// windows, so support can see why a client is refused. Connection and rate
+// limits depend on the moment of connecting and aren't evaluated.
+async fn check_ip(
+    Path(ip): Path<String>,
+    Query(query): Query<BlockQuery>,
+    State(state): State<Arc<RwLock<AppState>>>,
+) -> Result<Json<CheckIpResult>, (StatusCode, Json<ErrorResponse>)> {
+    let ip = normalize_ip_entry(&ip);
+    let Ok(addr) = ip.parse::<IpAddr>() else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                code: "invalid_request",
+                error: "Invalid IP address".to_string(),
+            }),
+        ));
+    };
+    let guard = state.read().await;
+    let refused = check_policy(&guard, &ip, query.port).err();
+    let now = Instant::now();
+    let new_connections_last_minute = guard.rate_counters.get(&ip).map_or(0, |window| {
+        window
+            .iter()
+            .filter(|at| now.duration_since(**at) <= Duration::from_secs(60))
+            .count()
+    });
+    Ok(Json(CheckIpResult {
+        port: query.port,
+        allowed: refused.is_none(),
+        block_reason: refused.as_ref().map(|refused| refused.reason),
+        reason: refused.map(|refused| refused.message),
+        country: guard.geo_db.as_ref().and_then(|db| geo::lookup_country(db, addr)),
+        asn: guard.asn_db.as_ref().and_then(|db| geo::lookup_asn(db, addr)),
+        rate_limit_bypass: bypasses_rate_limits(&guard, &ip),
+        new_connections_last_minute,
+        ip,
+    }))
+}
+
+fn start_block_reaper(state: Arc<RwLock<AppState>>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(BLOCK_REAP_INTERVAL).await;
+            {
+                let mut guard = state.write().await;
+                // Age-based history trimming also runs here so a quiet proxy
+                // still sheds old entries.
+                let reaped = reap_expired_blocks(&mut guard);
+                if !(trim_history(&mut guard) || reaped) {
+                    continue;
+                }
+            }
+            persist_state(&state).await;
+        }
+    });
 }
 
 async fn geo_blocklist(State(state): State<Arc<RwLock<AppState>>>) -> Json<Vec<geo::GeoEntry>> {
@@ -882,6 +3775,7 @@ async fn add_geo_block(
             return Err((
                 StatusCode::BAD_REQUEST,
                 Json(ErrorResponse {
+                    code: "invalid_request",
                     error: err.to_string(),
                 }),
             ))
@@ -892,13 +3786,14 @@ async fn add_geo_block(
             return Err((
                 StatusCode::BAD_REQUEST,
                 Json(ErrorResponse {
+                    code: "invalid_request",
                     error: "Port must be between 1 and 65535".to_string(),
                 }),
             ));
         }
     }
 
-    let snapshot = {
+    {
         let mut guard = state.write().await;
         match payload.port {
             Some(port) => {
@@ -912,10 +3807,9 @@ async fn add_geo_block(
                 guard.geo_blocklist.insert(country);
             }
         }
-        snapshot_state(&guard)
-    };
+    }
 
-    persist_state(state.clone(), snapshot).await;
+    persist_state(&state).await;
     Ok(geo_blocklist(State(state)).await)
 }
 
@@ -930,12 +3824,13 @@ async fn remove_geo_block(
             return Err((
                 StatusCode::BAD_REQUEST,
                 Json(ErrorResponse {
+                    code: "invalid_request",
                     error: err.to_string(),
                 }),
             ))
         }
     };
-    let snapshot = {
+    {
         let mut guard = state.write().await;
         if let Some(port) = query.port {
             if let Some(countries) = guard.geo_port_blocklist.get_mut(&port) {
@@ -947,19 +3842,151 @@ async fn remove_geo_block(
         } else {
             guard.geo_blocklist.remove(&country);
         }
-        snapshot_state(&guard)
-    };
-    persist_state(state.clone(), snapshot).await;
+    }
+    persist_state(&state).await;
     Ok(geo_blocklist(State(state)).await)
 }
 
+// Replaces the country (or ASN) database with the raw .mmdb request body, for
+// hosts that can't reach the download mirrors.
+async fn upload_geo_db(
+    State(state): State<Arc<RwLock<AppState>>>,
+    Query(query): Query<GeoDbUploadQuery>,
+    body: Bytes,
+) -> Result<Json<geo::GeoDbInfo>, (StatusCode, Json<ErrorResponse>)> {
+    let bad_request = |error: String| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                code: "invalid_request",
+                error,
+            }),
+        )
+    };
+    let asn = match query.kind.as_deref().unwrap_or("country") {
+        "country" => false,
+        "asn" => true,
+        other => return Err(bad_request(format!("Unknown database kind: {}", other))),
+    };
+    if body.len() < geo_update::MIN_DB_SIZE {
+        return Err(bad_request("Database file too small".to_string()));
+    }
+    let expected_type = if asn { "ASN" } else { "Country" };
+    let (db, info) = geo::open_db_bytes(body.to_vec(), expected_type).map_err(|err| bad_request(err.to_string()))?;
+
+    let data_dir = state.read().await.config.data_dir.clone();
+    let filename = if asn { geo::ASN_DB_FILENAME } else { geo::GEO_DB_FILENAME };
+    let write_result = async {
+        tokio::fs::create_dir_all(&data_dir).await?;
+        geo_update::write_db_file(&data_dir.join(filename), &body).await
+    }
+    .await;
+    if let Err(err) = write_result {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse {
+                code: "save_failed",
+                error: format!("Failed to save database: {}", err),
+            }),
+        ));
+    }
+
+    {
+        let mut guard = state.write().await;
+        if asn {
+            guard.asn_db = Some(db);
+        } else {
+            guard.geo_db = Some(db);
+        }
+    }
+    info!("{} replaced by upload ({} bytes, built {})", filename, info.size, info.build_epoch);
+    Ok(Json(info))
+}
+
+// Re-reads the panel certificate and key, e.g. after a renewal. SIGHUP does
+// the same.
+async fn reload_tls(
+    State(state): State<Arc<RwLock<AppState>>>,
+) -> Result<Json<tls::TlsInfo>, (StatusCode, Json<ErrorResponse>)> {
+    let Some(panel_tls) = state.read().await.tls.clone() else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                code: "tls_disabled",
+                error: "TLS is not enabled".to_string(),
+            }),
+        ));
+    };
+    panel_tls.reload().map(Json).map_err(|err| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse {
+                code: "tls_reload_failed",
+                error: format!("TLS reload failed: {:#}", err),
+            }),
+        )
+    })
+}
+
+async fn asn_blocklist(State(state): State<Arc<RwLock<AppState>>>) -> Json<Vec<geo::AsnEntry>> {
+    let guard = state.read().await;
+    let mut items = guard
+        .asn_blocklist
+        .iter()
+        .map(|asn| geo::AsnEntry { asn: *asn })
+        .collect::<Vec<_>>();
+    items.sort_by_key(|item| item.asn);
+    Json(items)
+}
+
+async fn add_asn_block(
+    State(state): State<Arc<RwLock<AppState>>>,
+    Json(payload): Json<geo::AsnBlockRequest>,
+) -> Result<Json<Vec<geo::AsnEntry>>, (StatusCode, Json<ErrorResponse>)> {
+    if payload.asn == 0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                code: "invalid_request",
+                error: "ASN must be a positive number".to_string(),
+            }),
+        ));
+    }
+    state.write().await.asn_blocklist.insert(payload.asn);
+    persist_state(&state).await;
+    Ok(asn_blocklist(State(state)).await)
+}
+
+async fn remove_asn_block(
+    Path(asn): Path<String>,
+    State(state): State<Arc<RwLock<AppState>>>,
+) -> Result<Json<Vec<geo::AsnEntry>>, (StatusCode, Json<ErrorResponse>)> {
+    let asn = match geo::normalize_asn(&asn) {
+        Ok(value) => value,
+        Err(err) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    code: "invalid_request",
+                    error: err.to_string(),
+                }),
+            ))
+        }
+    };
+    state.write().await.asn_blocklist.remove(&asn);
+    persist_state(&state).await;
+    Ok(asn_blocklist(State(state)).await)
+}
+
 async fn allowlist(State(state): State<Arc<RwLock<AppState>>>) -> Json<Vec<AllowEntry>> {
     let guard = state.read().await;
     let mut items = Vec::new();
+    let note = |ip: &String, port: Option<u16>| guard.allow_notes.get(&(ip.clone(), port)).cloned();
     for ip in &guard.allowlist {
         items.push(AllowEntry {
             ip: ip.clone(),
             port: None,
+            note: note(ip, None),
         });
     }
     for (port, ips) in &guard.allowlist_ports {
@@ -967,6 +3994,7 @@ async fn allowlist(State(state): State<Arc<RwLock<AppState>>>) -> Json<Vec<Allow
             items.push(AllowEntry {
                 ip: ip.clone(),
                 port: Some(*port),
+                note: note(ip, Some(*port)),
             });
         }
     }
@@ -988,6 +4016,7 @@ async fn add_allow(
         return Err((
             StatusCode::BAD_REQUEST,
             Json(ErrorResponse {
+                code: "invalid_request",
                 error: "IP is required".to_string(),
             }),
         ));
@@ -997,15 +4026,17 @@ async fn add_allow(
             return Err((
                 StatusCode::BAD_REQUEST,
                 Json(ErrorResponse {
+                    code: "invalid_request",
                     error: "Port must be between 1 and 65535".to_string(),
                 }),
             ));
         }
     }
 
-    let snapshot = {
+    {
         let mut guard = state.write().await;
-        let ip = payload.ip.trim().to_string();
+        let ip = normalize_ip_entry(&payload.ip);
+        set_note(&mut guard.allow_notes, &ip, payload.port, payload.note.as_deref());
         match payload.port {
             Some(port) => {
                 guard
@@ -1018,10 +4049,9 @@ async fn add_allow(
                 guard.allowlist.insert(ip);
             }
         }
-        snapshot_state(&guard)
-    };
+    }
 
-    persist_state(state.clone(), snapshot).await;
+    persist_state(&state).await;
     Ok(allowlist(State(state)).await)
 }
 
@@ -1030,22 +4060,22 @@ async fn remove_allow(
     Query(query): Query<AllowQuery>,
     State(state): State<Arc<RwLock<AppState>>>,
 ) -> Result<Json<Vec<AllowEntry>>, (StatusCode, Json<ErrorResponse>)> {
-    let snapshot = {
+    {
         let mut guard = state.write().await;
-        let ip = ip.trim();
+        let ip = normalize_ip_entry(&ip);
+        guard.allow_notes.remove(&(ip.clone(), query.port));
         if let Some(port) = query.port {
             if let Some(ips) = guard.allowlist_ports.get_mut(&port) {
-                ips.remove(ip);
+                ips.remove(&ip);
                 if ips.is_empty() {
                     guard.allowlist_ports.remove(&port);
                 }
             }
         } else {
-            guard.allowlist.remove(ip);
+            guard.allowlist.remove(&ip);
         }
-        snapshot_state(&guard)
-    };
-    persist_state(state.clone(), snapshot).await;
+    }
+    persist_state(&state).await;
     Ok(allowlist(State(state)).await)
 }
 
@@ -1060,12 +4090,8 @@ async fn update_allowlist_mode(
     State(state): State<Arc<RwLock<AppState>>>,
     Json(payload): Json<AllowlistModeRequest>,
 ) -> Result<Json<AllowlistMode>, (StatusCode, Json<ErrorResponse>)> {
-    let snapshot = {
-        let mut guard = state.write().await;
-        guard.allowlist_enabled = payload.enabled;
-        snapshot_state(&guard)
-    };
-    persist_state(state.clone(), snapshot).await;
+    state.write().await.allowlist_enabled = payload.enabled;
+    persist_state(&state).await;
     Ok(allowlist_mode(State(state)).await)
 }
 
@@ -1078,7 +4104,16 @@ async fn update_rate_limit(
     State(state): State<Arc<RwLock<AppState>>>,
     Json(payload): Json<RateLimitRequest>,
 ) -> Result<Json<RateLimitConfig>, (StatusCode, Json<ErrorResponse>)> {
-    let snapshot = {
+    if payload.load_shed_percent.is_some_and(|value| value > 100) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                code: "invalid_request",
+                error: "load_shed_percent must be between 0 and 100".to_string(),
+            }),
+        ));
+    }
+    {
         let mut guard = state.write().await;
         if let Some(value) = payload.max_new_connections_per_minute {
             guard.rate_limit.max_new_connections_per_minute = value.max(1);
@@ -1089,23 +4124,140 @@ async fn update_rate_limit(
         if let Some(value) = payload.max_concurrent_total {
             guard.rate_limit.max_concurrent_total = value.max(1);
         }
-        snapshot_state(&guard)
-    };
-
-    persist_state(state.clone(), snapshot).await;
+        if let Some(value) = payload.max_concurrent_per_ip_per_rule {
+            guard.rate_limit.max_concurrent_per_ip_per_rule = Some(value).filter(|value| *value > 0);
+        }
+        if let Some(value) = payload.auto_ban_secs {
+            guard.rate_limit.auto_ban_secs = Some(value).filter(|value| *value > 0);
+        }
+        if let Some(value) = payload.tarpit_threshold_per_minute {
+            guard.rate_limit.tarpit_threshold_per_minute = Some(value).filter(|value| *value > 0);
+        }
+        if let Some(value) = payload.tarpit_delay_ms {
+            guard.rate_limit.tarpit_delay_ms = Some(value).filter(|value| *value > 0);
+        }
+        if let Some(value) = payload.load_shed_percent {
+            guard.rate_limit.load_shed_percent = Some(value).filter(|value| *value > 0);
+        }
+        if let Some(value) = payload.allowlist_bypass {
+            guard.rate_limit.allowlist_bypass = value;
+        }
+    }
+
+    persist_state(&state).await;
     Ok(rate_limit(State(state)).await)
 }
 
-async fn load_state(data_dir: &StdPath) -> Result<AppState> {
+async fn port_rate_limits(State(state): State<Arc<RwLock<AppState>>>) -> Json<Vec<PortRateLimit>> {
+    let guard = state.read().await;
+    let mut items = guard.port_rate_limits.values().cloned().collect::<Vec<_>>();
+    items.sort_by_key(|limit| limit.port);
+    Json(items)
+}
+
+async fn update_port_rate_limit(
+    Path(port): Path<u16>,
+    State(state): State<Arc<RwLock<AppState>>>,
+    Json(payload): Json<PortRateLimitRequest>,
+) -> Result<Json<Vec<PortRateLimit>>, (StatusCode, Json<ErrorResponse>)> {
+    if port == 0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                code: "invalid_request",
+                error: "Port must be between 1 and 65535".to_string(),
+            }),
+        ));
+    }
+    {
+        let mut guard = state.write().await;
+        let mut limit = guard.port_rate_limits.remove(&port).unwrap_or(PortRateLimit {
+            port,
+            ..PortRateLimit::default()
+        });
+        if let Some(value) = payload.max_new_connections_per_minute {
+            limit.max_new_connections_per_minute = Some(value).filter(|value| *value > 0);
+        }
+        if let Some(value) = payload.max_concurrent_connections_per_ip {
+            limit.max_concurrent_connections_per_ip = Some(value).filter(|value| *value > 0);
+        }
+        if let Some(value) = payload.max_concurrent_total {
+            limit.max_concurrent_total = Some(value).filter(|value| *value > 0);
+        }
+        if limit.max_new_connections_per_minute.is_none() {
+            guard.port_rate_counters.retain(|(counter_port, _), _| *counter_port != port);
+        }
+        if !limit.is_empty() {
+            guard.port_rate_limits.insert(port, limit);
+        }
+    }
+    persist_state(&state).await;
+    Ok(port_rate_limits(State(state)).await)
+}
+
+async fn remove_port_rate_limit(
+    Path(port): Path<u16>,
+    State(state): State<Arc<RwLock<AppState>>>,
+) -> Json<Vec<PortRateLimit>> {
+    {
+        let mut guard = state.write().await;
+        guard.port_rate_limits.remove(&port);
+        guard.port_rate_counters.retain(|(counter_port, _), _| *counter_port != port);
+    }
+    persist_state(&state).await;
+    port_rate_limits(State(state)).await
+}
+
+async fn log_entries(
+    Query(params): Query<LogQuery>,
+) -> Result<Json<Vec<log_buffer::LogEntry>>, (StatusCode, Json<ErrorResponse>)> {
+    let since = params.since.as_deref().map(|value| parse_time_param("since", value)).transpose()?;
+    Ok(Json(log_buffer::entries(since)))
+}
+
+fn parse_time_param(name: &str, value: &str) -> Result<OffsetDateTime, (StatusCode, Json<ErrorResponse>)> {
+    OffsetDateTime::parse(value, &Rfc3339).map_err(|_| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                code: "invalid_request",
+                error: format!("Invalid {} timestamp: {}", name, value),
+            }),
+        )
+    })
+}
+
+async fn clear_log(Query(params): Query<ClearLogQuery>) -> Json<Vec<log_buffer::LogEntry>> {
+    log_buffer::clear(params.up_to_id);
+    Json(log_buffer::entries(None))
+}
+
+async fn load_state(config: &AppConfig) -> Result<AppState> {
+    let data_dir = config.data_dir.as_path();
     tokio::fs::create_dir_all(data_dir).await?;
     let data_path = data_dir.join(STATE_FILE);
-    let persisted = if tokio::fs::try_exists(&data_path).await.unwrap_or(false) {
-        let bytes = tokio::fs::read(&data_path).await?;
-        serde_json::from_slice::<PersistedState>(&bytes).unwrap_or_default()
-    } else {
-        PersistedState::default()
+    let existed = tokio::fs::try_exists(&data_path).await.unwrap_or(false);
+    let on_disk = file_stamp(&data_path).await;
+    let mut persisted = match read_persisted(&data_path).await? {
+        Some(value) => value,
+        None => {
+            let backup_path = data_path.with_extension(STATE_BACKUP_EXTENSION);
+            match read_persisted(&backup_path).await? {
+                Some(value) => {
+                    warn!("Loaded state from backup {}", backup_path.display());
+                    value
+                }
+                None => PersistedState::default(),
+            }
+        }
     };
 
+    // read_persisted moves an unreadable file aside before returning.
+    let state_load_problem = (existed && !tokio::fs::try_exists(&data_path).await.unwrap_or(false))
+        .then(|| format!("{} was unreadable at startup and moved aside", STATE_FILE));
+
+    validate_loaded_rules(&mut persisted.rules, config.disable_invalid_rules);
+
     let next_rule_id = persisted
         .rules
         .iter()
@@ -1116,25 +4268,149 @@ async fn load_state(data_dir: &StdPath) -> Result<AppState> {
     let next_conn_id = persisted
         .history
         .iter()
+        .chain(&persisted.blocked_history)
         .map(|log| log.id)
         .max()
         .unwrap_or(0)
         + 1;
 
+    // Entries written before redaction was turned on are masked too; the
+    // next save drops the full addresses from disk.
+    if config.redact_client_ip {
+        persisted.history.iter_mut().for_each(redact_log_entry);
+        persisted.blocked_history.iter_mut().for_each(redact_log_entry);
+    }
+
+    let mut rule_stats: HashMap<u64, RuleStats> = HashMap::new();
+    for entry in persisted.history.iter().chain(&persisted.blocked_history) {
+        rule_stats.entry(entry.rule_id).or_default().record(entry);
+    }
+    let mut top_talkers = talkers::TopTalkers::default();
+    for entry in persisted.history.iter().filter(|entry| !entry.blocked) {
+        if let Some(ended_at) = log_time(entry) {
+            top_talkers.record(&entry.client_ip, ended_at.unix_timestamp(), entry.bytes_up, entry.bytes_down);
+        }
+    }
+
+    let mut state = AppState {
+        rules: std::mem::take(&mut persisted.rules),
+        blocklist: HashSet::new(),
+        port_blocklist: HashMap::new(),
+        block_expiry: HashMap::new(),
+        allowlist: HashSet::new(),
+        allowlist_ports: HashMap::new(),
+        allowlist_enabled: false,
+        block_notes: HashMap::new(),
+        allow_notes: HashMap::new(),
+        geo_blocklist: HashSet::new(),
+        geo_port_blocklist: HashMap::new(),
+        geo_db: None,
+        asn_blocklist: HashSet::new(),
+        blocklist_files: config
+            .blocklist_files
+            .iter()
+            .map(|path| blocklist_file::BlocklistFile::load(path))
+            .collect::<Result<Vec<_>>>()?,
+        asn_db: None,
+        history: std::mem::take(&mut persisted.history),
+        blocked_history: std::mem::take(&mut persisted.blocked_history),
+        rule_stats,
+        top_talkers,
+        rate_limit: RateLimitConfig::default(),
+        listeners: HashMap::new(),
+        udp_listeners: HashMap::new(),
+        target_cursors: HashMap::new(),
+        target_load: HashMap::new(),
+        target_health: HashMap::new(),
+        accept_errors: HashMap::new(),
+        accept_errors_total: Arc::new(AcceptErrorStats::default()),
+        health_checks: HashMap::new(),
+        listener_status: HashMap::new(),
+        connection_tokens: HashMap::new(),
+        draining_tokens: Vec::new(),
+        active: HashMap::new(),
+        active_by_ip: HashMap::new(),
+        active_by_rule_ip: HashMap::new(),
+        active_by_rule: HashMap::new(),
+        active_by_port: HashMap::new(),
+        active_by_port_ip: HashMap::new(),
+        connections_by_country: HashMap::new(),
+        rate_counters: HashMap::new(),
+        rule_rate_counters: HashMap::new(),
+        port_rate_limits: HashMap::new(),
+        port_rate_counters: HashMap::new(),
+        data_path,
+        persist_failures: Arc::new(AtomicU64::new(0)),
+        saves: Arc::new(PendingSave::new(on_disk)),
+        state_load_problem,
+        config: Arc::new(config.clone()),
+        rdns: Arc::new(rdns::ReverseDnsCache::default()),
+        resolver: Arc::new(resolve::TargetResolver::new(config.dns_cache_ttl)),
+        auth: match config.auth_url.as_ref() {
+            Some(url) => Some(Arc::new(authz::AuthService::new(
+                url.clone(),
+                config.auth_cache_ttl,
+                config.auth_timeout,
+                config.auth_fail_open,
+            )?)),
+            None => None,
+        },
+        tls: tls::PanelTls::from_paths(config.tls_cert.as_deref(), config.tls_key.as_deref())?.map(Arc::new),
+        webhook: config
+            .webhook_url
+            .as_deref()
+            .map(|url| webhook::Webhook::new(url, &config.webhook_events))
+            .transpose()?
+            .map(Arc::new),
+        events: events::EventSocket::open(config.event_socket.as_deref())?,
+        access_log: access_log::AccessLog::open(
+            config.access_log.as_deref(),
+            access_log::Rotation {
+                max_size: config.access_log_max_size,
+                keep: config.access_log_keep,
+                daily: config.access_log_daily,
+            },
+        )?,
+        live: live::LiveFeed::new(),
+        next_rule_id,
+        next_conn_id,
+    };
+    apply_persisted_policy(&mut state, &mut persisted);
+    // A lowered --max-history or a new retention window applies right away.
+    trim_history(&mut state);
+    Ok(state)
+}
+
+// Takes the block/allow lists, notes and rate limits out of a loaded
+// state.json, at startup and again on SIGHUP.
+fn apply_persisted_policy(state: &mut AppState, persisted: &mut PersistedState) {
     let mut port_blocklist: HashMap<u16, HashSet<String>> = HashMap::new();
     for entry in &persisted.port_blocklist {
         port_blocklist
             .entry(entry.port)
-            .or_insert_with(HashSet::new)
-            .insert(entry.ip.clone());
+            .or_default()
+            .insert(normalize_ip_entry(&entry.ip));
+    }
+    let mut block_expiry = HashMap::new();
+    for entry in &persisted.block_expiry {
+        match OffsetDateTime::parse(&entry.expires_at, &Rfc3339) {
+            Ok(expires_at) => {
+                block_expiry.insert((normalize_ip_entry(&entry.ip), entry.port), expires_at);
+            }
+            Err(_) => warn!("Ignoring invalid block expiry for {}: {}", entry.ip, entry.expires_at),
+        }
     }
-    let allowlist = persisted.allowlist.iter().cloned().collect::<HashSet<_>>();
+    let allowlist = persisted
+        .allowlist
+        .iter()
+        .map(|ip| normalize_ip_entry(ip))
+        .collect::<HashSet<_>>();
     let mut allowlist_ports: HashMap<u16, HashSet<String>> = HashMap::new();
     for entry in &persisted.allowlist_ports {
         allowlist_ports
             .entry(entry.port)
-            .or_insert_with(HashSet::new)
-            .insert(entry.ip.clone());
+            .or_default()
+            .insert(normalize_ip_entry(&entry.ip));
     }
     let allowlist_enabled = persisted.allowlist_enabled;
 
@@ -1147,56 +4423,488 @@ async fn load_state(data_dir: &StdPath) -> Result<AppState> {
     for entry in &persisted.geo_port_blocklist {
         geo_port_blocklist
             .entry(entry.port)
-            .or_insert_with(HashSet::new)
+            .or_default()
             .insert(entry.country.to_uppercase());
     }
 
-    Ok(AppState {
-        rules: persisted.rules,
-        blocklist: persisted.blocklist.into_iter().collect(),
-        port_blocklist,
-        allowlist,
-        allowlist_ports,
-        allowlist_enabled,
-        geo_blocklist,
-        geo_port_blocklist,
-        geo_db: None,
-        history: persisted.history,
-        rate_limit: persisted.rate_limit,
-        listeners: HashMap::new(),
-        udp_listeners: HashMap::new(),
-        active: HashMap::new(),
-        active_by_ip: HashMap::new(),
-        rate_counters: HashMap::new(),
-        data_path,
-        next_rule_id,
-        next_conn_id,
-    })
+    state.blocklist = persisted.blocklist.iter().map(|ip| normalize_ip_entry(ip)).collect();
+    state.port_blocklist = port_blocklist;
+    state.block_expiry = block_expiry;
+    state.allowlist = allowlist;
+    state.allowlist_ports = allowlist_ports;
+    state.allowlist_enabled = allowlist_enabled;
+    state.block_notes = load_notes(std::mem::take(&mut persisted.block_notes));
+    state.allow_notes = load_notes(std::mem::take(&mut persisted.allow_notes));
+    state.geo_blocklist = geo_blocklist;
+    state.geo_port_blocklist = geo_port_blocklist;
+    state.asn_blocklist = persisted.asn_blocklist.iter().copied().collect();
+    state.rate_limit = persisted.rate_limit.clone();
+    state.port_rate_limits = std::mem::take(&mut persisted.port_rate_limits)
+        .into_iter()
+        .filter(|limit| limit.port != 0 && !limit.is_empty())
+        .map(|limit| (limit.port, limit))
+        .collect();
+}
+
+fn validate_rule_addresses(rule: &ProxyRule) -> Result<()> {
+    port_range::expand_listen_targets(&rule.listen_addr, &rule.target_addr)?;
+    for target in &rule.target_addrs {
+        port_range::expand_listen_targets(&rule.listen_addr, target)?;
+    }
+    Ok(())
+}
+
+// `proxy_panel check`: validates what startup would load without binding
+// ports, downloading anything or touching the data dir (an unreadable
+// state.json is reported, not moved aside). Prints one line per finding and
+// fails if any of them is an error.
+pub async fn check_config(config: &AppConfig) -> Result<()> {
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+
+    let path = config.data_dir.join(STATE_FILE);
+    let mut rules = match check_persisted(&path).await {
+        Ok(Some(persisted)) => persisted.rules,
+        Ok(None) => {
+            warnings.push(format!("{} not found; the panel would start with no rules", path.display()));
+            Vec::new()
+        }
+        Err(err) => {
+            errors.push(format!("{}: {}", path.display(), err));
+            Vec::new()
+        }
+    };
+    for rule in rules.iter_mut() {
+        rule.listen_addr = rule.listen_addr.trim().to_string();
+        rule.target_addr = rule.target_addr.trim().to_string();
+        rule.target_addrs = normalize_targets(Some(&rule.target_addrs));
+    }
+    for (index, rule) in rules.iter().enumerate() {
+        if let Err(err) = validate_rule_addresses(rule) {
+            errors.push(format!(
+                "rule {} ({} -> {}): {}",
+                rule.id, rule.listen_addr, rule.target_addr, err
+            ));
+            continue;
+        }
+        let earlier = &rules[..index];
+        if let Some((other_id, addr)) = find_listen_conflict(earlier, rule) {
+            errors.push(format!("rule {}: {} is already used by rule {}", rule.id, addr, other_id));
+        }
+    }
+
+    if let Some(path) = config.rules_file.as_ref() {
+        let parsed = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|err| anyhow!("{}: {}", path.display(), err))
+            .and_then(|text| rules_file::parse::<CreateRuleRequest>(path, &text))
+            .and_then(|entries| file_rules(entries).map_err(|err| anyhow!("{}: {}", path.display(), err)));
+        match parsed {
+            Ok(file) => println!("ok: {} ({} rules)", path.display(), file.len()),
+            Err(err) => errors.push(format!("{:#}", err)),
+        }
+    }
+
+    for (name, loaded) in [
+        ("geo DB", geo::load_geo_db(&config.data_dir)),
+        ("ASN DB", geo::load_asn_db(&config.data_dir)),
+    ] {
+        match loaded {
+            Ok(Some(_)) => println!("ok: {}", name),
+            Ok(None) => warnings.push(format!("{} not found in {}", name, config.data_dir.display())),
+            Err(err) => errors.push(format!("{}: {}", name, err)),
+        }
+    }
+
+    println!("ok: {} rules checked", rules.len());
+    for warning in &warnings {
+        println!("warning: {}", warning);
+    }
+    for error in &errors {
+        println!("error: {}", error);
+    }
+    if !errors.is_empty() {
+        return Err(anyhow!("{} problem(s) found", errors.len()));
+    }
+    Ok(())
+}
+
+// read_persisted without its recovery: no backup fallback, no salvage, and
+// the file stays where it is. Migrations run on the in-memory copy only.
+// Used by `check` and the SIGHUP reload.
+async fn check_persisted(path: &StdPath) -> Result<Option<PersistedState>> {
+    if !tokio::fs::try_exists(path).await.unwrap_or(false) {
+        return Ok(None);
+    }
+    let bytes = tokio::fs::read(path).await?;
+    let serde_json::Value::Object(mut fields) = serde_json::from_slice::<serde_json::Value>(&bytes)? else {
+        return Err(anyhow!("not a JSON object"));
+    };
+    migrate::migrate(&mut fields)?;
+    Ok(Some(serde_json::from_value(serde_json::Value::Object(fields))?))
+}
+
+// Trims stored addresses and reports rules whose addresses can't be parsed,
+// optionally disabling them so startup doesn't try to bind them.
+fn validate_loaded_rules(rules: &mut [ProxyRule], disable_invalid: bool) {
+    for rule in rules.iter_mut() {
+        rule.listen_addr = rule.listen_addr.trim().to_string();
+        rule.target_addr = rule.target_addr.trim().to_string();
+        rule.target_addrs = normalize_targets(Some(&rule.target_addrs));
+        let Err(err) = validate_rule_addresses(rule) else {
+            continue;
+        };
+        warn!(
+            "Rule {} has invalid address {} -> {}: {}",
+            rule.id, rule.listen_addr, rule.target_addr, err
+        );
+        if disable_invalid && rule.enabled {
+            rule.enabled = false;
+            rule.disabled_reason = Some(format!("Invalid address: {}", err));
+            warn!("Rule {} disabled at startup", rule.id);
+        }
+    }
+}
+
+async fn read_persisted(path: &StdPath) -> Result<Option<PersistedState>> {
+    if !tokio::fs::try_exists(path).await.unwrap_or(false) {
+        return Ok(None);
+    }
+    let bytes = tokio::fs::read(path).await?;
+    let mut fields = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(serde_json::Value::Object(fields)) => fields,
+        Ok(_) => {
+            error!("Failed to parse {}: not a JSON object", path.display());
+            move_corrupt_state(path).await;
+            return Ok(None);
+        }
+        Err(err) => {
+            error!(
+                "Failed to parse {} (byte offset {}): {}",
+                path.display(),
+                byte_offset(&bytes, err.line(), err.column()),
+                err
+            );
+            move_corrupt_state(path).await;
+            return Ok(None);
+        }
+    };
+
+    let migrated_from = migrate::migrate(&mut fields).map_err(|err| anyhow!("{}: {}", path.display(), err))?;
+    // Unmigrated files are parsed from the bytes so shape errors keep their
+    // line and column.
+    let parsed = match migrated_from {
+        Some(_) => serde_json::from_value::<PersistedState>(serde_json::Value::Object(fields.clone())),
+        None => serde_json::from_slice::<PersistedState>(&bytes),
+    };
+    let err = match parsed {
+        Ok(value) => {
+            if let Some(version) = migrated_from {
+                keep_premigration_copy(path, version).await;
+            }
+            return Ok(Some(value));
+        }
+        Err(err) => err,
+    };
+    if err.line() > 0 {
+        error!(
+            "Failed to parse {} (byte offset {}): {}",
+            path.display(),
+            byte_offset(&bytes, err.line(), err.column()),
+            err
+        );
+    } else {
+        error!("Failed to parse {}: {}", path.display(), err);
+    }
+
+    // Valid JSON of the wrong shape still holds most of the config; only a
+    // syntax error loses the whole file. Either way the original is moved
+    // aside before the next save can overwrite it.
+    let salvaged = salvage_persisted(fields, path);
+    move_corrupt_state(path).await;
+    Ok(Some(salvaged))
+}
+
+// The first save after a migration rewrites the file in the new shape, so
+// the original is copied to state.json.v<N> first.
+async fn keep_premigration_copy(path: &StdPath, version: u32) {
+    let copy_path = path.with_file_name(format!(
+        "{}.v{}",
+        path.file_name().and_then(|name| name.to_str()).unwrap_or(STATE_FILE),
+        version
+    ));
+    match tokio::fs::copy(path, &copy_path).await {
+        Ok(_) => info!(
+            "Migrated {} from version {} to {}; original kept at {}",
+            path.display(),
+            version,
+            migrate::STATE_VERSION,
+            copy_path.display()
+        ),
+        Err(err) => warn!("Failed to keep a copy of {} before migrating: {}", path.display(), err),
+    }
+}
+
+async fn move_corrupt_state(path: &StdPath) {
+    let corrupt_path = path.with_file_name(format!(
+        "{}.corrupt-{}",
+        path.file_name().and_then(|name| name.to_str()).unwrap_or(STATE_FILE),
+        OffsetDateTime::now_utc().unix_timestamp()
+    ));
+    match tokio::fs::rename(path, &corrupt_path).await {
+        Ok(()) => warn!("Moved unreadable {} to {}", path.display(), corrupt_path.display()),
+        Err(err) => error!("Failed to move unreadable {} aside: {}", path.display(), err),
+    }
+}
+
+// serde_json reports 1-based line and column; operators with a hex editor or
+// `head -c` want the byte position.
+fn byte_offset(bytes: &[u8], line: usize, column: usize) -> usize {
+    let preceding = bytes
+        .split(|byte| *byte == b'\n')
+        .take(line.saturating_sub(1))
+        .map(|line| line.len() + 1)
+        .sum::<usize>();
+    preceding + column.saturating_sub(1)
+}
+
+// Reads each top-level field on its own, and list fields item by item, so a
+// hand-edit typo or an older field shape only costs the values it touches.
+fn salvage_persisted(mut fields: serde_json::Map<String, serde_json::Value>, path: &StdPath) -> PersistedState {
+    let mut dropped = Vec::new();
+    let persisted = PersistedState {
+        version: migrate::STATE_VERSION,
+        rules: salvage_items(&mut fields, "rules", &mut dropped),
+        blocklist: salvage_items(&mut fields, "blocklist", &mut dropped),
+        port_blocklist: salvage_items(&mut fields, "port_blocklist", &mut dropped),
+        allowlist: salvage_items(&mut fields, "allowlist", &mut dropped),
+        allowlist_ports: salvage_items(&mut fields, "allowlist_ports", &mut dropped),
+        allowlist_enabled: salvage_field(&mut fields, "allowlist_enabled", &mut dropped),
+        geo_blocklist: salvage_items(&mut fields, "geo_blocklist", &mut dropped),
+        geo_port_blocklist: salvage_items(&mut fields, "geo_port_blocklist", &mut dropped),
+        asn_blocklist: salvage_items(&mut fields, "asn_blocklist", &mut dropped),
+        history: salvage_items(&mut fields, "history", &mut dropped),
+        blocked_history: salvage_items(&mut fields, "blocked_history", &mut dropped),
+        rate_limit: salvage_field(&mut fields, "rate_limit", &mut dropped),
+        port_rate_limits: salvage_items(&mut fields, "port_rate_limits", &mut dropped),
+        block_expiry: salvage_items(&mut fields, "block_expiry", &mut dropped),
+        block_notes: salvage_items(&mut fields, "block_notes", &mut dropped),
+        allow_notes: salvage_items(&mut fields, "allow_notes", &mut dropped),
+    };
+    warn!(
+        "Recovered {} rules from {}; dropped {} unreadable values{}",
+        persisted.rules.len(),
+        path.display(),
+        dropped.len(),
+        if dropped.is_empty() {
+            String::new()
+        } else {
+            format!(": {}", dropped.iter().take(10).cloned().collect::<Vec<_>>().join("; "))
+        }
+    );
+    persisted
+}
+
+fn salvage_field<T: serde::de::DeserializeOwned + Default>(
+    fields: &mut serde_json::Map<String, serde_json::Value>,
+    name: &str,
+    dropped: &mut Vec<String>,
+) -> T {
+    match fields.remove(name) {
+        None | Some(serde_json::Value::Null) => T::default(),
+        Some(value) => serde_json::from_value(value).unwrap_or_else(|err| {
+            dropped.push(format!("{} ({})", name, err));
+            T::default()
+        }),
+    }
+}
+
+fn salvage_items<T: serde::de::DeserializeOwned>(
+    fields: &mut serde_json::Map<String, serde_json::Value>,
+    name: &str,
+    dropped: &mut Vec<String>,
+) -> Vec<T> {
+    let items = match fields.remove(name) {
+        None | Some(serde_json::Value::Null) => return Vec::new(),
+        Some(serde_json::Value::Array(items)) => items,
+        Some(_) => {
+            dropped.push(format!("{} (not a list)", name));
+            return Vec::new();
+        }
+    };
+    let mut kept = Vec::with_capacity(items.len());
+    for (index, item) in items.into_iter().enumerate() {
+        match serde_json::from_value(item) {
+            Ok(value) => kept.push(value),
+            Err(err) => dropped.push(format!("{}[{}] ({})", name, index, err)),
+        }
+    }
+    kept
+}
+
+// What starting a rule does about a target hostname that doesn't resolve.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum UnresolvedTarget {
+    // The start fails, so create, update and enable report it right away
+    // instead of the rule failing at its first connection.
+    Fail,
+    // Rules restored at boot start anyway with a warning: DNS may not be up
+    // yet, and the connect path and health checks retry the lookup.
+    Warn,
 }
 
 async fn start_rule_listeners(state: &Arc<RwLock<AppState>>, rule: &ProxyRule) -> Result<()> {
+    start_rule_listeners_with(state, rule, UnresolvedTarget::Fail).await
+}
+
+async fn start_rule_listeners_with(
+    state: &Arc<RwLock<AppState>>,
+    rule: &ProxyRule,
+    unresolved: UnresolvedTarget,
+) -> Result<()> {
     let listen_targets =
         port_range::expand_listen_targets(&rule.listen_addr, &rule.target_addr)?;
 
+    // Each listen port gets its own backend list: the primary target followed
+    // by the extra targets, all expanded against the same listen range.
+    let mut backends = listen_targets
+        .iter()
+        .map(|target| vec![target.target_addr.clone()])
+        .collect::<Vec<_>>();
+    for extra in &rule.target_addrs {
+        let expanded = port_range::expand_listen_targets(&rule.listen_addr, extra)?;
+        for (list, target) in backends.iter_mut().zip(expanded) {
+            list.push(target.target_addr);
+        }
+    }
+
+    // One lookup per host covers a whole port range, and warms the cache.
+    let resolver = state.read().await.resolver.clone();
+    let mut resolved_hosts = HashSet::new();
+    for target in backends.iter().flatten() {
+        if resolved_hosts.insert(addr_host(target).to_ascii_lowercase()) {
+            match (resolver.resolve(target).await, unresolved) {
+                (Ok(_), _) => {}
+                (Err(err), UnresolvedTarget::Fail) => return Err(anyhow!("Target {}: {}", target, err)),
+                (Err(err), UnresolvedTarget::Warn) => {
+                    warn!("Rule {}: target {} does not resolve yet: {}", rule.id, target, err);
+                }
+            }
+        }
+    }
+    state.write().await.listener_status.insert(rule.id, Vec::new());
+
+    let accept_errors = {
+        let mut guard = state.write().await;
+        let rule_stats = guard.accept_errors.entry(rule.id).or_default().clone();
+        AcceptErrorCounter {
+            rule: rule_stats,
+            total: guard.accept_errors_total.clone(),
+        }
+    };
+
     if rule.protocol.uses_tcp() {
-        for target in &listen_targets {
-            if let Err(err) = start_tcp_listener(
+        let context = {
+            let mut guard = state.write().await;
+            let drain = guard
+                .connection_tokens
+                .entry(rule.id)
+                .or_insert_with(CancellationToken::new)
+                .clone();
+            let next_target = guard
+                .target_cursors
+                .entry(rule.id)
+                .or_default()
+                .clone();
+            let target_load = guard.target_load.entry(rule.id).or_default().clone();
+            let health = Arc::new(health::RuleHealth::default());
+            guard.target_health.insert(rule.id, health.clone());
+            if let Some(interval) = rule.health_check_interval_secs {
+                let mut targets = backends.iter().flatten().cloned().collect::<Vec<_>>();
+                targets.sort();
+                targets.dedup();
+                let timeout = rule
+                    .health_check_timeout_ms
+                    .map(Duration::from_millis)
+                    .unwrap_or(health::DEFAULT_CHECK_TIMEOUT);
+                let handle = health::start_health_checker(
+                    targets,
+                    Duration::from_secs(interval),
+                    timeout,
+                    health.clone(),
+                );
+                guard.health_checks.insert(rule.id, handle);
+            }
+            let connect_timeout = rule
+                .connect_timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(guard.config.connect_timeout);
+            let idle_timeout = rule
+                .tcp_idle_timeout_secs
+                .map(Duration::from_secs)
+                .or(guard.config.tcp_idle_timeout);
+            Arc::new(RuleContext {
+                rule: rule.clone(),
+                drain,
+                next_target,
+                target_load,
+                health,
+                connect_timeout,
+                idle_timeout,
+                max_duration: rule.max_connection_duration_secs.map(Duration::from_secs),
+                buffer_size: guard.config.buffer_size,
+                accept_errors: accept_errors.clone(),
+                resolver: resolver.clone(),
+            })
+        };
+        let mut failures = Vec::new();
+        for (target, backends) in listen_targets.iter().zip(backends) {
+            let result = start_tcp_listener(
                 state,
-                rule.id,
+                context.clone(),
                 target.listen_addr.clone(),
                 target.listen_port,
-                target.target_addr.clone(),
+                target.v6_only,
+                Arc::new(backends),
             )
-            .await
-            {
-                stop_rule_listeners(state, rule.id).await;
-                return Err(err);
+            .await;
+            record_listener_status(state, rule.id, target, ProtocolMode::Tcp, result.as_ref().err()).await;
+            if let Err(err) = result {
+                failures.push((target.listen_addr.clone(), err));
             }
         }
+        if !failures.is_empty() {
+            stop_rule_listeners(state, rule.id).await;
+            return Err(bind_failures_error("TCP", listen_targets.len(), failures));
+        }
     }
 
     if rule.protocol.uses_udp() {
-        if let Err(err) = start_udp_listener(state, rule.id, &listen_targets).await {
+        let mirror = match rule.mirror_addr.as_deref() {
+            Some(addr) => match udp_proxy::UdpMirror::bind(addr).await {
+                Ok(mirror) => Some(Arc::new(mirror)),
+                Err(err) => {
+                    stop_rule_listeners(state, rule.id).await;
+                    return Err(err);
+                }
+            },
+            None => None,
+        };
+        let idle_timeout = match rule.udp_idle_timeout_secs {
+            Some(secs) => Duration::from_secs(secs),
+            None => state.read().await.config.udp_idle_timeout,
+        };
+        let options = udp_proxy::UdpOptions {
+            log_five_tuple: rule.log_five_tuple,
+            idle_timeout,
+            source_addr: rule.source_addr,
+            resolver,
+            dscp: rule.dscp,
+            accept_errors,
+            mirror,
+            max_send_errors: state.read().await.config.udp_max_send_errors,
+        };
+        if let Err(err) = start_udp_listener(state, rule.id, &listen_targets, options).await {
             stop_rule_listeners(state, rule.id).await;
             return Err(err);
         }
@@ -1204,97 +4912,250 @@ async fn start_rule_listeners(state: &Arc<RwLock<AppState>>, rule: &ProxyRule) -
     Ok(())
 }
 
+async fn record_listener_status(
+    state: &Arc<RwLock<AppState>>,
+    rule_id: u64,
+    target: &port_range::ListenTarget,
+    protocol: ProtocolMode,
+    error: Option<&anyhow::Error>,
+) {
+    let status = ListenerStatus {
+        listen_addr: target.listen_addr.clone(),
+        listen_port: target.listen_port,
+        protocol,
+        state: if error.is_some() { ListenerState::Failed } else { ListenerState::Listening },
+        error: error.map(|err| format!("{:#}", err)),
+    };
+    state
+        .write()
+        .await
+        .listener_status
+        .entry(rule_id)
+        .or_default()
+        .push(status);
+}
+
 async fn stop_rule_listeners(state: &Arc<RwLock<AppState>>, rule_id: u64) {
+    let health_check = {
+        let mut guard = state.write().await;
+        guard.target_health.remove(&rule_id);
+        for status in guard.listener_status.get_mut(&rule_id).into_iter().flatten() {
+            if status.state == ListenerState::Listening {
+                status.state = ListenerState::Stopped;
+            }
+        }
+        guard.health_checks.remove(&rule_id)
+    };
+    if let Some(handle) = health_check {
+        handle.shutdown.cancel();
+        handle.task.abort();
+    }
     stop_tcp_listener(state, rule_id).await;
     stop_udp_listener(state, rule_id).await;
+    drain_rule_connections(state, rule_id).await;
+}
+
+// Existing TCP connections are left to finish on their own; with a drain
+// timeout configured they are force-closed once it elapses. Without one the
+// token stays in connection_tokens, so shutdown can still close them (and a
+// restarted rule's new connections share it). Returns without waiting for
+// the drain to complete.
+async fn drain_rule_connections(state: &Arc<RwLock<AppState>>, rule_id: u64) {
+    let (token, drain_timeout) = {
+        let mut guard = state.write().await;
+        let Some(drain_timeout) = guard.config.drain_timeout else {
+            return;
+        };
+        let Some(token) = guard.connection_tokens.remove(&rule_id) else {
+            return;
+        };
+        guard.draining_tokens.retain(|token| !token.is_cancelled());
+        guard.draining_tokens.push(token.clone());
+        (token, drain_timeout)
+    };
+    tokio::spawn(async move {
+        tokio::time::sleep(drain_timeout).await;
+        token.cancel();
+    });
+}
+
+// Runtime pieces shared by every TCP listener of one rule.
+struct RuleContext {
+    rule: ProxyRule,
+    drain: CancellationToken,
+    next_target: Arc<AtomicUsize>,
+    target_load: Arc<balance::TargetLoad>,
+    health: Arc<health::RuleHealth>,
+    connect_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    max_duration: Option<Duration>,
+    buffer_size: usize,
+    accept_errors: AcceptErrorCounter,
+    resolver: Arc<resolve::TargetResolver>,
 }
 
 async fn start_tcp_listener(
     state: &Arc<RwLock<AppState>>,
-    rule_id: u64,
+    context: Arc<RuleContext>,
     listen_addr: String,
     listen_port: u16,
-    target_addr: String,
+    v6_only: bool,
+    targets: Arc<Vec<String>>,
 ) -> Result<()> {
-    let listener = TcpListener::bind(listen_addr.as_str()).await?;
-    let shutdown = CancellationToken::new();
-    let shutdown_signal = shutdown.clone();
-    let state_clone = state.clone();
-    let target_addr = target_addr.clone();
-
-    let task = tokio::spawn(async move {
-        loop {
-            tokio::select! {
-                _ = shutdown_signal.cancelled() => {
-                    break;
-                }
-                accept_result = listener.accept() => {
-                    let (inbound, peer_addr) = match accept_result {
-                        Ok(value) => value,
-                        Err(err) => {
-                            warn!("Listener accept error: {}", err);
-                            continue;
-                        }
-                    };
-                    let client_ip = peer_addr.ip().to_string();
-                    let state_for_conn = state_clone.clone();
-                    let target_addr = target_addr.clone();
-                    let local_port = inbound
-                        .local_addr()
-                        .map(|addr| addr.port())
-                        .unwrap_or(listen_port);
-                    tokio::spawn(async move {
-                        handle_connection(
-                            state_for_conn,
-                            inbound,
-                            target_addr,
-                            rule_id,
-                            local_port,
-                            client_ip,
-                        )
-                        .await;
-                    });
-                }
-            }
-        }
-    });
+    let rule_id = context.rule.id;
+    let config = state.read().await.config.clone();
+    let listeners = bind_tcp_listeners(&listen_addr, v6_only, &config).await?;
+    let mut handles = Vec::new();
+    for listener in listeners {
+        let shutdown = CancellationToken::new();
+        let task = tokio::spawn(accept_loop(
+            state.clone(),
+            listener,
+            context.clone(),
+            listen_port,
+            targets.clone(),
+            shutdown.clone(),
+        ));
+        handles.push(ListenerHandle { shutdown, task });
+    }
 
     let mut guard = state.write().await;
     guard
         .listeners
         .entry(rule_id)
         .or_insert_with(Vec::new)
-        .push(ListenerHandle { shutdown, task });
+        .extend(handles);
     Ok(())
 }
 
-async fn stop_tcp_listener(state: &Arc<RwLock<AppState>>, rule_id: u64) {
-    let handle = {
-        let mut guard = state.write().await;
-        guard.listeners.remove(&rule_id)
-    };
-    if let Some(handles) = handle {
-        for handle in handles {
-            handle.shutdown.cancel();
-            handle.task.abort();
+// With --reuseport, one SO_REUSEPORT socket per acceptor task; otherwise, or
+// if those can't be set up, a single ordinary listener. Both use
+// --listen-backlog. Within this process find_listen_conflict keeps two rules
+// off one address; the kernel won't, since every socket here allows sharing.
+async fn bind_tcp_listeners(listen_addr: &str, v6_only: bool, config: &AppConfig) -> Result<Vec<TcpListener>> {
+    if let (Some(count), Ok(addr)) = (config.reuseport_acceptors, listen_addr.parse::<SocketAddr>()) {
+        let listeners = sockopt::reuseport_listeners(addr, count, v6_only, config.listen_backlog).and_then(|listeners| {
+            listeners
+                .into_iter()
+                .map(TcpListener::from_std)
+                .collect::<std::io::Result<Vec<_>>>()
+        });
+        match listeners {
+            Ok(listeners) => return Ok(listeners),
+            Err(err) => warn!("SO_REUSEPORT listeners on {} failed ({}); using a single listener", addr, err),
+        }
+    }
+    // Host names resolve like TcpListener::bind: the first address that
+    // binds wins.
+    let mut last_err = None;
+    for addr in tokio::net::lookup_host(listen_addr).await? {
+        match sockopt::tcp_listener(addr, v6_only, config.listen_backlog) {
+            Ok(listener) => return Ok(vec![TcpListener::from_std(listener)?]),
+            Err(err) => last_err = Some(err),
         }
     }
+    Err(last_err.map_or_else(|| anyhow!("{} did not resolve to any address", listen_addr), Into::into))
 }
 
-async fn start_udp_listener(
-    state: &Arc<RwLock<AppState>>,
-    rule_id: u64,
-    listen_targets: &[port_range::ListenTarget],
+async fn accept_loop(
+    state: Arc<RwLock<AppState>>,
+    listener: TcpListener,
+    context: Arc<RuleContext>,
+    listen_port: u16,
+    targets: Arc<Vec<String>>,
+    shutdown: CancellationToken,
+) {
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => {
+                break;
+            }
+            accept_result = listener.accept() => {
+                let (inbound, peer_addr) = match accept_result {
+                    Ok(value) => value,
+                    Err(err) => {
+                        warn!("Listener accept error: {}", err);
+                        context.accept_errors.record_error();
+                        if matches!(
+                            err.kind(),
+                            std::io::ErrorKind::ConnectionAborted | std::io::ErrorKind::ConnectionReset
+                        ) {
+                            context.accept_errors.record_dropped();
+                        } else {
+                            tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                        }
+                        continue;
+                    }
+                };
+                let client_ip = canonical_ip(peer_addr.ip());
+                let state_for_conn = state.clone();
+                let context = context.clone();
+                let targets = targets.clone();
+                let local_port = inbound
+                    .local_addr()
+                    .map(|addr| addr.port())
+                    .unwrap_or(listen_port);
+                tokio::spawn(async move {
+                    handle_connection(
+                        state_for_conn,
+                        inbound,
+                        context,
+                        targets,
+                        local_port,
+                        client_ip,
+                    )
+                    .await;
+                });
+            }
+        }
+    }
+}
+
+async fn stop_tcp_listener(state: &Arc<RwLock<AppState>>, rule_id: u64) {
+    let handle = {
+        let mut guard = state.write().await;
+        guard.listeners.remove(&rule_id)
+    };
+    if let Some(handles) = handle {
+        for handle in &handles {
+            handle.shutdown.cancel();
+            handle.task.abort();
+        }
+        // Wait for the aborted tasks so their sockets are closed before a
+        // restarted rule tries to bind the same address.
+        for handle in handles {
+            let _ = handle.task.await;
+        }
+    }
+}
+
+async fn start_udp_listener(
+    state: &Arc<RwLock<AppState>>,
+    rule_id: u64,
+    listen_targets: &[port_range::ListenTarget],
+    options: udp_proxy::UdpOptions,
 ) -> Result<()> {
+    let mut failures = Vec::new();
     for target in listen_targets {
-        let handle = udp_proxy::start_udp_listener(
+        let handle = match udp_proxy::start_udp_listener(
             state.clone(),
             rule_id,
             target.listen_addr.clone(),
             Some(target.listen_port),
+            target.v6_only,
             target.target_addr.clone(),
+            options.clone(),
         )
-        .await?;
+        .await
+        {
+            Ok(handle) => handle,
+            Err(err) => {
+                record_listener_status(state, rule_id, target, ProtocolMode::Udp, Some(&err)).await;
+                failures.push((target.listen_addr.clone(), err));
+                continue;
+            }
+        };
+        record_listener_status(state, rule_id, target, ProtocolMode::Udp, None).await;
         let mut guard = state.write().await;
         guard
             .udp_listeners
@@ -1302,131 +5163,1097 @@ async fn start_udp_listener(
             .or_insert_with(Vec::new)
             .push(handle);
     }
+    if !failures.is_empty() {
+        return Err(bind_failures_error("UDP", listen_targets.len(), failures));
+    }
     Ok(())
 }
 
+// Every port of a range is attempted so the error names all the ones that
+// failed, but a rule only runs when all of them bound: the caller stops the
+// listeners that did start, for TCP and UDP alike.
+fn bind_failures_error(protocol: &str, attempted: usize, failures: Vec<(String, anyhow::Error)>) -> anyhow::Error {
+    const MAX_LISTED: usize = 10;
+    let mut listed = failures
+        .iter()
+        .take(MAX_LISTED)
+        .map(|(addr, err)| format!("{} ({})", addr, err))
+        .collect::<Vec<_>>();
+    if failures.len() > MAX_LISTED {
+        listed.push(format!("and {} more", failures.len() - MAX_LISTED));
+    }
+    anyhow!(
+        "{} bind failed on {} of {} ports: {}",
+        protocol,
+        failures.len(),
+        attempted,
+        listed.join(", ")
+    )
+}
+
 async fn stop_udp_listener(state: &Arc<RwLock<AppState>>, rule_id: u64) {
     let handle = {
         let mut guard = state.write().await;
         guard.udp_listeners.remove(&rule_id)
     };
     if let Some(handles) = handle {
-        for handle in handles {
+        for handle in &handles {
             handle.shutdown.cancel();
             handle.task.abort();
         }
+        // Wait for the aborted tasks so their sockets are closed before a
+        // restarted rule tries to bind the same address.
+        for handle in handles {
+            let _ = handle.task.await;
+        }
     }
 }
 
-async fn disable_rule_after_start_failure(state: &Arc<RwLock<AppState>>, rule_id: u64) {
-    let snapshot = {
+// What a rules-file load or state.json reload changed, and the listeners to
+// stop and start for it.
+#[derive(Default)]
+struct RulesFilePlan {
+    added: usize,
+    updated: usize,
+    removed: usize,
+    unchanged: usize,
+    // Rules disabled because another rule holds their listen address.
+    displaced: Vec<u64>,
+    stop: Vec<u64>,
+    start: Vec<ProxyRule>,
+}
+
+impl RulesFilePlan {
+    fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} added, {} updated, {} removed, {} unchanged",
+            self.added, self.updated, self.removed, self.unchanged
+        );
+        if !self.displaced.is_empty() {
+            summary.push_str(&format!("; disabled conflicting rules {:?}", self.displaced));
+        }
+        summary
+    }
+}
+
+// Builds and validates the file's rules. Ids are provisional (their position
+// in the file) until reconcile_rules_file matches them to existing rules.
+fn file_rules(entries: Vec<CreateRuleRequest>) -> Result<Vec<ProxyRule>> {
+    let mut wanted: Vec<ProxyRule> = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        let listen_addr = entry.listen_addr.trim().to_string();
+        let describe = |err: String| anyhow!("rule {} ({}): {}", index + 1, listen_addr, err);
+        let mut rule = build_rule(entry).map_err(|(_, Json(err))| describe(err.error))?;
+        validate_rule_addresses(&rule).map_err(|err| describe(err.to_string()))?;
+        if wanted.iter().any(|other| other.listen_addr == rule.listen_addr) {
+            return Err(describe("listen_addr is declared more than once".to_string()));
+        }
+        rule.from_rules_file = true;
+        rule.id = index as u64 + 1;
+        if let Some((_, addr)) = find_listen_conflict(&wanted, &rule) {
+            return Err(describe(format!("{} overlaps an earlier rule in the file", addr)));
+        }
+        wanted.push(rule);
+    }
+    Ok(wanted)
+}
+
+// Reads the rules file and reconciles the rule list with it. Listeners are
+// left to the caller.
+async fn load_rules_file(state: &Arc<RwLock<AppState>>, path: &StdPath) -> Result<RulesFilePlan> {
+    let text = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|err| anyhow!("Rules file {}: {}", path.display(), err))?;
+    let entries = rules_file::parse::<CreateRuleRequest>(path, &text)?;
+    let plan = {
         let mut guard = state.write().await;
-        if let Some(rule) = guard.rules.iter_mut().find(|rule| rule.id == rule_id) {
-            rule.enabled = false;
+        reconcile_rules_file(&mut guard, entries).map_err(|err| anyhow!("{}: {}", path.display(), err))?
+    };
+    persist_state(state).await;
+    Ok(plan)
+}
+
+// Applies a changed rules file to the running proxy, touching only the
+// listeners of rules that changed.
+pub(crate) async fn reload_rules_file(state: &Arc<RwLock<AppState>>, path: &StdPath) -> Result<String> {
+    let plan = load_rules_file(state, path).await?;
+    apply_listener_plan(state, &plan).await;
+    Ok(plan.summary())
+}
+
+async fn apply_listener_plan(state: &Arc<RwLock<AppState>>, plan: &RulesFilePlan) {
+    for id in &plan.stop {
+        stop_rule_listeners(state, *id).await;
+    }
+    let mut start = plan.start.iter().collect::<Vec<_>>();
+    start.sort_by_key(|rule| (rule.order, rule.id));
+    for rule in start {
+        if let Err(err) = start_rule_listeners(state, rule).await {
+            warn!(
+                "Failed to start listener {} -> {}: {}",
+                rule.listen_addr, rule.target_addr, err
+            );
+            disable_rule_after_start_failure(state, rule, &err).await;
         }
-        snapshot_state(&guard)
+    }
+}
+
+// SIGHUP (systemd's ExecReload) is the one signal handler, and reloads
+// everything that is read from disk at startup:
+// - state.json, after a hand edit: the lists and rate limits are replaced
+//   and the API-managed rules reconciled, restarting only the listeners of
+//   rules that changed so other connections carry on. History stays as it
+//   is in memory. Until then the writer leaves the edited file alone.
+// - the panel certificate and key with --tls-cert, which are also picked up
+//   on their own when the files change (tls::start_reloader).
+// The rules file and --blocklist-file lists are watched and need no signal.
+#[cfg(unix)]
+fn reload_on_sighup(state: Arc<RwLock<AppState>>) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match reload_state(&state).await {
+                Ok(summary) => info!("Reloaded {}: {}", STATE_FILE, summary),
+                Err(err) => warn!("Reload of {} failed, keeping the running state: {:#}", STATE_FILE, err),
+            }
+            let panel_tls = state.read().await.tls.clone();
+            if let Some(panel_tls) = panel_tls {
+                if let Err(err) = panel_tls.reload() {
+                    warn!("Panel TLS reload failed: {:#}", err);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn reload_on_sighup(_state: Arc<RwLock<AppState>>) -> Result<()> {
+    Ok(())
+}
+
+async fn reload_state(state: &Arc<RwLock<AppState>>) -> Result<String> {
+    let (path, disable_invalid, saves) = {
+        let guard = state.read().await;
+        (guard.data_path.clone(), guard.config.disable_invalid_rules, guard.saves.clone())
+    };
+    // Unlike at startup a bad file isn't moved aside: the running state is
+    // still good and is what the next save writes back.
+    let mut persisted = {
+        let _guard = SAVE_LOCK.lock().await;
+        // Stamped before the read: an edit landing in between differs from
+        // the stamp and is held back like any other.
+        let stamp = file_stamp(&path).await;
+        let persisted = check_persisted(&path)
+            .await?
+            .ok_or_else(|| anyhow!("{} not found", path.display()))?;
+        saves.loaded(stamp);
+        persisted
     };
-    persist_state(state.clone(), snapshot).await;
+    validate_loaded_rules(&mut persisted.rules, disable_invalid);
+    let plan = {
+        let mut guard = state.write().await;
+        apply_persisted_policy(&mut guard, &mut persisted);
+        reconcile_state_rules(&mut guard, std::mem::take(&mut persisted.rules), true)
+    };
+    persist_state(state).await;
+    apply_listener_plan(state, &plan).await;
+    Ok(plan.summary())
+}
+
+// Makes the API-managed rules match a reloaded state.json or an import.
+// Rules from the rules file are left alone since the file is their source of
+// truth. With `keep_running_usage` (the SIGHUP reload) usage counters stay as
+// counted in memory, since the file on disk lags behind them; an import
+// brings its own.
+fn reconcile_state_rules(state: &mut AppState, loaded: Vec<ProxyRule>, keep_running_usage: bool) -> RulesFilePlan {
+    let loaded = loaded
+        .into_iter()
+        .filter(|rule| !rule.from_rules_file)
+        .collect::<Vec<_>>();
+    let mut plan = RulesFilePlan::default();
+    let mut starting = Vec::new();
+    let loaded_ids = loaded.iter().map(|rule| rule.id).collect::<HashSet<_>>();
+    let (kept, removed): (Vec<_>, Vec<_>) = std::mem::take(&mut state.rules)
+        .into_iter()
+        .partition(|rule| rule.from_rules_file || loaded_ids.contains(&rule.id));
+    state.rules = kept;
+    for rule in removed {
+        if rule.enabled {
+            plan.stop.push(rule.id);
+        }
+        state.target_cursors.remove(&rule.id);
+        state.target_load.remove(&rule.id);
+        state.accept_errors.remove(&rule.id);
+        state.rule_rate_counters.remove(&rule.id);
+        state.live.publish("rule_removed", &rule);
+        plan.removed += 1;
+    }
+
+    for mut rule in loaded {
+        state.next_rule_id = state.next_rule_id.max(rule.id + 1);
+        match state.rules.iter_mut().find(|current| current.id == rule.id) {
+            Some(current) if current.from_rules_file => {
+                warn!("Ignoring reloaded rule {}: the id belongs to a rules file rule", rule.id);
+                continue;
+            }
+            Some(current) => {
+                if keep_running_usage {
+                    rule.usage = current.usage.clone();
+                }
+                if serde_json::to_value(&*current).ok() == serde_json::to_value(&rule).ok() {
+                    plan.unchanged += 1;
+                    continue;
+                }
+                if current.enabled {
+                    plan.stop.push(rule.id);
+                }
+                *current = rule.clone();
+                state.live.publish("rule_updated", &rule);
+                plan.updated += 1;
+            }
+            None => {
+                state.rules.push(rule.clone());
+                state.live.publish("rule_added", &rule);
+                plan.added += 1;
+            }
+        }
+        if rule.enabled {
+            starting.push(rule);
+        }
+    }
+
+    // Checked once every edited rule is in place, so rules that trade
+    // addresses in one edit don't collide with each other's old ones. A
+    // hand edit can't take the address of a rule it left alone, and of two
+    // edited rules wanting one address the first in order gets it; the
+    // other is switched off.
+    starting.sort_by_key(|rule| (rule.order, rule.id));
+    let starting_ids = starting.iter().map(|rule| rule.id).collect::<HashSet<_>>();
+    for rule in starting {
+        let holders = state
+            .rules
+            .iter()
+            .filter(|other| !starting_ids.contains(&other.id) || plan.start.iter().any(|started| started.id == other.id))
+            .cloned()
+            .collect::<Vec<_>>();
+        if let Some((other_id, addr)) = find_listen_conflict(&holders, &rule) {
+            if let Some(current) = state.rules.iter_mut().find(|current| current.id == rule.id) {
+                current.enabled = false;
+                current.disabled_reason = Some(format!("{} is already used by rule {}", addr, other_id));
+                state.live.publish("rule_updated", &*current);
+            }
+            plan.displaced.push(rule.id);
+            continue;
+        }
+        plan.start.push(rule);
+    }
+    plan
+}
+
+// Makes the file-managed rules match `entries`. Everything is built and
+// checked before the first change, so a bad file leaves the rules as they
+// were.
+fn reconcile_rules_file(state: &mut AppState, entries: Vec<CreateRuleRequest>) -> Result<RulesFilePlan> {
+    let mut wanted = file_rules(entries)?;
+    let api_rules = state.rules.iter().filter(|rule| !rule.from_rules_file);
+    let rule_count = api_rules.clone().count() + wanted.len();
+    if rule_count > state.config.max_rules {
+        return Err(anyhow!("{} rules in total exceeds --max-rules {}", rule_count, state.config.max_rules));
+    }
+    let ports = api_rules.chain(&wanted).map(listen_port_count).sum::<usize>();
+    if ports > state.config.max_listen_ports {
+        return Err(anyhow!(
+            "{} listen ports in total exceeds --max-listen-ports {}",
+            ports,
+            state.config.max_listen_ports
+        ));
+    }
+    for rule in &mut wanted {
+        rule.id = match state
+            .rules
+            .iter()
+            .find(|current| current.from_rules_file && current.listen_addr == rule.listen_addr)
+        {
+            Some(current) => current.id,
+            None => {
+                state.next_rule_id += 1;
+                state.next_rule_id - 1
+            }
+        };
+    }
+
+    let mut plan = RulesFilePlan::default();
+    let wanted_ids = wanted.iter().map(|rule| rule.id).collect::<HashSet<_>>();
+    let (kept, removed): (Vec<_>, Vec<_>) = std::mem::take(&mut state.rules)
+        .into_iter()
+        .partition(|rule| !rule.from_rules_file || wanted_ids.contains(&rule.id));
+    state.rules = kept;
+    for rule in removed {
+        if rule.enabled {
+            plan.stop.push(rule.id);
+        }
+        state.target_cursors.remove(&rule.id);
+        state.target_load.remove(&rule.id);
+        state.accept_errors.remove(&rule.id);
+        state.rule_rate_counters.remove(&rule.id);
+        state.live.publish("rule_removed", &rule);
+        plan.removed += 1;
+    }
+
+    for mut rule in wanted {
+        match state.rules.iter_mut().find(|current| current.id == rule.id) {
+            Some(current) => {
+                rule.created_at = current.created_at.clone();
+                rule.usage = current.usage.clone();
+                if !rule.enabled {
+                    rule.disabled_reason = current.disabled_reason.clone();
+                }
+                if serde_json::to_value(&*current).ok() == serde_json::to_value(&rule).ok() {
+                    plan.unchanged += 1;
+                    continue;
+                }
+                if current.enabled {
+                    plan.stop.push(rule.id);
+                }
+                *current = rule.clone();
+                state.live.publish("rule_updated", &rule);
+                plan.updated += 1;
+            }
+            None => {
+                state.rules.push(rule.clone());
+                state.live.publish("rule_added", &rule);
+                plan.added += 1;
+            }
+        }
+        if !rule.enabled {
+            continue;
+        }
+        // The file wins: an API rule on the same address is switched off
+        // rather than left to fail the bind.
+        while let Some((other_id, _)) = find_listen_conflict(&state.rules, &rule) {
+            let Some(other) = state
+                .rules
+                .iter_mut()
+                .find(|other| other.id == other_id && !other.from_rules_file)
+            else {
+                break;
+            };
+            other.enabled = false;
+            other.disabled_reason = Some(format!("Listen address taken by rules file rule {}", rule.id));
+            state.live.publish("rule_updated", &*other);
+            plan.stop.push(other_id);
+            plan.displaced.push(other_id);
+        }
+        plan.start.push(rule);
+    }
+    Ok(plan)
+}
+
+async fn disable_rule_after_start_failure(state: &Arc<RwLock<AppState>>, rule: &ProxyRule, err: &anyhow::Error) {
+    {
+        let mut guard = state.write().await;
+        let disabled = guard.rules.iter_mut().find(|item| item.id == rule.id).map(|item| {
+            item.enabled = false;
+            item.disabled_reason = Some(format!("Listener failed: {}", err));
+            item.clone()
+        });
+        if let Some(disabled) = disabled {
+            guard.live.publish("rule_updated", &disabled);
+        }
+        if let Some(webhook) = guard.webhook.as_ref() {
+            webhook.notify(
+                webhook::RULE_START_FAILED,
+                format!("Rule {} ({} -> {}) failed to start: {}", rule.id, rule.listen_addr, rule.target_addr, err),
+                &serde_json::json!({
+                    "rule_id": rule.id,
+                    "listen_addr": rule.listen_addr,
+                    "target_addr": rule.target_addr,
+                    "error": err.to_string(),
+                }),
+            );
+        }
+    }
+    persist_state(state).await;
 }
 
 async fn handle_connection(
     state: Arc<RwLock<AppState>>,
-    inbound: TcpStream,
-    target_addr: String,
-    rule_id: u64,
+    mut inbound: TcpStream,
+    context: Arc<RuleContext>,
+    targets: Arc<Vec<String>>,
     listen_port: u16,
-    client_ip: String,
+    mut client_ip: String,
 ) {
+    let rule = &context.rule;
+    let drain = &context.drain;
+    let rule_id = rule.id;
     let listen_port = Some(listen_port);
-    let conn_id = match register_connection(&state, rule_id, &client_ip, listen_port).await {
+    let mut client_addr = inbound.peer_addr().ok();
+    if rule.accept_proxy_protocol {
+        let header = tokio::time::timeout(PROXY_HEADER_TIMEOUT, proxy_protocol::read_header(&mut inbound))
+            .await
+            .unwrap_or_else(|_| Err("PROXY protocol header timed out".to_string()));
+        match header {
+            Ok(Some((source, _))) => {
+                client_ip = canonical_ip(source.ip());
+                client_addr = Some(source);
+            }
+            Ok(None) => {}
+            Err(reason) => {
+                let refused = BlockReason::ProxyProtocol.refuse(reason);
+                record_blocked(&state, rule_id, listen_port, ProtocolMode::Tcp, client_ip, refused, None).await;
+                return;
+            }
+        }
+    }
+    let five_tuple = if rule.log_five_tuple {
+        Some(FiveTuple {
+            protocol: ProtocolMode::Tcp,
+            client_addr: client_addr.map(|addr| addr.to_string()).unwrap_or_default(),
+            local_addr: inbound.local_addr().map(|addr| addr.to_string()).unwrap_or_default(),
+            target_addr: None,
+        })
+    } else {
+        None
+    };
+    if !rule.rdns_allow_suffixes.is_empty() {
+        if let Err(reason) = check_reverse_dns(&state, &client_ip, &rule.rdns_allow_suffixes).await {
+            record_blocked(&state, rule_id, listen_port, ProtocolMode::Tcp, client_ip, reason, five_tuple).await;
+            return;
+        }
+    }
+    // Read before the connection is registered, like the PROXY header, so a
+    // refused handshake is logged as a blocked attempt and never charges the
+    // rule's budget. A client holding back its ClientHello ties up only its
+    // own socket, and only until CLIENT_HELLO_TIMEOUT.
+    let client_hello = if rule.sni_allow.is_empty() && rule.sni_deny.is_empty() {
+        Vec::new()
+    } else {
+        match check_sni(&mut inbound, rule).await {
+            Ok(consumed) => consumed,
+            Err(refused) => {
+                record_blocked(&state, rule_id, listen_port, ProtocolMode::Tcp, client_ip, refused, five_tuple).await;
+                return;
+            }
+        }
+    };
+    let conn_id = match register_connection(&state, rule_id, &client_ip, listen_port, ProtocolMode::Tcp, five_tuple.clone()).await {
         Ok(value) => value,
         Err(reason) => {
-            record_blocked(&state, rule_id, listen_port, client_ip, reason).await;
+            record_blocked(&state, rule_id, listen_port, ProtocolMode::Tcp, client_ip, reason, five_tuple).await;
             return;
         }
     };
 
-    let outbound = match TcpStream::connect(target_addr.as_str()).await {
-        Ok(stream) => stream,
-        Err(err) => {
+    if let Some(delay) = tarpit_delay(&state, conn_id, &client_ip).await {
+        tokio::time::sleep(delay).await;
+    }
+
+    let egress_source = if rule.transparent_egress {
+        client_ip.parse::<IpAddr>().ok()
+    } else {
+        None
+    };
+    let connected = if rule.http_connect {
+        match connect_requested(&mut inbound, &context, egress_source).await {
+            Ok((outbound, target)) => Ok((outbound, target, None)),
+            Err(refusal) => {
+                let _ = inbound.write_all(refusal.response).await;
+                Err(refusal.reason)
+            }
+        }
+    } else {
+        connect_balanced(&targets, &context, egress_source).await.map_err(|err| {
+            if err.kind() == std::io::ErrorKind::TimedOut {
+                "Target connect timed out".to_string()
+            } else if err.kind() == std::io::ErrorKind::NotConnected {
+                "No healthy targets".to_string()
+            } else if err.kind() == std::io::ErrorKind::ResourceBusy {
+                "All backends at capacity".to_string()
+            } else {
+                format!("Target connect failed: {}", err)
+            }
+        })
+    };
+    // `_target_slot` holds this connection's place in the backend's
+    // max_connections_per_target until the function returns.
+    let (mut outbound, target_addr, _target_slot) = match connected {
+        // Logged as the address actually connected to, so a hostname target
+        // or a balanced rule still shows which backend served the session.
+        Ok((outbound, target, slot)) => {
+            let target_addr = outbound.peer_addr().map(|addr| addr.to_string()).unwrap_or(target);
+            (outbound, target_addr, slot)
+        }
+        Err(reason) => {
+            record_connection_end(&state, conn_id, 0, 0, Some(reason), None).await;
+            return;
+        }
+    };
+
+    if let Some(dscp) = rule.dscp {
+        apply_dscp(&inbound, dscp);
+        apply_dscp(&outbound, dscp);
+    }
+    apply_tcp_options(&inbound, rule);
+    apply_tcp_options(&outbound, rule);
+
+    if let Err(err) = send_proxy_header(rule.send_proxy_protocol, client_addr, &inbound, &mut outbound).await {
+        record_connection_end(
+            &state,
+            conn_id,
+            0,
+            0,
+            Some(format!("PROXY header write failed: {}", err)),
+            Some(target_addr),
+        )
+        .await;
+        return;
+    }
+    // What check_sni read goes to the target first, so the handshake carries
+    // on as if the client had been talking to it all along.
+    if !client_hello.is_empty() {
+        if let Err(err) = outbound.write_all(&client_hello).await {
+            record_connection_end(
+                &state,
+                conn_id,
+                0,
+                0,
+                Some(format!("ClientHello replay failed: {}", err)),
+                Some(target_addr),
+            )
+            .await;
+            return;
+        }
+    }
+    if rule.http_connect {
+        if let Err(err) = inbound.write_all(http_connect::ESTABLISHED).await {
             record_connection_end(
                 &state,
                 conn_id,
                 0,
                 0,
-                Some(format!("Target connect failed: {}", err)),
+                Some(format!("CONNECT reply failed: {}", err)),
+                Some(target_addr),
             )
             .await;
             return;
         }
+    }
+
+    // Cancelled by the drain token, the idle watchdog or the lifetime timer.
+    let stop = drain.child_token();
+    let bytes = connection_bytes(&state, conn_id).await;
+    bytes.add(client_hello.len() as u64);
+    let relay_started = Instant::now();
+    let transfer_result = copy_bidirectional_with_tracking(
+        inbound,
+        outbound,
+        &bytes,
+        &context,
+        &stop,
+    )
+    .await;
+    match transfer_result {
+        Ok((bytes_up, bytes_down)) => {
+            let reason = if drain.is_cancelled() {
+                Some("Closed after drain timeout".to_string())
+            } else if stop.is_cancelled()
+                && context.max_duration.is_some_and(|max| relay_started.elapsed() >= max)
+            {
+                Some("Max duration reached".to_string())
+            } else if stop.is_cancelled() {
+                Some("Idle timeout".to_string())
+            } else {
+                None
+            };
+            let bytes_up = bytes_up + client_hello.len() as u64;
+            record_connection_end(&state, conn_id, bytes_up, bytes_down, reason, Some(target_addr)).await;
+        }
+        Err(err) => {
+            record_connection_end(
+                &state,
+                conn_id,
+                0,
+                0,
+                Some(format!("Proxy error: {}", err)),
+                Some(target_addr),
+            )
+            .await;
+        }
+    }
+
+}
+
+// Reads the ClientHello of a rule with sni_allow or sni_deny and decides
+// whether the client goes on; if it does, returns the bytes read so they can
+// be replayed to the target.
+async fn check_sni(inbound: &mut TcpStream, rule: &ProxyRule) -> Result<Vec<u8>, Refused> {
+    let deadline = tokio::time::Instant::now() + CLIENT_HELLO_TIMEOUT;
+    let hello = sni::read_client_hello(inbound, deadline).await;
+    match hello.server_name {
+        Ok(host) if sni::host_listed(&rule.sni_deny, &host) => {
+            Err(BlockReason::SniBlocked.refuse(format!("SNI {} is denied", host)))
+        }
+        Ok(host) if !rule.sni_allow.is_empty() && !sni::host_listed(&rule.sni_allow, &host) => {
+            Err(BlockReason::SniBlocked.refuse(format!("SNI {} not in allowlist", host)))
+        }
+        Ok(_) => Ok(hello.consumed),
+        Err(_) if rule.sni_fail_open => Ok(hello.consumed),
+        Err(reason) => Err(BlockReason::SniBlocked.refuse(format!("SNI check failed: {}", reason))),
+    }
+}
+
+// Reads the client's CONNECT request and dials the destination it names,
+// if the rule's connect_allowed_targets permit it. A refusal carries the
+// HTTP response to send back before closing.
+async fn connect_requested(
+    inbound: &mut TcpStream,
+    context: &RuleContext,
+    egress_source: Option<IpAddr>,
+) -> Result<(TcpStream, String), http_connect::Refusal> {
+    let destination = tokio::time::timeout(CONNECT_REQUEST_TIMEOUT, http_connect::read_request(inbound))
+        .await
+        .unwrap_or_else(|_| {
+            Err(http_connect::Refusal {
+                reason: "CONNECT request timed out".to_string(),
+                response: http_connect::REQUEST_TIMEOUT,
+            })
+        })?;
+    let authority = destination.authority();
+    if !http_connect::destination_allowed(&context.rule.connect_allowed_targets, &destination) {
+        return Err(http_connect::Refusal {
+            reason: format!("CONNECT destination not allowed: {}", authority),
+            response: http_connect::FORBIDDEN,
+        });
+    }
+    let attempt = tokio::time::timeout(
+        context.connect_timeout,
+        connect_target(&authority, &context.resolver, egress_source, context.rule.source_addr),
+    )
+    .await;
+    match attempt {
+        Ok(Ok(stream)) => Ok((stream, authority)),
+        Ok(Err(err)) => Err(http_connect::Refusal {
+            reason: format!("Target connect failed: {}: {}", authority, err),
+            response: http_connect::BAD_GATEWAY,
+        }),
+        Err(_) => Err(http_connect::Refusal {
+            reason: format!("Target connect timed out: {}", authority),
+            response: http_connect::GATEWAY_TIMEOUT,
+        }),
+    }
+}
+
+// `client_addr` is the address to report as the source: the one from an
+// inbound PROXY header when the rule accepts them, else the peer address.
+async fn send_proxy_header(
+    mode: ProxyProtocolMode,
+    client_addr: Option<SocketAddr>,
+    inbound: &TcpStream,
+    outbound: &mut TcpStream,
+) -> std::io::Result<()> {
+    if mode == ProxyProtocolMode::None {
+        return Ok(());
+    }
+    let source = match client_addr {
+        Some(addr) => addr,
+        None => inbound.peer_addr()?,
+    };
+    let header = proxy_protocol::encode_header(mode, source, inbound.local_addr()?);
+    if let Some(header) = header {
+        outbound.write_all(&header).await?;
+    }
+    Ok(())
+}
+
+fn apply_dscp(stream: &TcpStream, dscp: u8) {
+    let ipv6 = stream.local_addr().map(|addr| addr.is_ipv6()).unwrap_or(false);
+    if let Err(err) = sockopt::set_dscp(socket2::SockRef::from(stream), dscp, ipv6) {
+        warn!("Failed to set DSCP {}: {}", dscp, err);
+    }
+}
+
+fn apply_tcp_options(stream: &TcpStream, rule: &ProxyRule) {
+    if rule.tcp_nodelay {
+        if let Err(err) = stream.set_nodelay(true) {
+            warn!("Failed to set TCP_NODELAY: {}", err);
+        }
+    }
+    if let Some(secs) = rule.tcp_keepalive_secs {
+        let interval = rule.tcp_keepalive_interval_secs.map(Duration::from_secs);
+        if let Err(err) = sockopt::set_keepalive(socket2::SockRef::from(stream), Duration::from_secs(secs), interval) {
+            warn!("Failed to set TCP keepalive: {}", err);
+        }
+    }
+}
+
+// Tries the rule's healthy targets in balancing order until one accepts the
+// connection: round-robin starts at the next slot and walks the rest, weighted
+// random draws by weight without replacement. Each attempt is bounded by the
+// rule's connect timeout. Targets already at max_connections_per_target are
+// skipped; the returned slot keeps the connection counted against its target.
+async fn connect_balanced(
+    targets: &[String],
+    context: &RuleContext,
+    egress_source: Option<IpAddr>,
+) -> std::io::Result<(TcpStream, String, Option<balance::TargetSlot>)> {
+    let healthy = targets
+        .iter()
+        .enumerate()
+        .filter(|(_, target)| context.health.is_healthy(target))
+        .collect::<Vec<_>>();
+    let order = match context.rule.balance {
+        BalanceMode::RoundRobin if healthy.is_empty() => Vec::new(),
+        BalanceMode::RoundRobin => {
+            let start = context.next_target.fetch_add(1, Ordering::Relaxed);
+            (0..healthy.len())
+                .map(|offset| healthy[(start + offset) % healthy.len()].1)
+                .collect::<Vec<_>>()
+        }
+        BalanceMode::WeightedRandom => {
+            let weights = healthy
+                .iter()
+                .map(|(index, _)| context.rule.target_weights.get(*index).copied().unwrap_or(1))
+                .collect::<Vec<_>>();
+            balance::weighted_order(&weights, geo_update::random_unit)
+                .into_iter()
+                .map(|position| healthy[position].1)
+                .collect::<Vec<_>>()
+        }
+    };
+    if order.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotConnected,
+            "no healthy targets",
+        ));
+    }
+    let mut last_err = std::io::Error::new(std::io::ErrorKind::NotFound, "no targets configured");
+    let mut attempted = false;
+    for target in order {
+        let slot = match context.rule.max_connections_per_target {
+            Some(max) => match context.target_load.try_acquire(target, max) {
+                Some(slot) => Some(slot),
+                None => continue,
+            },
+            None => None,
+        };
+        attempted = true;
+        let attempt = tokio::time::timeout(
+            context.connect_timeout,
+            connect_target(target, &context.resolver, egress_source, context.rule.source_addr),
+        )
+            .await
+            .unwrap_or_else(|_| {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "connect timed out",
+                ))
+            });
+        match attempt {
+            Ok(stream) => return Ok((stream, target.clone(), slot)),
+            Err(err) => {
+                warn!("Target {} connect failed: {}", target, err);
+                last_err = err;
+            }
+        }
+    }
+    if !attempted {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::ResourceBusy,
+            "all backends at capacity",
+        ));
+    }
+    Err(last_err)
+}
+
+// Tries each of the target's addresses in turn. With an egress source the
+// socket is bound to that (client) address through IP_TRANSPARENT; otherwise
+// it is bound to the rule's source_addr, if any. With either, only addresses
+// of the source's family are tried.
+async fn connect_target(
+    target: &str,
+    resolver: &resolve::TargetResolver,
+    egress_source: Option<IpAddr>,
+    source_addr: Option<IpAddr>,
+) -> std::io::Result<TcpStream> {
+    let source = egress_source.map(|source| source.to_canonical()).or(source_addr);
+    let addrs = resolver
+        .resolve(target)
+        .await?
+        .into_iter()
+        .filter(|addr| source.is_none_or(|source| addr.is_ipv4() == source.is_ipv4()))
+        .collect::<Vec<_>>();
+    let mut last_err = std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "target has no address in the source address's family",
+    );
+    for addr in addrs {
+        let attempt = async {
+            match source {
+                None => TcpStream::connect(addr).await,
+                Some(source) if egress_source.is_some() => {
+                    sockopt::transparent_socket(source, addr)?.connect(addr).await
+                }
+                Some(source) => sockopt::bound_socket(source, addr)?.connect(addr).await,
+            }
+        };
+        match attempt.await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+async fn check_reverse_dns(
+    state: &Arc<RwLock<AppState>>,
+    client_ip: &str,
+    suffixes: &[String],
+) -> Result<(), Refused> {
+    let ip: IpAddr = client_ip
+        .parse()
+        .map_err(|_| BlockReason::ReverseDns.refuse("Reverse DNS unverifiable"))?;
+    let cache = state.read().await.rdns.clone();
+    match cache.verified_hostname(ip).await {
+        Some(hostname) if rdns::hostname_matches(&hostname, suffixes) => Ok(()),
+        Some(hostname) => Err(BlockReason::ReverseDns.refuse(format!("Reverse DNS not allowed: {}", hostname))),
+        None => Err(BlockReason::ReverseDns.refuse("Reverse DNS unverifiable")),
+    }
+}
+
+pub(crate) async fn register_connection(
+    state: &Arc<RwLock<AppState>>,
+    rule_id: u64,
+    client_ip: &str,
+    listen_port: Option<u16>,
+    protocol: ProtocolMode,
+    five_tuple: Option<FiveTuple>,
+) -> Result<u64, Refused> {
+    // The allow/deny lists and geo lookups only read state, so they run under
+    // the read lock and don't hold up other admissions. A block added before
+    // the write lock below is taken applies from the next connection on, as
+    // if this one had arrived a moment earlier.
+    let (auth, country) = {
+        let guard = state.read().await;
+        check_policy(&guard, client_ip, listen_port)?;
+        let country = client_country(&guard, client_ip);
+        // Clients named in the local lists are decided locally; everyone
+        // else is checked with the auth service before taking the write lock.
+        let auth = guard
+            .auth
+            .clone()
+            .filter(|_| !listed_locally(&guard, client_ip, listen_port));
+        (auth, country)
+    };
+    if let Some(auth) = auth {
+        auth.check(client_ip, listen_port)
+            .await
+            .map_err(|message| BlockReason::AuthDenied.refuse(message))?;
+    }
+
+    let mut guard = state.write().await;
+    if let Some(rule) = guard.rules.iter().find(|rule| rule.id == rule_id) {
+        if budget_exhausted(rule).is_some() {
+            return Err(BlockReason::BudgetExhausted.refuse("Connection budget exhausted"));
+        }
+    }
+    check_limits(&mut guard, rule_id, client_ip, listen_port)?;
+
+    let conn_id = guard.next_conn_id;
+    guard.next_conn_id += 1;
+    let started_at = now_string();
+    guard.active.insert(
+        conn_id,
+        ActiveConn {
+            conn_id,
+            rule_id,
+            client_ip: client_ip.to_string(),
+            listen_port,
+            protocol,
+            started_at: started_at.clone(),
+            started: Instant::now(),
+            bytes: Arc::new(ByteCounter::new()),
+            five_tuple,
+            tarpit_ms: None,
+            hostname: None,
+            country: country.clone(),
+        },
+    );
+    if let Some(active) = guard.active.get(&conn_id) {
+        guard.events.emit("open", active);
+        guard.live.publish("open", active);
+    }
+    *guard
+        .active_by_ip
+        .entry(client_ip.to_string())
+        .or_insert(0) += 1;
+    *guard
+        .active_by_rule_ip
+        .entry((rule_id, client_ip.to_string()))
+        .or_insert(0) += 1;
+    *guard.active_by_rule.entry(rule_id).or_insert(0) += 1;
+    if let Some(port) = listen_port {
+        *guard.active_by_port.entry(port).or_insert(0) += 1;
+        *guard
+            .active_by_port_ip
+            .entry((port, client_ip.to_string()))
+            .or_insert(0) += 1;
+    }
+    *guard
+        .connections_by_country
+        .entry(country.unwrap_or_else(|| "unknown".to_string()))
+        .or_insert(0) += 1;
+
+    if let Some(reason) = consume_rule_budget(&mut guard, rule_id, 1, 0) {
+        tokio::spawn(disable_rule_for_budget(state.clone(), rule_id, reason));
+    }
+    if guard.config.resolve_client_hostnames {
+        if let Ok(ip) = client_ip.parse() {
+            tokio::spawn(resolve_client_hostname(state.clone(), guard.rdns.clone(), conn_id, ip));
+        }
+    }
+
+    Ok(conn_id)
+}
+
+// Looks up the client's hostname off the connection path and attaches it to
+// the connection if it is still open.
+async fn resolve_client_hostname(
+    state: Arc<RwLock<AppState>>,
+    cache: Arc<rdns::ReverseDnsCache>,
+    conn_id: u64,
+    ip: IpAddr,
+) {
+    let Some(hostname) = cache.verified_hostname(ip).await else {
+        return;
+    };
+    let mut guard = state.write().await;
+    let state_ref = &mut *guard;
+    if let Some(active) = state_ref.active.get_mut(&conn_id) {
+        active.hostname = Some(hostname);
+        state_ref.live.publish("hostname", &*active);
+    }
+}
+
+// Clients opening connections faster than the tarpit threshold are slowed
+// down rather than refused. The delay is recorded on the connection. The
+// decision only needs the read lock; the write lock is taken just for a
+// client that is actually tarpitted.
+async fn tarpit_delay(state: &Arc<RwLock<AppState>>, conn_id: u64, client_ip: &str) -> Option<Duration> {
+    let (delay, recent) = {
+        let guard = state.read().await;
+        let threshold = guard.rate_limit.tarpit_threshold_per_minute?;
+        if bypasses_rate_limits(&guard, client_ip) {
+            return None;
+        }
+        let recent = guard.rate_counters.get(client_ip).map(|window| window.len()).unwrap_or(0);
+        if recent as u32 <= threshold {
+            return None;
+        }
+        let delay = guard
+            .rate_limit
+            .tarpit_delay_ms
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_TARPIT_DELAY);
+        (delay, recent)
     };
+    {
+        let mut guard = state.write().await;
+        let state_ref = &mut *guard;
+        if let Some(active) = state_ref.active.get_mut(&conn_id) {
+            active.tarpit_ms = Some(delay.as_millis() as u64);
+            state_ref.events.emit("tarpit", active);
+        }
+    }
+    info!(
+        "Tarpitting {} for {}ms ({} connections in the last minute)",
+        client_ip,
+        delay.as_millis(),
+        recent
+    );
+    Some(delay)
+}
 
-    let transfer_result = copy_bidirectional_with_tracking(inbound, outbound, &state, conn_id).await;
-    match transfer_result {
-        Ok((bytes_up, bytes_down)) => {
-            record_connection_end(&state, conn_id, bytes_up, bytes_down, None).await;
+fn budget_exhausted(rule: &ProxyRule) -> Option<String> {
+    if let Some(max) = rule.max_total_connections {
+        if rule.usage.connections >= max {
+            return Some(format!("Connection budget of {} exhausted", max));
         }
-        Err(err) => {
-            record_connection_end(
-                &state,
-                conn_id,
-                0,
-                0,
-                Some(format!("Proxy error: {}", err)),
-            )
-            .await;
+    }
+    if let Some(max) = rule.max_total_bytes {
+        if rule.usage.bytes >= max {
+            return Some(format!("Byte budget of {} exhausted", max));
         }
     }
+    None
+}
 
+// Adds usage to the rule and returns the reason if this pushed it over budget.
+fn consume_rule_budget(state: &mut AppState, rule_id: u64, connections: u64, bytes: u64) -> Option<String> {
+    let rule = state.rules.iter_mut().find(|rule| rule.id == rule_id)?;
+    rule.usage.connections = rule.usage.connections.saturating_add(connections);
+    rule.usage.bytes = rule.usage.bytes.saturating_add(bytes);
+    if rule.enabled {
+        budget_exhausted(rule)
+    } else {
+        None
+    }
 }
 
-pub(crate) async fn register_connection(
-    state: &Arc<RwLock<AppState>>,
-    rule_id: u64,
-    client_ip: &str,
-    listen_port: Option<u16>,
-) -> Result<u64, String> {
-    let mut guard = state.write().await;
-    if let Err(reason) = check_allow(&mut guard, client_ip, listen_port) {
-        return Err(reason);
+async fn disable_rule_for_budget(state: Arc<RwLock<AppState>>, rule_id: u64, reason: String) {
+    {
+        let mut guard = state.write().await;
+        match guard.rules.iter_mut().find(|rule| rule.id == rule_id) {
+            Some(rule) if rule.enabled => {
+                rule.enabled = false;
+                rule.disabled_reason = Some(reason.clone());
+            }
+            _ => return,
+        }
     }
+    warn!("Rule {} disabled: {}", rule_id, reason);
+    stop_rule_listeners(&state, rule_id).await;
+    persist_state(&state).await;
+}
 
-    let conn_id = guard.next_conn_id;
-    guard.next_conn_id += 1;
-    let started_at = now_string();
-    guard.active.insert(
-        conn_id,
-        ActiveConn {
-            conn_id,
-            rule_id,
-            client_ip: client_ip.to_string(),
-            listen_port,
-            started_at: started_at.clone(),
-            bytes_transferred: 0,
-            last_update: started_at.clone(),
-        },
-    );
-    *guard
-        .active_by_ip
-        .entry(client_ip.to_string())
-        .or_insert(0) += 1;
+fn client_country(state: &AppState, client_ip: &str) -> Option<String> {
+    let db = state.geo_db.as_ref()?;
+    geo::lookup_country(db, client_ip.parse().ok()?)
+}
 
-    Ok(conn_id)
+fn listed_locally(state: &AppState, client_ip: &str, listen_port: Option<u16>) -> bool {
+    if state.allowlist.contains(client_ip) || block_in_effect(state, client_ip, None) {
+        return true;
+    }
+    listen_port.is_some_and(|port| {
+        state
+            .allowlist_ports
+            .get(&port)
+            .is_some_and(|ips| ips.contains(client_ip))
+            || block_in_effect(state, client_ip, Some(port))
+    })
 }
 
-fn check_allow(
-    state: &mut AppState,
-    client_ip: &str,
-    listen_port: Option<u16>,
-) -> Result<(), String> {
+// The allow/deny lists alone: everything about admission that doesn't
+// depend on live connection counts or rate windows. Read-only, so it runs
+// under the read lock, and /api/check-ip can answer "would this IP get in"
+// without side effects.
+fn check_policy(state: &AppState, client_ip: &str, listen_port: Option<u16>) -> Result<(), Refused> {
+    if persistence_failing(state) {
+        return Err(BlockReason::PersistenceFailing.refuse("Persistence failing"));
+    }
+
     if state.allowlist_enabled && !state.allowlist.contains(client_ip) {
-        return Err("Not in allowlist".to_string());
+        return Err(BlockReason::NotAllowlisted.refuse("Not in allowlist"));
     }
 
     if let Some(port) = listen_port {
         if let Some(ips) = state.allowlist_ports.get(&port) {
             if !ips.contains(client_ip) {
-                return Err(format!("Not in allowlist for port {}", port));
+                return Err(BlockReason::NotAllowlisted.refuse(format!("Not in allowlist for port {}", port)));
             }
         }
     }
@@ -1437,43 +6264,252 @@ fn check_allow(
                 if let Some(port) = listen_port {
                     if let Some(countries) = state.geo_port_blocklist.get(&port) {
                         if countries.contains(&country) {
-                            return Err(format!("Geo blocked for port {}: {}", port, country));
+                            return Err(BlockReason::GeoBlocked.refuse(format!("Geo blocked for port {}: {}", port, country)));
                         }
                     }
                 }
                 if state.geo_blocklist.contains(&country) {
-                    return Err(format!("Geo blocked: {}", country));
+                    return Err(BlockReason::GeoBlocked.refuse(format!("Geo blocked: {}", country)));
                 }
             }
         }
     }
 
-    if state.blocklist.contains(client_ip) {
-        return Err("Blocked by rule".to_string());
+    if !state.asn_blocklist.is_empty() {
+        if let Some(db) = state.asn_db.as_ref() {
+            if let Ok(ip) = client_ip.parse() {
+                if let Some(asn) = geo::lookup_asn(db, ip) {
+                    if state.asn_blocklist.contains(&asn) {
+                        return Err(BlockReason::AsnBlocked.refuse(format!("ASN blocked: AS{}", asn)));
+                    }
+                }
+            }
+        }
     }
 
-    if let Some(port) = listen_port {
-        if let Some(ips) = state.port_blocklist.get(&port) {
-            if ips.contains(client_ip) {
-                return Err(format!("Blocked for port {}", port));
+    if block_in_effect(state, client_ip, None) {
+        return Err(BlockReason::Blocklisted.refuse("Blocked by rule"));
+    }
+
+    if !state.blocklist_files.is_empty() {
+        if let Ok(ip) = client_ip.parse() {
+            if let Some(file) = state.blocklist_files.iter().find(|file| file.contains(ip)) {
+                return Err(BlockReason::Blocklisted.refuse(format!("Blocked by list {}", file.path().display())));
             }
         }
     }
 
-    if state.active.len() as u32 >= state.rate_limit.max_concurrent_total {
-        return Err("Too many total connections".to_string());
+    if let Some(port) = listen_port {
+        if block_in_effect(state, client_ip, Some(port)) {
+            return Err(BlockReason::Blocklisted.refuse(format!("Blocked for port {}", port)));
+        }
+    }
+    Ok(())
+}
+
+// Connection caps and rate windows, checked under the write lock after
+// check_policy passed; pushes the new connection into the windows it counts
+// against.
+fn check_limits(
+    state: &mut AppState,
+    rule_id: u64,
+    client_ip: &str,
+    listen_port: Option<u16>,
+) -> Result<(), Refused> {
+    let (rule_max_concurrent, rule_max_new, priority) = state
+        .rules
+        .iter()
+        .find(|rule| rule.id == rule_id)
+        .map(|rule| (rule.max_concurrent, rule.max_new_per_minute, rule.priority))
+        .unwrap_or_default();
+
+    let active_total = state.active.len() as u32;
+    let max_total = state.rate_limit.max_concurrent_total;
+    if active_total >= max_total {
+        return Err(BlockReason::ConnectionLimit.refuse("Too many total connections"));
+    }
+    if let Some(percent) = state.rate_limit.load_shed_percent {
+        if let Some(limit) = priority.shed_limit(max_total, percent) {
+            if active_total >= limit {
+                return Err(BlockReason::LoadShed.refuse(format!(
+                    "Load shed ({} priority): {}/{} connections",
+                    priority.as_str(),
+                    active_total,
+                    max_total
+                )));
+            }
+        }
     }
 
+    let trusted = bypasses_rate_limits(state, client_ip);
     let active_for_ip = state.active_by_ip.get(client_ip).copied().unwrap_or(0) as u32;
-    if active_for_ip >= state.rate_limit.max_concurrent_connections_per_ip {
-        return Err("Too many active connections for IP".to_string());
+    if !trusted && active_for_ip >= state.rate_limit.max_concurrent_connections_per_ip {
+        return Err(BlockReason::ConnectionLimit.refuse("Too many active connections for IP"));
+    }
+
+    if let Some(limit) = state.rate_limit.max_concurrent_per_ip_per_rule.filter(|_| !trusted) {
+        let active_for_rule_ip = state
+            .active_by_rule_ip
+            .get(&(rule_id, client_ip.to_string()))
+            .copied()
+            .unwrap_or(0) as u32;
+        if active_for_rule_ip >= limit {
+            return Err(BlockReason::ConnectionLimit.refuse(format!("Too many active connections for IP on rule {}", rule_id)));
+        }
+    }
+
+    if let Some(limit) = rule_max_concurrent {
+        let active_for_rule = state.active_by_rule.get(&rule_id).copied().unwrap_or(0) as u32;
+        if active_for_rule >= limit {
+            return Err(BlockReason::ConnectionLimit.refuse(format!("Too many active connections for rule {}", rule_id)));
+        }
+    }
+
+    let port_limit = listen_port.and_then(|port| state.port_rate_limits.get(&port)).cloned();
+    if let Some(port_limit) = port_limit.as_ref() {
+        let port = port_limit.port;
+        if let Some(limit) = port_limit.max_concurrent_total {
+            let active_on_port = state.active_by_port.get(&port).copied().unwrap_or(0) as u32;
+            if active_on_port >= limit {
+                return Err(BlockReason::ConnectionLimit.refuse(format!("Too many total connections on port {}", port)));
+            }
+        }
+        if let Some(limit) = port_limit.max_concurrent_connections_per_ip.filter(|_| !trusted) {
+            let active_for_ip_on_port = state
+                .active_by_port_ip
+                .get(&(port, client_ip.to_string()))
+                .copied()
+                .unwrap_or(0) as u32;
+            if active_for_ip_on_port >= limit {
+                return Err(BlockReason::ConnectionLimit.refuse(format!("Too many active connections for IP on port {}", port)));
+            }
+        }
     }
 
     let now = Instant::now();
     let window = state
         .rate_counters
         .entry(client_ip.to_string())
-        .or_insert_with(VecDeque::new);
+        .or_default();
+    prune_rate_window(window, now);
+    if !trusted && window.len() as u32 >= state.rate_limit.max_new_connections_per_minute {
+        if let Some(secs) = state.rate_limit.auto_ban_secs {
+            insert_block(state, client_ip.to_string(), None, Some(Duration::from_secs(secs)));
+            warn!("Auto-banned {} for {}s after exceeding the rate limit", client_ip, secs);
+            if let Some(webhook) = state.webhook.as_ref() {
+                webhook.notify(
+                    webhook::AUTO_BAN,
+                    format!("Auto-banned {} for {}s after exceeding the rate limit", client_ip, secs),
+                    &serde_json::json!({
+                        "client_ip": client_ip,
+                        "rule_id": rule_id,
+                        "ban_secs": secs,
+                    }),
+                );
+            }
+            return Err(BlockReason::RateLimit.refuse(format!("Rate limit exceeded, banned for {}s", secs)));
+        }
+        return Err(BlockReason::RateLimit.refuse("Rate limit exceeded"));
+    }
+
+    let port_rate_key = port_limit
+        .as_ref()
+        .filter(|_| !trusted)
+        .and_then(|port_limit| Some((port_limit.port, port_limit.max_new_connections_per_minute?)));
+    if let Some((port, limit)) = port_rate_key {
+        let window = state
+            .port_rate_counters
+            .entry((port, client_ip.to_string()))
+            .or_default();
+        prune_rate_window(window, now);
+        if window.len() as u32 >= limit {
+            return Err(BlockReason::RateLimit.refuse(format!("Rate limit exceeded for port {}", port)));
+        }
+    }
+
+    // A rule-wide refusal isn't the client's fault, so it never triggers the
+    // auto-ban and doesn't count against the client's own window.
+    if let Some(limit) = rule_max_new {
+        let window = state.rule_rate_counters.entry(rule_id).or_default();
+        prune_rate_window(window, now);
+        if window.len() as u32 >= limit {
+            return Err(BlockReason::RateLimit.refuse(format!("Rate limit exceeded for rule {}", rule_id)));
+        }
+        window.push_back(now);
+    }
+
+    if let Some(window) = state.rate_counters.get_mut(client_ip) {
+        window.push_back(now);
+    }
+    if let Some((port, _)) = port_rate_key {
+        if let Some(window) = state.port_rate_counters.get_mut(&(port, client_ip.to_string())) {
+            window.push_back(now);
+        }
+    }
+    Ok(())
+}
+
+// Client addresses are keyed by this form everywhere (active tables, rate
+// windows, block/allow lists): IPv4-mapped IPv6 peers from dual-stack
+// listeners become plain IPv4.
+pub(crate) fn canonical_ip(ip: IpAddr) -> String {
+    ip.to_canonical().to_string()
+}
+
+// Block/allow entries as typed by users: any IPv6 spelling (expanded,
+// uppercase, with a %scope, [bracketed], v4-mapped) is stored in the same
+// form as canonical_ip. Anything that isn't an IP is kept as given.
+fn normalize_ip_entry(value: &str) -> String {
+    let value = value.trim();
+    let unbracketed = value.trim_start_matches('[').trim_end_matches(']');
+    let without_scope = unbracketed.split('%').next().unwrap_or(unbracketed);
+    match without_scope.parse::<IpAddr>() {
+        Ok(ip) => canonical_ip(ip),
+        Err(_) => value.to_string(),
+    }
+}
+
+// Rate windows are otherwise only pruned when the same client (or rule)
+// connects again, so every address that connected once would keep an entry
+// forever; a scan from many sources would grow the maps without bound.
+fn start_rate_counter_reaper(state: Arc<RwLock<AppState>>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(RATE_COUNTER_REAP_INTERVAL).await;
+            let mut guard = state.write().await;
+            reap_rate_counters(&mut guard);
+        }
+    });
+}
+
+fn reap_rate_counters(state: &mut AppState) {
+    let now = Instant::now();
+    state.rate_counters.retain(|_, window| {
+        prune_rate_window(window, now);
+        !window.is_empty()
+    });
+    state.rule_rate_counters.retain(|_, window| {
+        prune_rate_window(window, now);
+        !window.is_empty()
+    });
+    state.port_rate_counters.retain(|_, window| {
+        prune_rate_window(window, now);
+        !window.is_empty()
+    });
+    // release_counter removes keys as they reach zero; these only catch
+    // anything that slipped through.
+    state.active_by_ip.retain(|_, count| *count > 0);
+    state.active_by_rule_ip.retain(|_, count| *count > 0);
+    state.active_by_rule.retain(|_, count| *count > 0);
+    state.active_by_port.retain(|_, count| *count > 0);
+    state.active_by_port_ip.retain(|_, count| *count > 0);
+}
+
+fn bypasses_rate_limits(state: &AppState, client_ip: &str) -> bool {
+    state.rate_limit.allowlist_bypass && state.allowlist.contains(client_ip)
+}
+
+fn prune_rate_window(window: &mut VecDeque<Instant>, now: Instant) {
     while let Some(front) = window.front().copied() {
         if now.duration_since(front) > Duration::from_secs(60) {
             window.pop_front();
@@ -1481,44 +6517,86 @@ fn check_allow(
             break;
         }
     }
-    if window.len() as u32 >= state.rate_limit.max_new_connections_per_minute {
-        return Err("Rate limit exceeded".to_string());
-    }
-    window.push_back(now);
-    Ok(())
-}
-
-fn is_ddos_reason(reason: &str) -> bool {
-    reason.contains("Rate limit") || reason.contains("Too many")
 }
 
 pub(crate) async fn record_blocked(
     state: &Arc<RwLock<AppState>>,
     rule_id: u64,
     listen_port: Option<u16>,
+    protocol: ProtocolMode,
     client_ip: String,
-    reason: String,
+    refused: Refused,
+    five_tuple: Option<FiveTuple>,
 ) {
-    let snapshot = {
+    {
         let mut guard = state.write().await;
         let conn_id = guard.next_conn_id;
         guard.next_conn_id += 1;
-        guard.history.push(ConnectionLog {
+        let country = client_country(&guard, &client_ip);
+        let mut entry = ConnectionLog {
             id: conn_id,
             rule_id,
             client_ip,
             listen_port,
+            protocol: Some(protocol),
             started_at: now_string(),
             ended_at: Some(now_string()),
             bytes_up: 0,
             bytes_down: 0,
             blocked: true,
-            reason: Some(reason),
-        });
-        trim_history(&mut guard.history);
-        snapshot_state(&guard)
-    };
-    persist_state(state.clone(), snapshot).await;
+            reason: Some(refused.message),
+            block_reason: Some(refused.reason),
+            five_tuple: five_tuple.map(|tuple| FiveTuple {
+                target_addr: None,
+                ..tuple
+            }),
+            tarpit_ms: None,
+            hostname: None,
+            country,
+            target_addr: None,
+            duration_ms: None,
+        };
+        if guard.config.redact_client_ip {
+            redact_log_entry(&mut entry);
+        }
+        let state_ref = &mut *guard;
+        state_ref.rule_stats.entry(rule_id).or_default().record(&entry);
+        if !rule_logs(state_ref, rule_id, |rule| rule.log_blocked) {
+            return;
+        }
+        state_ref.events.emit("blocked", &entry);
+        state_ref.access_log.write("blocked", &entry);
+        state_ref.live.publish("blocked", &entry);
+        state_ref.blocked_history.push(entry);
+        trim_history(&mut guard);
+    }
+    persist_state(state).await;
+}
+
+// A rule's log_connections/log_blocked; rules deleted since the connection
+// started are logged.
+fn rule_logs(state: &AppState, rule_id: u64, flag: impl Fn(&ProxyRule) -> bool) -> bool {
+    state.rules.iter().find(|rule| rule.id == rule_id).is_none_or(flag)
+}
+
+// Decrements a per-key active counter, dropping the key at zero. A missing
+// key means the counters drifted from `active`: that fails debug builds and
+// is logged in release ones, where /api/debug/consistency can repair it.
+fn release_counter<K: std::hash::Hash + Eq + std::fmt::Debug>(
+    counters: &mut HashMap<K, usize>,
+    key: &K,
+    name: &str,
+) {
+    match counters.get_mut(key) {
+        Some(counter) if *counter > 1 => *counter -= 1,
+        Some(_) => {
+            counters.remove(key);
+        }
+        None => {
+            warn!("Connection accounting mismatch: {} has no count for {:?}", name, key);
+            debug_assert!(false, "{} has no count for {:?}", name, key);
+        }
+    }
 }
 
 pub(crate) async fn record_connection_end(
@@ -1527,86 +6605,212 @@ pub(crate) async fn record_connection_end(
     bytes_up: u64,
     bytes_down: u64,
     reason: Option<String>,
+    target_addr: Option<String>,
 ) {
-    let snapshot = {
+    {
         let mut guard = state.write().await;
         let active = guard.active.remove(&conn_id);
         if let Some(active) = active {
-            if let Some(counter) = guard.active_by_ip.get_mut(&active.client_ip) {
-                *counter = counter.saturating_sub(1);
-                if *counter == 0 {
-                    guard.active_by_ip.remove(&active.client_ip);
-                }
+            release_counter(&mut guard.active_by_ip, &active.client_ip, "active_by_ip");
+            let rule_ip = (active.rule_id, active.client_ip.clone());
+            release_counter(&mut guard.active_by_rule_ip, &rule_ip, "active_by_rule_ip");
+            release_counter(&mut guard.active_by_rule, &active.rule_id, "active_by_rule");
+            if let Some(port) = active.listen_port {
+                release_counter(&mut guard.active_by_port, &port, "active_by_port");
+                let port_ip = (port, active.client_ip.clone());
+                release_counter(&mut guard.active_by_port_ip, &port_ip, "active_by_port_ip");
+            }
+            if let Some(reason) = consume_rule_budget(
+                &mut guard,
+                active.rule_id,
+                0,
+                bytes_up.saturating_add(bytes_down),
+            ) {
+                tokio::spawn(disable_rule_for_budget(state.clone(), active.rule_id, reason));
             }
-            guard.history.push(ConnectionLog {
+            // Short connections often close before their own lookup
+            // finishes; a cached answer from an earlier one still counts.
+            let hostname = active.hostname.or_else(|| {
+                guard
+                    .config
+                    .resolve_client_hostnames
+                    .then(|| active.client_ip.parse().ok())
+                    .flatten()
+                    .and_then(|ip| guard.rdns.cached_hostname(ip))
+            });
+            let mut entry = ConnectionLog {
                 id: conn_id,
                 rule_id: active.rule_id,
                 client_ip: active.client_ip,
                 listen_port: active.listen_port,
+                protocol: Some(active.protocol),
                 started_at: active.started_at,
                 ended_at: Some(now_string()),
                 bytes_up,
                 bytes_down,
                 blocked: false,
                 reason,
-            });
-            trim_history(&mut guard.history);
+                block_reason: None,
+                five_tuple: active.five_tuple.map(|tuple| FiveTuple {
+                    target_addr: target_addr.clone().or(tuple.target_addr),
+                    ..tuple
+                }),
+                tarpit_ms: active.tarpit_ms,
+                hostname,
+                country: active.country,
+                target_addr,
+                duration_ms: Some(active.started.elapsed().as_millis() as u64),
+            };
+            if guard.config.redact_client_ip {
+                redact_log_entry(&mut entry);
+            }
+            let state_ref = &mut *guard;
+            state_ref.rule_stats.entry(entry.rule_id).or_default().record(&entry);
+            state_ref.top_talkers.record(
+                &entry.client_ip,
+                OffsetDateTime::now_utc().unix_timestamp(),
+                entry.bytes_up,
+                entry.bytes_down,
+            );
+            state_ref.events.emit("close", &entry);
+            state_ref.live.publish("close", &entry);
+            if rule_logs(state_ref, entry.rule_id, |rule| rule.log_connections) {
+                state_ref.access_log.write("close", &entry);
+                state_ref.history.push(entry);
+                trim_history(&mut guard);
+            } else if !state_ref.rules.iter().any(|rule| {
+                rule.id == entry.rule_id && (rule.max_total_connections.is_some() || rule.max_total_bytes.is_some())
+            }) {
+                // An unlogged connection only changed the rule's usage, which
+                // the next save picks up unless a budget depends on it.
+                return;
+            }
         }
-        snapshot_state(&guard)
-    };
-    persist_state(state.clone(), snapshot).await;
+    }
+    persist_state(state).await;
 }
 
-pub(crate) async fn update_connection_bytes(
-    state: &Arc<RwLock<AppState>>,
-    conn_id: u64,
-    bytes_transferred: u64,
-) {
-    let mut guard = state.write().await;
-    if let Some(conn) = guard.active.get_mut(&conn_id) {
-        conn.bytes_transferred = bytes_transferred;
-        conn.last_update = now_string();
-    }
+// The relay's handle on a registered connection's byte counter; a detached
+// counter if the connection is already gone.
+pub(crate) async fn connection_bytes(state: &Arc<RwLock<AppState>>, conn_id: u64) -> Arc<ByteCounter> {
+    let guard = state.read().await;
+    guard
+        .active
+        .get(&conn_id)
+        .map(|conn| conn.bytes.clone())
+        .unwrap_or_else(|| Arc::new(ByteCounter::new()))
+}
+
+// Sends a live "bytes" event for each connection whose counter moved since
+// the last tick. Only needs the read lock.
+fn start_bytes_publisher(state: Arc<RwLock<AppState>>) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(BYTES_PUBLISH_INTERVAL);
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tick.tick().await;
+            let guard = state.read().await;
+            if guard.live.is_idle() {
+                continue;
+            }
+            for conn in guard.active.values() {
+                if conn.bytes.take_unpublished() {
+                    guard.live.publish("bytes", conn);
+                }
+            }
+        }
+    });
 }
 
-fn trim_history(history: &mut Vec<ConnectionLog>) {
-    if history.len() > MAX_HISTORY {
-        let over = history.len() - MAX_HISTORY;
-        history.drain(0..over);
+// Trims both logs to their caps and the retention window. Returns whether
+// anything was dropped.
+fn trim_history(state: &mut AppState) -> bool {
+    let retention = state.config.history_retention;
+    let closed = trim_log(&mut state.history, state.config.max_history, retention);
+    let blocked = trim_log(&mut state.blocked_history, state.config.max_blocked_history, retention);
+    closed || blocked
+}
+
+// Drops the oldest entries past `max`, and any older than `retention`. Logs
+// are appended in end order, so both cuts come off the front.
+fn trim_log(log: &mut Vec<ConnectionLog>, max: usize, retention: Option<Duration>) -> bool {
+    let before = log.len();
+    if log.len() > max {
+        let over = log.len() - max;
+        log.drain(0..over);
+    }
+    // A window reaching back past what OffsetDateTime can hold cuts nothing.
+    let cutoff = retention
+        .and_then(|retention| time::Duration::try_from(retention).ok())
+        .and_then(|retention| OffsetDateTime::now_utc().checked_sub(retention));
+    if let Some(cutoff) = cutoff {
+        let expired = log
+            .iter()
+            .take_while(|entry| log_time(entry).is_some_and(|at| at < cutoff))
+            .count();
+        log.drain(0..expired);
     }
+    log.len() != before
+}
+
+// Both logs newest first, interleaved by end time the way the single
+// combined log used to be.
+fn merged_history_rev(state: &AppState) -> impl Iterator<Item = &ConnectionLog> {
+    let mut closed = state.history.iter().rev().peekable();
+    let mut blocked = state.blocked_history.iter().rev().peekable();
+    std::iter::from_fn(move || match (closed.peek(), blocked.peek()) {
+        (Some(a), Some(b)) if log_time(a) < log_time(b) => blocked.next(),
+        _ => closed.next().or_else(|| blocked.next()),
+    })
+}
+
+fn log_time(entry: &ConnectionLog) -> Option<OffsetDateTime> {
+    let at = entry.ended_at.as_deref().unwrap_or(&entry.started_at);
+    OffsetDateTime::parse(at, &Rfc3339).ok()
 }
 
 async fn copy_bidirectional_with_tracking(
     mut inbound: TcpStream,
     mut outbound: TcpStream,
-    state: &Arc<RwLock<AppState>>,
-    conn_id: u64,
+    bytes: &ByteCounter,
+    context: &RuleContext,
+    stop: &CancellationToken,
 ) -> Result<(u64, u64), Box<dyn std::error::Error + Send + Sync>> {
+    let max_bytes_per_sec = context.rule.max_bytes_per_sec;
+    let idle_timeout = context.idle_timeout;
+    let max_duration = context.max_duration;
+    let buffer_size = context.buffer_size;
     let (mut ri, mut wi) = inbound.split();
     let (mut ro, mut wo) = outbound.split();
-    
-    let state_clone = state.clone();
-    let conn_id_clone = conn_id;
+
+    // Milliseconds since `started` of the last successful read in either
+    // direction; stored once per chunk, checked by the idle watchdog.
+    let started = Instant::now();
+    let last_activity = AtomicU64::new(0);
+    let last_activity = &last_activity;
     
     // Task to read from inbound and write to outbound
     let client_to_server = async move {
-        let mut buffer = [0; 8192];
+        let mut buffer = vec![0; buffer_size];
         let mut total_bytes = 0u64;
-        let mut last_update = std::time::Instant::now();
+        let mut throttle = max_bytes_per_sec.map(Throttle::new);
         
         loop {
-            match ri.read(&mut buffer).await {
+            let read = tokio::select! {
+                _ = stop.cancelled() => break,
+                read = ri.read(&mut buffer) => read,
+            };
+            match read {
                 Ok(0) => break,
                 Ok(n) => {
+                    last_activity.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
                     total_bytes += n as u64;
                     if wo.write_all(&buffer[..n]).await.is_err() {
                         break;
                     }
-                    
-                    // Update bytes every 100ms or every 1MB
-                    if last_update.elapsed().as_millis() >= 100 || total_bytes % (1024 * 1024) == 0 {
-                        update_connection_bytes(&state_clone, conn_id_clone, total_bytes).await;
-                        last_update = std::time::Instant::now();
+                    bytes.add(n as u64);
+                    if let Some(throttle) = throttle.as_mut() {
+                        throttle.consume(n).await;
                     }
                 }
                 Err(_) => break,
@@ -1615,28 +6819,28 @@ async fn copy_bidirectional_with_tracking(
         total_bytes
     };
     
-    let state_clone = state.clone();
-    let conn_id_clone = conn_id;
-    
     // Task to read from outbound and write to inbound
     let server_to_client = async move {
-        let mut buffer = [0; 8192];
+        let mut buffer = vec![0; buffer_size];
         let mut total_bytes = 0u64;
-        let mut last_update = std::time::Instant::now();
+        let mut throttle = max_bytes_per_sec.map(Throttle::new);
         
         loop {
-            match ro.read(&mut buffer).await {
+            let read = tokio::select! {
+                _ = stop.cancelled() => break,
+                read = ro.read(&mut buffer) => read,
+            };
+            match read {
                 Ok(0) => break,
                 Ok(n) => {
+                    last_activity.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
                     total_bytes += n as u64;
                     if wi.write_all(&buffer[..n]).await.is_err() {
                         break;
                     }
-                    
-                    // Update bytes every 100ms or every 1MB
-                    if last_update.elapsed().as_millis() >= 100 || total_bytes % (1024 * 1024) == 0 {
-                        update_connection_bytes(&state_clone, conn_id_clone, total_bytes).await;
-                        last_update = std::time::Instant::now();
+                    bytes.add(n as u64);
+                    if let Some(throttle) = throttle.as_mut() {
+                        throttle.consume(n).await;
                     }
                 }
                 Err(_) => break,
@@ -1645,11 +6849,73 @@ async fn copy_bidirectional_with_tracking(
         total_bytes
     };
     
+    // Sleeps until the connection could first become idle, then re-checks;
+    // cancels `stop` once nothing has moved for the whole timeout.
+    let idle_watchdog = async move {
+        let Some(timeout) = idle_timeout else {
+            return std::future::pending().await;
+        };
+        loop {
+            let last = Duration::from_millis(last_activity.load(Ordering::Relaxed));
+            let idle = started.elapsed().saturating_sub(last);
+            if idle >= timeout {
+                stop.cancel();
+                return std::future::pending().await;
+            }
+            tokio::time::sleep(timeout - idle).await;
+        }
+    };
+
+    // Stops the relay once the connection reaches the rule's max lifetime;
+    // like the idle watchdog it only cancels `stop`, so both directions
+    // still return what they relayed.
+    let lifetime = async move {
+        if let Some(max) = max_duration {
+            tokio::time::sleep(max).await;
+            stop.cancel();
+        }
+        std::future::pending::<()>().await
+    };
+
     // Run both tasks concurrently
-    let (bytes_up, bytes_down) = tokio::join!(client_to_server, server_to_client);
+    let (bytes_up, bytes_down) = tokio::select! {
+        totals = async { tokio::join!(client_to_server, server_to_client) } => totals,
+        _ = idle_watchdog => unreachable!(),
+        _ = lifetime => unreachable!(),
+    };
     Ok((bytes_up, bytes_down))
 }
 
+// Token bucket holding up to one second of budget. Each relay direction owns
+// its own bucket, so a throttled direction never stalls the other one.
+struct Throttle {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Throttle {
+    fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+        Self {
+            rate,
+            tokens: rate,
+            last_refill: Instant::now(),
+        }
+    }
+
+    async fn consume(&mut self, bytes: usize) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-self.tokens / self.rate)).await;
+        }
+    }
+}
+
 fn snapshot_state(state: &AppState) -> PersistedState {
     let mut port_blocklist = Vec::new();
     for (port, ips) in &state.port_blocklist {
@@ -1682,42 +6948,263 @@ fn snapshot_state(state: &AppState) -> PersistedState {
             });
         }
     }
-    geo_port_blocklist.sort_by(|a, b| {
-        a.port
-            .cmp(&b.port)
-            .then_with(|| a.country.cmp(&b.country))
-    });
+    geo_port_blocklist.sort_by(|a, b| {
+        a.port
+            .cmp(&b.port)
+            .then_with(|| a.country.cmp(&b.country))
+    });
+
+    let mut asn_blocklist = state.asn_blocklist.iter().copied().collect::<Vec<_>>();
+    asn_blocklist.sort_unstable();
+
+    let mut port_rate_limits = state.port_rate_limits.values().cloned().collect::<Vec<_>>();
+    port_rate_limits.sort_by_key(|limit| limit.port);
+
+    let mut block_expiry = state
+        .block_expiry
+        .iter()
+        .filter_map(|((ip, port), expires_at)| {
+            Some(BlockExpiryEntry {
+                ip: ip.clone(),
+                port: *port,
+                expires_at: expires_at.format(&Rfc3339).ok()?,
+            })
+        })
+        .collect::<Vec<_>>();
+    block_expiry.sort_by(|a, b| a.port.cmp(&b.port).then_with(|| a.ip.cmp(&b.ip)));
+
+    PersistedState {
+        version: migrate::STATE_VERSION,
+        rules: state.rules.clone(),
+        blocklist: state.blocklist.iter().cloned().collect(),
+        port_blocklist,
+        allowlist: state.allowlist.iter().cloned().collect(),
+        allowlist_ports,
+        allowlist_enabled: state.allowlist_enabled,
+        geo_blocklist: state.geo_blocklist.iter().cloned().collect(),
+        geo_port_blocklist,
+        asn_blocklist,
+        history: state.history.clone(),
+        blocked_history: state.blocked_history.clone(),
+        rate_limit: state.rate_limit.clone(),
+        port_rate_limits,
+        block_expiry,
+        block_notes: save_notes(&state.block_notes),
+        allow_notes: save_notes(&state.allow_notes),
+    }
+}
+
+fn save_notes(notes: &HashMap<(String, Option<u16>), String>) -> Vec<EntryNote> {
+    let mut notes = notes
+        .iter()
+        .map(|((ip, port), note)| EntryNote {
+            ip: ip.clone(),
+            port: *port,
+            note: note.clone(),
+        })
+        .collect::<Vec<_>>();
+    notes.sort_by(|a, b| a.port.cmp(&b.port).then_with(|| a.ip.cmp(&b.ip)));
+    notes
+}
+
+fn load_notes(notes: Vec<EntryNote>) -> HashMap<(String, Option<u16>), String> {
+    notes
+        .into_iter()
+        .filter(|entry| !entry.note.trim().is_empty())
+        .map(|entry| ((normalize_ip_entry(&entry.ip), entry.port), entry.note))
+        .collect()
+}
+
+// Modification time and size of state.json.
+type FileStamp = (SystemTime, u64);
+
+// Set by persist_state and taken by the writer task, so a burst of changes
+// becomes one save.
+#[derive(Default)]
+struct PendingSave {
+    dirty: AtomicBool,
+    wake: Notify,
+    // state.json as last written or loaded here. A file on disk that no
+    // longer matches was edited by hand and is kept until SIGHUP loads it.
+    on_disk: std::sync::Mutex<Option<FileStamp>>,
+    held_back: AtomicBool,
+}
+
+impl PendingSave {
+    fn new(on_disk: Option<FileStamp>) -> Self {
+        Self {
+            on_disk: std::sync::Mutex::new(on_disk),
+            ..Self::default()
+        }
+    }
+
+    fn loaded(&self, stamp: Option<FileStamp>) {
+        if let Ok(mut on_disk) = self.on_disk.lock() {
+            *on_disk = stamp;
+        }
+        self.held_back.store(false, Ordering::Relaxed);
+    }
 
-    PersistedState {
-        rules: state.rules.clone(),
-        blocklist: state.blocklist.iter().cloned().collect(),
-        port_blocklist,
-        allowlist: state.allowlist.iter().cloned().collect(),
-        allowlist_ports,
-        allowlist_enabled: state.allowlist_enabled,
-        geo_blocklist: state.geo_blocklist.iter().cloned().collect(),
-        geo_port_blocklist,
-        history: state.history.clone(),
-        rate_limit: state.rate_limit.clone(),
+    // A missing file is never protected: there is nothing to lose.
+    fn edited_on_disk(&self, current: Option<FileStamp>) -> bool {
+        current.is_some() && self.on_disk.lock().is_ok_and(|on_disk| *on_disk != current)
     }
 }
 
-async fn persist_state(state: Arc<RwLock<AppState>>, snapshot: PersistedState) {
-    let data_path = { state.read().await.data_path.clone() };
+async fn file_stamp(path: &StdPath) -> Option<FileStamp> {
+    let meta = tokio::fs::metadata(path).await.ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+async fn persist_state(state: &Arc<RwLock<AppState>>) {
+    let saves = state.read().await.saves.clone();
+    saves.dirty.store(true, Ordering::Relaxed);
+    saves.wake.notify_one();
+}
+
+// Waits for the first change after a save, gives later ones
+// --persist-interval-ms to join it, then writes them all at once.
+fn start_state_writer(state: Arc<RwLock<AppState>>) {
     tokio::spawn(async move {
-        if let Err(err) = save_snapshot(data_path, snapshot).await {
-            error!("Failed to save state: {}", err);
+        let (saves, interval, failures, fail_safe) = {
+            let guard = state.read().await;
+            (
+                guard.saves.clone(),
+                guard.config.persist_interval,
+                guard.persist_failures.clone(),
+                guard.config.persist_fail_safe,
+            )
+        };
+        loop {
+            saves.wake.notified().await;
+            tokio::time::sleep(interval).await;
+            if !saves.dirty.load(Ordering::Relaxed) {
+                continue;
+            }
+            match save_state(&state).await {
+                Ok(()) => {
+                    if failures.swap(0, Ordering::Relaxed) > 0 {
+                        info!("State saves recovered");
+                    }
+                }
+                Err(err) => {
+                    // Still unsaved; the next change retries.
+                    saves.dirty.store(true, Ordering::Relaxed);
+                    let count = failures.fetch_add(1, Ordering::Relaxed) + 1;
+                    error!("Failed to save state ({} in a row): {}", count, err);
+                    if fail_safe == Some(count) {
+                        error!("Refusing new connections until state can be saved again");
+                    }
+                }
+            }
         }
     });
 }
 
-async fn save_snapshot(path: PathBuf, snapshot: PersistedState) -> Result<()> {
-    let bytes = serde_json::to_vec_pretty(&snapshot)?;
-    tokio::fs::write(path, bytes).await?;
+// Only with --persist-fail-safe; otherwise failed saves are just logged.
+fn persistence_failing(state: &AppState) -> bool {
+    state
+        .config
+        .persist_fail_safe
+        .is_some_and(|threshold| state.persist_failures.load(Ordering::Relaxed) >= threshold)
+}
+
+// The writer task and the shutdown save both go through save_state; holding
+// this from snapshot to rename keeps them off each other's temp file and
+// makes the last snapshot taken the last one written.
+static SAVE_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+async fn save_state(state: &Arc<RwLock<AppState>>) -> Result<()> {
+    let _guard = SAVE_LOCK.lock().await;
+    let (path, snapshot, compact, saves) = {
+        let guard = state.read().await;
+        guard.saves.dirty.store(false, Ordering::Relaxed);
+        (
+            guard.data_path.clone(),
+            snapshot_state(&guard),
+            guard.config.compact_state,
+            guard.saves.clone(),
+        )
+    };
+    if saves.edited_on_disk(file_stamp(&path).await) {
+        // Saved once the edit is loaded (which persists) or the next change
+        // finds it gone.
+        saves.dirty.store(true, Ordering::Relaxed);
+        if !saves.held_back.swap(true, Ordering::Relaxed) {
+            warn!(
+                "{} changed on disk since it was last saved; not overwriting it until it is reloaded (SIGHUP)",
+                path.display()
+            );
+        }
+        return Ok(());
+    }
+    save_snapshot(path.clone(), snapshot, compact).await?;
+    saves.loaded(file_stamp(&path).await);
+    Ok(())
+}
+
+// Writes to a temp file and renames it into place, keeping the previous file
+// as a backup, so a crash mid-write never leaves a truncated state.json. The
+// previous file only replaces the backup if it still reads as a state file;
+// otherwise it is moved aside and the last good backup stays.
+async fn save_snapshot(path: PathBuf, snapshot: PersistedState, compact: bool) -> Result<()> {
+    let bytes = if compact {
+        serde_json::to_vec(&snapshot)?
+    } else {
+        serde_json::to_vec_pretty(&snapshot)?
+    };
+    let tmp_path = path.with_extension(STATE_TMP_EXTENSION);
+    let mut file = tokio::fs::File::create(&tmp_path).await?;
+    file.write_all(&bytes).await?;
+    file.sync_all().await?;
+    drop(file);
+    match check_persisted(&path).await {
+        Ok(Some(_)) => {
+            let _ = tokio::fs::rename(&path, path.with_extension(STATE_BACKUP_EXTENSION)).await;
+        }
+        Ok(None) => {}
+        Err(err) => {
+            warn!("Keeping the previous backup: {} does not read as state: {}", path.display(), err);
+            move_corrupt_state(&path).await;
+        }
+    }
+    tokio::fs::rename(&tmp_path, &path).await?;
     Ok(())
 }
 
-fn now_string() -> String {
+// Live enforcement keeps using the full address; only what goes into the
+// connection log (and from there to disk and the API) is masked.
+fn redact_log_entry(entry: &mut ConnectionLog) {
+    entry.client_ip = redact_ip(&entry.client_ip);
+    entry.hostname = None;
+    if let Some(tuple) = entry.five_tuple.as_mut() {
+        tuple.client_addr = redact_ip(&tuple.client_addr);
+    }
+}
+
+// Masks an IP (or ip:port, dropping the port) down to its /24 or /64 network.
+// Anything else, including an already redacted value, is returned unchanged.
+fn redact_ip(value: &str) -> String {
+    let ip = match value.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => match value.parse::<SocketAddr>() {
+            Ok(addr) => addr.ip(),
+            Err(_) => return value.to_string(),
+        },
+    };
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}/24", Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, d, ..] = ip.segments();
+            format!("{}/64", Ipv6Addr::new(a, b, c, d, 0, 0, 0, 0))
+        }
+    }
+}
+
+pub(crate) fn now_string() -> String {
     OffsetDateTime::now_utc()
         .format(&Rfc3339)
         .unwrap_or_else(|_| "1970-01-01T00:00:00Z".to_string())
@@ -1730,6 +7217,7 @@ fn build_index_html() -> String {
         .replace("{{PROTOCOL_JSON_FIELDS}}", crate::protocol::RULE_JSON_FIELDS)
         .replace("{{PROTOCOL_JS_HOOKS}}", crate::protocol::RULE_JS_HOOKS)
         .replace("{{GEO_BLOCK_SECTION}}", geo::GEO_SECTION_HTML)
+        .replace("{{ASN_BLOCK_SECTION}}", geo::ASN_SECTION_HTML)
         .replace("{{GEO_JS_HOOKS}}", geo::GEO_JS_HOOKS)
         .replace("{{GEO_REFRESH_VARS}}", geo::GEO_REFRESH_VARS)
         .replace("{{GEO_REFRESH_CALLS}}", geo::GEO_REFRESH_CALLS)
@@ -1779,7 +7267,7 @@ const INDEX_HTML: &str = r#"<!doctype html>
       <div id="recent-section">
         <table>
           <thead>
-            <tr><th>ID</th><th>Rule</th><th>Port</th><th>Client IP</th><th>Started</th><th>Ended</th><th>Up</th><th>Down</th></tr>
+            <tr><th>ID</th><th>Rule</th><th>Port</th><th>Proto</th><th>Client IP</th><th>Country</th><th>Target</th><th>Started</th><th>Ended</th><th>Duration</th><th>Up</th><th>Down</th></tr>
           </thead>
           <tbody id="recent-body"></tbody>
         </table>
@@ -1794,7 +7282,7 @@ const INDEX_HTML: &str = r#"<!doctype html>
       <div id="blocked-section">
         <table>
           <thead>
-            <tr><th>ID</th><th>Rule</th><th>Port</th><th>Client IP</th><th>Started</th><th>Ended</th><th>Reason</th></tr>
+            <tr><th>ID</th><th>Rule</th><th>Port</th><th>Proto</th><th>Client IP</th><th>Country</th><th>Started</th><th>Ended</th><th>Reason</th></tr>
           </thead>
           <tbody id="blocked-body"></tbody>
         </table>
@@ -1824,7 +7312,7 @@ const INDEX_HTML: &str = r#"<!doctype html>
       <div id="active-section">
         <table>
           <thead>
-            <tr><th>Conn ID</th><th>Rule</th><th>Port</th><th>Client IP</th><th>Started</th><th>Speed</th></tr>
+            <tr><th>Conn ID</th><th>Rule</th><th>Port</th><th>Proto</th><th>Client IP</th><th>Country</th><th>Started</th><th>Speed</th></tr>
           </thead>
           <tbody id="active-body"></tbody>
         </table>
@@ -1840,12 +7328,14 @@ const INDEX_HTML: &str = r#"<!doctype html>
         <div class="row">
           <input id="block-ip" placeholder="IP to block">
           <input id="block-port" placeholder="Port (optional)" size="12">
+          <input id="block-ttl" placeholder="TTL seconds (optional)" size="18">
+          <input id="block-note" placeholder="Note (optional)" size="24">
           <button onclick="addBlock()">Block</button>
           <span id="block-error" class="muted"></span>
         </div>
         <table>
           <thead>
-            <tr><th>IP</th><th>Port</th><th>Action</th></tr>
+            <tr><th>IP</th><th>Port</th><th>Expires in</th><th>Note</th><th>Action</th></tr>
           </thead>
           <tbody id="block-body"></tbody>
         </table>
@@ -1854,6 +7344,8 @@ const INDEX_HTML: &str = r#"<!doctype html>
 
 {{GEO_BLOCK_SECTION}}
 
+{{ASN_BLOCK_SECTION}}
+
     <div class="section">
       <div class="section-header">
         <h3>Allowlist</h3>
@@ -1870,18 +7362,44 @@ const INDEX_HTML: &str = r#"<!doctype html>
         <div class="row">
           <input id="allow-ip" placeholder="IP to allow">
           <input id="allow-port" placeholder="Port (optional)" size="12">
+          <input id="allow-note" placeholder="Note (optional)" size="24">
           <button onclick="addAllow()">Allow</button>
           <span id="allow-error" class="muted"></span>
         </div>
         <div class="muted">If a port has allowlist entries, only those IPs can access that port.</div>
         <table>
           <thead>
-            <tr><th>IP</th><th>Port</th><th>Action</th></tr>
+            <tr><th>IP</th><th>Port</th><th>Note</th><th>Action</th></tr>
           </thead>
           <tbody id="allow-body"></tbody>
         </table>
       </div>
     </div>
+
+    <div class="section">
+      <div class="section-header">
+        <h3>Rate limits</h3>
+        <button class="toggle" data-section="rate-limit-section" onclick="toggleSection('rate-limit-section', this)">Hide</button>
+      </div>
+      <div id="rate-limit-section">
+        <div class="muted" id="rate-limit-global"></div>
+        <div class="row">
+          <input id="port-limit-port" placeholder="Port" size="8">
+          <input id="port-limit-rate" placeholder="New/min per IP" size="14">
+          <input id="port-limit-ip" placeholder="Concurrent per IP" size="16">
+          <input id="port-limit-total" placeholder="Concurrent total" size="16">
+          <button onclick="setPortLimit()">Set</button>
+          <span id="port-limit-error" class="muted"></span>
+        </div>
+        <div class="muted">Per-port limits apply on top of the global ones; the lower value wins. Blank leaves a field as it is, 0 clears it.</div>
+        <table>
+          <thead>
+            <tr><th>Port</th><th>New/min per IP</th><th>Concurrent per IP</th><th>Concurrent total</th><th>Action</th></tr>
+          </thead>
+          <tbody id="port-limit-body"></tbody>
+        </table>
+      </div>
+    </div>
   </div>
 
   <div class="tab-content" id="tab-rules">
@@ -1911,7 +7429,7 @@ const INDEX_HTML: &str = r#"<!doctype html>
       </div>
       <div id="json-editor" style="display:none;">
         <textarea id="rule-json"></textarea>
-      <div class="muted">JSON fields: listen_addr, target_addr, enabled{{PROTOCOL_JSON_FIELDS}}</div>
+      <div class="muted">JSON fields: listen_addr, target_addr, enabled{{PROTOCOL_JSON_FIELDS}}, max_bytes_per_sec, log_five_tuple, log_connections, log_blocked, max_total_connections, max_total_bytes, rdns_allow_suffixes, target_addrs, balance, target_weights, max_connections_per_target, health_check_interval_secs, health_check_timeout_ms, dscp, connect_timeout_ms, tcp_idle_timeout_secs, max_connection_duration_secs, tcp_nodelay, tcp_keepalive_secs, tcp_keepalive_interval_secs, udp_idle_timeout_secs, send_proxy_protocol, accept_proxy_protocol, http_connect, connect_allowed_targets, sni_allow, sni_deny, sni_fail_open, transparent_egress, source_addr, max_concurrent, max_new_per_minute, mirror_addr, priority, name, tags, order</div>
       </div>
       <div id="rule-error" class="muted"></div>
     </div>
@@ -1922,9 +7440,14 @@ const INDEX_HTML: &str = r#"<!doctype html>
         <button class="toggle" data-section="rules-section" onclick="toggleSection('rules-section', this)">Hide</button>
       </div>
       <div id="rules-section">
+        <div class="row">
+          <button onclick="setAllRules(true)">Enable all</button>
+          <button onclick="setAllRules(false)">Disable all</button>
+          <span id="bulk-rule-result" class="muted"></span>
+        </div>
         <table>
           <thead>
-            <tr><th>ID</th><th>Listen</th><th>Target</th>{{PROTOCOL_RULE_HEADER}}<th>Enabled</th><th>Actions</th></tr>
+            <tr><th>ID</th><th>Name</th><th>Listen</th><th>Target</th>{{PROTOCOL_RULE_HEADER}}<th>Enabled</th><th>Actions</th></tr>
           </thead>
           <tbody id="rules-body"></tbody>
         </table>
@@ -1936,6 +7459,11 @@ const INDEX_HTML: &str = r#"<!doctype html>
 let currentRuleId = null;
 let jsonMode = false;
 let cachedRules = [];
+let cachedActive = new Map();
+let cachedRecent = [];
+let cachedBlocked = [];
+let liveSocket = null;
+let refreshTick = 0;
 
 const templates = [
   { name: "HTTPS 443 -> 10.250.2.7:443 (TCP)", listen_addr: "0.0.0.0:443", target_addr: "10.250.2.7:443", enabled: true, protocol: "tcp" },
@@ -2175,7 +7703,9 @@ async function refresh() {
       ddos,
       blocks{{GEO_REFRESH_VARS}},
       allows,
-      allowMode
+      allowMode,
+      rateLimit,
+      portLimits
     ] = await Promise.all([
       api("/api/rules"),
       api("/api/active"),
@@ -2184,9 +7714,14 @@ async function refresh() {
       api("/api/ddos"),
       api("/api/blocklist"){{GEO_REFRESH_CALLS}},
       api("/api/allowlist"),
-      api("/api/allowlist-mode")
+      api("/api/allowlist-mode"),
+      api("/api/rate-limit"),
+      api("/api/rate-limit/ports")
     ]);
     cachedRules = rules;
+    cachedActive = new Map(active.map(conn => [conn.conn_id, conn]));
+    cachedRecent = recent;
+    cachedBlocked = blocked;
     renderRules(rules);
     renderActive(active);
     renderRecent(recent);
@@ -2196,11 +7731,18 @@ async function refresh() {
 {{GEO_REFRESH_RENDER}}
     renderAllowlist(allows);
     setAllowlistMode(allowMode.enabled);
+    renderRateLimits(rateLimit, portLimits);
   } catch (err) {
     console.warn(err);
   }
 }
 
+function escapeHtml(value) {
+  return String(value).replace(/[&<>"']/g, ch => ({
+    "&": "&amp;", "<": "&lt;", ">": "&gt;", '"': "&quot;", "'": "&#39;"
+  })[ch]);
+}
+
 function renderRules(items) {
   const body = document.getElementById("rules-body");
   body.innerHTML = "";
@@ -2211,14 +7753,16 @@ function renderRules(items) {
     const row = document.createElement("tr");
     row.innerHTML = `
       <td>${rule.id}</td>
+      <td>${escapeHtml(rule.name || "")}${(rule.tags || []).map(tag => ` <span class="muted">#${escapeHtml(tag)}</span>`).join("")}</td>
       <td>${rule.listen_addr}</td>
-      <td>${rule.target_addr}</td>
+      <td>${[rule.target_addr, ...(rule.target_addrs || [])].join("<br>")}</td>
       ${extraColumns}
-      <td>${rule.enabled}</td>
+      <td>${rule.enabled}${rule.disabled_reason ? ` (${rule.disabled_reason})` : ""}</td>
       <td>
+        ${rule.from_rules_file ? `<span class="muted">rules file</span>` : `
         <button onclick="toggleRule(${rule.id}, ${rule.enabled})">${rule.enabled ? "Disable" : "Enable"}</button>
         <button onclick="editRuleById(${rule.id})">Edit</button>
-        <button onclick="deleteRule(${rule.id})">Delete</button>
+        <button onclick="deleteRule(${rule.id})">Delete</button>`}
       </td>
     `;
     body.appendChild(row);
@@ -2236,7 +7780,9 @@ function renderActive(items) {
       <td>${conn.conn_id}</td>
       <td>${conn.rule_id}</td>
       <td>${conn.listen_port || ""}</td>
-      <td>${conn.client_ip}</td>
+      <td>${conn.protocol || ""}</td>
+      <td>${conn.client_ip}${hostnameSuffix(conn.hostname)}</td>
+      <td>${conn.country || ""}</td>
       <td>${conn.started_at}</td>
       <td>${speed}</td>
     `;
@@ -2244,6 +7790,19 @@ function renderActive(items) {
   });
 }
 
+function hostnameSuffix(hostname) {
+  return hostname ? ` <span class="muted">${escapeHtml(hostname)}</span>` : "";
+}
+
+function formatDuration(ms) {
+  if (ms == null) return "";
+  if (ms < 1000) return `${ms}ms`;
+  const secs = Math.floor(ms / 1000);
+  if (secs < 60) return `${(ms / 1000).toFixed(1)}s`;
+  if (secs < 3600) return `${Math.floor(secs / 60)}m ${secs % 60}s`;
+  return `${Math.floor(secs / 3600)}h ${Math.floor(secs % 3600 / 60)}m`;
+}
+
 function calculateSpeed(bytesTransferred, lastUpdate, startedAt) {
   if (bytesTransferred === 0) return "0 B/s";
   
@@ -2277,9 +7836,13 @@ function renderRecent(items) {
       <td>${entry.id}</td>
       <td>${entry.rule_id}</td>
       <td>${entry.listen_port || ""}</td>
-      <td>${entry.client_ip}</td>
+      <td>${entry.protocol || ""}</td>
+      <td>${entry.client_ip}${hostnameSuffix(entry.hostname)}</td>
+      <td>${entry.country || ""}</td>
+      <td>${entry.target_addr || ""}</td>
       <td>${entry.started_at}</td>
       <td>${entry.ended_at || ""}</td>
+      <td>${formatDuration(entry.duration_ms)}</td>
       <td>${entry.bytes_up}</td>
       <td>${entry.bytes_down}</td>
     `;
@@ -2296,7 +7859,9 @@ function renderBlocked(items) {
       <td>${entry.id}</td>
       <td>${entry.rule_id}</td>
       <td>${entry.listen_port || ""}</td>
+      <td>${entry.protocol || ""}</td>
       <td>${entry.client_ip}</td>
+      <td>${entry.country || ""}</td>
       <td>${entry.started_at}</td>
       <td>${entry.ended_at || ""}</td>
       <td>${entry.reason || ""}</td>
@@ -2331,6 +7896,8 @@ function renderBlocks(items) {
     row.innerHTML = `
       <td>${item.ip}</td>
       <td>${label}</td>
+      <td>${item.remaining_secs == null ? "never" : `${item.remaining_secs}s`}</td>
+      <td>${escapeHtml(item.note || "")}</td>
       <td><button onclick="removeBlock('${item.ip}', '${port}')">Remove</button></td>
     `;
     body.appendChild(row);
@@ -2347,12 +7914,33 @@ function renderAllowlist(items) {
     row.innerHTML = `
       <td>${item.ip}</td>
       <td>${label}</td>
+      <td>${escapeHtml(item.note || "")}</td>
       <td><button onclick="removeAllow('${item.ip}', '${port}')">Remove</button></td>
     `;
     body.appendChild(row);
   });
 }
 
+function renderRateLimits(global, items) {
+  document.getElementById("rate-limit-global").textContent =
+    `Global: ${global.max_new_connections_per_minute} new/min per IP, ` +
+    `${global.max_concurrent_connections_per_ip} concurrent per IP, ` +
+    `${global.max_concurrent_total} concurrent total`;
+  const body = document.getElementById("port-limit-body");
+  body.innerHTML = "";
+  items.forEach(item => {
+    const row = document.createElement("tr");
+    row.innerHTML = `
+      <td>${item.port}</td>
+      <td>${item.max_new_connections_per_minute ?? ""}</td>
+      <td>${item.max_concurrent_connections_per_ip ?? ""}</td>
+      <td>${item.max_concurrent_total ?? ""}</td>
+      <td><button onclick="removePortLimit(${item.port})">Remove</button></td>
+    `;
+    body.appendChild(row);
+  });
+}
+
 function setAllowlistMode(enabled) {
   const checkbox = document.getElementById("allowlist-enabled");
   checkbox.checked = !!enabled;
@@ -2364,6 +7952,19 @@ async function toggleRule(id, enabled) {
   await refresh();
 }
 
+async function setAllRules(enabled) {
+  const box = document.getElementById("bulk-rule-result");
+  try {
+    const result = await api(enabled ? "/api/rules/enable-all" : "/api/rules/disable-all", { method: "POST" });
+    const failures = result.failed.map(item => `rule ${item.id}: ${item.error}`);
+    box.textContent = `${result.succeeded} ${enabled ? "enabled" : "disabled"}` +
+      (failures.length ? `, ${failures.length} failed (${failures.join("; ")})` : "");
+  } catch (err) {
+    box.textContent = err.message;
+  }
+  await refresh();
+}
+
 function editRuleById(id) {
   const rule = cachedRules.find(item => item.id === id);
   if (!rule) return;
@@ -2387,6 +7988,7 @@ async function deleteRule(id) {
 async function addBlock() {
   const ip = document.getElementById("block-ip").value.trim();
   const portText = document.getElementById("block-port").value.trim();
+  const ttlText = document.getElementById("block-ttl").value.trim();
   const errorBox = document.getElementById("block-error");
   errorBox.textContent = "";
   let port = null;
@@ -2397,14 +7999,24 @@ async function addBlock() {
       return;
     }
   }
+  let ttl_seconds = null;
+  if (ttlText) {
+    ttl_seconds = parseInt(ttlText, 10);
+    if (Number.isNaN(ttl_seconds) || ttl_seconds < 0) {
+      errorBox.textContent = "Invalid TTL";
+      return;
+    }
+  }
   try {
     await api("/api/blocklist", {
       method: "POST",
       headers: { "Content-Type": "application/json" },
-      body: JSON.stringify({ ip, port })
+      body: JSON.stringify({ ip, port, ttl_seconds, note: document.getElementById("block-note").value })
     });
     document.getElementById("block-ip").value = "";
+    document.getElementById("block-note").value = "";
     document.getElementById("block-port").value = "";
+    document.getElementById("block-ttl").value = "";
     await refresh();
   } catch (err) {
     errorBox.textContent = err.message;
@@ -2434,9 +8046,10 @@ async function addAllow() {
     await api("/api/allowlist", {
       method: "POST",
       headers: { "Content-Type": "application/json" },
-      body: JSON.stringify({ ip, port })
+      body: JSON.stringify({ ip, port, note: document.getElementById("allow-note").value })
     });
     document.getElementById("allow-ip").value = "";
+    document.getElementById("allow-note").value = "";
     document.getElementById("allow-port").value = "";
     await refresh();
   } catch (err) {
@@ -2450,6 +8063,50 @@ async function removeAllow(ip, port) {
   await refresh();
 }
 
+async function setPortLimit() {
+  const errorBox = document.getElementById("port-limit-error");
+  errorBox.textContent = "";
+  const port = parseInt(document.getElementById("port-limit-port").value.trim(), 10);
+  if (Number.isNaN(port) || port < 1 || port > 65535) {
+    errorBox.textContent = "Invalid port";
+    return;
+  }
+  const fields = {
+    max_new_connections_per_minute: "port-limit-rate",
+    max_concurrent_connections_per_ip: "port-limit-ip",
+    max_concurrent_total: "port-limit-total"
+  };
+  const payload = {};
+  for (const [field, id] of Object.entries(fields)) {
+    const text = document.getElementById(id).value.trim();
+    if (!text) continue;
+    const value = parseInt(text, 10);
+    if (Number.isNaN(value) || value < 0) {
+      errorBox.textContent = `Invalid ${field}`;
+      return;
+    }
+    payload[field] = value;
+  }
+  try {
+    await api(`/api/rate-limit/port/${port}`, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify(payload)
+    });
+    ["port-limit-port", ...Object.values(fields)].forEach(id => {
+      document.getElementById(id).value = "";
+    });
+    await refresh();
+  } catch (err) {
+    errorBox.textContent = err.message;
+  }
+}
+
+async function removePortLimit(port) {
+  await api(`/api/rate-limit/port/${port}`, { method: "DELETE" });
+  await refresh();
+}
+
 async function toggleAllowlistMode() {
   const enabled = document.getElementById("allowlist-enabled").checked;
   await api("/api/allowlist-mode", {
@@ -2463,9 +8120,688 @@ async function toggleAllowlistMode() {
 loadTemplates();
 resetEditor();
 applySectionState();
+function renderLive() {
+  renderActive([...cachedActive.values()].sort((a, b) => a.conn_id - b.conn_id));
+  renderRecent(cachedRecent);
+  renderBlocked(cachedBlocked);
+}
+
+function handleLiveEvent(message) {
+  switch (message.event) {
+    case "open":
+    case "bytes":
+    case "hostname":
+      cachedActive.set(message.conn_id, message);
+      break;
+    case "close":
+      cachedActive.delete(message.id);
+      cachedRecent = [message, ...cachedRecent].slice(0, 100);
+      break;
+    case "blocked":
+      cachedBlocked = [message, ...cachedBlocked].slice(0, 100);
+      break;
+    case "resync":
+    case "rule_added":
+    case "rule_updated":
+    case "rule_removed":
+    case "block_added":
+    case "block_removed":
+    case "history_cleared":
+      refresh();
+      return;
+    default:
+      return;
+  }
+  renderLive();
+}
+
+function connectLive() {
+  const scheme = location.protocol === "https:" ? "wss" : "ws";
+  const socket = new WebSocket(`${scheme}://${location.host}/api/ws`);
+  socket.onopen = () => {
+    liveSocket = socket;
+    refresh();
+  };
+  socket.onmessage = event => {
+    try {
+      handleLiveEvent(JSON.parse(event.data));
+    } catch (err) {
+      console.warn(err);
+    }
+  };
+  socket.onclose = () => {
+    liveSocket = null;
+    setTimeout(connectLive, 5000);
+  };
+}
+
 refresh();
-setInterval(refresh, 3000);
+connectLive();
+// Poll every 3s without a live socket; with one, only rules and lists need
+// an occasional refresh.
+setInterval(() => {
+  refreshTick += 1;
+  if (!liveSocket || refreshTick % 10 === 0) {
+    refresh();
+  }
+}, 3000);
 </script>
 </body>
 </html>
 "#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+
+    static NEXT_DATA_DIR: AtomicU32 = AtomicU32::new(0);
+
+    // A state loaded from an empty data directory of its own.
+    async fn test_state() -> (Arc<RwLock<AppState>>, PathBuf) {
+        let data_dir = std::env::temp_dir().join(format!(
+            "proxypanel-test-{}-{}",
+            std::process::id(),
+            NEXT_DATA_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut config = AppConfig::new("127.0.0.1:0", data_dir.to_str().unwrap(), Vec::new()).unwrap();
+        config.geo_update_enabled = false;
+        let state = load_state(&config).await.unwrap();
+        (Arc::new(RwLock::new(state)), data_dir)
+    }
+
+    // Builds a rule the way POST /api/rules does, from the request fields,
+    // and adds it to the state.
+    async fn add_rule(state: &Arc<RwLock<AppState>>, fields: serde_json::Value) -> ProxyRule {
+        let payload = serde_json::from_value::<CreateRuleRequest>(fields).unwrap();
+        let mut rule = build_rule(payload).unwrap_or_else(|(_, Json(err))| panic!("{}", err.error));
+        let mut guard = state.write().await;
+        rule.id = guard.next_rule_id;
+        guard.next_rule_id += 1;
+        guard.rules.push(rule.clone());
+        rule
+    }
+
+    async fn wait_until(state: &Arc<RwLock<AppState>>, timeout: Duration, done: impl Fn(&AppState) -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if done(&*state.read().await) {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    fn free_tcp_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    // First of `count` consecutive UDP ports that were all free just now.
+    fn free_udp_ports(count: u16) -> u16 {
+        loop {
+            let first = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            let base = first.local_addr().unwrap().port();
+            if base.checked_add(count).is_none() {
+                continue;
+            }
+            let rest = (1..count)
+                .map(|offset| std::net::UdpSocket::bind(("127.0.0.1", base + offset)))
+                .collect::<std::io::Result<Vec<_>>>();
+            if rest.is_ok() {
+                return base;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn enabling_a_rule_checks_for_listen_conflicts() {
+        let (state, data_dir) = test_state().await;
+        let taken = format!("127.0.0.1:{}", free_tcp_port());
+        let shared = format!("127.0.0.1:{}", free_tcp_port());
+        let rule = |listen_addr: &str, enabled: bool| {
+            serde_json::json!({
+                "listen_addr": listen_addr,
+                "target_addr": "127.0.0.1:9",
+                "enabled": enabled,
+            })
+        };
+        let holder = add_rule(&state, rule(&taken, true)).await;
+        let blocked = add_rule(&state, rule(&taken, false)).await;
+        let first = add_rule(&state, rule(&shared, false)).await;
+        let second = add_rule(&state, rule(&shared, false)).await;
+
+        let Err((status, Json(err))) = enable_rule(Path(blocked.id), State(state.clone())).await else {
+            panic!("enabled a rule on an address in use");
+        };
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(err.code, "listen_conflict");
+
+        // Of two disabled rules on one address, only the first is enabled.
+        let Json(result) = enable_all_rules(State(state.clone())).await;
+        assert_eq!(result.succeeded, 1);
+        let failed = result.failed.iter().map(|failure| failure.id).collect::<Vec<_>>();
+        assert_eq!(failed, vec![blocked.id, second.id]);
+        {
+            let guard = state.read().await;
+            let enabled = |id: u64| guard.rules.iter().find(|rule| rule.id == id).unwrap().enabled;
+            assert!(enabled(holder.id) && enabled(first.id));
+            assert!(!enabled(blocked.id) && !enabled(second.id));
+        }
+
+        stop_rule_listeners(&state, first.id).await;
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[tokio::test]
+    async fn displaced_api_rule_cannot_be_enabled_over_a_rules_file_rule() {
+        let (state, data_dir) = test_state().await;
+        let listen_addr = format!("127.0.0.1:{}", free_tcp_port());
+        let fields = serde_json::json!({
+            "listen_addr": listen_addr,
+            "target_addr": "127.0.0.1:9",
+        });
+        let api_rule = add_rule(&state, fields.clone()).await;
+        let plan = {
+            let entry = serde_json::from_value::<CreateRuleRequest>(fields).unwrap();
+            reconcile_rules_file(&mut *state.write().await, vec![entry]).unwrap()
+        };
+        assert_eq!(plan.displaced, vec![api_rule.id]);
+        let file_rule_id = plan.start[0].id;
+
+        let Err((status, Json(err))) = enable_rule(Path(api_rule.id), State(state.clone())).await else {
+            panic!("re-enabled a rule displaced by the rules file");
+        };
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(err.error, format!("{} is already in use by rule {}", listen_addr, file_rule_id));
+        let Json(result) = enable_all_rules(State(state.clone())).await;
+        assert_eq!(result.succeeded, 0);
+        assert_eq!(result.failed.len(), 1);
+        assert!(!state.read().await.rules.iter().any(|rule| rule.id == api_rule.id && rule.enabled));
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[tokio::test]
+    async fn reload_lets_rules_swap_listen_addresses() {
+        let (state, data_dir) = test_state().await;
+        let first_addr = format!("127.0.0.1:{}", free_tcp_port());
+        let second_addr = format!("127.0.0.1:{}", free_tcp_port());
+        let rule = |listen_addr: &str| serde_json::json!({ "listen_addr": listen_addr, "target_addr": "127.0.0.1:9" });
+        let first = add_rule(&state, rule(&first_addr)).await;
+        let second = add_rule(&state, rule(&second_addr)).await;
+
+        let swapped = vec![
+            ProxyRule {
+                listen_addr: second_addr.clone(),
+                ..first.clone()
+            },
+            ProxyRule {
+                listen_addr: first_addr.clone(),
+                ..second.clone()
+            },
+        ];
+        let plan = reconcile_state_rules(&mut *state.write().await, swapped, true);
+        assert!(plan.displaced.is_empty(), "displaced {:?}", plan.displaced);
+        assert_eq!(plan.stop, vec![first.id, second.id]);
+        assert_eq!(plan.start.iter().map(|rule| rule.id).collect::<Vec<_>>(), vec![first.id, second.id]);
+
+        // Two edited rules moving to one address: the first gets it.
+        let third_addr = format!("127.0.0.1:{}", free_tcp_port());
+        let clash = vec![
+            ProxyRule {
+                listen_addr: third_addr.clone(),
+                ..first.clone()
+            },
+            ProxyRule {
+                listen_addr: third_addr,
+                ..second.clone()
+            },
+        ];
+        let plan = reconcile_state_rules(&mut *state.write().await, clash, true);
+        assert_eq!(plan.displaced, vec![second.id]);
+        assert_eq!(plan.start.iter().map(|rule| rule.id).collect::<Vec<_>>(), vec![first.id]);
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[tokio::test]
+    async fn hand_edited_state_file_is_kept_until_reloaded() {
+        let (state, data_dir) = test_state().await;
+        let rule = add_rule(
+            &state,
+            serde_json::json!({
+                "listen_addr": format!("127.0.0.1:{}", free_tcp_port()),
+                "target_addr": "127.0.0.1:9",
+                "enabled": false,
+            }),
+        )
+        .await;
+        save_state(&state).await.unwrap();
+
+        let path = data_dir.join(STATE_FILE);
+        let mut edited = serde_json::from_slice::<serde_json::Value>(&std::fs::read(&path).unwrap()).unwrap();
+        edited["rules"][0]["target_addr"] = serde_json::json!("127.0.0.1:10");
+        let edited = serde_json::to_vec_pretty(&edited).unwrap();
+        std::fs::write(&path, &edited).unwrap();
+
+        // A change made through the API before the reload doesn't clobber
+        // the edit.
+        state.write().await.next_rule_id += 1;
+        save_state(&state).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), edited);
+        assert!(state.read().await.saves.dirty.load(Ordering::Relaxed));
+
+        reload_state(&state).await.unwrap();
+        save_state(&state).await.unwrap();
+        let saved = serde_json::from_slice::<serde_json::Value>(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(saved["rules"][0]["id"], serde_json::json!(rule.id));
+        assert_eq!(saved["rules"][0]["target_addr"], serde_json::json!("127.0.0.1:10"));
+        assert!(!state.read().await.saves.dirty.load(Ordering::Relaxed));
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[tokio::test]
+    async fn replace_import_keeps_the_imported_usage() {
+        let (state, data_dir) = test_state().await;
+        let rule = add_rule(
+            &state,
+            serde_json::json!({
+                "listen_addr": format!("127.0.0.1:{}", free_tcp_port()),
+                "target_addr": "127.0.0.1:9",
+                "enabled": false,
+            }),
+        )
+        .await;
+        state.write().await.rules[0].usage = RuleUsage { connections: 5, bytes: 500 };
+        let mut export = serde_json::to_value(snapshot_state(&*state.read().await)).unwrap();
+        export["rules"][0]["usage"] = serde_json::json!({ "connections": 42, "bytes": 4200 });
+
+        let query = ImportQuery { mode: Some(ImportMode::Replace) };
+        let Json(result) = import_state(State(state.clone()), Query(query), Json(export))
+            .await
+            .unwrap_or_else(|(_, Json(err))| panic!("{}", err.error));
+        assert_eq!(result.updated, 1);
+        let guard = state.read().await;
+        let usage = &guard.rules.iter().find(|current| current.id == rule.id).unwrap().usage;
+        assert_eq!((usage.connections, usage.bytes), (42, 4200));
+        drop(guard);
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_connections_of_a_rule_stopped_without_drain_timeout() {
+        let (state, data_dir) = test_state().await;
+        {
+            let mut guard = state.write().await;
+            let config = Arc::make_mut(&mut guard.config);
+            config.drain_timeout = None;
+            config.shutdown_timeout = Duration::from_millis(200);
+        }
+        let target = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = format!("127.0.0.1:{}", free_tcp_port());
+        let rule = add_rule(
+            &state,
+            serde_json::json!({
+                "listen_addr": listen_addr,
+                "target_addr": target.local_addr().unwrap().to_string(),
+            }),
+        )
+        .await;
+        start_rule_listeners(&state, &rule).await.unwrap();
+
+        let _client = tokio::net::TcpStream::connect(&listen_addr).await.unwrap();
+        let (_upstream, _) = target.accept().await.unwrap();
+        assert!(wait_until(&state, Duration::from_secs(2), |state| state.active.len() == 1).await);
+
+        // Disabling the rule leaves the connection running.
+        stop_rule_listeners(&state, rule.id).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(state.read().await.active.len(), 1);
+
+        let shutdown = CancellationToken::new();
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(5), drain_on_shutdown(state.clone(), shutdown))
+            .await
+            .unwrap();
+        let guard = state.read().await;
+        assert!(guard.active.is_empty());
+        assert_eq!(guard.history.len(), 1);
+        drop(guard);
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[tokio::test]
+    async fn refused_sni_is_blocked_without_charging_the_budget() {
+        let (state, data_dir) = test_state().await;
+        let target = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = format!("127.0.0.1:{}", free_tcp_port());
+        let rule = add_rule(
+            &state,
+            serde_json::json!({
+                "listen_addr": listen_addr,
+                "target_addr": target.local_addr().unwrap().to_string(),
+                "sni_deny": ["blocked.test"],
+                "max_total_connections": 1,
+            }),
+        )
+        .await;
+        start_rule_listeners(&state, &rule).await.unwrap();
+
+        let mut client = tokio::net::TcpStream::connect(&listen_addr).await.unwrap();
+        client.write_all(&crate::sni::tests::client_hello("blocked.test")).await.unwrap();
+        assert!(wait_until(&state, Duration::from_secs(2), |state| !state.blocked_history.is_empty()).await);
+
+        let guard = state.read().await;
+        let entry = &guard.blocked_history[0];
+        assert_eq!(entry.block_reason, Some(BlockReason::SniBlocked));
+        assert!(guard.active.is_empty());
+        assert!(guard.history.is_empty());
+        let current = guard.rules.iter().find(|current| current.id == rule.id).unwrap();
+        assert_eq!(current.usage.connections, 0);
+        assert!(current.enabled);
+        assert_eq!(guard.rule_stats.get(&rule.id).map_or(0, |stats| stats.connections), 0);
+        drop(guard);
+
+        stop_rule_listeners(&state, rule.id).await;
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[tokio::test]
+    async fn enable_all_disables_a_rule_whose_listener_fails() {
+        let (state, data_dir) = test_state().await;
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let good = add_rule(
+            &state,
+            serde_json::json!({
+                "listen_addr": format!("127.0.0.1:{}", free_tcp_port()),
+                "target_addr": "127.0.0.1:9",
+                "enabled": false,
+            }),
+        )
+        .await;
+        let bad = add_rule(
+            &state,
+            serde_json::json!({
+                "listen_addr": taken.local_addr().unwrap().to_string(),
+                "target_addr": "127.0.0.1:9",
+                "enabled": false,
+            }),
+        )
+        .await;
+        let mut live = state.read().await.live.subscribe();
+
+        let Json(result) = enable_all_rules(State(state.clone())).await;
+        assert_eq!(result.succeeded, 1);
+        assert_eq!(result.failed.iter().map(|failure| failure.id).collect::<Vec<_>>(), vec![bad.id]);
+
+        let guard = state.read().await;
+        let rule = guard.rules.iter().find(|rule| rule.id == bad.id).unwrap();
+        assert!(!rule.enabled);
+        assert!(rule.disabled_reason.as_deref().is_some_and(|reason| reason.starts_with("Listener failed")));
+        assert!(guard.rules.iter().any(|rule| rule.id == good.id && rule.enabled));
+        drop(guard);
+        // The failed rule is published as disabled, like any other failed start.
+        let updates = std::iter::from_fn(|| live.try_recv().ok())
+            .map(|text| serde_json::from_str::<serde_json::Value>(&text).unwrap())
+            .filter(|event| event["event"] == "rule_updated" && event["id"] == bad.id)
+            .collect::<Vec<_>>();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0]["enabled"], false);
+
+        stop_rule_listeners(&state, good.id).await;
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[tokio::test]
+    async fn unresolvable_target_fails_the_start_except_at_boot() {
+        let (state, data_dir) = test_state().await;
+        let listen_addr = format!("127.0.0.1:{}", free_tcp_port());
+        let rule = add_rule(
+            &state,
+            serde_json::json!({
+                "listen_addr": listen_addr,
+                "target_addr": "does-not-exist.invalid:80",
+                "enabled": false,
+            }),
+        )
+        .await;
+        let err = start_rule_listeners(&state, &rule).await.unwrap_err().to_string();
+        assert!(err.contains("does-not-exist.invalid"), "{}", err);
+        assert!(std::net::TcpStream::connect(&listen_addr).is_err());
+
+        let Err((status, Json(err))) = enable_rule(Path(rule.id), State(state.clone())).await else {
+            panic!("enabled a rule whose target does not resolve");
+        };
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(err.code, "listener_failed");
+        assert!(err.error.contains("does-not-exist.invalid"), "{}", err.error);
+        assert!(!state.read().await.rules.iter().any(|item| item.id == rule.id && item.enabled));
+
+        // A rule restored at boot still starts, and retries the lookup on
+        // connect.
+        start_rule_listeners_with(&state, &rule, UnresolvedTarget::Warn).await.unwrap();
+        assert!(std::net::TcpStream::connect(&listen_addr).is_ok());
+
+        stop_rule_listeners(&state, rule.id).await;
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[tokio::test]
+    async fn port_concurrency_limits_use_the_port_counters() {
+        let (state, data_dir) = test_state().await;
+        state.write().await.port_rate_limits.insert(
+            8000,
+            PortRateLimit {
+                port: 8000,
+                max_new_connections_per_minute: None,
+                max_concurrent_connections_per_ip: Some(1),
+                max_concurrent_total: Some(2),
+            },
+        );
+        let open = |client_ip: &'static str, port: u16| {
+            let state = state.clone();
+            async move { register_connection(&state, 1, client_ip, Some(port), ProtocolMode::Tcp, None).await }
+        };
+
+        let first = open("192.0.2.1", 8000).await.unwrap_or_else(|refused| panic!("{}", refused.message));
+        let refused = open("192.0.2.1", 8000).await.unwrap_err();
+        assert_eq!(refused.message, "Too many active connections for IP on port 8000");
+        // Other ports have their own counts.
+        assert!(open("192.0.2.1", 8001).await.is_ok());
+        assert!(open("192.0.2.2", 8000).await.is_ok());
+        let refused = open("192.0.2.3", 8000).await.unwrap_err();
+        assert_eq!(refused.message, "Too many total connections on port 8000");
+
+        record_connection_end(&state, first, 0, 0, None, None).await;
+        assert!(open("192.0.2.3", 8000).await.is_ok());
+        {
+            let guard = state.read().await;
+            assert_eq!(guard.active_by_port.get(&8000), Some(&2));
+            assert_eq!(guard.active_by_port_ip.get(&(8000, "192.0.2.1".to_string())), None);
+            assert!(ExpectedCounters::from_active(&guard).report(&guard).consistent);
+        }
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[tokio::test]
+    async fn unlogged_connection_still_publishes_its_close() {
+        let (state, data_dir) = test_state().await;
+        let rule = add_rule(
+            &state,
+            serde_json::json!({
+                "listen_addr": "127.0.0.1:8000",
+                "target_addr": "127.0.0.1:9",
+                "enabled": false,
+                "log_connections": false,
+            }),
+        )
+        .await;
+        let mut live = state.read().await.live.subscribe();
+        let conn_id = register_connection(&state, rule.id, "192.0.2.1", Some(8000), ProtocolMode::Tcp, None)
+            .await
+            .unwrap_or_else(|refused| panic!("{}", refused.message));
+        record_connection_end(&state, conn_id, 10, 20, None, None).await;
+
+        let events = std::iter::from_fn(|| live.try_recv().ok())
+            .map(|text| serde_json::from_str::<serde_json::Value>(&text).unwrap()["event"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(events, vec![serde_json::json!("open"), serde_json::json!("close")]);
+        let guard = state.read().await;
+        assert!(guard.history.is_empty());
+        assert_eq!(guard.rule_stats.get(&rule.id).map(|stats| stats.connections), Some(1));
+        drop(guard);
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn trim_log_applies_the_retention_window() {
+        let entry = |ended_at: OffsetDateTime| {
+            serde_json::from_value::<ConnectionLog>(serde_json::json!({
+                "id": 1,
+                "rule_id": 1,
+                "client_ip": "192.0.2.1",
+                "started_at": ended_at.format(&Rfc3339).unwrap(),
+                "ended_at": ended_at.format(&Rfc3339).unwrap(),
+                "bytes_up": 0,
+                "bytes_down": 0,
+                "blocked": false,
+                "reason": null,
+            }))
+            .unwrap()
+        };
+        let now = OffsetDateTime::now_utc();
+        let mut log = vec![entry(now - time::Duration::days(3)), entry(now - time::Duration::hours(1))];
+
+        // A window too long to subtract from now keeps everything.
+        assert!(!trim_log(&mut log, 10, Some(Duration::MAX)));
+        assert!(!trim_log(&mut log, 10, Some(Duration::from_secs(365 * 24 * 60 * 60))));
+        assert_eq!(log.len(), 2);
+        assert!(trim_log(&mut log, 10, Some(Duration::from_secs(24 * 60 * 60))));
+        assert_eq!(log.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn tcp_connect_gives_up_after_connect_timeout() {
+        // With its accept queue full a listener drops further SYNs, so a
+        // connect to it hangs like one to a blackholed address.
+        let blackhole = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+        blackhole.bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into()).unwrap();
+        blackhole.listen(0).unwrap();
+        let blackhole_addr = blackhole.local_addr().unwrap().as_socket().unwrap();
+        let _queued = std::net::TcpStream::connect(blackhole_addr).unwrap();
+
+        let (state, data_dir) = test_state().await;
+        let listen_port = free_tcp_port();
+        let rule = add_rule(
+            &state,
+            serde_json::json!({
+                "listen_addr": format!("127.0.0.1:{}", listen_port),
+                "target_addr": blackhole_addr.to_string(),
+                "connect_timeout_ms": 200,
+            }),
+        )
+        .await;
+        start_rule_listeners(&state, &rule).await.unwrap();
+
+        let started = Instant::now();
+        let _client = TcpStream::connect(("127.0.0.1", listen_port)).await.unwrap();
+        let timed_out = wait_until(&state, Duration::from_secs(5), |state| {
+            state
+                .history
+                .iter()
+                .any(|entry| entry.reason.as_deref() == Some("Target connect timed out"))
+        })
+        .await;
+        assert!(timed_out);
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        stop_rule_listeners(&state, rule.id).await;
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[tokio::test]
+    async fn udp_range_binds_every_port_or_none() {
+        let (state, data_dir) = test_state().await;
+        let listen = free_udp_ports(3);
+        let target = free_udp_ports(3);
+        let fields = serde_json::json!({
+            "listen_addr": format!("127.0.0.1:{}-{}", listen, listen + 2),
+            "target_addr": format!("127.0.0.1:{}-{}", target, target + 2),
+            "protocol": "udp",
+        });
+
+        let rule = add_rule(&state, fields.clone()).await;
+        start_rule_listeners(&state, &rule).await.unwrap();
+        {
+            let guard = state.read().await;
+            let statuses = &guard.listener_status[&rule.id];
+            assert_eq!(
+                statuses.iter().map(|status| status.listen_port).collect::<Vec<_>>(),
+                vec![listen, listen + 1, listen + 2]
+            );
+            assert!(statuses.iter().all(|status| status.state == ListenerState::Listening));
+        }
+        assert!(std::net::UdpSocket::bind(("127.0.0.1", listen + 1)).is_err());
+        stop_rule_listeners(&state, rule.id).await;
+
+        // One taken port fails the rule, names that port, and releases the
+        // ports that did bind.
+        let taken = std::net::UdpSocket::bind(("127.0.0.1", listen + 1)).unwrap();
+        let rule = add_rule(&state, fields).await;
+        let err = start_rule_listeners(&state, &rule).await.unwrap_err().to_string();
+        assert!(err.starts_with("UDP bind failed on 1 of 3 ports"), "{}", err);
+        assert!(err.contains(&format!("127.0.0.1:{}", listen + 1)), "{}", err);
+        drop(taken);
+        for port in listen..=listen + 2 {
+            assert!(std::net::UdpSocket::bind(("127.0.0.1", port)).is_ok());
+        }
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[tokio::test]
+    async fn idle_udp_session_is_reaped_after_udp_idle_timeout_secs() {
+        let (state, data_dir) = test_state().await;
+        let target = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen_port = free_udp_ports(1);
+        let rule = add_rule(
+            &state,
+            serde_json::json!({
+                "listen_addr": format!("127.0.0.1:{}", listen_port),
+                "target_addr": target.local_addr().unwrap().to_string(),
+                "protocol": "udp",
+                "udp_idle_timeout_secs": 1,
+            }),
+        )
+        .await;
+        start_rule_listeners(&state, &rule).await.unwrap();
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"ping", ("127.0.0.1", listen_port)).await.unwrap();
+        let mut buf = [0u8; 16];
+        let (len, _) = target.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        let sent = Instant::now();
+        assert_eq!(state.read().await.active.len(), 1);
+
+        let reaped = wait_until(&state, Duration::from_secs(5), |state| {
+            state.active.is_empty() && state.history.iter().any(|entry| entry.protocol == Some(ProtocolMode::Udp))
+        })
+        .await;
+        assert!(reaped);
+        // Checked on a tick of min(timeout, 5s), so within two timeouts.
+        let elapsed = sent.elapsed();
+        assert!(elapsed >= Duration::from_millis(900), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+        let entry = state.read().await.history.last().cloned().unwrap();
+        assert_eq!(entry.bytes_up, 4);
+
+        stop_rule_listeners(&state, rule.id).await;
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Serialize)]
+struct AuthRequest<'a> {
+    client_ip: &'a str,
+    port: Option<u16>,
+}
+
+#[derive(Deserialize)]
+struct AuthResponse {
+    allow: bool,
+}
+
+struct CacheEntry {
+    allow: bool,
+    expires_at: Instant,
+}
+
+// Asks a central policy service whether a client may connect. The service is
+// POSTed `{"client_ip", "port"}` and must answer 2xx with `{"allow": bool}`;
+// anything else counts as a service error and is resolved by `fail_open`.
+// Verdicts are cached per (ip, port); errors are not.
+pub struct AuthService {
+    url: String,
+    cache_ttl: Duration,
+    fail_open: bool,
+    client: reqwest::Client,
+    cache: Mutex<HashMap<(String, Option<u16>), CacheEntry>>,
+}
+
+impl AuthService {
+    pub fn new(url: String, cache_ttl: Duration, timeout: Duration, fail_open: bool) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .user_agent("proxy-panel/0.1")
+            .build()?;
+        Ok(Self {
+            url,
+            cache_ttl,
+            fail_open,
+            client,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub async fn check(&self, client_ip: &str, port: Option<u16>) -> Result<(), String> {
+        let key = (client_ip.to_string(), port);
+        {
+            let guard = self.cache.lock().await;
+            if let Some(entry) = guard.get(&key) {
+                if entry.expires_at > Instant::now() {
+                    return verdict(entry.allow);
+                }
+            }
+        }
+
+        let allow = match self.query(client_ip, port).await {
+            Ok(allow) => allow,
+            Err(err) => {
+                warn!("Auth service error for {}: {}", client_ip, err);
+                return if self.fail_open {
+                    Ok(())
+                } else {
+                    Err("Auth service unavailable".to_string())
+                };
+            }
+        };
+
+        let mut guard = self.cache.lock().await;
+        if guard.len() >= MAX_ENTRIES {
+            let now = Instant::now();
+            guard.retain(|_, entry| entry.expires_at > now);
+            if guard.len() >= MAX_ENTRIES {
+                guard.clear();
+            }
+        }
+        guard.insert(
+            key,
+            CacheEntry {
+                allow,
+                expires_at: Instant::now() + self.cache_ttl,
+            },
+        );
+        verdict(allow)
+    }
+
+    async fn query(&self, client_ip: &str, port: Option<u16>) -> Result<bool> {
+        let response = self
+            .client
+            .post(&self.url)
+            .json(&AuthRequest { client_ip, port })
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("status {}", response.status()));
+        }
+        Ok(response.json::<AuthResponse>().await?.allow)
+    }
+}
+
+fn verdict(allow: bool) -> Result<(), String> {
+    if allow {
+        Ok(())
+    } else {
+        Err("Denied by auth service".to_string())
+    }
+}
@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceMode {
+    #[default]
+    RoundRobin,
+    WeightedRandom,
+}
+
+// Order in which to try the candidates, as indexes into `weights`. Each pick
+// is weighted over the candidates not picked yet, so the share of an excluded
+// (unhealthy or failed) target is spread over the rest in proportion to their
+// own weights. Zero-weight candidates are never picked. `roll` yields values
+// in [0, 1).
+pub fn weighted_order(weights: &[u32], mut roll: impl FnMut() -> f64) -> Vec<usize> {
+    let mut remaining = weights
+        .iter()
+        .enumerate()
+        .filter(|(_, weight)| **weight > 0)
+        .map(|(index, weight)| (index, u64::from(*weight)))
+        .collect::<Vec<_>>();
+    let mut order = Vec::with_capacity(remaining.len());
+    while !remaining.is_empty() {
+        let total = remaining.iter().map(|(_, weight)| weight).sum::<u64>();
+        let mut point = ((roll() * total as f64) as u64).min(total - 1);
+        let position = remaining
+            .iter()
+            .position(|(_, weight)| {
+                if point < *weight {
+                    true
+                } else {
+                    point -= weight;
+                    false
+                }
+            })
+            .unwrap_or(remaining.len() - 1);
+        order.push(remaining.remove(position).0);
+    }
+    order
+}
+
+// Open connections per target of one rule, for max_connections_per_target.
+// Kept across listener restarts so connections that outlive an update still
+// count.
+#[derive(Default)]
+pub struct TargetLoad {
+    active: Mutex<HashMap<String, u32>>,
+}
+
+impl TargetLoad {
+    // Takes a slot on `target` unless it already has `max` connections; the
+    // slot is given back when dropped.
+    pub fn try_acquire(self: &Arc<Self>, target: &str, max: u32) -> Option<TargetSlot> {
+        let mut guard = self.active.lock().unwrap_or_else(|err| err.into_inner());
+        let count = guard.entry(target.to_string()).or_insert(0);
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(TargetSlot {
+            load: self.clone(),
+            target: target.to_string(),
+        })
+    }
+}
+
+pub struct TargetSlot {
+    load: Arc<TargetLoad>,
+    target: String,
+}
+
+impl Drop for TargetSlot {
+    fn drop(&mut self) {
+        let mut guard = self.load.active.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(count) = guard.get_mut(&self.target) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                guard.remove(&self.target);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // How often each candidate is tried first when the first roll sweeps
+    // [0, 1) in `samples` even steps.
+    fn first_picks(weights: &[u32], samples: usize) -> Vec<usize> {
+        let mut picks = vec![0; weights.len()];
+        for step in 0..samples {
+            let mut rolls = std::iter::once(step as f64 / samples as f64).chain(std::iter::repeat(0.0));
+            let order = weighted_order(weights, || rolls.next().unwrap());
+            picks[order[0]] += 1;
+        }
+        picks
+    }
+
+    #[test]
+    fn first_pick_follows_the_weights() {
+        assert_eq!(first_picks(&[1, 2, 1], 400), vec![100, 200, 100]);
+    }
+
+    #[test]
+    fn unhealthy_target_share_goes_to_the_rest_by_weight() {
+        // As in connect_balanced: only healthy targets' weights are passed
+        // and positions map back to target indexes.
+        let weights = [1, 3, 2, 2];
+        let healthy = [0, 2, 3];
+        let healthy_weights = healthy.iter().map(|index| weights[*index]).collect::<Vec<_>>();
+        let picks = first_picks(&healthy_weights, 500);
+        let mut by_target = [0; 4];
+        for (position, count) in picks.into_iter().enumerate() {
+            by_target[healthy[position]] = count;
+        }
+        // Target 1's 3/8 share is split 1:2:2 across the others.
+        assert_eq!(by_target, [100, 0, 200, 200]);
+    }
+
+    #[test]
+    fn every_weighted_candidate_is_tried_once_and_zero_never() {
+        let mut order = weighted_order(&[2, 0, 5, 1], || 0.5);
+        assert!(!order.contains(&1));
+        order.sort();
+        assert_eq!(order, vec![0, 2, 3]);
+        assert!(weighted_order(&[0, 0], || 0.5).is_empty());
+    }
+
+    #[test]
+    fn later_picks_are_weighted_over_what_is_left() {
+        // A roll at the top of the range lands on the last candidate; the
+        // next pick spreads over the other two by their own weights.
+        let mut rolls = [0.99, 0.0, 0.0].into_iter();
+        assert_eq!(weighted_order(&[1, 1, 2], || rolls.next().unwrap()), vec![2, 0, 1]);
+        let mut rolls = [0.99, 0.99, 0.0].into_iter();
+        assert_eq!(weighted_order(&[1, 1, 2], || rolls.next().unwrap()), vec![2, 1, 0]);
+    }
+}
@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+
+// Why a connection was refused, stored next to the human-readable message
+// so views like /api/ddos filter on it instead of on the wording.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockReason {
+    // --persist-fail-safe tripped.
+    PersistenceFailing,
+    NotAllowlisted,
+    GeoBlocked,
+    AsnBlocked,
+    // The blocklist, a port block or a --blocklist-file.
+    Blocklisted,
+    AuthDenied,
+    ReverseDns,
+    // A rule's sni_allow/sni_deny, or no SNI to check.
+    SniBlocked,
+    ProxyProtocol,
+    BudgetExhausted,
+    LoadShed,
+    // Concurrent connection caps, global or per IP, rule or port.
+    ConnectionLimit,
+    // New-connection rate windows, global or per port or rule.
+    RateLimit,
+}
+
+impl BlockReason {
+    // Refusals that come from a client opening too many connections or too
+    // fast, which is what the DDoS view lists.
+    pub fn is_ddos(self) -> bool {
+        matches!(self, BlockReason::ConnectionLimit | BlockReason::RateLimit)
+    }
+
+    pub fn refuse(self, message: impl Into<String>) -> Refused {
+        Refused {
+            reason: self,
+            message: message.into(),
+        }
+    }
+
+    // Entries logged before the category was stored only have the message;
+    // migrate::categorize_blocked_history sorts them once on load.
+    pub fn from_message(message: &str) -> Option<BlockReason> {
+        let reason = if message.starts_with("Persistence failing") {
+            BlockReason::PersistenceFailing
+        } else if message.starts_with("Not in allowlist") {
+            BlockReason::NotAllowlisted
+        } else if message.starts_with("Geo blocked") {
+            BlockReason::GeoBlocked
+        } else if message.starts_with("ASN blocked") {
+            BlockReason::AsnBlocked
+        } else if message.starts_with("Blocked ") {
+            BlockReason::Blocklisted
+        } else if message.contains("auth service") || message.starts_with("Auth service") {
+            BlockReason::AuthDenied
+        } else if message.starts_with("Reverse DNS") {
+            BlockReason::ReverseDns
+        } else if message.contains("PROXY protocol header") {
+            BlockReason::ProxyProtocol
+        } else if message.contains("budget") {
+            BlockReason::BudgetExhausted
+        } else if message.starts_with("Load shed") {
+            BlockReason::LoadShed
+        } else if message.starts_with("Too many") {
+            BlockReason::ConnectionLimit
+        } else if message.starts_with("Rate limit") {
+            BlockReason::RateLimit
+        } else {
+            return None;
+        };
+        Some(reason)
+    }
+}
+
+// An admission check's refusal: the category plus the message that is
+// logged and shown in the panel.
+#[derive(Debug)]
+pub struct Refused {
+    pub reason: BlockReason,
+    pub message: String,
+}
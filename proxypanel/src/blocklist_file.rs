@@ -0,0 +1,190 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::app::{now_string, AppState};
+
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+// A read-only blocklist kept in a file that something else (cron, a feed
+// sync job) maintains. One IP or CIDR per line; `#` starts a comment and
+// anything after the first whitespace or `;` is ignored, so common feed
+// formats like "1.2.3.0/24 ; SBL123" load as is.
+pub struct BlocklistFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    // Networks keyed by prefix length, stored masked, so a lookup costs one
+    // hash probe per distinct prefix length instead of a scan of every line.
+    networks: HashMap<u8, HashSet<IpAddr>>,
+    entries: usize,
+    invalid_lines: usize,
+    loaded_at: String,
+}
+
+#[derive(Serialize)]
+pub struct BlocklistFileInfo {
+    pub path: String,
+    pub entries: usize,
+    pub invalid_lines: usize,
+    pub loaded_at: String,
+}
+
+impl BlocklistFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let modified = std::fs::metadata(path)
+            .with_context(|| format!("Failed to read blocklist file {}", path.display()))?
+            .modified()
+            .ok();
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read blocklist file {}", path.display()))?;
+
+        let mut networks: HashMap<u8, HashSet<IpAddr>> = HashMap::new();
+        let mut entries = 0;
+        let mut invalid_lines = 0;
+        for (index, line) in contents.lines().enumerate() {
+            let Some(entry) = line
+                .split('#')
+                .next()
+                .and_then(|value| value.split(|c: char| c.is_whitespace() || c == ';').find(|part| !part.is_empty()))
+            else {
+                continue;
+            };
+            match parse_network(entry) {
+                Some((network, prefix)) => {
+                    if networks.entry(prefix).or_default().insert(network) {
+                        entries += 1;
+                    }
+                }
+                None => {
+                    if invalid_lines == 0 {
+                        warn!("{}:{}: not an IP or CIDR: {}", path.display(), index + 1, entry);
+                    }
+                    invalid_lines += 1;
+                }
+            }
+        }
+        if invalid_lines > 1 {
+            warn!("{}: {} invalid lines skipped", path.display(), invalid_lines);
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            modified,
+            networks,
+            entries,
+            invalid_lines,
+            loaded_at: now_string(),
+        })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.networks
+            .iter()
+            .any(|(prefix, networks)| networks.contains(&mask(ip, *prefix)))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn info(&self) -> BlocklistFileInfo {
+        BlocklistFileInfo {
+            path: self.path.display().to_string(),
+            entries: self.entries,
+            invalid_lines: self.invalid_lines,
+            loaded_at: self.loaded_at.clone(),
+        }
+    }
+}
+
+fn parse_network(value: &str) -> Option<(IpAddr, u8)> {
+    let (ip, prefix) = match value.split_once('/') {
+        Some((ip, prefix)) => (ip.parse::<IpAddr>().ok()?.to_canonical(), prefix.parse::<u8>().ok()?),
+        None => {
+            let ip = value.parse::<IpAddr>().ok()?.to_canonical();
+            (ip, max_prefix(ip))
+        }
+    };
+    if prefix > max_prefix(ip) {
+        return None;
+    }
+    Some((mask(ip, prefix), prefix))
+}
+
+fn max_prefix(ip: IpAddr) -> u8 {
+    if ip.is_ipv4() {
+        32
+    } else {
+        128
+    }
+}
+
+fn mask(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let bits = u32::MAX.checked_shl(32 - prefix.min(32) as u32).unwrap_or(0);
+            IpAddr::V4((u32::from(ip) & bits).into())
+        }
+        IpAddr::V6(ip) => {
+            let bits = u128::MAX.checked_shl(128 - prefix.min(128) as u32).unwrap_or(0);
+            IpAddr::V6((u128::from(ip) & bits).into())
+        }
+    }
+}
+
+// Polls each file's mtime and reloads it when it changes. A file that fails
+// to load (mid-write, deleted) keeps its previous entries.
+pub fn start_reloader(state: Arc<RwLock<AppState>>, paths: Vec<PathBuf>) {
+    if paths.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut failing = vec![false; paths.len()];
+        loop {
+            tokio::time::sleep(RELOAD_CHECK_INTERVAL).await;
+            for (index, path) in paths.iter().enumerate() {
+                let modified = tokio::fs::metadata(path).await.and_then(|meta| meta.modified());
+                let modified = match modified {
+                    Ok(modified) => modified,
+                    Err(err) => {
+                        if !failing[index] {
+                            warn!("Blocklist file {} unavailable, keeping previous entries: {}", path.display(), err);
+                            failing[index] = true;
+                        }
+                        continue;
+                    }
+                };
+                let current = state.read().await.blocklist_files.get(index).and_then(|file| file.modified);
+                if current == Some(modified) && !failing[index] {
+                    continue;
+                }
+                let load_path = path.clone();
+                match tokio::task::spawn_blocking(move || BlocklistFile::load(&load_path)).await {
+                    Ok(Ok(file)) => {
+                        info!("Reloaded blocklist file {} ({} entries)", path.display(), file.entries);
+                        failing[index] = false;
+                        if let Some(slot) = state.write().await.blocklist_files.get_mut(index) {
+                            *slot = file;
+                        }
+                    }
+                    Ok(Err(err)) => {
+                        if !failing[index] {
+                            warn!("{:#}; keeping previous entries", err);
+                            failing[index] = true;
+                        }
+                    }
+                    Err(err) => warn!("Blocklist reload task failed: {}", err),
+                }
+            }
+        }
+    });
+}
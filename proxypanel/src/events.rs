@@ -0,0 +1,49 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use tokio::net::UnixDatagram;
+
+#[derive(Serialize)]
+pub(crate) struct Envelope<'a, T: Serialize> {
+    pub(crate) event: &'a str,
+    #[serde(flatten)]
+    pub(crate) payload: &'a T,
+}
+
+// Sends one JSON datagram per connection event to a local collector. Sends
+// never block: if nobody is reading the socket the event is dropped.
+#[derive(Default)]
+pub struct EventSocket {
+    #[cfg(unix)]
+    target: Option<(UnixDatagram, PathBuf)>,
+}
+
+impl EventSocket {
+    #[cfg(unix)]
+    pub fn open(path: Option<&Path>) -> Result<Self> {
+        let target = match path {
+            Some(path) => Some((UnixDatagram::unbound()?, path.to_path_buf())),
+            None => None,
+        };
+        Ok(Self { target })
+    }
+
+    #[cfg(not(unix))]
+    pub fn open(_path: Option<&Path>) -> Result<Self> {
+        Ok(Self::default())
+    }
+
+    pub fn emit<T: Serialize>(&self, event: &str, payload: &T) {
+        #[cfg(unix)]
+        if let Some((socket, path)) = self.target.as_ref() {
+            if let Ok(bytes) = serde_json::to_vec(&Envelope { event, payload }) {
+                let _ = socket.try_send_to(&bytes, path);
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = (event, payload);
+    }
+}
@@ -2,16 +2,107 @@ use anyhow::{anyhow, Result};
 use maxminddb::geoip2;
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     net::IpAddr,
     path::Path,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
 };
 use tracing::warn;
 
 pub const GEO_DB_FILENAME: &str = "GeoLite2-Country.mmdb";
+pub const ASN_DB_FILENAME: &str = "GeoLite2-ASN.mmdb";
+// Lookups remembered per database; repeat clients skip the mmdb search.
+const LOOKUP_CACHE_ENTRIES: usize = 65_536;
 
+// The cache lives with the reader, so a reloaded or uploaded database
+// starts with an empty one.
 pub struct GeoDb {
     reader: maxminddb::Reader<Vec<u8>>,
+    countries: LookupCache<Option<String>>,
+    asns: LookupCache<Option<u32>>,
+}
+
+impl GeoDb {
+    fn new(reader: maxminddb::Reader<Vec<u8>>) -> Self {
+        Self {
+            reader,
+            countries: LookupCache::default(),
+            asns: LookupCache::default(),
+        }
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        let (countries, asns) = (self.countries.stats(), self.asns.stats());
+        CacheStats {
+            hits: countries.hits + asns.hits,
+            misses: countries.misses + asns.misses,
+            entries: countries.entries + asns.entries,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+// Approximate LRU in two generations: hits in the older one are promoted,
+// and once the newer one fills up it becomes the older one, dropping
+// whatever wasn't used since the last swap.
+struct LookupCache<V> {
+    generations: Mutex<(HashMap<IpAddr, V>, HashMap<IpAddr, V>)>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<V> Default for LookupCache<V> {
+    fn default() -> Self {
+        Self {
+            generations: Mutex::new((HashMap::new(), HashMap::new())),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<V: Clone> LookupCache<V> {
+    fn get_or_insert_with(&self, ip: IpAddr, lookup: impl FnOnce() -> V) -> V {
+        let mut guard = self.generations.lock().unwrap_or_else(|err| err.into_inner());
+        let (current, previous) = &mut *guard;
+        if let Some(value) = current.get(&ip) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return value.clone();
+        }
+        let value = match previous.remove(&ip) {
+            Some(value) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                value
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                lookup()
+            }
+        };
+        if current.len() >= LOOKUP_CACHE_ENTRIES / 2 {
+            *previous = std::mem::take(current);
+        }
+        current.insert(ip, value.clone());
+        value
+    }
+
+    fn stats(&self) -> CacheStats {
+        let guard = self.generations.lock().unwrap_or_else(|err| err.into_inner());
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: guard.0.len() + guard.1.len(),
+        }
+    }
 }
 
 pub type SharedGeoDb = Arc<GeoDb>;
@@ -39,20 +130,91 @@ pub struct GeoBlockQuery {
     pub port: Option<u16>,
 }
 
+#[derive(Clone, Serialize)]
+pub struct AsnEntry {
+    pub asn: u32,
+}
+
+#[derive(Deserialize)]
+pub struct AsnBlockRequest {
+    pub asn: u32,
+}
+
+#[derive(Serialize)]
+pub struct GeoDbInfo {
+    pub database_type: String,
+    pub build_epoch: u64,
+    pub node_count: u32,
+    pub size: usize,
+}
+
+// Parses an uploaded database in memory; `expected_type` must appear in its
+// metadata type (e.g. "Country" for GeoLite2-Country).
+pub fn open_db_bytes(bytes: Vec<u8>, expected_type: &str) -> Result<(SharedGeoDb, GeoDbInfo)> {
+    let size = bytes.len();
+    let reader = maxminddb::Reader::from_source(bytes).map_err(|err| anyhow!("Invalid mmdb file: {}", err))?;
+    let metadata = &reader.metadata;
+    if !metadata.database_type.contains(expected_type) {
+        return Err(anyhow!(
+            "Not a {} database: {}",
+            expected_type,
+            metadata.database_type
+        ));
+    }
+    let info = GeoDbInfo {
+        database_type: metadata.database_type.clone(),
+        build_epoch: metadata.build_epoch,
+        node_count: metadata.node_count,
+        size,
+    };
+    Ok((Arc::new(GeoDb::new(reader)), info))
+}
+
 pub fn load_geo_db(data_dir: &Path) -> Result<Option<SharedGeoDb>> {
-    let path = data_dir.join(GEO_DB_FILENAME);
+    load_db(data_dir, GEO_DB_FILENAME)
+}
+
+pub fn load_asn_db(data_dir: &Path) -> Result<Option<SharedGeoDb>> {
+    load_db(data_dir, ASN_DB_FILENAME)
+}
+
+fn load_db(data_dir: &Path, filename: &str) -> Result<Option<SharedGeoDb>> {
+    let path = data_dir.join(filename);
     if !path.exists() {
         warn!("Geo DB not found: {}", path.display());
         return Ok(None);
     }
     let reader = maxminddb::Reader::open_readfile(&path)?;
-    Ok(Some(Arc::new(GeoDb { reader })))
+    Ok(Some(Arc::new(GeoDb::new(reader))))
 }
 
 pub fn lookup_country(db: &GeoDb, ip: IpAddr) -> Option<String> {
-    let result: geoip2::Country = db.reader.lookup(ip).ok()?;
-    let iso = result.country?.iso_code?;
-    Some(iso.to_uppercase())
+    db.countries.get_or_insert_with(ip, || {
+        let result: geoip2::Country = db.reader.lookup(ip).ok()?;
+        let iso = result.country?.iso_code?;
+        Some(iso.to_uppercase())
+    })
+}
+
+pub fn lookup_asn(db: &GeoDb, ip: IpAddr) -> Option<u32> {
+    db.asns.get_or_insert_with(ip, || {
+        let result: geoip2::Asn = db.reader.lookup(ip).ok()?;
+        result.autonomous_system_number
+    })
+}
+
+// Accepts "13335" as well as "AS13335".
+pub fn normalize_asn(value: &str) -> Result<u32> {
+    let trimmed = value.trim();
+    let digits = trimmed
+        .strip_prefix("AS")
+        .or_else(|| trimmed.strip_prefix("as"))
+        .unwrap_or(trimmed);
+    digits
+        .parse::<u32>()
+        .ok()
+        .filter(|asn| *asn > 0)
+        .ok_or_else(|| anyhow!("ASN must be a positive number"))
 }
 
 pub fn normalize_country(value: &str) -> Result<String> {
@@ -90,9 +252,32 @@ pub const GEO_SECTION_HTML: &str = r#"
     </div>
 "#;
 
-pub const GEO_REFRESH_VARS: &str = ", geoBlocks";
-pub const GEO_REFRESH_CALLS: &str = ", api(\"/api/geo-blocklist\")";
-pub const GEO_REFRESH_RENDER: &str = "    renderGeoBlocks(geoBlocks);\n";
+pub const ASN_SECTION_HTML: &str = r#"
+    <div class="section">
+      <div class="section-header">
+        <h3>ASN blocklist</h3>
+        <button class="toggle" data-section="asn-section" onclick="toggleSection('asn-section', this)">Hide</button>
+      </div>
+      <div id="asn-section">
+        <div class="row">
+          <input id="asn-value" placeholder="ASN (AS13335)">
+          <button onclick="addAsnBlock()">Block</button>
+          <span id="asn-error" class="muted"></span>
+        </div>
+        <div class="muted">Requires GeoLite2-ASN.mmdb in data folder.</div>
+        <table>
+          <thead>
+            <tr><th>ASN</th><th>Action</th></tr>
+          </thead>
+          <tbody id="asn-body"></tbody>
+        </table>
+      </div>
+    </div>
+"#;
+
+pub const GEO_REFRESH_VARS: &str = ", geoBlocks, asnBlocks";
+pub const GEO_REFRESH_CALLS: &str = ", api(\"/api/geo-blocklist\"), api(\"/api/asn-blocklist\")";
+pub const GEO_REFRESH_RENDER: &str = "    renderGeoBlocks(geoBlocks);\n    renderAsnBlocks(asnBlocks);\n";
 
 pub const GEO_JS_HOOKS: &str = r#"
 function renderGeoBlocks(items) {
@@ -144,4 +329,45 @@ async function removeGeoBlock(country, port) {
   await api(`/api/geo-blocklist/${encodeURIComponent(country)}${query}`, { method: "DELETE" });
   await refresh();
 }
+
+function renderAsnBlocks(items) {
+  const body = document.getElementById("asn-body");
+  if (!body) return;
+  body.innerHTML = "";
+  items.forEach(item => {
+    const row = document.createElement("tr");
+    row.innerHTML = `
+      <td>AS${item.asn}</td>
+      <td><button onclick="removeAsnBlock(${item.asn})">Remove</button></td>
+    `;
+    body.appendChild(row);
+  });
+}
+
+async function addAsnBlock() {
+  const text = document.getElementById("asn-value").value.trim().replace(/^as/i, "");
+  const errorBox = document.getElementById("asn-error");
+  errorBox.textContent = "";
+  const asn = parseInt(text, 10);
+  if (Number.isNaN(asn) || asn < 1) {
+    errorBox.textContent = "Invalid ASN";
+    return;
+  }
+  try {
+    await api("/api/asn-blocklist", {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({ asn })
+    });
+    document.getElementById("asn-value").value = "";
+    await refresh();
+  } catch (err) {
+    errorBox.textContent = err.message;
+  }
+}
+
+async function removeAsnBlock(asn) {
+  await api(`/api/asn-blocklist/${asn}`, { method: "DELETE" });
+  await refresh();
+}
 "#;
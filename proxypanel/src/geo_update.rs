@@ -1,6 +1,8 @@
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use std::{
-    path::{Path, PathBuf},
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    path::Path,
     sync::Arc,
     time::{Duration, SystemTime},
 };
@@ -8,47 +10,111 @@ use tokio::sync::RwLock;
 use tracing::{info, warn};
 
 use crate::{
-    app::AppState,
-    geo::{self, GEO_DB_FILENAME},
+    app::{AppConfig, AppState},
+    geo::{self, ASN_DB_FILENAME, GEO_DB_FILENAME},
+    webhook,
 };
 
-const UPDATE_INTERVAL: Duration = Duration::from_secs(60 * 60 * 24);
-const MIN_DB_SIZE: usize = 100_000;
+pub const DEFAULT_UPDATE_INTERVAL: Duration = Duration::from_secs(60 * 60 * 24);
+pub(crate) const MIN_DB_SIZE: usize = 100_000;
 
-const GEO_URLS: [&str; 3] = [
-    "https://git.io/GeoLite2-Country.mmdb",
+pub const DEFAULT_GEO_URLS: [&str; 2] = [
     "https://raw.githubusercontent.com/P3TERX/GeoLite.mmdb/main/GeoLite2-Country.mmdb",
     "https://github.com/P3TERX/GeoLite.mmdb/raw/main/GeoLite2-Country.mmdb",
 ];
 
-pub fn start_geo_updater(state: Arc<RwLock<AppState>>, data_dir: PathBuf) {
+pub const DEFAULT_ASN_URLS: [&str; 2] = [
+    "https://raw.githubusercontent.com/P3TERX/GeoLite.mmdb/download/GeoLite2-ASN.mmdb",
+    "https://github.com/P3TERX/GeoLite.mmdb/raw/download/GeoLite2-ASN.mmdb",
+];
+
+// With updates disabled the databases already in the data dir are loaded once
+// and never downloaded. An empty URL list does the same for that one database.
+pub fn start_geo_updater(state: Arc<RwLock<AppState>>, config: &AppConfig) {
+    let data_dir = config.data_dir.clone();
+    let jitter = config.geo_update_jitter.clamp(0.0, 1.0);
+    let interval = config.geo_update_interval;
+    let stale_after = config.geo_stale_after;
+    let enabled = config.geo_update_enabled;
+    let (geo_urls, asn_urls) = if enabled {
+        (config.geo_db_urls.clone(), config.asn_db_urls.clone())
+    } else {
+        info!("Geo DB auto-update disabled; using databases in {}", data_dir.display());
+        (Vec::new(), Vec::new())
+    };
     tokio::spawn(async move {
-        if let Err(err) = refresh_geo_db(&state, &data_dir).await {
-            warn!("Geo DB refresh failed: {}", err);
+        // The startup load stays immediate so geo blocking works right away;
+        // only the periodic refreshes are spread out.
+        refresh_all(&state, &data_dir, interval, stale_after, &geo_urls, &asn_urls).await;
+        if !enabled {
+            return;
         }
+        let mut wait = interval.mul_f64(jitter * random_unit()) + jittered(interval, jitter);
         loop {
-            tokio::time::sleep(UPDATE_INTERVAL).await;
-            if let Err(err) = refresh_geo_db(&state, &data_dir).await {
-                warn!("Geo DB refresh failed: {}", err);
-            }
+            tokio::time::sleep(wait).await;
+            refresh_all(&state, &data_dir, interval, stale_after, &geo_urls, &asn_urls).await;
+            wait = jittered(interval, jitter);
         }
     });
 }
 
-async fn refresh_geo_db(state: &Arc<RwLock<AppState>>, data_dir: &Path) -> Result<()> {
+async fn refresh_all(
+    state: &Arc<RwLock<AppState>>,
+    data_dir: &Path,
+    interval: Duration,
+    stale_after: Duration,
+    geo_urls: &[String],
+    asn_urls: &[String],
+) {
+    if let Err(err) = refresh_geo_db(state, data_dir, interval, stale_after, geo_urls).await {
+        warn!("Geo DB refresh failed: {}", err);
+    }
+    if let Err(err) = refresh_asn_db(state, data_dir, interval, stale_after, asn_urls).await {
+        warn!("ASN DB refresh failed: {}", err);
+    }
+}
+
+// Scales `base` by a random factor in [1 - jitter, 1 + jitter].
+fn jittered(base: Duration, jitter: f64) -> Duration {
+    let factor = 1.0 + jitter * (2.0 * random_unit() - 1.0);
+    base.mul_f64(factor.max(0.0))
+}
+
+// Uniform value in [0, 1). RandomState is seeded per process, which is all the
+// spread we need across a fleet.
+pub(crate) fn random_unit() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|value| value.as_nanos())
+        .unwrap_or(0);
+    hasher.write_u128(nanos);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+async fn refresh_geo_db(
+    state: &Arc<RwLock<AppState>>,
+    data_dir: &Path,
+    interval: Duration,
+    stale_after: Duration,
+    urls: &[String],
+) -> Result<()> {
     tokio::fs::create_dir_all(data_dir).await?;
     let path = data_dir.join(GEO_DB_FILENAME);
-    let should_download = should_download(&path)?;
     let mut downloaded = false;
 
-    if should_download {
-        match download_geo_db(&path).await {
+    if !urls.is_empty() && should_download(&path, interval)? {
+        match download_db(&path, urls).await {
             Ok(true) => {
                 downloaded = true;
             }
-            Ok(false) => {}
+            Ok(false) => {
+                warn!("Geo DB download failed from all {} URLs", urls.len());
+                notify_if_stale(state, &path, stale_after).await;
+            }
             Err(err) => {
                 warn!("Geo DB download failed: {}", err);
+                notify_if_stale(state, &path, stale_after).await;
             }
         }
     }
@@ -64,40 +130,125 @@ async fn refresh_geo_db(state: &Arc<RwLock<AppState>>, data_dir: &Path) -> Resul
     Ok(())
 }
 
-fn should_download(path: &Path) -> Result<bool> {
+async fn refresh_asn_db(
+    state: &Arc<RwLock<AppState>>,
+    data_dir: &Path,
+    interval: Duration,
+    stale_after: Duration,
+    urls: &[String],
+) -> Result<()> {
+    tokio::fs::create_dir_all(data_dir).await?;
+    let path = data_dir.join(ASN_DB_FILENAME);
+    let mut downloaded = false;
+
+    if !urls.is_empty() && should_download(&path, interval)? {
+        match download_db(&path, urls).await {
+            Ok(true) => {
+                downloaded = true;
+            }
+            Ok(false) => {
+                warn!("ASN DB download failed from all {} URLs", urls.len());
+                notify_if_stale(state, &path, stale_after).await;
+            }
+            Err(err) => {
+                warn!("ASN DB download failed: {}", err);
+                notify_if_stale(state, &path, stale_after).await;
+            }
+        }
+    }
+
+    let needs_load = downloaded || state.read().await.asn_db.is_none();
+    if needs_load && path.exists() {
+        if let Ok(Some(db)) = geo::load_asn_db(data_dir) {
+            state.write().await.asn_db = Some(db);
+            info!("ASN DB loaded");
+        }
+    }
+
+    Ok(())
+}
+
+// After a failed download: raises geo_db_stale once the file on disk is
+// older than `stale_after`, or if there has never been one.
+async fn notify_if_stale(state: &Arc<RwLock<AppState>>, path: &Path, stale_after: Duration) {
+    let Some(webhook) = state.read().await.webhook.clone() else {
+        return;
+    };
+    let age = std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .map(|modified| modified.elapsed().unwrap_or_default());
+    if age.is_some_and(|age| age < stale_after) {
+        return;
+    }
+    let age_days = age.map(|age| age.as_secs() / (24 * 60 * 60));
+    let text = match age_days {
+        Some(days) => format!("{} could not be updated and is {} days old", path.display(), days),
+        None => format!("{} could not be downloaded", path.display()),
+    };
+    webhook.notify(
+        webhook::GEO_DB_STALE,
+        text,
+        &serde_json::json!({
+            "path": path.display().to_string(),
+            "age_days": age_days,
+        }),
+    );
+}
+
+fn should_download(path: &Path, interval: Duration) -> Result<bool> {
     if !path.exists() {
         return Ok(true);
     }
     let metadata = std::fs::metadata(path)?;
     let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
-    let elapsed = modified.elapsed().unwrap_or(UPDATE_INTERVAL);
-    Ok(elapsed >= UPDATE_INTERVAL)
+    let elapsed = modified.elapsed().unwrap_or(interval);
+    Ok(elapsed >= interval)
 }
 
-async fn download_geo_db(path: &Path) -> Result<bool> {
+// Tries each URL in turn; a dead or misbehaving mirror only moves on to the
+// next one. Ok(false) means none of them produced a usable file.
+async fn download_db(path: &Path, urls: &[String]) -> Result<bool> {
     let client = reqwest::Client::builder()
         .timeout(Duration::from_secs(60))
         .user_agent("proxy-panel/0.1")
         .build()?;
 
-    for url in &GEO_URLS {
-        let response = client.get(*url).send().await?;
+    for url in urls {
+        let response = match client.get(url).send().await {
+            Ok(response) => response,
+            Err(err) => {
+                warn!("{} download failed: {}: {}", path.display(), url, err);
+                continue;
+            }
+        };
         if !response.status().is_success() {
-            warn!("Geo DB download failed ({}): {}", response.status(), url);
+            warn!("{} download failed ({}): {}", path.display(), response.status(), url);
             continue;
         }
-        let bytes = response.bytes().await?;
+        let bytes = match response.bytes().await {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                warn!("{} download failed: {}: {}", path.display(), url, err);
+                continue;
+            }
+        };
         if bytes.len() < MIN_DB_SIZE {
-            return Err(anyhow!("Geo DB file too small"));
+            warn!("{} download too small ({} bytes): {}", path.display(), bytes.len(), url);
+            continue;
         }
 
-        let tmp_path = path.with_extension("mmdb.tmp");
-        tokio::fs::write(&tmp_path, &bytes).await?;
-        let _ = tokio::fs::remove_file(path).await;
-        tokio::fs::rename(&tmp_path, path).await?;
-        info!("Geo DB downloaded from {}", url);
+        write_db_file(path, &bytes).await?;
+        info!("{} downloaded from {}", path.display(), url);
         return Ok(true);
     }
 
     Ok(false)
 }
+
+pub(crate) async fn write_db_file(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension("mmdb.tmp");
+    tokio::fs::write(&tmp_path, bytes).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
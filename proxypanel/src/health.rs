@@ -0,0 +1,88 @@
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::net::TcpStream;
+use tokio_util::sync::CancellationToken;
+
+use crate::app::{now_string, ListenerHandle};
+
+pub const DEFAULT_CHECK_TIMEOUT: Duration = Duration::from_millis(2000);
+
+#[derive(Clone, Serialize)]
+pub struct TargetHealth {
+    pub target: String,
+    pub healthy: bool,
+    pub last_checked: String,
+    pub latency_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+// Health of one rule's targets. Targets that have not been checked yet are
+// treated as healthy so a fresh rule can serve traffic immediately.
+#[derive(Default)]
+pub struct RuleHealth {
+    targets: Mutex<HashMap<String, TargetHealth>>,
+}
+
+impl RuleHealth {
+    pub fn is_healthy(&self, target: &str) -> bool {
+        let guard = self.targets.lock().unwrap_or_else(|err| err.into_inner());
+        guard.get(target).map(|entry| entry.healthy).unwrap_or(true)
+    }
+
+    pub fn snapshot(&self) -> Vec<TargetHealth> {
+        let guard = self.targets.lock().unwrap_or_else(|err| err.into_inner());
+        let mut items = guard.values().cloned().collect::<Vec<_>>();
+        items.sort_by(|a, b| a.target.cmp(&b.target));
+        items
+    }
+
+    fn update(&self, entry: TargetHealth) {
+        let mut guard = self.targets.lock().unwrap_or_else(|err| err.into_inner());
+        guard.insert(entry.target.clone(), entry);
+    }
+}
+
+pub fn start_health_checker(
+    targets: Vec<String>,
+    interval: Duration,
+    timeout: Duration,
+    health: Arc<RuleHealth>,
+) -> ListenerHandle {
+    let shutdown = CancellationToken::new();
+    let shutdown_signal = shutdown.clone();
+    let task = tokio::spawn(async move {
+        let mut tick = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = shutdown_signal.cancelled() => break,
+                _ = tick.tick() => {
+                    for target in &targets {
+                        health.update(check_target(target, timeout).await);
+                    }
+                }
+            }
+        }
+    });
+    ListenerHandle { shutdown, task }
+}
+
+async fn check_target(target: &str, timeout: Duration) -> TargetHealth {
+    let started = Instant::now();
+    let result = tokio::time::timeout(timeout, TcpStream::connect(target)).await;
+    let (healthy, latency_ms, error) = match result {
+        Ok(Ok(_)) => (true, Some(started.elapsed().as_millis() as u64), None),
+        Ok(Err(err)) => (false, None, Some(err.to_string())),
+        Err(_) => (false, None, Some("Health check timed out".to_string())),
+    };
+    TargetHealth {
+        target: target.to_string(),
+        healthy,
+        last_checked: now_string(),
+        latency_ms,
+        error,
+    }
+}
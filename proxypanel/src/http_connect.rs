@@ -0,0 +1,235 @@
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// Request line plus headers; CONNECT requests are a few hundred bytes at most.
+const MAX_REQUEST_LEN: usize = 8192;
+
+pub const ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+pub const BAD_REQUEST: &[u8] = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+pub const FORBIDDEN: &[u8] = b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+pub const REQUEST_TIMEOUT: &[u8] = b"HTTP/1.1 408 Request Timeout\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+pub const METHOD_NOT_ALLOWED: &[u8] =
+    b"HTTP/1.1 405 Method Not Allowed\r\nAllow: CONNECT\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+pub const BAD_GATEWAY: &[u8] = b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+pub const GATEWAY_TIMEOUT: &[u8] = b"HTTP/1.1 504 Gateway Timeout\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+// A CONNECT request that couldn't be accepted: the reason to log and the
+// response to send back before closing.
+pub struct Refusal {
+    pub reason: String,
+    pub response: &'static [u8],
+}
+
+// The destination of a CONNECT request, with the host lowercased and IPv6
+// brackets kept so `authority()` can be dialed as is.
+pub struct Destination {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Destination {
+    pub fn authority(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+// Reads and consumes the request line and headers from the start of
+// `reader`, leaving anything after the blank line unread so it reaches the
+// target once the tunnel is up.
+pub async fn read_request<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Destination, Refusal> {
+    let mut request = Vec::new();
+    // Byte at a time so nothing past the blank line is consumed.
+    while !request.ends_with(b"\r\n\r\n") && !request.ends_with(b"\n\n") {
+        if request.len() >= MAX_REQUEST_LEN {
+            return Err(bad_request("request too long"));
+        }
+        let byte = reader.read_u8().await.map_err(|_| bad_request("request truncated"))?;
+        request.push(byte);
+    }
+    let request = std::str::from_utf8(&request).map_err(|_| bad_request("request not UTF-8"))?;
+    let line = request.lines().next().unwrap_or_default();
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(bad_request("malformed request line"));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(bad_request("unsupported HTTP version"));
+    }
+    if method != "CONNECT" {
+        return Err(Refusal {
+            reason: format!("CONNECT expected, got {}", method),
+            response: METHOD_NOT_ALLOWED,
+        });
+    }
+    parse_authority(target).ok_or_else(|| bad_request("invalid CONNECT target"))
+}
+
+fn parse_authority(target: &str) -> Option<Destination> {
+    let (host, port) = target.rsplit_once(':')?;
+    let port = port.parse::<u16>().ok().filter(|port| *port > 0)?;
+    let bare = host.strip_prefix('[').and_then(|host| host.strip_suffix(']'));
+    let valid = match bare {
+        Some(ip) => ip.parse::<std::net::Ipv6Addr>().is_ok(),
+        None => {
+            !host.is_empty()
+                && host
+                    .chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || ch == '.' || ch == '-' || ch == '_')
+        }
+    };
+    valid.then(|| Destination {
+        host: host.to_ascii_lowercase(),
+        port,
+    })
+}
+
+// Entries are `host:port`. The host may be `*` for any host or `*.suffix`
+// for names under a domain; the port may be `*`. An empty list allows
+// nothing, so a CONNECT rule is never an open proxy by accident.
+pub fn destination_allowed(allowed: &[String], destination: &Destination) -> bool {
+    allowed.iter().any(|entry| {
+        let Some((host, port)) = entry.rsplit_once(':') else {
+            return false;
+        };
+        let port_matches = port == "*" || port.parse::<u16>().is_ok_and(|port| port == destination.port);
+        let host = host.to_ascii_lowercase();
+        let host_matches = if host == "*" {
+            true
+        } else if let Some(suffix) = host.strip_prefix("*.") {
+            destination
+                .host
+                .strip_suffix(suffix)
+                .is_some_and(|rest| rest.ends_with('.'))
+        } else {
+            host == destination.host
+        };
+        port_matches && host_matches
+    })
+}
+
+// Checks the shape of allowlist entries when a rule is saved.
+pub fn valid_entry(entry: &str) -> bool {
+    let Some((host, port)) = entry.rsplit_once(':') else {
+        return false;
+    };
+    let port_valid = port == "*" || port.parse::<u16>().is_ok_and(|port| port > 0);
+    let host_valid = host == "*"
+        || parse_authority(&format!("{}:1", host.strip_prefix("*.").unwrap_or(host))).is_some();
+    port_valid && host_valid
+}
+
+fn bad_request(detail: &str) -> Refusal {
+    Refusal {
+        reason: format!("Invalid CONNECT request: {}", detail),
+        response: BAD_REQUEST,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(input: &[u8]) -> (Result<Destination, Refusal>, usize) {
+        let mut reader = input;
+        let result = read_request(&mut reader).await;
+        (result, reader.len())
+    }
+
+    fn reason(result: Result<Destination, Refusal>) -> String {
+        match result {
+            Ok(destination) => panic!("accepted {}", destination.authority()),
+            Err(refusal) => refusal.reason,
+        }
+    }
+
+    fn destination(authority: &str) -> Destination {
+        parse_authority(authority).unwrap()
+    }
+
+    fn allowed(entries: &[&str], authority: &str) -> bool {
+        let entries = entries.iter().map(|entry| entry.to_string()).collect::<Vec<_>>();
+        destination_allowed(&entries, &destination(authority))
+    }
+
+    #[tokio::test]
+    async fn connect_request_leaves_the_payload_unread() {
+        let (result, left) = read(b"CONNECT Example.COM:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n\x16\x03\x01").await;
+        let destination = result.unwrap_or_else(|refusal| panic!("{}", refusal.reason));
+        assert_eq!(destination.authority(), "example.com:443");
+        assert_eq!(left, 3);
+
+        let (result, _) = read(b"CONNECT [2001:DB8::1]:8443 HTTP/1.0\n\n").await;
+        let destination = result.unwrap_or_else(|refusal| panic!("{}", refusal.reason));
+        assert_eq!(destination.authority(), "[2001:db8::1]:8443");
+    }
+
+    #[tokio::test]
+    async fn truncated_request_is_refused() {
+        let (result, _) = read(b"CONNECT example.com:443 HTTP/1.1\r\nHost: exa").await;
+        assert_eq!(reason(result), "Invalid CONNECT request: request truncated");
+    }
+
+    #[tokio::test]
+    async fn oversize_request_is_refused() {
+        let mut input = b"CONNECT example.com:443 HTTP/1.1\r\nX-Padding: ".to_vec();
+        input.resize(MAX_REQUEST_LEN + 100, b'a');
+        let (result, left) = read(&input).await;
+        assert_eq!(reason(result), "Invalid CONNECT request: request too long");
+        assert_eq!(left, 100);
+    }
+
+    #[tokio::test]
+    async fn other_methods_get_method_not_allowed() {
+        let (result, _) = read(b"GET http://example.com/ HTTP/1.1\r\n\r\n").await;
+        let Err(refusal) = result else {
+            panic!("accepted a GET");
+        };
+        assert_eq!(refusal.reason, "CONNECT expected, got GET");
+        assert_eq!(refusal.response, METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_are_bad_requests() {
+        for (input, expected) in [
+            (&b"CONNECT example.com:443\r\n\r\n"[..], "malformed request line"),
+            (b"CONNECT example.com:443 HTTP/2\r\n\r\n", "unsupported HTTP version"),
+            (b"CONNECT example.com HTTP/1.1\r\n\r\n", "invalid CONNECT target"),
+            (b"CONNECT example.com:0 HTTP/1.1\r\n\r\n", "invalid CONNECT target"),
+            (b"CONNECT exa mple.com:443 HTTP/1.1\r\n\r\n", "malformed request line"),
+            (b"CONNECT [not-ipv6]:443 HTTP/1.1\r\n\r\n", "invalid CONNECT target"),
+        ] {
+            let (result, _) = read(input).await;
+            assert_eq!(reason(result), format!("Invalid CONNECT request: {}", expected));
+        }
+    }
+
+    #[test]
+    fn wildcard_host_matches_subdomains_only() {
+        let entries = ["*.example.com:443"];
+        assert!(allowed(&entries, "api.example.com:443"));
+        assert!(allowed(&entries, "a.b.example.com:443"));
+        assert!(!allowed(&entries, "example.com:443"));
+        assert!(!allowed(&entries, "evilexample.com:443"));
+        assert!(!allowed(&entries, "api.example.com:80"));
+    }
+
+    #[test]
+    fn exact_and_any_entries() {
+        assert!(allowed(&["Example.com:*"], "example.com:8443"));
+        assert!(!allowed(&["example.com:443"], "www.example.com:443"));
+        assert!(allowed(&["*:443"], "anything.test:443"));
+        assert!(!allowed(&["*:443"], "anything.test:22"));
+        assert!(allowed(&["[2001:db8::1]:443"], "[2001:db8::1]:443"));
+        assert!(!allowed(&[], "example.com:443"));
+    }
+
+    #[test]
+    fn entry_validation() {
+        assert!(valid_entry("*.example.com:443"));
+        assert!(valid_entry("*:*"));
+        assert!(valid_entry("[::1]:22"));
+        assert!(!valid_entry("example.com"));
+        assert!(!valid_entry("example.com:0"));
+        assert!(!valid_entry("exa mple.com:443"));
+    }
+}
@@ -0,0 +1,81 @@
+use crate::events::Envelope;
+use axum::{
+    extract::ws::{Message, WebSocket},
+    response::sse::Event,
+};
+use futures_util::{stream, Stream};
+use serde::Serialize;
+use std::convert::Infallible;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+// Bounded so a stalled browser can't make the panel buffer without limit; a
+// client that falls behind is told to resync instead.
+const LIVE_BUFFER: usize = 256;
+
+// Fan-out of connection and state events to panel WebSocket and SSE clients.
+// Messages are serialized once at publish time and shared by all subscribers.
+pub(crate) struct LiveFeed {
+    sender: broadcast::Sender<String>,
+}
+
+impl LiveFeed {
+    pub(crate) fn new() -> Self {
+        let (sender, _) = broadcast::channel(LIVE_BUFFER);
+        Self { sender }
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.sender.subscribe()
+    }
+
+    // No panel is listening, so there is nothing to publish.
+    pub(crate) fn is_idle(&self) -> bool {
+        self.sender.receiver_count() == 0
+    }
+
+    pub(crate) fn publish<T: Serialize>(&self, event: &str, payload: &T) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        if let Ok(text) = serde_json::to_string(&Envelope { event, payload }) {
+            let _ = self.sender.send(text);
+        }
+    }
+}
+
+pub(crate) async fn serve(mut socket: WebSocket, mut receiver: broadcast::Receiver<String>) {
+    loop {
+        tokio::select! {
+            message = receiver.recv() => {
+                let text = match message {
+                    Ok(text) => text,
+                    Err(RecvError::Lagged(_)) => r#"{"event":"resync"}"#.to_string(),
+                    Err(RecvError::Closed) => break,
+                };
+                if socket.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+            incoming = socket.recv() => {
+                match incoming {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
+    }
+}
+
+// Same envelopes as the WebSocket, one per SSE `data:` line.
+pub(crate) fn event_stream(
+    receiver: broadcast::Receiver<String>,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    stream::unfold(receiver, |mut receiver| async move {
+        let text = match receiver.recv().await {
+            Ok(text) => text,
+            Err(RecvError::Lagged(_)) => r#"{"event":"resync"}"#.to_string(),
+            Err(RecvError::Closed) => return None,
+        };
+        Some((Ok(Event::default().data(text)), receiver))
+    })
+}
@@ -0,0 +1,109 @@
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    fmt::{self, Write},
+    sync::Mutex,
+};
+use time::OffsetDateTime;
+use tracing::{field::Field, Event, Level, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::app::now_string;
+
+const CAPACITY: usize = 500;
+
+// Recent warnings and errors for the panel, oldest first. Ids keep
+// increasing across clears so a client can acknowledge exactly what it saw.
+static BUFFER: Mutex<Buffer> = Mutex::new(Buffer {
+    entries: VecDeque::new(),
+    next_id: 1,
+});
+
+struct Buffer {
+    entries: VecDeque<LogEntry>,
+    next_id: u64,
+}
+
+#[derive(Clone, Serialize)]
+pub struct LogEntry {
+    pub id: u64,
+    pub timestamp: String,
+    #[serde(skip)]
+    at: OffsetDateTime,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+}
+
+// tracing layer that copies WARN and ERROR events into the buffer.
+pub struct LogBufferLayer;
+
+impl<S: Subscriber> Layer<S> for LogBufferLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if *metadata.level() > Level::WARN {
+            return;
+        }
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        let Ok(mut buffer) = BUFFER.lock() else {
+            return;
+        };
+        let id = buffer.next_id;
+        buffer.next_id += 1;
+        if buffer.entries.len() >= CAPACITY {
+            buffer.entries.pop_front();
+        }
+        buffer.entries.push_back(LogEntry {
+            id,
+            timestamp: now_string(),
+            at: OffsetDateTime::now_utc(),
+            level: metadata.level().to_string(),
+            target: metadata.target().to_string(),
+            message: visitor.message,
+        });
+    }
+}
+
+// Entries logged strictly after `since`, or all of them.
+pub fn entries(since: Option<OffsetDateTime>) -> Vec<LogEntry> {
+    let Ok(buffer) = BUFFER.lock() else {
+        return Vec::new();
+    };
+    buffer
+        .entries
+        .iter()
+        .filter(|entry| since.is_none_or(|since| entry.at > since))
+        .cloned()
+        .collect()
+}
+
+// Drops entries up to and including `up_to_id` (everything when None), so
+// anything logged after the client last fetched survives the clear.
+pub fn clear(up_to_id: Option<u64>) {
+    let Ok(mut buffer) = BUFFER.lock() else {
+        return;
+    };
+    match up_to_id {
+        Some(id) => buffer.entries.retain(|entry| entry.id > id),
+        None => buffer.entries.clear(),
+    }
+}
+
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+}
+
+impl tracing::field::Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            if !self.message.is_empty() {
+                self.message.push(' ');
+            }
+            let _ = write!(self.message, "{}={:?}", field.name(), value);
+        }
+    }
+}
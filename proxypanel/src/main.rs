@@ -1,26 +1,144 @@
+mod access_log;
+mod api_limit;
 mod app;
+mod authz;
+mod balance;
+mod block_reason;
+mod blocklist_file;
+mod events;
 mod geo;
 mod geo_update;
+mod health;
+mod http_connect;
+mod live;
+mod log_buffer;
+mod metrics;
+mod migrate;
+mod panel_socket;
 mod port_range;
 mod protocol;
+mod proxy_protocol;
+mod rdns;
+mod resolve;
+mod rules_file;
+mod sni;
+mod sockopt;
+mod talkers;
+mod tls;
 mod udp_proxy;
+mod webhook;
 #[cfg(windows)]
 mod service;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
 use tokio_util::sync::CancellationToken;
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 #[derive(Parser)]
 #[command(author, version, about = "TCP proxy manager with web panel\n\nCross-platform commands:\n  install             Install as system service\n  run                 Run in console mode\n\nLinux specific:\n  uninstall-service   Uninstall systemd service\n  generate-service    Generate systemd service file\n\nExample usage:\n  proxy_panel --http-addr 0.0.0.0:1024 --data-dir /data --allowed-networks 10.250.1.0/16 install --service-name ProxyPanel\n  proxy_panel --http-addr 0.0.0.0:9090 run\n  proxy_panel generate-service > /etc/systemd/system/proxy-panel.service")]
 struct Cli {
-    #[arg(long, default_value = "0.0.0.0:8080")]
+    #[arg(long, default_value = "0.0.0.0:8080", help = "Panel address: host:port, or unix:/path/to.sock for a Unix socket (Unix only)")]
     http_addr: String,
     #[arg(long, default_value = "data")]
     data_dir: String,
     #[arg(long, value_delimiter = ',', help = "Allowed IP networks (e.g., 10.250.1.0/16,192.168.1.0/24)")]
     allowed_networks: Vec<String>,
+    #[arg(long, default_value_t = 0.1, help = "Random spread applied to the geo DB refresh interval (0.0-1.0)")]
+    geo_update_jitter: f64,
+    #[arg(long, default_value_t = 24, help = "Hours between geo DB update checks")]
+    geo_update_interval: u64,
+    #[arg(long, help = "Never download geo DBs; only use files already in the data dir")]
+    no_geo_update: bool,
+    #[arg(long, value_delimiter = ',', help = "URLs tried in order to download GeoLite2-Country.mmdb")]
+    geo_db_urls: Option<Vec<String>>,
+    #[arg(long, help = "Seconds to let connections of a disabled rule finish before force-closing them")]
+    drain_timeout: Option<u64>,
+    #[arg(
+        long,
+        default_value_t = 10,
+        help = "Seconds to let open connections finish on shutdown before force-closing them (0 closes them at once)"
+    )]
+    shutdown_timeout: u64,
+    #[cfg(unix)]
+    #[arg(long, help = "Unix datagram socket that receives JSON connection events")]
+    event_socket: Option<std::path::PathBuf>,
+    #[arg(long, help = "Append a JSON line per closed or blocked connection to this file")]
+    access_log: Option<std::path::PathBuf>,
+    #[arg(long, help = "Rotate the access log when it reaches this many megabytes")]
+    access_log_max_mb: Option<u64>,
+    #[arg(long, default_value_t = app::DEFAULT_ACCESS_LOG_KEEP, help = "Size-rotated access log files to keep")]
+    access_log_keep: usize,
+    #[arg(long, help = "Write the access log to one file per UTC day (<path>.YYYY-MM-DD)")]
+    access_log_daily: bool,
+    #[arg(long, help = "Disable rules with invalid listen/target addresses at startup instead of trying to bind them")]
+    disable_invalid_rules: bool,
+    #[arg(long, help = "Write state.json without indentation (smaller, faster saves)")]
+    compact_state: bool,
+    #[arg(long, default_value_t = 10, help = "Seconds to wait for a TCP target to accept before giving up")]
+    connect_timeout: u64,
+    #[arg(long, help = "URL of an HTTP service that approves or denies clients not in the local lists")]
+    auth_url: Option<String>,
+    #[arg(long, default_value_t = 30, help = "Seconds to cache auth service verdicts")]
+    auth_cache_ttl: u64,
+    #[arg(long, default_value_t = 500, help = "Milliseconds to wait for the auth service")]
+    auth_timeout_ms: u64,
+    #[arg(long, help = "Allow connections when the auth service is unreachable or errors (default: deny)")]
+    auth_fail_open: bool,
+    #[arg(long, help = "Close TCP connections after this many seconds without traffic")]
+    tcp_idle_timeout: Option<u64>,
+    #[arg(long, default_value_t = 60, help = "End UDP sessions after this many seconds without datagrams")]
+    udp_idle_timeout: u64,
+    #[arg(long, default_value_t = 100, help = "End a UDP session after this many consecutive failed sends (0 never does)")]
+    udp_max_send_errors: u32,
+    #[arg(long, default_value_t = 60, help = "Seconds to reuse resolved target hostnames (0 resolves on every connection)")]
+    dns_cache_ttl: u64,
+    #[arg(long, default_value_t = 20, help = "Countries listed individually in /metrics; the rest are reported as \"other\"")]
+    metrics_country_limit: usize,
+    #[arg(long, value_delimiter = ',', help = "URLs tried in order to download GeoLite2-ASN.mmdb (pass \"\" to never download)")]
+    asn_db_urls: Option<Vec<String>>,
+    #[arg(long, help = "Log only the client's /24 (IPv4) or /64 (IPv6) network instead of the full address")]
+    redact_client_ip: bool,
+    #[arg(long, help = "Show verified reverse DNS names of clients (sends PTR queries for every new client)")]
+    resolve_client_hostnames: bool,
+    #[arg(long, value_name = "N", help = "Refuse new connections after N consecutive failed state saves, until a save succeeds")]
+    persist_fail_safe: Option<u64>,
+    #[arg(long, value_name = "MS", default_value_t = app::DEFAULT_PERSIST_INTERVAL.as_millis() as u64, help = "Write state changes to disk at most this often, in milliseconds (0 saves after every change)")]
+    persist_interval_ms: u64,
+    #[arg(long, value_name = "PEM", help = "Serve the panel over HTTPS with this certificate chain (needs --tls-key; reloaded when the files change or on SIGHUP)")]
+    tls_cert: Option<std::path::PathBuf>,
+    #[arg(long, value_name = "PEM", help = "Private key for --tls-cert")]
+    tls_key: Option<std::path::PathBuf>,
+    #[arg(long, help = "URL to POST JSON notifications to (Slack/Discord compatible)")]
+    webhook_url: Option<String>,
+    #[arg(long, value_delimiter = ',', help = "Webhook events to send: auto_ban, rule_start_failed, geo_db_stale (default: all)")]
+    webhook_events: Option<Vec<String>>,
+    #[arg(long, default_value_t = 3, help = "Days a geo/ASN database may go without a successful update before geo_db_stale is sent")]
+    geo_stale_days: u64,
+    #[arg(long, value_name = "PATH", help = "Refuse clients listed in this IP/CIDR file (repeatable; reloaded when the file changes)")]
+    blocklist_file: Vec<std::path::PathBuf>,
+    #[arg(long, help = "Bind TCP listeners with SO_REUSEPORT and accept on several tasks per address (Unix only). Another process of the same user can then bind the same port and take a share of its connections")]
+    reuseport: bool,
+    #[arg(long, value_name = "N", help = "Acceptor tasks per listen address with --reuseport (default: number of CPUs)")]
+    reuseport_acceptors: Option<usize>,
+    #[arg(long, value_name = "N", default_value_t = app::DEFAULT_LISTEN_BACKLOG, help = "Pending-connection queue of each TCP listener; the kernel caps it (net.core.somaxconn on Linux, kern.ipc.somaxconn on BSD/macOS)")]
+    listen_backlog: u32,
+    #[arg(long, value_name = "BYTES", default_value_t = app::DEFAULT_BUFFER_SIZE, help = "Relay buffer per direction of each TCP connection (1024-16777216)")]
+    buffer_size: usize,
+    #[arg(long, value_name = "N", default_value_t = app::DEFAULT_MAX_HISTORY, help = "Connection log entries to keep")]
+    max_history: usize,
+    #[arg(long, value_name = "N", default_value_t = app::DEFAULT_MAX_HISTORY, help = "Blocked-attempt log entries to keep, separately from --max-history")]
+    max_blocked_history: usize,
+    #[arg(long, value_name = "N", default_value_t = app::DEFAULT_MAX_RULES, help = "Most rules the API accepts")]
+    max_rules: usize,
+    #[arg(long, value_name = "N", default_value_t = app::DEFAULT_MAX_LISTEN_PORTS, help = "Most listen ports across all rules, counting every port of a range")]
+    max_listen_ports: usize,
+    #[arg(long, value_name = "N", help = "Panel API requests allowed per client address per minute (429 with Retry-After beyond it)")]
+    api_max_requests_per_minute: Option<u32>,
+    #[arg(long, value_name = "PATH", help = "TOML file of [[rule]] tables kept in sync with the running rules (reloaded when it changes)")]
+    rules_file: Option<std::path::PathBuf>,
+    #[arg(long, value_name = "DAYS", value_parser = clap::value_parser!(u64).range(0..=app::MAX_HISTORY_RETENTION_DAYS), help = "Also drop connection log entries older than this many days")]
+    history_retention_days: Option<u64>,
     #[command(subcommand)]
     command: Option<Command>,
 }
@@ -28,6 +146,9 @@ struct Cli {
 #[derive(Subcommand)]
 enum Command {
     Run,
+    // Validates state.json, --rules-file and the geo DBs, then exits
+    // (non-zero when something is wrong) without binding any ports.
+    Check,
     #[cfg(windows)]
     Service {
         #[arg(long, default_value = "ProxyPanel")]
@@ -61,13 +182,117 @@ enum Command {
 #[tokio::main]
 async fn main() -> Result<()> {
     let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
-    tracing_subscriber::fmt().with_env_filter(env_filter).init();
+    tracing_subscriber::registry()
+        .with(env_filter)
+        .with(tracing_subscriber::fmt::layer())
+        .with(log_buffer::LogBufferLayer)
+        .init();
 
     let cli = Cli::parse();
-    let config = app::AppConfig::new(&cli.http_addr, &cli.data_dir, cli.allowed_networks.clone())?;
+    let mut config = app::AppConfig::new(&cli.http_addr, &cli.data_dir, cli.allowed_networks.clone())?;
+    config.geo_update_jitter = cli.geo_update_jitter;
+    config.geo_update_enabled = !cli.no_geo_update;
+    config.geo_update_interval = std::time::Duration::from_secs(cli.geo_update_interval.max(1) * 60 * 60);
+    if let Some(urls) = cli.geo_db_urls.as_ref() {
+        config.geo_db_urls = urls
+            .iter()
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .collect();
+    }
+    config.drain_timeout = cli.drain_timeout.map(std::time::Duration::from_secs);
+    config.shutdown_timeout = std::time::Duration::from_secs(cli.shutdown_timeout);
+    config.disable_invalid_rules = cli.disable_invalid_rules;
+    config.compact_state = cli.compact_state;
+    config.connect_timeout = std::time::Duration::from_secs(cli.connect_timeout.max(1));
+    config.auth_url = cli.auth_url.clone();
+    config.auth_cache_ttl = std::time::Duration::from_secs(cli.auth_cache_ttl);
+    config.auth_timeout = std::time::Duration::from_millis(cli.auth_timeout_ms.max(1));
+    config.auth_fail_open = cli.auth_fail_open;
+    config.tcp_idle_timeout = cli
+        .tcp_idle_timeout
+        .filter(|secs| *secs > 0)
+        .map(std::time::Duration::from_secs);
+    config.udp_idle_timeout = std::time::Duration::from_secs(cli.udp_idle_timeout.max(1));
+    config.udp_max_send_errors = Some(cli.udp_max_send_errors).filter(|count| *count > 0);
+    config.dns_cache_ttl = std::time::Duration::from_secs(cli.dns_cache_ttl);
+    config.metrics_country_limit = cli.metrics_country_limit;
+    if let Some(urls) = cli.asn_db_urls.as_ref() {
+        config.asn_db_urls = urls
+            .iter()
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .collect();
+    }
+    config.redact_client_ip = cli.redact_client_ip;
+    config.resolve_client_hostnames = cli.resolve_client_hostnames;
+    config.persist_fail_safe = cli.persist_fail_safe.filter(|count| *count > 0);
+    config.persist_interval = std::time::Duration::from_millis(cli.persist_interval_ms);
+    config.tls_cert = cli.tls_cert.clone();
+    config.tls_key = cli.tls_key.clone();
+    config.webhook_url = cli.webhook_url.clone();
+    if let Some(events) = cli.webhook_events.as_ref() {
+        config.webhook_events = events
+            .iter()
+            .map(|event| event.trim().to_string())
+            .filter(|event| !event.is_empty())
+            .collect();
+    }
+    config.geo_stale_after = std::time::Duration::from_secs(cli.geo_stale_days.max(1) * 24 * 60 * 60);
+    config.blocklist_files = cli.blocklist_file.clone();
+    if !(app::MIN_BUFFER_SIZE..=app::MAX_BUFFER_SIZE).contains(&cli.buffer_size) {
+        anyhow::bail!(
+            "--buffer-size must be between {} and {} bytes",
+            app::MIN_BUFFER_SIZE,
+            app::MAX_BUFFER_SIZE
+        );
+    }
+    config.buffer_size = cli.buffer_size;
+    config.max_history = cli.max_history.max(1);
+    config.max_blocked_history = cli.max_blocked_history.max(1);
+    config.max_rules = cli.max_rules;
+    config.max_listen_ports = cli.max_listen_ports;
+    config.api_max_requests_per_minute = cli.api_max_requests_per_minute.filter(|limit| *limit > 0);
+    config.rules_file = cli.rules_file.clone();
+    config.history_retention = cli
+        .history_retention_days
+        .filter(|days| *days > 0)
+        .and_then(|days| days.checked_mul(24 * 60 * 60))
+        .map(std::time::Duration::from_secs);
+    if cli.reuseport {
+        if sockopt::REUSEPORT_SUPPORTED {
+            let acceptors = cli.reuseport_acceptors.filter(|count| *count > 0).unwrap_or_else(|| {
+                std::thread::available_parallelism().map_or(1, |count| count.get())
+            });
+            config.reuseport_acceptors = Some(acceptors);
+        } else {
+            tracing::warn!("--reuseport is not supported on this platform; using a single acceptor per listener");
+        }
+    }
+    config.listen_backlog = cli.listen_backlog.max(1);
+    if let Some(max) = sockopt::max_listen_backlog().filter(|max| config.listen_backlog > *max) {
+        tracing::warn!(
+            "--listen-backlog {} exceeds net.core.somaxconn ({}); the kernel caps each listener's queue at {}",
+            config.listen_backlog,
+            max,
+            max
+        );
+    }
+    #[cfg(unix)]
+    {
+        config.event_socket = cli.event_socket.clone();
+    }
+    config.access_log = cli.access_log.clone();
+    config.access_log_max_size = cli
+        .access_log_max_mb
+        .filter(|mb| *mb > 0)
+        .map(|mb| mb.saturating_mul(1024 * 1024));
+    config.access_log_keep = cli.access_log_keep.max(1);
+    config.access_log_daily = cli.access_log_daily;
 
     match cli.command.unwrap_or(Command::Run) {
         Command::Run => run_console(config).await,
+        Command::Check => app::check_config(&config).await,
         #[cfg(windows)]
         Command::Service { service_name } => service::run_service(service_name, config),
         Command::Install { service_name } => {
@@ -106,12 +331,29 @@ async fn run_console(config: app::AppConfig) -> Result<()> {
     let shutdown = CancellationToken::new();
     let shutdown_signal = shutdown.clone();
     tokio::spawn(async move {
-        let _ = tokio::signal::ctrl_c().await;
+        wait_for_stop_signal().await;
         shutdown_signal.cancel();
     });
     app::run_app(config, shutdown).await
 }
 
+// Ctrl-C, or SIGTERM from systemd / `kill` on Unix.
+async fn wait_for_stop_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
 #[cfg(unix)]
 fn install_linux_service(
     service_name: &str,
@@ -138,8 +380,8 @@ fn install_linux_service(
     
     // Create directories
     fs::create_dir_all(install_dir)?;
-    fs::create_dir_all(&format!("{}/data", install_dir))?;
-    fs::create_dir_all(&format!("{}/logs", install_dir))?;
+    fs::create_dir_all(format!("{}/data", install_dir))?;
+    fs::create_dir_all(format!("{}/logs", install_dir))?;
     
     // Copy binary
     fs::copy(&current_exe, &binary_path)?;
@@ -234,7 +476,7 @@ Type=simple
 User={}
 Group={}
 WorkingDirectory={}
-ExecStart={} --http-addr {} --data-dir {}
+ExecStart={}/proxy_panel --http-addr {} --data-dir {}
 ExecReload=/bin/kill -HUP $MAINPID
 Restart=always
 RestartSec=5
@@ -264,7 +506,7 @@ WantedBy=multi-user.target
         service_user,
         service_user,
         install_dir,
-        format!("{}/proxy_panel", install_dir),
+        install_dir,
         http_addr,
         data_dir,
         install_dir
@@ -0,0 +1,77 @@
+use std::{collections::HashMap, fmt::Write};
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+// Minimal Prometheus text exposition writer.
+#[derive(Default)]
+pub struct MetricsWriter {
+    out: String,
+}
+
+impl MetricsWriter {
+    pub fn gauge(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, help, "gauge");
+        let _ = writeln!(self.out, "{} {}", name, value);
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, help, "counter");
+        let _ = writeln!(self.out, "{} {}", name, value);
+    }
+
+    pub fn labeled_counter(&mut self, name: &str, help: &str, label: &str, values: &[(String, u64)]) {
+        self.labeled(name, help, "counter", label, values);
+    }
+
+    // For label sets that change between scrapes, such as top_with_other's:
+    // a series that drops out or shrinks would read as a counter reset.
+    pub fn labeled_gauge(&mut self, name: &str, help: &str, label: &str, values: &[(String, u64)]) {
+        self.labeled(name, help, "gauge", label, values);
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+
+    fn labeled(&mut self, name: &str, help: &str, kind: &str, label: &str, values: &[(String, u64)]) {
+        self.header(name, help, kind);
+        for (label_value, value) in values {
+            let _ = writeln!(
+                self.out,
+                "{}{{{}=\"{}\"}} {}",
+                name,
+                label,
+                escape_label(label_value),
+                value
+            );
+        }
+    }
+
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+}
+
+// Keeps the `limit` largest entries and folds the rest into a single
+// "other" series so label cardinality stays bounded.
+pub fn top_with_other(counts: &HashMap<String, u64>, limit: usize) -> Vec<(String, u64)> {
+    let mut items = counts
+        .iter()
+        .map(|(key, value)| (key.clone(), *value))
+        .collect::<Vec<_>>();
+    items.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    if items.len() > limit {
+        let other = items[limit..].iter().map(|(_, value)| *value).sum::<u64>();
+        items.truncate(limit);
+        items.push(("other".to_string(), other));
+    }
+    items
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,76 @@
+use crate::block_reason::BlockReason;
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Value};
+
+// Upgrades the raw state.json object from version N to N + 1 (MIGRATIONS[0]
+// takes 1 to 2). Renamed or reshaped fields are handled here, before serde
+// sees them, so `#[serde(default)]` never quietly drops the old data.
+type Migration = fn(&mut Map<String, Value>);
+
+const MIGRATIONS: &[Migration] = &[split_blocked_history, categorize_blocked_history];
+
+// Files written before versioning have no `version` field and count as 1.
+pub const STATE_VERSION: u32 = MIGRATIONS.len() as u32 + 1;
+
+pub fn default_version() -> u32 {
+    1
+}
+
+// Brings the state object up to STATE_VERSION. Returns the version it
+// started at when anything was migrated, None when it was already current.
+// A file from a newer build is an error rather than a best-effort load, since
+// saving it back would drop whatever this build doesn't know about.
+pub fn migrate(fields: &mut Map<String, Value>) -> Result<Option<u32>> {
+    let found = match fields.get("version") {
+        None => default_version(),
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .filter(|version| *version >= 1)
+            .ok_or_else(|| anyhow!("invalid state version {}", version))?,
+    };
+    if found > STATE_VERSION {
+        return Err(anyhow!(
+            "state version {} is newer than this build supports ({}); upgrade proxy_panel or restore an older backup",
+            found,
+            STATE_VERSION
+        ));
+    }
+    if found == STATE_VERSION {
+        return Ok(None);
+    }
+    for migration in &MIGRATIONS[(found - 1) as usize..] {
+        migration(fields);
+    }
+    fields.insert("version".to_string(), Value::from(STATE_VERSION));
+    Ok(Some(found))
+}
+
+// 1 -> 2: blocked attempts moved out of `history` into `blocked_history`.
+fn split_blocked_history(fields: &mut Map<String, Value>) {
+    let Some(Value::Array(history)) = fields.get_mut("history") else {
+        return;
+    };
+    let (blocked, closed): (Vec<Value>, Vec<Value>) = std::mem::take(history)
+        .into_iter()
+        .partition(|entry| entry.get("blocked").and_then(Value::as_bool).unwrap_or(false));
+    *history = closed;
+    fields.insert("blocked_history".to_string(), Value::Array(blocked));
+}
+
+// 2 -> 3: blocked attempts gained `block_reason`; older ones get it from
+// their message, or stay uncategorized if it doesn't match any.
+fn categorize_blocked_history(fields: &mut Map<String, Value>) {
+    let Some(Value::Array(blocked)) = fields.get_mut("blocked_history") else {
+        return;
+    };
+    for entry in blocked.iter_mut().filter_map(Value::as_object_mut) {
+        let reason = entry
+            .get("reason")
+            .and_then(Value::as_str)
+            .and_then(BlockReason::from_message);
+        if let Some(reason) = reason.and_then(|reason| serde_json::to_value(reason).ok()) {
+            entry.insert("block_reason".to_string(), reason);
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use anyhow::{anyhow, Result};
+use std::{fmt, net::SocketAddr};
+#[cfg(unix)]
+use axum::Router;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use tokio::{net::UnixListener, task::JoinSet};
+#[cfg(unix)]
+use tokio_util::sync::CancellationToken;
+#[cfg(unix)]
+use tracing::warn;
+
+#[cfg(unix)]
+use crate::app::ACCEPT_ERROR_BACKOFF;
+
+// Where the panel listens: a TCP address, or `unix:<path>` for a Unix domain
+// socket that only local processes (typically a reverse proxy) can reach.
+#[derive(Clone, Debug)]
+pub enum PanelAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl PanelAddr {
+    pub fn parse(value: &str) -> Result<Self> {
+        if let Some(path) = value.strip_prefix("unix:") {
+            #[cfg(unix)]
+            {
+                if path.is_empty() {
+                    return Err(anyhow!("Invalid http-addr: {} (missing socket path)", value));
+                }
+                return Ok(Self::Unix(PathBuf::from(path)));
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = path;
+                return Err(anyhow!(
+                    "Invalid http-addr: {} (Unix sockets are not supported on this platform)",
+                    value
+                ));
+            }
+        }
+        value
+            .parse()
+            .map(Self::Tcp)
+            .map_err(|_| anyhow!("Invalid http-addr: {}", value))
+    }
+}
+
+impl fmt::Display for PanelAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// Serves the panel on a Unix socket until `shutdown`, then removes the socket
+// file and waits for in-flight requests to finish. No ConnectInfo is attached:
+// access is governed by the socket file's permissions, not client addresses.
+#[cfg(unix)]
+pub async fn serve(path: &Path, app: Router, shutdown: CancellationToken) -> Result<()> {
+    remove_stale_socket(path)?;
+    let listener = UnixListener::bind(path).map_err(|err| anyhow!("Bind {}: {}", path.display(), err))?;
+    let mut connections = JoinSet::new();
+    loop {
+        while connections.try_join_next().is_some() {}
+        let stream = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    warn!("Panel accept error: {}", err);
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            },
+        };
+        let app = app.clone();
+        let shutdown = shutdown.clone();
+        connections.spawn(async move {
+            let connection = hyper::server::conn::Http::new()
+                .serve_connection(stream, app)
+                .with_upgrades();
+            tokio::pin!(connection);
+            tokio::select! {
+                _ = connection.as_mut() => return,
+                _ = shutdown.cancelled() => connection.as_mut().graceful_shutdown(),
+            }
+            let _ = connection.await;
+        });
+    }
+    drop(listener);
+    let _ = std::fs::remove_file(path);
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
+
+// A socket file left behind by an unclean exit is replaced; one that still
+// accepts connections belongs to a running instance and is left alone.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(anyhow!("{} is already in use", path.display()));
+            }
+            std::fs::remove_file(path)?;
+            Ok(())
+        }
+        Ok(_) => Err(anyhow!("{} exists and is not a socket", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
@@ -7,14 +7,26 @@ pub struct ListenTarget {
     pub listen_addr: String,
     pub listen_port: u16,
     pub target_addr: String,
+    // Set on the [::] half of a `*` listen address, so it can bind alongside
+    // the 0.0.0.0 socket on the same port.
+    pub v6_only: bool,
 }
 
+// Maps listen ports to target ports, the same way for TCP and UDP:
+//   8000       -> 9000       one to one
+//   8000-8010  -> 9000       every listen port fans in to the one target
+//   8000-8010  -> 9000-9010  1:1 by position; the ranges must be equal length
+// A single listen port with a target range is rejected, since there is no
+// way to pick which target port it should use.
+//
+// A `*` host (`*:443`, `*:8000-8010`) listens on both 0.0.0.0 and [::] for
+// every port, each pair sharing the listen port.
 pub fn expand_listen_targets(listen_addr: &str, target_addr: &str) -> Result<Vec<ListenTarget>> {
     let (listen_host, listen_port_raw) = split_host_port(listen_addr)?;
-    let listen_ports = parse_ports(&listen_port_raw)?;
+    let listen_ports = parse_ports(&listen_port_raw).map_err(|err| anyhow!("Listen {}: {}", listen_addr.trim(), err))?;
 
     let (target_host, target_port_raw) = split_host_port(target_addr)?;
-    let target_ports = parse_ports(&target_port_raw)?;
+    let target_ports = parse_ports(&target_port_raw).map_err(|err| anyhow!("Target {}: {}", target_addr.trim(), err))?;
 
     let targets = if target_ports.len() == 1 {
         listen_ports
@@ -23,6 +35,7 @@ pub fn expand_listen_targets(listen_addr: &str, target_addr: &str) -> Result<Vec
                 listen_addr: format!("{}:{}", listen_host, listen_port),
                 listen_port,
                 target_addr: format!("{}:{}", target_host, target_ports[0]),
+                v6_only: false,
             })
             .collect::<Vec<_>>()
     } else if target_ports.len() == listen_ports.len() {
@@ -33,19 +46,47 @@ pub fn expand_listen_targets(listen_addr: &str, target_addr: &str) -> Result<Vec
                 listen_addr: format!("{}:{}", listen_host, listen_port),
                 listen_port,
                 target_addr: format!("{}:{}", target_host, target_ports[idx]),
+                v6_only: false,
             })
             .collect::<Vec<_>>()
+    } else if listen_ports.len() == 1 {
+        return Err(anyhow!(
+            "Single listen port {} cannot map to target port range {} ({} ports); use one target port",
+            listen_ports[0],
+            describe_ports(&target_ports),
+            target_ports.len()
+        ));
     } else {
         return Err(anyhow!(
-            "Port range mismatch: listen has {} ports, target has {} ports",
+            "Port range mismatch: listen ports {} ({} ports) vs target ports {} ({} ports); use one target port to fan in, or a range of the same length to map 1:1",
+            describe_ports(&listen_ports),
             listen_ports.len(),
+            describe_ports(&target_ports),
             target_ports.len()
         ));
     };
 
+    if listen_host == "*" {
+        return Ok(targets.into_iter().flat_map(dual_stack).collect());
+    }
     Ok(targets)
 }
 
+fn dual_stack(target: ListenTarget) -> [ListenTarget; 2] {
+    let port = target.listen_port;
+    [
+        ListenTarget {
+            listen_addr: format!("0.0.0.0:{}", port),
+            ..target.clone()
+        },
+        ListenTarget {
+            listen_addr: format!("[::]:{}", port),
+            v6_only: true,
+            ..target
+        },
+    ]
+}
+
 fn split_host_port(addr: &str) -> Result<(String, String)> {
     let addr = addr.trim();
     if addr.is_empty() {
@@ -83,11 +124,11 @@ fn parse_ports(raw: &str) -> Result<Vec<u16>> {
             return Err(anyhow!("Port range cannot include 0"));
         }
         if start > end {
-            return Err(anyhow!("Port range start is greater than end"));
+            return Err(anyhow!("Port range {}-{} starts after it ends", start, end));
         }
         let len = (end - start) as usize + 1;
         if len > MAX_PORT_RANGE {
-            return Err(anyhow!("Port range too large (max {})", MAX_PORT_RANGE));
+            return Err(anyhow!("Port range {}-{} has {} ports (max {})", start, end, len, MAX_PORT_RANGE));
         }
         return Ok((start..=end).collect());
     }
@@ -97,6 +138,108 @@ fn parse_ports(raw: &str) -> Result<Vec<u16>> {
 }
 
 fn parse_port_value(raw: &str) -> Result<u16> {
-    let value = raw.trim().parse::<u16>()?;
-    Ok(value)
+    raw.trim()
+        .parse::<u16>()
+        .map_err(|_| anyhow!("Invalid port \"{}\"", raw.trim()))
+}
+
+// Ports from parse_ports are one value or a contiguous ascending range.
+fn describe_ports(ports: &[u16]) -> String {
+    match (ports.first(), ports.last()) {
+        (Some(first), Some(last)) if first != last => format!("{}-{}", first, last),
+        (Some(first), _) => first.to_string(),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(listen_addr: &str, target_addr: &str) -> Vec<(String, String)> {
+        expand_listen_targets(listen_addr, target_addr)
+            .unwrap()
+            .into_iter()
+            .map(|target| (target.listen_addr, target.target_addr))
+            .collect()
+    }
+
+    fn error(listen_addr: &str, target_addr: &str) -> String {
+        expand_listen_targets(listen_addr, target_addr).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn single_port_maps_one_to_one() {
+        assert_eq!(
+            pairs("0.0.0.0:8000", "10.0.0.1:9000"),
+            vec![("0.0.0.0:8000".to_string(), "10.0.0.1:9000".to_string())]
+        );
+    }
+
+    #[test]
+    fn equal_ranges_map_by_position() {
+        assert_eq!(
+            pairs("0.0.0.0:8000-8002", "backend:9000-9002"),
+            vec![
+                ("0.0.0.0:8000".to_string(), "backend:9000".to_string()),
+                ("0.0.0.0:8001".to_string(), "backend:9001".to_string()),
+                ("0.0.0.0:8002".to_string(), "backend:9002".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn listen_range_fans_in_to_one_target_port() {
+        let targets = pairs("[::1]:8000-8002", "10.0.0.1:9000");
+        let listen = targets.iter().map(|(listen, _)| listen.as_str()).collect::<Vec<_>>();
+        assert_eq!(listen, vec!["[::1]:8000", "[::1]:8001", "[::1]:8002"]);
+        assert!(targets.iter().all(|(_, target)| target == "10.0.0.1:9000"));
+    }
+
+    #[test]
+    fn single_listen_port_cannot_map_to_a_target_range() {
+        assert_eq!(
+            error("0.0.0.0:8000", "10.0.0.1:9000-9002"),
+            "Single listen port 8000 cannot map to target port range 9000-9002 (3 ports); use one target port"
+        );
+    }
+
+    #[test]
+    fn ranges_of_different_lengths_are_rejected() {
+        let err = error("0.0.0.0:8000-8002", "10.0.0.1:9000-9001");
+        assert!(
+            err.starts_with("Port range mismatch: listen ports 8000-8002 (3 ports) vs target ports 9000-9001 (2 ports)"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn reversed_and_invalid_ranges_are_rejected() {
+        assert_eq!(
+            error("0.0.0.0:8010-8000", "10.0.0.1:9000"),
+            "Listen 0.0.0.0:8010-8000: Port range 8010-8000 starts after it ends"
+        );
+        assert_eq!(error("0.0.0.0:8000", "10.0.0.1:0-10"), "Target 10.0.0.1:0-10: Port range cannot include 0");
+        assert_eq!(parse_ports("1-1025").unwrap_err().to_string(), "Port range 1-1025 has 1025 ports (max 1024)");
+        assert_eq!(parse_ports("80-x").unwrap_err().to_string(), "Invalid port \"x\"");
+        assert_eq!(parse_ports("1-1024").unwrap().len(), MAX_PORT_RANGE);
+    }
+
+    #[test]
+    fn wildcard_host_listens_on_both_families() {
+        let targets = expand_listen_targets("*:443", "10.0.0.1:8443").unwrap();
+        let listen = targets
+            .iter()
+            .map(|target| (target.listen_addr.as_str(), target.v6_only))
+            .collect::<Vec<_>>();
+        assert_eq!(listen, vec![("0.0.0.0:443", false), ("[::]:443", true)]);
+    }
+
+    #[test]
+    fn describe_ports_shows_a_value_or_a_range() {
+        assert_eq!(describe_ports(&[8000]), "8000");
+        assert_eq!(describe_ports(&[8000, 8001, 8002]), "8000-8002");
+        assert_eq!(describe_ports(&[]), "");
+    }
 }
@@ -1,19 +1,14 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum ProtocolMode {
+    #[default]
     Tcp,
     Udp,
     Both,
 }
 
-impl Default for ProtocolMode {
-    fn default() -> Self {
-        ProtocolMode::Tcp
-    }
-}
-
 impl ProtocolMode {
     pub fn uses_tcp(self) -> bool {
         matches!(self, ProtocolMode::Tcp | ProtocolMode::Both)
@@ -0,0 +1,292 @@
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// Version 2, PROXY command.
+const V2_VERSION_PROXY: u8 = 0x21;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+// Longest possible v1 line, including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+const MISSING: &str = "Missing PROXY protocol header";
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolMode {
+    #[default]
+    None,
+    V1,
+    V2,
+}
+
+// Builds the header sent to the target ahead of the client's bytes, or None
+// when the rule doesn't send one. `source` is the client, `destination` the
+// address it connected to on this host.
+pub fn encode_header(mode: ProxyProtocolMode, source: SocketAddr, destination: SocketAddr) -> Option<Vec<u8>> {
+    let (source, destination) = same_family(source, destination);
+    match mode {
+        ProxyProtocolMode::None => None,
+        ProxyProtocolMode::V1 => Some(encode_v1(source, destination)),
+        ProxyProtocolMode::V2 => Some(encode_v2(source, destination)),
+    }
+}
+
+// The header carries one address family, so IPv4-mapped IPv6 addresses are
+// unwrapped and, if the two ends still differ, IPv4 is mapped into IPv6.
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let source = SocketAddr::new(source.ip().to_canonical(), source.port());
+    let destination = SocketAddr::new(destination.ip().to_canonical(), destination.port());
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(ip), IpAddr::V6(_)) => (
+            SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), source.port()),
+            destination,
+        ),
+        (IpAddr::V6(_), IpAddr::V4(ip)) => (
+            source,
+            SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), destination.port()),
+        ),
+        _ => (source, destination),
+    }
+}
+
+fn encode_v1(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        family,
+        source.ip(),
+        destination.ip(),
+        source.port(),
+        destination.port()
+    )
+    .into_bytes()
+}
+
+fn encode_v2(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = Vec::with_capacity(16 + 36);
+    header.extend_from_slice(&V2_SIGNATURE);
+    header.push(V2_VERSION_PROXY);
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            header.push(V2_TCP4);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            header.push(V2_TCP6);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&as_v6(src).octets());
+            header.extend_from_slice(&as_v6(dst).octets());
+        }
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
+
+fn as_v6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+// Reads and consumes a v1 or v2 header from the start of `reader`, leaving
+// any bytes after it unread. Returns the (source, destination) it carries, or
+// None for v1 UNKNOWN / v2 LOCAL and address families other than TCP/UDP over
+// IPv4/IPv6, where the caller should fall back to the socket's own addresses.
+pub async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<(SocketAddr, SocketAddr)>, String> {
+    let mut prefix = [0u8; 8];
+    reader.read_exact(&mut prefix).await.map_err(|_| MISSING.to_string())?;
+    if prefix.starts_with(b"PROXY ") {
+        read_v1(reader, &prefix).await
+    } else if prefix == V2_SIGNATURE[..8] {
+        read_v2(reader).await
+    } else {
+        Err(MISSING.to_string())
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(reader: &mut R, prefix: &[u8]) -> Result<Option<(SocketAddr, SocketAddr)>, String> {
+    let mut line = prefix.to_vec();
+    // Byte at a time so nothing past the CRLF is consumed.
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("v1 line too long"));
+        }
+        let byte = reader.read_u8().await.map_err(|_| invalid("v1 line truncated"))?;
+        line.push(byte);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("v1 line is not ASCII"))?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let source = parse_v1_addr(source, source_port)?;
+            let destination = parse_v1_addr(destination, destination_port)?;
+            let expect_v4 = *family == "TCP4";
+            if source.is_ipv4() != expect_v4 || destination.is_ipv4() != expect_v4 {
+                return Err(invalid("v1 address does not match family"));
+            }
+            Ok(Some((source, destination)))
+        }
+        _ => Err(invalid("malformed v1 line")),
+    }
+}
+
+fn parse_v1_addr(ip: &str, port: &str) -> Result<SocketAddr, String> {
+    let ip = ip.parse::<IpAddr>().map_err(|_| invalid("bad v1 address"))?;
+    // Ports are plain decimal without sign or leading zeros.
+    if port.is_empty() || port.starts_with('+') || (port.len() > 1 && port.starts_with('0')) {
+        return Err(invalid("bad v1 port"));
+    }
+    let port = port.parse::<u16>().map_err(|_| invalid("bad v1 port"))?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<(SocketAddr, SocketAddr)>, String> {
+    let mut rest = [0u8; 8];
+    reader.read_exact(&mut rest).await.map_err(|_| invalid("v2 header truncated"))?;
+    if rest[..4] != V2_SIGNATURE[8..] {
+        return Err(MISSING.to_string());
+    }
+    let (version_command, family) = (rest[4], rest[5]);
+    let len = u16::from_be_bytes([rest[6], rest[7]]) as usize;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported v2 version"));
+    }
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await.map_err(|_| invalid("v2 header truncated"))?;
+    match version_command & 0x0f {
+        // LOCAL: health checks from the balancer itself, no addresses.
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unsupported v2 command")),
+    }
+    // The high nibble is the address family, the low one the transport;
+    // TCP and UDP share the same address layout.
+    match family >> 4 {
+        0x1 if body.len() >= 12 => Ok(Some(v2_addresses(
+            IpAddr::from(array::<4>(&body[0..4])),
+            IpAddr::from(array::<4>(&body[4..8])),
+            &body[8..12],
+        ))),
+        0x2 if body.len() >= 36 => Ok(Some(v2_addresses(
+            IpAddr::from(array::<16>(&body[0..16])),
+            IpAddr::from(array::<16>(&body[16..32])),
+            &body[32..36],
+        ))),
+        0x1 | 0x2 => Err(invalid("v2 address block too short")),
+        _ => Ok(None),
+    }
+}
+
+fn v2_addresses(source: IpAddr, destination: IpAddr, ports: &[u8]) -> (SocketAddr, SocketAddr) {
+    (
+        SocketAddr::new(source, u16::from_be_bytes([ports[0], ports[1]])),
+        SocketAddr::new(destination, u16::from_be_bytes([ports[2], ports[3]])),
+    )
+}
+
+fn array<const N: usize>(bytes: &[u8]) -> [u8; N] {
+    let mut out = [0u8; N];
+    out.copy_from_slice(bytes);
+    out
+}
+
+fn invalid(detail: &str) -> String {
+    format!("Invalid PROXY protocol header: {}", detail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(value: &str) -> SocketAddr {
+        value.parse().unwrap()
+    }
+
+    // Reads a header from `input` and returns it with whatever was left
+    // unread behind it.
+    async fn read(input: &[u8]) -> (Result<Option<(SocketAddr, SocketAddr)>, String>, Vec<u8>) {
+        let mut reader = input;
+        let header = read_header(&mut reader).await;
+        (header, reader.to_vec())
+    }
+
+    #[tokio::test]
+    async fn reads_v1_and_leaves_the_payload() {
+        let (header, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.2 51000 443\r\nGET /").await;
+        assert_eq!(header, Ok(Some((addr("192.0.2.1:51000"), addr("198.51.100.2:443")))));
+        assert_eq!(rest, b"GET /");
+
+        let (header, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 1 2\r\n").await;
+        assert_eq!(header, Ok(Some((addr("[2001:db8::1]:1"), addr("[2001:db8::2]:2")))));
+    }
+
+    #[tokio::test]
+    async fn reads_v2_and_leaves_the_payload() {
+        let source = addr("192.0.2.1:51000");
+        let destination = addr("198.51.100.2:443");
+        let mut input = encode_header(ProxyProtocolMode::V2, source, destination).unwrap();
+        input.extend_from_slice(b"payload");
+        let (header, rest) = read(&input).await;
+        assert_eq!(header, Ok(Some((source, destination))));
+        assert_eq!(rest, b"payload");
+
+        let source = addr("[2001:db8::1]:1");
+        let destination = addr("[2001:db8::2]:2");
+        let input = encode_header(ProxyProtocolMode::V2, source, destination).unwrap();
+        assert_eq!(read(&input).await.0, Ok(Some((source, destination))));
+    }
+
+    #[tokio::test]
+    async fn unknown_and_local_fall_back_to_socket_addresses() {
+        let (header, rest) = read(b"PROXY UNKNOWN\r\nrest").await;
+        assert_eq!(header, Ok(None));
+        assert_eq!(rest, b"rest");
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        local.extend_from_slice(b"rest");
+        let (header, rest) = read(&local).await;
+        assert_eq!(header, Ok(None));
+        assert_eq!(rest, b"rest");
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_headers() {
+        assert_eq!(read(b"PROX").await.0, Err(MISSING.to_string()));
+        assert_eq!(
+            read(b"PROXY TCP4 192.0.2.1").await.0,
+            Err(invalid("v1 line truncated"))
+        );
+
+        let full = encode_header(ProxyProtocolMode::V2, addr("192.0.2.1:1"), addr("192.0.2.2:2")).unwrap();
+        assert_eq!(read(&full[..14]).await.0, Err(invalid("v2 header truncated")));
+        assert_eq!(read(&full[..full.len() - 1]).await.0, Err(invalid("v2 header truncated")));
+    }
+
+    #[tokio::test]
+    async fn rejects_an_oversize_v1_line() {
+        let mut input = b"PROXY TCP4 ".to_vec();
+        input.extend(std::iter::repeat_n(b'1', V1_MAX_LEN));
+        input.extend_from_slice(b"\r\n");
+        assert_eq!(read(&input).await.0, Err(invalid("v1 line too long")));
+    }
+
+    #[tokio::test]
+    async fn rejects_other_protocols() {
+        assert_eq!(read(b"GET / HTTP/1.1\r\n\r\n").await.0, Err(MISSING.to_string()));
+        assert_eq!(
+            read(b"PROXY TCP4 192.0.2.1 198.51.100.2 01 443\r\n").await.0,
+            Err(invalid("bad v1 port"))
+        );
+        assert_eq!(
+            read(b"PROXY TCP4 2001:db8::1 198.51.100.2 1 443\r\n").await.0,
+            Err(invalid("v1 address does not match family"))
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+const POSITIVE_TTL: Duration = Duration::from_secs(600);
+const NEGATIVE_TTL: Duration = Duration::from_secs(60);
+const MAX_ENTRIES: usize = 10_000;
+
+struct CacheEntry {
+    hostname: Option<String>,
+    expires_at: Instant,
+}
+
+#[derive(Default)]
+pub struct ReverseDnsCache {
+    entries: Mutex<HashMap<IpAddr, CacheEntry>>,
+}
+
+impl ReverseDnsCache {
+    // Returns the PTR hostname for `ip` only if it forward-resolves back to
+    // `ip`, so a spoofed PTR record can't claim a trusted domain.
+    pub async fn verified_hostname(&self, ip: IpAddr) -> Option<String> {
+        {
+            let guard = self.entries.lock().await;
+            if let Some(entry) = guard.get(&ip) {
+                if entry.expires_at > Instant::now() {
+                    return entry.hostname.clone();
+                }
+            }
+        }
+
+        let hostname = tokio::time::timeout(LOOKUP_TIMEOUT, lookup_verified(ip))
+            .await
+            .ok()
+            .flatten();
+        let ttl = if hostname.is_some() { POSITIVE_TTL } else { NEGATIVE_TTL };
+
+        let mut guard = self.entries.lock().await;
+        if guard.len() >= MAX_ENTRIES {
+            let now = Instant::now();
+            guard.retain(|_, entry| entry.expires_at > now);
+            if guard.len() >= MAX_ENTRIES {
+                guard.clear();
+            }
+        }
+        guard.insert(
+            ip,
+            CacheEntry {
+                hostname: hostname.clone(),
+                expires_at: Instant::now() + ttl,
+            },
+        );
+        hostname
+    }
+
+    // A still-fresh cached answer, without waiting on the lock or the network.
+    pub fn cached_hostname(&self, ip: IpAddr) -> Option<String> {
+        let guard = self.entries.try_lock().ok()?;
+        guard
+            .get(&ip)
+            .filter(|entry| entry.expires_at > Instant::now())
+            .and_then(|entry| entry.hostname.clone())
+    }
+}
+
+async fn lookup_verified(ip: IpAddr) -> Option<String> {
+    let hostname = tokio::task::spawn_blocking(move || dns_lookup::lookup_addr(&ip))
+        .await
+        .ok()?
+        .ok()?;
+    let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+    if hostname.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    let confirmed = tokio::net::lookup_host((hostname.as_str(), 0))
+        .await
+        .ok()?
+        .any(|addr| addr.ip() == ip);
+    if confirmed {
+        Some(hostname)
+    } else {
+        None
+    }
+}
+
+pub fn normalize_suffix(value: &str) -> Option<String> {
+    let trimmed = value.trim().trim_start_matches('.').trim_end_matches('.');
+    if trimmed.is_empty() {
+        return None;
+    }
+    Some(trimmed.to_ascii_lowercase())
+}
+
+pub fn hostname_matches(hostname: &str, suffixes: &[String]) -> bool {
+    suffixes.iter().any(|suffix| {
+        hostname == suffix
+            || (hostname.len() > suffix.len()
+                && hostname.ends_with(suffix.as_str())
+                && hostname.as_bytes()[hostname.len() - suffix.len() - 1] == b'.')
+    })
+}
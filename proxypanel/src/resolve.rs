@@ -0,0 +1,101 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+pub const DEFAULT_TTL: Duration = Duration::from_secs(60);
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct CacheEntry {
+    ips: Vec<IpAddr>,
+    expires_at: Instant,
+}
+
+// Target hostname -> addresses, so hostname targets aren't looked up on every
+// connection. Entries are keyed by host alone: a port range on one hostname
+// shares a single lookup. Expired entries are refreshed on next use; if the
+// refresh fails the stale addresses keep being used.
+pub struct TargetResolver {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl TargetResolver {
+    // A zero TTL resolves on every call.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // All addresses for a host:port target, in resolver order. IP literals
+    // are returned as is.
+    pub async fn resolve(&self, target: &str) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(addr) = target.parse::<SocketAddr>() {
+            return Ok(vec![addr]);
+        }
+        let (host, port) = target
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid target {}", target)))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        let ips = self.resolve_host(&host).await?;
+        Ok(ips.into_iter().map(|ip| SocketAddr::new(ip, port)).collect())
+    }
+
+    async fn resolve_host(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let stale = {
+            let guard = self.entries.lock().await;
+            match guard.get(host) {
+                Some(entry) if entry.expires_at > Instant::now() => return Ok(entry.ips.clone()),
+                Some(entry) => Some(entry.ips.clone()),
+                None => None,
+            }
+        };
+
+        let lookup = tokio::time::timeout(LOOKUP_TIMEOUT, tokio::net::lookup_host((host, 0)))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "DNS lookup timed out")))
+            .map(|addrs| {
+                let mut ips = Vec::new();
+                for addr in addrs {
+                    if !ips.contains(&addr.ip()) {
+                        ips.push(addr.ip());
+                    }
+                }
+                ips
+            })
+            .and_then(|ips| {
+                if ips.is_empty() {
+                    Err(io::Error::new(io::ErrorKind::NotFound, "no addresses"))
+                } else {
+                    Ok(ips)
+                }
+            });
+        let ips = match (lookup, stale) {
+            (Ok(ips), _) => ips,
+            (Err(err), Some(stale)) => {
+                warn!("Refreshing {} failed, keeping cached addresses: {}", host, err);
+                stale
+            }
+            (Err(err), None) => {
+                return Err(io::Error::new(err.kind(), format!("{} does not resolve: {}", host, err)));
+            }
+        };
+        if !self.ttl.is_zero() {
+            self.entries.lock().await.insert(
+                host.to_string(),
+                CacheEntry {
+                    ips: ips.clone(),
+                    expires_at: Instant::now() + self.ttl,
+                },
+            );
+        }
+        Ok(ips)
+    }
+}
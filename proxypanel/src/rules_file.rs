@@ -0,0 +1,61 @@
+use crate::app::{self, AppState};
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Rules declared in a TOML file (--rules-file), one table per rule with the
+// same fields as POST /api/rules:
+//
+//   [[rule]]
+//   name = "web"
+//   listen_addr = "0.0.0.0:443"
+//   target_addr = "10.0.0.5:443"
+//
+// A rule is identified across reloads by its listen_addr: changing anything
+// else updates it in place (keeping its id and usage), changing the
+// listen_addr replaces it. File rules can't be edited over the API, and the
+// file wins over API rules: an API rule whose listen address a file rule
+// needs is disabled (not deleted), while the API refuses new rules that
+// collide with an enabled file rule.
+#[derive(Deserialize)]
+struct RulesFile<T> {
+    #[serde(default = "Vec::new")]
+    rule: Vec<T>,
+}
+
+pub(crate) fn parse<T: DeserializeOwned>(path: &Path, text: &str) -> Result<Vec<T>> {
+    let file: RulesFile<T> = toml::from_str(text).map_err(|err| anyhow!("{}: {}", path.display(), err))?;
+    Ok(file.rule)
+}
+
+pub(crate) fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+// Polls the file's mtime and reconciles the rules when it changes. A file
+// that fails to read or parse leaves the current rules running.
+pub fn start_reloader(state: Arc<RwLock<AppState>>, path: PathBuf) {
+    tokio::spawn(async move {
+        let mut loaded = modified(&path);
+        loop {
+            tokio::time::sleep(RELOAD_CHECK_INTERVAL).await;
+            let current = modified(&path);
+            if current.is_none() || current == loaded {
+                continue;
+            }
+            loaded = current;
+            match app::reload_rules_file(&state, &path).await {
+                Ok(summary) => info!("Reloaded rules file {}: {}", path.display(), summary),
+                Err(err) => warn!("{:#}; keeping current rules", err),
+            }
+        }
+    });
+}
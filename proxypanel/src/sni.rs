@@ -0,0 +1,369 @@
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::Instant;
+
+// Largest ClientHello accepted, across however many records it spans; real
+// ones, post-quantum key shares included, stay well under this.
+const MAX_HELLO_LEN: usize = 64 * 1024;
+const RECORD_HEADER_LEN: usize = 5;
+const MAX_RECORD_LEN: usize = 16384 + 2048;
+const CONTENT_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const NAME_TYPE_HOST: u8 = 0;
+
+// What `read_client_hello` took off the connection: every byte read, which
+// must reach the target before relaying starts, and the lowercased SNI host
+// or why there isn't one.
+pub struct ClientHello {
+    pub consumed: Vec<u8>,
+    pub server_name: Result<String, String>,
+}
+
+// Reads the TLS records carrying the ClientHello from the start of `reader`
+// without answering anything. Gives up at `deadline`; whatever arrived by
+// then is still returned in `consumed` so a pass-through fallback loses
+// nothing.
+pub async fn read_client_hello<R: AsyncRead + Unpin>(reader: &mut R, deadline: Instant) -> ClientHello {
+    let mut consumed = Vec::new();
+    let server_name = read_server_name(reader, deadline, &mut consumed).await;
+    ClientHello { consumed, server_name }
+}
+
+async fn read_server_name<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    deadline: Instant,
+    consumed: &mut Vec<u8>,
+) -> Result<String, String> {
+    // A first byte that can't start a TLS record settles it at once. A
+    // protocol where the server speaks first sends no byte at all, so it
+    // waits out the whole deadline here.
+    fill(reader, deadline, consumed, 1).await?;
+    if consumed[0] != CONTENT_HANDSHAKE {
+        return Err("not a TLS connection".to_string());
+    }
+    let mut handshake = Vec::new();
+    let mut position = 0;
+    loop {
+        fill(reader, deadline, consumed, position + RECORD_HEADER_LEN).await?;
+        let header = &consumed[position..position + RECORD_HEADER_LEN];
+        if header[0] != CONTENT_HANDSHAKE || header[1] != 3 {
+            return Err("not a TLS connection".to_string());
+        }
+        let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+        if length == 0 || length > MAX_RECORD_LEN {
+            return Err("malformed TLS record".to_string());
+        }
+        let start = position + RECORD_HEADER_LEN;
+        fill(reader, deadline, consumed, start + length).await?;
+        handshake.extend_from_slice(&consumed[start..start + length]);
+        position = start + length;
+
+        if handshake.len() < 4 {
+            continue;
+        }
+        if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+            return Err("TLS handshake did not start with a ClientHello".to_string());
+        }
+        let hello_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+        if hello_len > MAX_HELLO_LEN {
+            return Err("ClientHello too large".to_string());
+        }
+        if handshake.len() >= 4 + hello_len {
+            return parse_server_name(&handshake[4..4 + hello_len])
+                .ok_or_else(|| "malformed ClientHello".to_string())?
+                .ok_or_else(|| "ClientHello has no SNI".to_string());
+        }
+    }
+}
+
+// Reads until `buffer` holds at least `len` bytes; a read may bring more,
+// which stays in the buffer for the replay.
+async fn fill<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    deadline: Instant,
+    buffer: &mut Vec<u8>,
+    len: usize,
+) -> Result<(), String> {
+    while buffer.len() < len {
+        match tokio::time::timeout_at(deadline, reader.read_buf(buffer)).await {
+            Ok(Ok(0)) => return Err("client closed before the ClientHello".to_string()),
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => return Err(format!("ClientHello read failed: {}", err)),
+            Err(_) => return Err("ClientHello timed out".to_string()),
+        }
+    }
+    Ok(())
+}
+
+// None when the message is malformed, Some(None) when it carries no
+// host_name entry.
+fn parse_server_name(hello: &[u8]) -> Option<Option<String>> {
+    let mut reader = Reader(hello);
+    reader.skip(2 + 32)?; // legacy_version, random
+    let session_id = reader.u8()? as usize;
+    reader.skip(session_id)?;
+    let cipher_suites = reader.u16()? as usize;
+    reader.skip(cipher_suites)?;
+    let compression = reader.u8()? as usize;
+    reader.skip(compression)?;
+    if reader.0.is_empty() {
+        return Some(None);
+    }
+    let extensions_len = reader.u16()? as usize;
+    let mut extensions = Reader(reader.take(extensions_len)?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let body = extensions.take(len)?;
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut body = Reader(body);
+        let list_len = body.u16()? as usize;
+        let mut names = Reader(body.take(list_len)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let len = names.u16()? as usize;
+            let name = names.take(len)?;
+            if name_type == NAME_TYPE_HOST {
+                return normalize_host(name).map(Some);
+            }
+        }
+        return Some(None);
+    }
+    Some(None)
+}
+
+fn normalize_host(name: &[u8]) -> Option<String> {
+    let name = std::str::from_utf8(name).ok()?;
+    let name = name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase();
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '.' || ch == '-' || ch == '_');
+    valid.then_some(name)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+// Entries are a host name, matched exactly, or `*.domain` for any name under
+// that domain (not the domain itself).
+pub fn host_listed(entries: &[String], host: &str) -> bool {
+    entries.iter().any(|entry| match entry.strip_prefix("*.") {
+        Some(suffix) => host.strip_suffix(suffix).is_some_and(|rest| rest.ends_with('.')),
+        None => entry == host,
+    })
+}
+
+// Lowercases a list entry for storage; None if it isn't a host or `*.domain`.
+pub fn normalize_entry(entry: &str) -> Option<String> {
+    let entry = entry.trim();
+    match entry.strip_prefix("*.") {
+        Some(domain) => normalize_host(domain.as_bytes()).map(|domain| format!("*.{}", domain)),
+        None => normalize_host(entry.as_bytes()),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn server_name_extension(name: &str) -> Vec<u8> {
+        let mut entry = vec![NAME_TYPE_HOST];
+        entry.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        entry.extend_from_slice(name.as_bytes());
+        let mut body = (entry.len() as u16).to_be_bytes().to_vec();
+        body.extend_from_slice(&entry);
+        extension(EXTENSION_SERVER_NAME, &body)
+    }
+
+    fn extension(kind: u16, body: &[u8]) -> Vec<u8> {
+        let mut extension = kind.to_be_bytes().to_vec();
+        extension.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        extension.extend_from_slice(body);
+        extension
+    }
+
+    // The ClientHello body: version, random, a session id, two cipher
+    // suites, null compression, then `extensions` (None leaves the block
+    // out, as pre-TLS 1.2 clients may).
+    fn hello(extensions: Option<&[u8]>) -> Vec<u8> {
+        let mut hello = vec![3, 3];
+        hello.extend_from_slice(&[7; 32]);
+        hello.push(4);
+        hello.extend_from_slice(&[1, 2, 3, 4]);
+        hello.extend_from_slice(&[0, 4, 0x13, 0x01, 0x13, 0x02]);
+        hello.extend_from_slice(&[1, 0]);
+        if let Some(extensions) = extensions {
+            hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+            hello.extend_from_slice(extensions);
+        }
+        hello
+    }
+
+    // `hello` as a handshake message split into records of at most
+    // `record_len` bytes.
+    fn records(hello: &[u8], record_len: usize) -> Vec<u8> {
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(hello);
+        let mut records = Vec::new();
+        for chunk in handshake.chunks(record_len) {
+            records.extend_from_slice(&[CONTENT_HANDSHAKE, 3, 1]);
+            records.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            records.extend_from_slice(chunk);
+        }
+        records
+    }
+
+    // A ClientHello for `name` in a single record, for tests that drive a
+    // listener.
+    pub(crate) fn client_hello(name: &str) -> Vec<u8> {
+        records(&hello(Some(&server_name_extension(name))), 16384)
+    }
+
+    async fn read(input: &[u8]) -> ClientHello {
+        let mut reader = input;
+        read_client_hello(&mut reader, Instant::now() + Duration::from_secs(5)).await
+    }
+
+    fn entries(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| normalize_entry(value).unwrap()).collect()
+    }
+
+    #[test]
+    fn server_name_is_lowercased_without_the_trailing_dot() {
+        let extensions = server_name_extension("WWW.Example.com.");
+        assert_eq!(
+            parse_server_name(&hello(Some(&extensions))),
+            Some(Some("www.example.com".to_string()))
+        );
+    }
+
+    #[test]
+    fn hello_without_server_name() {
+        assert_eq!(parse_server_name(&hello(None)), Some(None));
+        assert_eq!(parse_server_name(&hello(Some(&[]))), Some(None));
+        let other = extension(0x002b, &[2, 3, 4]);
+        assert_eq!(parse_server_name(&hello(Some(&other))), Some(None));
+    }
+
+    #[test]
+    fn zero_length_extensions() {
+        // An empty extension before the server name is skipped over.
+        let mut extensions = extension(0x0017, &[]);
+        extensions.extend_from_slice(&server_name_extension("example.com"));
+        assert_eq!(
+            parse_server_name(&hello(Some(&extensions))),
+            Some(Some("example.com".to_string()))
+        );
+        // A server_name extension with no body is malformed.
+        let empty = extension(EXTENSION_SERVER_NAME, &[]);
+        assert_eq!(parse_server_name(&hello(Some(&empty))), None);
+        let empty_name = server_name_extension("");
+        assert_eq!(parse_server_name(&hello(Some(&empty_name))), None);
+    }
+
+    #[test]
+    fn overrunning_lengths_are_malformed() {
+        let mut extension = server_name_extension("example.com");
+        extension[3] += 1;
+        assert_eq!(parse_server_name(&hello(Some(&extension))), None);
+
+        let mut list = server_name_extension("example.com");
+        list[5] += 1;
+        assert_eq!(parse_server_name(&hello(Some(&list))), None);
+
+        let mut body = hello(Some(&server_name_extension("example.com")));
+        let extensions_at = body.len() - server_name_extension("example.com").len() - 2;
+        body[extensions_at + 1] += 1;
+        assert_eq!(parse_server_name(&body), None);
+    }
+
+    #[tokio::test]
+    async fn hello_split_across_records() {
+        let extensions = server_name_extension("example.com");
+        for record_len in [2, 3, 10, 40, 16384] {
+            let input = records(&hello(Some(&extensions)), record_len);
+            let mut with_data = input.clone();
+            with_data.extend_from_slice(b"early data");
+            let hello = read(&with_data).await;
+            assert_eq!(hello.server_name, Ok("example.com".to_string()), "records of {}", record_len);
+            // Everything read goes to the target; reads may run past the
+            // hello, never short of it.
+            assert!(hello.consumed.starts_with(&input));
+        }
+    }
+
+    #[tokio::test]
+    async fn not_tls_or_cut_short() {
+        let hello = read(b"SSH-2.0-OpenSSH_9.6\r\n").await;
+        assert_eq!(hello.server_name, Err("not a TLS connection".to_string()));
+        assert!(b"SSH-2.0-OpenSSH_9.6\r\n".starts_with(&hello.consumed));
+
+        let full = records(&self::hello(Some(&server_name_extension("example.com"))), 16384);
+        let hello = read(&full[..full.len() - 1]).await;
+        assert_eq!(hello.server_name, Err("client closed before the ClientHello".to_string()));
+        assert_eq!(hello.consumed, &full[..full.len() - 1]);
+
+        let mut not_hello = full.clone();
+        not_hello[RECORD_HEADER_LEN] = 2;
+        let hello = read(&not_hello).await;
+        assert_eq!(
+            hello.server_name,
+            Err("TLS handshake did not start with a ClientHello".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn silent_client_times_out() {
+        let (mut client, _server) = tokio::io::duplex(64);
+        let hello = read_client_hello(&mut client, Instant::now() + Duration::from_millis(50)).await;
+        assert_eq!(hello.server_name, Err("ClientHello timed out".to_string()));
+        assert!(hello.consumed.is_empty());
+    }
+
+    #[test]
+    fn wildcard_entries_match_subdomains_only() {
+        let listed = entries(&["*.Example.com", "exact.test."]);
+        assert_eq!(listed, vec!["*.example.com", "exact.test"]);
+        assert!(host_listed(&listed, "api.example.com"));
+        assert!(host_listed(&listed, "a.b.example.com"));
+        assert!(!host_listed(&listed, "example.com"));
+        assert!(!host_listed(&listed, "evilexample.com"));
+        assert!(host_listed(&listed, "exact.test"));
+        assert!(!host_listed(&listed, "sub.exact.test"));
+    }
+
+    #[test]
+    fn invalid_entries_are_rejected() {
+        assert_eq!(normalize_entry("exa mple.com"), None);
+        assert_eq!(normalize_entry("*."), None);
+        assert_eq!(normalize_entry(""), None);
+        assert_eq!(normalize_entry("*.*.example.com"), None);
+    }
+}
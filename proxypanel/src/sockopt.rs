@@ -0,0 +1,218 @@
+use socket2::{SockRef, TcpKeepalive};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::net::TcpSocket;
+
+pub const MAX_DSCP: u8 = 63;
+
+// DSCP occupies the upper six bits of the IPv4 TOS / IPv6 traffic class byte.
+pub fn set_dscp(socket: SockRef<'_>, dscp: u8, ipv6: bool) -> io::Result<()> {
+    let value = u32::from(dscp.min(MAX_DSCP)) << 2;
+    if ipv6 {
+        set_tclass_v6(socket, value)
+    } else {
+        socket.set_tos(value)
+    }
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+fn set_tclass_v6(socket: SockRef<'_>, value: u32) -> io::Result<()> {
+    socket.set_tclass_v6(value)
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd"
+)))]
+fn set_tclass_v6(_socket: SockRef<'_>, _value: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "IPv6 traffic class is not supported on this platform",
+    ))
+}
+
+// SO_KEEPALIVE with the first probe after `idle`. The probe interval is left
+// to the OS where it can't be set per socket.
+pub fn set_keepalive(socket: SockRef<'_>, idle: Duration, interval: Option<Duration>) -> io::Result<()> {
+    let keepalive = TcpKeepalive::new().with_time(idle);
+    socket.set_tcp_keepalive(&with_interval(keepalive, interval))
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "windows"
+))]
+fn with_interval(keepalive: TcpKeepalive, interval: Option<Duration>) -> TcpKeepalive {
+    match interval {
+        Some(interval) => keepalive.with_interval(interval),
+        None => keepalive,
+    }
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "windows"
+)))]
+fn with_interval(keepalive: TcpKeepalive, _interval: Option<Duration>) -> TcpKeepalive {
+    keepalive
+}
+
+// Outbound socket bound to a local source address before connecting.
+pub fn bound_socket(source: IpAddr, target: SocketAddr) -> io::Result<TcpSocket> {
+    let socket = if target.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.bind(SocketAddr::new(source, 0))?;
+    Ok(socket)
+}
+
+pub const TRANSPARENT_SUPPORTED: bool = cfg!(target_os = "linux");
+
+// Outbound socket for transparent egress: IP_TRANSPARENT lets it bind to the
+// client's address even though that address is not local, so the target sees
+// the real client as the source.
+//
+// This needs CAP_NET_ADMIN, and replies from the target must be routed back
+// through this host, typically with the target using this host as its
+// gateway plus policy routing here, e.g.:
+//
+//   iptables -t mangle -A PREROUTING -p tcp -m socket --transparent -j MARK --set-mark 1
+//   ip rule add fwmark 1 lookup 100
+//   ip route add local 0.0.0.0/0 dev lo table 100
+//
+// Without the return route the handshake never completes and connects time out.
+#[cfg(target_os = "linux")]
+pub fn transparent_socket(source: IpAddr, target: SocketAddr) -> io::Result<TcpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(target), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_ip_transparent(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::new(source, 0).into())?;
+    Ok(TcpSocket::from_std_stream(socket.into()))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn transparent_socket(_source: IpAddr, _target: SocketAddr) -> io::Result<TcpSocket> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "transparent egress is only supported on Linux",
+    ))
+}
+
+pub const REUSEPORT_SUPPORTED: bool = cfg!(all(unix, not(any(target_os = "solaris", target_os = "illumos"))));
+
+// `count` listening sockets on the same address, each with SO_REUSEPORT, so
+// every acceptor task gets its own accept queue. How connections are spread
+// is up to the kernel: Linux hashes them across the sockets, while some BSDs
+// hand them all to the most recently bound one.
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+pub fn reuseport_listeners(
+    addr: SocketAddr,
+    count: usize,
+    v6_only: bool,
+    backlog: u32,
+) -> io::Result<Vec<std::net::TcpListener>> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    (0..count.max(1))
+        .map(|_| {
+            let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+            if v6_only {
+                socket.set_only_v6(true)?;
+            }
+            socket.set_reuse_address(true)?;
+            socket.set_reuse_port(true)?;
+            socket.set_nonblocking(true)?;
+            socket.bind(&addr.into())?;
+            socket.listen(backlog_arg(backlog))?;
+            Ok(socket.into())
+        })
+        .collect()
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+pub fn reuseport_listeners(
+    _addr: SocketAddr,
+    _count: usize,
+    _v6_only: bool,
+    _backlog: u32,
+) -> io::Result<Vec<std::net::TcpListener>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
+
+// A listener with an explicit accept queue (--listen-backlog). `v6_only`
+// sets IPV6_V6ONLY for the [::] half of a dual-stack rule; without it, [::]
+// would also claim the IPv4 port on most hosts and collide with the rule's
+// own 0.0.0.0 socket.
+pub fn tcp_listener(addr: SocketAddr, v6_only: bool, backlog: u32) -> io::Result<std::net::TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if v6_only {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog_arg(backlog))?;
+    Ok(socket.into())
+}
+
+fn backlog_arg(backlog: u32) -> i32 {
+    i32::try_from(backlog.max(1)).unwrap_or(i32::MAX)
+}
+
+// The kernel silently truncates larger backlogs to net.core.somaxconn on
+// Linux (kern.ipc.somaxconn on the BSDs and macOS, not checked here).
+#[cfg(target_os = "linux")]
+pub fn max_listen_backlog() -> Option<u32> {
+    std::fs::read_to_string("/proc/sys/net/core/somaxconn")
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn max_listen_backlog() -> Option<u32> {
+    None
+}
+
+pub fn v6_only_udp_socket(addr: SocketAddr) -> io::Result<std::net::UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
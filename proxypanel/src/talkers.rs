@@ -0,0 +1,94 @@
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+
+const BUCKET_SECS: i64 = 60;
+// Longest window /api/top-talkers can look back over.
+pub const MAX_WINDOW_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Copy, Default)]
+struct Totals {
+    connections: u64,
+    bytes_up: u64,
+    bytes_down: u64,
+}
+
+#[derive(Serialize)]
+pub struct TalkerEntry {
+    pub client_ip: String,
+    pub connections: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub bytes: u64,
+}
+
+// Bytes per client IP of closed connections, in one-minute buckets by end
+// time covering the last MAX_WINDOW_SECS. A leaderboard sums the buckets in
+// its window instead of rescanning the history, and trimming the history
+// doesn't shrink it.
+#[derive(Default)]
+pub struct TopTalkers {
+    buckets: VecDeque<(i64, HashMap<String, Totals>)>,
+}
+
+impl TopTalkers {
+    // `ended_at` is a unix timestamp; entries older than the longest window
+    // are ignored.
+    pub fn record(&mut self, client_ip: &str, ended_at: i64, bytes_up: u64, bytes_down: u64) {
+        let start = ended_at - ended_at.rem_euclid(BUCKET_SECS);
+        let latest = ended_at.max(self.newest());
+        self.prune(latest);
+        if start < oldest_kept(latest) {
+            return;
+        }
+        let position = self.buckets.partition_point(|(bucket, _)| *bucket < start);
+        if self.buckets.get(position).is_none_or(|(bucket, _)| *bucket != start) {
+            self.buckets.insert(position, (start, HashMap::new()));
+        }
+        let totals = self.buckets[position].1.entry(client_ip.to_string()).or_default();
+        totals.connections += 1;
+        totals.bytes_up = totals.bytes_up.saturating_add(bytes_up);
+        totals.bytes_down = totals.bytes_down.saturating_add(bytes_down);
+    }
+
+    // Busiest clients over the `window_secs` before `now`, most bytes first.
+    pub fn top(&self, now: i64, window_secs: u64, limit: usize) -> Vec<TalkerEntry> {
+        let since = now - window_secs.min(MAX_WINDOW_SECS) as i64;
+        let mut totals: HashMap<&str, Totals> = HashMap::new();
+        for (_, bucket) in self.buckets.iter().filter(|(start, _)| *start + BUCKET_SECS > since) {
+            for (ip, value) in bucket {
+                let total = totals.entry(ip).or_default();
+                total.connections += value.connections;
+                total.bytes_up = total.bytes_up.saturating_add(value.bytes_up);
+                total.bytes_down = total.bytes_down.saturating_add(value.bytes_down);
+            }
+        }
+        let mut entries = totals
+            .into_iter()
+            .map(|(ip, total)| TalkerEntry {
+                client_ip: ip.to_string(),
+                connections: total.connections,
+                bytes_up: total.bytes_up,
+                bytes_down: total.bytes_down,
+                bytes: total.bytes_up.saturating_add(total.bytes_down),
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.client_ip.cmp(&b.client_ip)));
+        entries.truncate(limit);
+        entries
+    }
+
+    fn newest(&self) -> i64 {
+        self.buckets.back().map_or(i64::MIN, |(start, _)| *start)
+    }
+
+    fn prune(&mut self, now: i64) {
+        let oldest = oldest_kept(now);
+        while self.buckets.front().is_some_and(|(start, _)| *start < oldest) {
+            self.buckets.pop_front();
+        }
+    }
+}
+
+fn oldest_kept(now: i64) -> i64 {
+    now - MAX_WINDOW_SECS as i64 - BUCKET_SECS
+}
@@ -0,0 +1,203 @@
+use anyhow::{anyhow, Context, Result};
+use axum::{extract::ConnectInfo, Extension, Router};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use serde::Serialize;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::{net::TcpListener, task::JoinSet};
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::app::{now_string, ACCEPT_ERROR_BACKOFF};
+
+// Clients that connect but never finish the handshake are dropped after this.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// How often the cert and key files are checked for a renewal written in
+// place (certbot, acme.sh); SIGHUP and POST /api/tls/reload act at once.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+// Modification time and size of the cert and key files.
+type FileStamp = [Option<(SystemTime, u64)>; 2];
+
+// Certificate and key for the panel, reloadable in place. A reload only
+// affects handshakes that start after it; open connections keep their
+// session.
+pub struct PanelTls {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<(Arc<ServerConfig>, TlsInfo)>,
+    // The files as of the last load attempt, successful or not.
+    stamp: Mutex<FileStamp>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct TlsInfo {
+    pub cert_path: String,
+    pub key_path: String,
+    pub certificates: usize,
+    pub loaded_at: String,
+}
+
+impl PanelTls {
+    // Both paths or neither; a cert or key that can't be loaded fails startup.
+    pub fn from_paths(cert_path: Option<&Path>, key_path: Option<&Path>) -> Result<Option<Self>> {
+        let (cert_path, key_path) = match (cert_path, key_path) {
+            (Some(cert_path), Some(key_path)) => (cert_path.to_path_buf(), key_path.to_path_buf()),
+            (None, None) => return Ok(None),
+            _ => return Err(anyhow!("--tls-cert and --tls-key must be given together")),
+        };
+        let stamp = file_stamp(&cert_path, &key_path);
+        let current = load(&cert_path, &key_path)?;
+        Ok(Some(Self {
+            cert_path,
+            key_path,
+            current: RwLock::new(current),
+            stamp: Mutex::new(stamp),
+        }))
+    }
+
+    // Re-reads both files; on error the previous certificate stays in use.
+    pub fn reload(&self) -> Result<TlsInfo> {
+        self.set_stamp(file_stamp(&self.cert_path, &self.key_path));
+        let loaded = load(&self.cert_path, &self.key_path)?;
+        let info = loaded.1.clone();
+        if let Ok(mut current) = self.current.write() {
+            *current = loaded;
+        }
+        info!("Panel TLS certificate reloaded from {}", self.cert_path.display());
+        Ok(info)
+    }
+
+    // True when either file changed since the last load attempt. A renewal
+    // that writes the cert and key separately can fail to load in between;
+    // the second write changes the stamp again and retries.
+    fn files_changed(&self) -> bool {
+        let stamp = file_stamp(&self.cert_path, &self.key_path);
+        self.stamp.lock().is_ok_and(|last| *last != stamp)
+    }
+
+    fn set_stamp(&self, stamp: FileStamp) {
+        if let Ok(mut last) = self.stamp.lock() {
+            *last = stamp;
+        }
+    }
+
+    fn acceptor(&self) -> Option<TlsAcceptor> {
+        self.current
+            .read()
+            .ok()
+            .map(|current| TlsAcceptor::from(current.0.clone()))
+    }
+}
+
+fn file_stamp(cert_path: &Path, key_path: &Path) -> FileStamp {
+    [cert_path, key_path].map(|path| {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    })
+}
+
+// Polls the cert and key files and reloads when they change, so automated
+// renewals are picked up without a signal.
+pub fn start_reloader(tls: Arc<PanelTls>) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(RELOAD_CHECK_INTERVAL);
+        tick.tick().await;
+        loop {
+            tick.tick().await;
+            if !tls.files_changed() {
+                continue;
+            }
+            if let Err(err) = tls.reload() {
+                warn!("Panel TLS reload after a file change failed: {:#}", err);
+            }
+        }
+    });
+}
+
+fn load(cert_path: &Path, key_path: &Path) -> Result<(Arc<ServerConfig>, TlsInfo)> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read TLS certificate {}", cert_path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates found in {}", cert_path.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("Failed to read TLS key {}", key_path.display()))?;
+    let certificates = certs.len();
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .with_context(|| format!("TLS key {} does not match the certificate", key_path.display()))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let info = TlsInfo {
+        cert_path: cert_path.display().to_string(),
+        key_path: key_path.display().to_string(),
+        certificates,
+        loaded_at: now_string(),
+    };
+    Ok((Arc::new(config), info))
+}
+
+// Serves the panel over TLS until `shutdown`; in-flight requests are then
+// allowed to finish before this returns. ConnectInfo is attached per
+// connection so handlers see the client address just as with the plain
+// HTTP server.
+pub async fn serve(
+    addr: SocketAddr,
+    app: Router,
+    tls: Arc<PanelTls>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let mut connections = JoinSet::new();
+    loop {
+        while connections.try_join_next().is_some() {}
+        let (stream, peer_addr) = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(value) => value,
+                Err(err) => {
+                    warn!("Panel accept error: {}", err);
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            },
+        };
+        let Some(acceptor) = tls.acceptor() else {
+            continue;
+        };
+        let app = app.clone().layer(Extension(ConnectInfo(peer_addr)));
+        let shutdown = shutdown.clone();
+        connections.spawn(async move {
+            let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream));
+            let stream = tokio::select! {
+                _ = shutdown.cancelled() => return,
+                handshake = handshake => match handshake {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(err)) => {
+                        warn!("Panel TLS handshake with {} failed: {}", peer_addr, err);
+                        return;
+                    }
+                    Err(_) => return,
+                },
+            };
+            let connection = hyper::server::conn::Http::new()
+                .serve_connection(stream, app)
+                .with_upgrades();
+            tokio::pin!(connection);
+            tokio::select! {
+                _ = connection.as_mut() => return,
+                _ = shutdown.cancelled() => connection.as_mut().graceful_shutdown(),
+            }
+            let _ = connection.await;
+        });
+    }
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
@@ -1,7 +1,7 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::{
     collections::HashMap,
-    net::SocketAddr,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -12,18 +12,73 @@ use tokio::{
 use tokio_util::sync::CancellationToken;
 use tracing::{info, warn};
 
-use crate::app::{record_blocked, record_connection_end, register_connection, AppState, ListenerHandle};
+use crate::app::{
+    canonical_ip, connection_bytes, record_blocked, record_connection_end, register_connection, AcceptErrorCounter, AppState, ByteCounter,
+    FiveTuple, ListenerHandle,
+};
+use crate::protocol::ProtocolMode;
+use crate::resolve::TargetResolver;
+use crate::sockopt;
 
 const UDP_BUFFER_SIZE: usize = 65_507;
-const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// Idle sessions are looked for on this tick, so one is reaped up to a tick
+// after its timeout. Timeouts shorter than the tick check at the timeout.
 const UDP_IDLE_TICK: Duration = Duration::from_secs(5);
+pub const DEFAULT_UDP_MAX_SEND_ERRORS: u32 = 100;
+
+#[derive(Clone)]
+pub(crate) struct UdpOptions {
+    pub(crate) log_five_tuple: bool,
+    pub(crate) idle_timeout: Duration,
+    pub(crate) source_addr: Option<IpAddr>,
+    pub(crate) resolver: Arc<TargetResolver>,
+    pub(crate) dscp: Option<u8>,
+    pub(crate) accept_errors: AcceptErrorCounter,
+    pub(crate) mirror: Option<Arc<UdpMirror>>,
+    // Consecutive failed sends to the target that end the session.
+    pub(crate) max_send_errors: Option<u32>,
+}
+
+// Copies client->target datagrams to a collector over one socket shared by
+// all of a rule's listeners. Copies are best-effort: a full send buffer or
+// any error drops them without touching the primary flow, and nothing the
+// collector sends back is read.
+pub(crate) struct UdpMirror {
+    socket: UdpSocket,
+    addr: SocketAddr,
+}
+
+impl UdpMirror {
+    pub(crate) async fn bind(addr: &str) -> Result<Self> {
+        let addr = tokio::net::lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow!("mirror_addr {} did not resolve", addr))?;
+        let bind_addr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        Ok(Self { socket, addr })
+    }
+
+    fn send(&self, datagram: &[u8]) {
+        let _ = self.socket.try_send_to(datagram, self.addr);
+    }
+}
 
 struct ClientEntry {
     conn_id: u64,
     upstream: Arc<UdpSocket>,
+    target: SocketAddr,
     last_seen: Instant,
     bytes_up: u64,
     bytes_down: u64,
+    // Shared with the active connection table.
+    bytes: Arc<ByteCounter>,
+    // Failed sends to the target since the last one that went out.
+    send_failures: u32,
+    // Cancelled to end just this session; close_reason says why.
+    session: CancellationToken,
+    close_reason: Option<String>,
 }
 
 pub(crate) async fn start_udp_listener(
@@ -31,9 +86,20 @@ pub(crate) async fn start_udp_listener(
     rule_id: u64,
     listen_addr: String,
     listen_port: Option<u16>,
+    v6_only: bool,
     target_addr: String,
+    options: UdpOptions,
 ) -> Result<ListenerHandle> {
-    let listener = Arc::new(UdpSocket::bind(listen_addr.as_str()).await?);
+    let listener = if v6_only {
+        UdpSocket::from_std(sockopt::v6_only_udp_socket(listen_addr.parse()?)?)?
+    } else {
+        UdpSocket::bind(listen_addr.as_str()).await?
+    };
+    let listener = Arc::new(listener);
+    let local_addr = listener.local_addr()?;
+    if let Some(dscp) = options.dscp {
+        apply_dscp(&listener, dscp);
+    }
     let shutdown = CancellationToken::new();
     let shutdown_task = shutdown.clone();
     let clients: Arc<Mutex<HashMap<SocketAddr, ClientEntry>>> = Arc::new(Mutex::new(HashMap::new()));
@@ -55,11 +121,12 @@ pub(crate) async fn start_udp_listener(
                             Ok(value) => value,
                             Err(err) => {
                                 warn!("UDP recv error: {}", err);
+                                options.accept_errors.record_error();
                                 continue;
                             }
                         };
 
-                        let client_ip = client_addr.ip().to_string();
+                        let client_ip = canonical_ip(client_addr.ip());
                         let mut needs_session = false;
                         {
                             let guard = clients.lock().await;
@@ -69,34 +136,81 @@ pub(crate) async fn start_udp_listener(
                         }
 
                         if needs_session {
-                            let conn_id = match register_connection(&state, rule_id, &client_ip, listen_port).await {
+                            let five_tuple = if options.log_five_tuple {
+                                Some(FiveTuple {
+                                    protocol: ProtocolMode::Udp,
+                                    client_addr: client_addr.to_string(),
+                                    local_addr: local_addr.to_string(),
+                                    target_addr: Some(target_addr.clone()),
+                                })
+                            } else {
+                                None
+                            };
+                            let conn_id = match register_connection(&state, rule_id, &client_ip, listen_port, ProtocolMode::Udp, five_tuple.clone()).await {
                                 Ok(value) => value,
                                 Err(reason) => {
-                                    record_blocked(&state, rule_id, listen_port, client_ip, reason).await;
+                                    record_blocked(&state, rule_id, listen_port, ProtocolMode::Udp, client_ip, reason, five_tuple).await;
                                     continue;
                                 }
                             };
 
-                            let upstream = match UdpSocket::bind("0.0.0.0:0").await {
+                            // First address of the source's family; the upstream
+                            // socket is bound to match it.
+                            let target = options
+                                .resolver
+                                .resolve(&target_addr)
+                                .await
+                                .and_then(|addrs| {
+                                    addrs
+                                        .into_iter()
+                                        .find(|addr| options.source_addr.is_none_or(|source| source.is_ipv4() == addr.is_ipv4()))
+                                        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address in the source address's family"))
+                                });
+                            let target = match target {
+                                Ok(target) => target,
+                                Err(err) => {
+                                    options.accept_errors.record_dropped();
+                                    let _ = record_connection_end(&state, conn_id, 0, 0, Some(format!("UDP target resolve failed: {}", err)), None).await;
+                                    continue;
+                                }
+                            };
+                            let source = options.source_addr.unwrap_or(if target.is_ipv4() {
+                                IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+                            } else {
+                                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+                            });
+                            let upstream = match UdpSocket::bind(SocketAddr::new(source, 0)).await {
                                 Ok(socket) => socket,
                                 Err(err) => {
-                                    let _ = record_connection_end(&state, conn_id, 0, 0, Some(format!("UDP bind failed: {}", err))).await;
+                                    options.accept_errors.record_dropped();
+                                    let _ = record_connection_end(&state, conn_id, 0, 0, Some(format!("UDP bind failed: {}", err)), Some(target.to_string())).await;
                                     continue;
                                 }
                             };
 
-                            if let Err(err) = upstream.connect(target_addr.as_str()).await {
-                                let _ = record_connection_end(&state, conn_id, 0, 0, Some(format!("UDP connect failed: {}", err))).await;
+                            if let Some(dscp) = options.dscp {
+                                apply_dscp(&upstream, dscp);
+                            }
+
+                            if let Err(err) = upstream.connect(target).await {
+                                options.accept_errors.record_dropped();
+                                let _ = record_connection_end(&state, conn_id, 0, 0, Some(format!("UDP connect failed: {}", err)), Some(target.to_string())).await;
                                 continue;
                             }
 
                             let upstream = Arc::new(upstream);
+                            let session = shutdown.child_token();
                             let entry = ClientEntry {
                                 conn_id,
                                 upstream: upstream.clone(),
+                                target,
                                 last_seen: Instant::now(),
                                 bytes_up: 0,
                                 bytes_down: 0,
+                                bytes: connection_bytes(&state, conn_id).await,
+                                send_failures: 0,
+                                session: session.clone(),
+                                close_reason: None,
                             };
 
                             {
@@ -113,23 +227,47 @@ pub(crate) async fn start_udp_listener(
                                 clients.clone(),
                                 client_addr,
                                 upstream,
-                                shutdown.clone(),
+                                options.clone(),
+                                session,
                             );
                         }
 
-                        let upstream = {
+                        let (upstream, failing) = {
                             let mut guard = clients.lock().await;
                             if let Some(entry) = guard.get_mut(&client_addr) {
                                 entry.bytes_up = entry.bytes_up.saturating_add(len as u64);
+                                entry.bytes.add(len as u64);
                                 entry.last_seen = Instant::now();
-                                entry.upstream.clone()
+                                (entry.upstream.clone(), entry.send_failures > 0)
                             } else {
                                 continue;
                             }
                         };
 
-                        if let Err(err) = upstream.send(&buf[..len]).await {
-                            warn!("UDP send error: {}", err);
+                        // The lock is only retaken when the failure streak
+                        // changes, not for every datagram that goes out.
+                        match upstream.send(&buf[..len]).await {
+                            Ok(_) if failing => {
+                                if let Some(entry) = clients.lock().await.get_mut(&client_addr) {
+                                    entry.send_failures = 0;
+                                }
+                            }
+                            Ok(_) => {}
+                            Err(err) => {
+                                warn!("UDP send error: {}", err);
+                                options.accept_errors.record_send_error();
+                                if let Some(entry) = clients.lock().await.get_mut(&client_addr) {
+                                    entry.bytes.record_send_error();
+                                    entry.send_failures += 1;
+                                    if options.max_send_errors.is_some_and(|max| entry.send_failures >= max) && entry.close_reason.is_none() {
+                                        entry.close_reason = Some(format!("UDP send failed ({} in a row): {}", entry.send_failures, err));
+                                        entry.session.cancel();
+                                    }
+                                }
+                            }
+                        }
+                        if let Some(mirror) = options.mirror.as_ref() {
+                            mirror.send(&buf[..len]);
                         }
                     }
                 }
@@ -141,20 +279,29 @@ pub(crate) async fn start_udp_listener(
     Ok(ListenerHandle { shutdown, task })
 }
 
+fn apply_dscp(socket: &UdpSocket, dscp: u8) {
+    let ipv6 = socket.local_addr().map(|addr| addr.is_ipv6()).unwrap_or(false);
+    if let Err(err) = sockopt::set_dscp(socket2::SockRef::from(socket), dscp, ipv6) {
+        warn!("Failed to set DSCP {}: {}", dscp, err);
+    }
+}
+
 fn spawn_upstream_task(
     state: Arc<RwLock<AppState>>,
     listener: Arc<UdpSocket>,
     clients: Arc<Mutex<HashMap<SocketAddr, ClientEntry>>>,
     client_addr: SocketAddr,
     upstream: Arc<UdpSocket>,
-    shutdown: CancellationToken,
+    options: UdpOptions,
+    session: CancellationToken,
 ) {
+    let idle_timeout = options.idle_timeout;
     tokio::spawn(async move {
         let mut buf = vec![0u8; UDP_BUFFER_SIZE];
-        let mut tick = tokio::time::interval(UDP_IDLE_TICK);
+        let mut tick = tokio::time::interval(UDP_IDLE_TICK.min(idle_timeout));
         loop {
             tokio::select! {
-                _ = shutdown.cancelled() => {
+                _ = session.cancelled() => {
                     break;
                 }
                 recv = upstream.recv(&mut buf) => {
@@ -167,11 +314,17 @@ fn spawn_upstream_task(
                     };
                     if let Err(err) = listener.send_to(&buf[..len], client_addr).await {
                         warn!("UDP send_to error: {}", err);
+                        options.accept_errors.record_send_error();
+                        if let Some(entry) = clients.lock().await.get_mut(&client_addr) {
+                            entry.bytes.record_send_error();
+                            entry.close_reason.get_or_insert_with(|| format!("UDP send to client failed: {}", err));
+                        }
                         break;
                     }
                     let mut guard = clients.lock().await;
                     if let Some(entry) = guard.get_mut(&client_addr) {
                         entry.bytes_down = entry.bytes_down.saturating_add(len as u64);
+                        entry.bytes.add(len as u64);
                         entry.last_seen = Instant::now();
                     }
                 }
@@ -179,7 +332,7 @@ fn spawn_upstream_task(
                     let idle = {
                         let guard = clients.lock().await;
                         match guard.get(&client_addr) {
-                            Some(entry) => entry.last_seen.elapsed() > UDP_IDLE_TIMEOUT,
+                            Some(entry) => entry.last_seen.elapsed() >= idle_timeout,
                             None => true,
                         }
                     };
@@ -190,12 +343,13 @@ fn spawn_upstream_task(
             }
         }
 
+        drop(listener);
         let entry = {
             let mut guard = clients.lock().await;
             guard.remove(&client_addr)
         };
         if let Some(entry) = entry {
-            let _ = record_connection_end(&state, entry.conn_id, entry.bytes_up, entry.bytes_down, None).await;
+            let _ = record_connection_end(&state, entry.conn_id, entry.bytes_up, entry.bytes_down, entry.close_reason, Some(entry.target.to_string())).await;
         }
     });
 }
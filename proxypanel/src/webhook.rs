@@ -0,0 +1,79 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::time::Duration;
+use tracing::warn;
+
+use crate::app::now_string;
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const AUTO_BAN: &str = "auto_ban";
+pub const RULE_START_FAILED: &str = "rule_start_failed";
+pub const GEO_DB_STALE: &str = "geo_db_stale";
+pub const EVENT_TYPES: [&str; 3] = [AUTO_BAN, RULE_START_FAILED, GEO_DB_STALE];
+
+// POSTs a JSON notification per significant event. Each notification is
+// sent from its own task, so a slow or dead endpoint never holds up the
+// caller; failures are only logged.
+pub struct Webhook {
+    client: reqwest::Client,
+    url: String,
+    events: Vec<String>,
+}
+
+impl Webhook {
+    // An empty `events` list enables every event type.
+    pub fn new(url: &str, events: &[String]) -> Result<Self> {
+        for event in events {
+            if !EVENT_TYPES.contains(&event.as_str()) {
+                return Err(anyhow!(
+                    "Unknown webhook event {} (expected one of {})",
+                    event,
+                    EVENT_TYPES.join(", ")
+                ));
+            }
+        }
+        let events = if events.is_empty() {
+            EVENT_TYPES.iter().map(|event| event.to_string()).collect()
+        } else {
+            events.to_vec()
+        };
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(SEND_TIMEOUT)
+                .user_agent("proxy-panel/0.1")
+                .build()?,
+            url: url.to_string(),
+            events,
+        })
+    }
+
+    // `text` is a one-line summary, sent as both `text` (Slack) and
+    // `content` (Discord) next to the event fields.
+    pub fn notify<T: Serialize>(&self, event: &str, text: String, details: &T) {
+        if !self.events.iter().any(|enabled| enabled == event) {
+            return;
+        }
+        let mut body = match serde_json::to_value(details) {
+            Ok(Value::Object(map)) => map,
+            _ => Map::new(),
+        };
+        body.insert("event".to_string(), Value::from(event));
+        body.insert("timestamp".to_string(), Value::from(now_string()));
+        body.insert("content".to_string(), Value::from(text.clone()));
+        body.insert("text".to_string(), Value::from(text));
+
+        let request = self.client.post(&self.url).json(&body);
+        let event = event.to_string();
+        tokio::spawn(async move {
+            match request.send().await {
+                Ok(response) if !response.status().is_success() => {
+                    warn!("Webhook {} rejected ({})", event, response.status());
+                }
+                Ok(_) => {}
+                Err(err) => warn!("Webhook {} failed: {}", event, err),
+            }
+        });
+    }
+}